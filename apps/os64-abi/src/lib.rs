@@ -0,0 +1,285 @@
+//! 内核与用户程序共用的系统调用约定。
+//!
+//! 用户程序通过 `int 0x80` 进入内核: rax 为调用号, 参数依次放在 rdi, rsi, rdx, r10, r8, r9,
+//! 返回值放在 rax 中, 失败时为负的错误码 (-Errno)。除 rax 外其它寄存器保持不变。
+//! 字符串列表(如 EXEC 的参数)是依次排列的以 0 结尾的字符串, 与总字节数一起传递。
+#![no_std]
+
+use core::fmt;
+
+pub mod syscall;
+
+//最早实现的 5 个系统调用
+pub const OS64_API_EXIT             : u64 = 0x00000001;
+pub const OS64_API_YIELD            : u64 = 0x00000002;
+pub const OS64_API_PRINT            : u64 = 0x00000003;
+pub const OS64_API_HEAP_ALLOC       : u64 = 0x00000004;
+pub const OS64_API_HEAP_FREE        : u64 = 0x00000005;
+//内存
+pub const OS64_API_BRK              : u64 = 0x00000006;
+pub const OS64_API_SHM_CREATE       : u64 = 0x00000007;
+pub const OS64_API_SHM_MAP          : u64 = 0x00000008;
+pub const OS64_API_SHM_UNLINK       : u64 = 0x00000009;
+pub const OS64_API_MMAP_FILE        : u64 = 0x0000000A;
+//进程
+pub const OS64_API_FORK             : u64 = 0x0000000B;
+pub const OS64_API_EXEC             : u64 = 0x0000000C;
+//文件
+pub const OS64_API_OPEN             : u64 = 0x0000000D;
+pub const OS64_API_CLOSE            : u64 = 0x0000000E;
+pub const OS64_API_READ             : u64 = 0x0000000F;
+pub const OS64_API_WRITE            : u64 = 0x00000010;
+pub const OS64_API_SEEK             : u64 = 0x00000011;
+pub const OS64_API_STAT             : u64 = 0x00000012;
+pub const OS64_API_READDIR          : u64 = 0x00000013;
+pub const OS64_API_MKDIR            : u64 = 0x00000014;
+pub const OS64_API_UNLINK           : u64 = 0x00000015;
+pub const OS64_API_RENAME           : u64 = 0x00000016;
+//内存
+pub const OS64_API_MMAP             : u64 = 0x00000017;
+pub const OS64_API_MUNMAP           : u64 = 0x00000018;
+pub const OS64_API_MPROTECT         : u64 = 0x00000019;
+pub const OS64_API_MEMORY_USAGE     : u64 = 0x0000001A;
+//进程间通信
+pub const OS64_API_CHANNEL_CREATE   : u64 = 0x0000001B;
+pub const OS64_API_SEND             : u64 = 0x0000001C;
+pub const OS64_API_RECEIVE          : u64 = 0x0000001D;
+pub const OS64_API_CALL             : u64 = 0x0000001E;
+pub const OS64_API_PORT_CREATE      : u64 = 0x0000001F;
+pub const OS64_API_PORT_CONNECT     : u64 = 0x00000020;
+pub const OS64_API_PIPE             : u64 = 0x00000021;
+pub const OS64_API_DUP2             : u64 = 0x00000022;
+//进程生命周期和信号
+pub const OS64_API_WAITPID          : u64 = 0x00000023;
+pub const OS64_API_KILL             : u64 = 0x00000024;
+pub const OS64_API_SIGNAL           : u64 = 0x00000025;
+pub const OS64_API_SIGRETURN        : u64 = 0x00000026;
+pub const OS64_API_GETPID           : u64 = 0x00000027;
+pub const OS64_API_GETPPID          : u64 = 0x00000028;
+
+/// 文件名的最大长度
+pub const MAX_PATH_SIZE : u64 = 256;
+/// PRINT 一次最多输出的字节数
+pub const MAX_PRINT_SIZE : u64 = 4096;
+/// 参数和环境变量字符串的总大小上限
+pub const MAX_ARGUMENT_SIZE : usize = 16 * 1024;
+/// READ/WRITE 一次最多传输的字节数, 更多的数据要分多次
+pub const MAX_IO_SIZE : u64 = 64 * 1024;
+/// 每个进程最多打开的文件数
+pub const MAX_OPEN_FILES : usize = 64;
+
+//每个进程默认打开的描述符, 都指向控制台
+pub const STDIN : u64 = 0;
+pub const STDOUT : u64 = 1;
+pub const STDERR : u64 = 2;
+
+//OPEN 的模式, 与内核 file_system::FileOpenMode 相同; OPEN_EXISTING 与 OPEN_CREATE 至少要有一个
+/// 打开已存在的文件
+pub const OPEN_EXISTING : u64 = 0x01;
+/// 文件不存在时创建; 没有 OPEN_EXISTING 时文件必须不存在
+pub const OPEN_CREATE : u64 = 0x02;
+pub const OPEN_READ : u64 = 0x04;
+/// 写入, 已存在的文件先被清空
+pub const OPEN_WRITE : u64 = 0x08;
+/// 写入总是追加到文件尾, 不清空文件
+pub const OPEN_APPEND : u64 = 0x10;
+
+//SEEK 的起点
+pub const SEEK_START : u64 = 0;
+pub const SEEK_CURRENT : u64 = 1;
+pub const SEEK_END : u64 = 2;
+
+//STAT 和 READDIR 中的文件类型
+pub const FILE_KIND_FILE : u8 = 1;
+pub const FILE_KIND_DIRECTORY : u8 = 2;
+
+/// STAT 的结果
+///
+/// `write_time` 的高 16 位为 FAT 格式的日期, 低 16 位为时间。
+/// READDIR 的每一项是 1 字节的文件类型, 后面跟着以 0 结尾的文件名。
+#[repr(C)]
+#[derive(Clone,Copy,Debug,Default,PartialEq,Eq)]
+pub struct FileStat {
+    pub id : u64,
+    pub kind : u64,
+    pub size : u64,
+    pub write_time : u64,
+}
+
+//MMAP 和 MPROTECT 的访问权限, 与内核 vma::VmaFlags 相同
+pub const PROT_READ : u64 = 0x01;
+pub const PROT_WRITE : u64 = 0x02;
+pub const PROT_EXEC : u64 = 0x04;
+/// MMAP 的标志: 映射在 fork 后由父子进程共享, 而不是写时复制
+pub const MAP_SHARED : u64 = 0x01;
+
+/// MEMORY_USAGE 的结果, 都以字节为单位
+#[repr(C)]
+#[derive(Clone,Copy,Debug,Default,PartialEq,Eq)]
+pub struct MemoryUsage {
+    /// 所有 VMA 的大小
+    pub virtual_size : u64,
+    /// 已分配物理内存的大小
+    pub resident_size : u64,
+    pub heap_size : u64,
+    pub stack_size : u64,
+    /// mmap 区域的大小, 包括共享的部分
+    pub mapped_size : u64,
+    /// 共享内存和文件映射的大小
+    pub shared_size : u64,
+}
+
+/// 一条消息最多的数据字节数
+pub const MAX_MESSAGE_SIZE : u64 = 64 * 1024;
+/// 一条消息最多携带的描述符个数
+pub const MAX_MESSAGE_HANDLES : u64 = 8;
+/// SEND 和 RECEIVE 的标志: 不阻塞, 无法立即完成时返回 EAGAIN
+pub const IPC_NONBLOCK : u64 = 0x01;
+
+/// SEND、RECEIVE 和 CALL 使用的消息缓冲区
+///
+/// 发送时 `[data, data + data_size)` 是数据, `handles` 指向 `handle_count` 个描述符, 接收方得到它们的副本;
+/// 接收时两个 size 是缓冲区的容量, 内核改为实际收到的大小, 缓冲区不够时返回 E2BIG, 消息留在队列中。
+#[repr(C)]
+#[derive(Clone,Copy,Debug,Default,PartialEq,Eq)]
+pub struct IpcMessage {
+    pub data : u64,
+    pub data_size : u64,
+    pub handles : u64,
+    pub handle_count : u64,
+}
+
+/// WAITPID 的 pid: 等待任意一个子进程
+pub const WAIT_ANY : u64 = 0;
+/// WAITPID 的标志: 没有已结束的子进程时立即返回 0
+pub const WAIT_NOHANG : u64 = 0x01;
+
+//信号, 编号与 Linux 相同; 默认的处理方式都是结束进程
+pub const SIGINT : u64 = 2;
+pub const SIGILL : u64 = 4;
+/// 不能被捕获或忽略
+pub const SIGKILL : u64 = 9;
+/// 访问了无效的内存, 由内核在缺页和保护异常时发出
+pub const SIGSEGV : u64 = 11;
+pub const SIGTERM : u64 = 15;
+/// 信号编号的上限, 有效的信号为 `1..NSIG`
+pub const NSIG : u64 = 32;
+//SIGNAL 的处理函数: 默认处理和忽略, 其它值为处理函数的地址
+pub const SIG_DFL : u64 = 0;
+pub const SIG_IGN : u64 = 1;
+
+/// 进程结束的原因, WAITPID 写回的状态是 `to_raw` 的结果
+///
+/// 与 Linux 相同, 正常退出时第 8..16 位是退出码的低 8 位, 被信号结束时低 7 位是信号。
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum ExitStatus {
+    /// 调用 EXIT 退出, 带有退出码的低 8 位
+    Exited(u8),
+    /// 被信号结束
+    Signaled(u64),
+}
+
+impl ExitStatus {
+    pub fn from_raw(status : u64) -> ExitStatus {
+        match status & 0x7F {
+            0 => ExitStatus::Exited((status >> 8) as u8),
+            signal => ExitStatus::Signaled(signal),
+        }
+    }
+
+    pub fn to_raw(self) -> u64 {
+        match self {
+            ExitStatus::Exited(code) => (code as u64) << 8,
+            ExitStatus::Signaled(signal) => signal & 0x7F,
+        }
+    }
+
+    /// 是否以退出码 0 正常退出
+    pub fn success(self) -> bool {
+        self == ExitStatus::Exited(0)
+    }
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExitStatus::Exited(code) => write!(f, "exit code {}", code),
+            ExitStatus::Signaled(signal) => write!(f, "signal {}", signal),
+        }
+    }
+}
+
+//see also: https://gitlab.com/x86-psABIs/x86-64-ABI 3.4.3 Auxiliary Vector
+pub const AT_NULL : u64 = 0;
+/// 程序头表的地址
+pub const AT_PHDR : u64 = 3;
+/// 程序头表项的大小
+pub const AT_PHENT : u64 = 4;
+/// 程序头表项的个数
+pub const AT_PHNUM : u64 = 5;
+pub const AT_PAGESZ : u64 = 6;
+/// 程序入口
+pub const AT_ENTRY : u64 = 9;
+/// 16 字节随机数的地址
+pub const AT_RANDOM : u64 = 25;
+
+/// 系统调用的错误码, 与 Linux 的编号相同
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+#[repr(i64)]
+pub enum Errno {
+    ENOENT  = 2,
+    ESRCH   = 3,
+    EINTR   = 4,
+    E2BIG   = 7,
+    EBADF   = 9,
+    ECHILD  = 10,
+    EAGAIN  = 11,
+    ENOMEM  = 12,
+    EACCES  = 13,
+    EFAULT  = 14,
+    EBUSY   = 16,
+    EEXIST  = 17,
+    EXDEV   = 18,
+    ENOTDIR = 20,
+    EISDIR  = 21,
+    EINVAL  = 22,
+    EMFILE  = 24,
+    EFBIG   = 27,
+    ESPIPE  = 29,
+    EROFS   = 30,
+    EPIPE   = 32,
+    ENOSYS  = 38,
+    ENOTEMPTY = 39,
+}
+
+impl Errno {
+    /// 所有已定义的错误码
+    pub const ALL : [Errno; 23] = [
+        Errno::ENOENT, Errno::ESRCH, Errno::EINTR, Errno::E2BIG, Errno::EBADF, Errno::ECHILD, Errno::EAGAIN, Errno::ENOMEM,
+        Errno::EACCES, Errno::EFAULT, Errno::EBUSY, Errno::EEXIST, Errno::EXDEV, Errno::ENOTDIR, Errno::EISDIR, Errno::EINVAL,
+        Errno::EMFILE, Errno::EFBIG, Errno::ESPIPE, Errno::EROFS, Errno::EPIPE, Errno::ENOSYS, Errno::ENOTEMPTY,
+    ];
+
+    /// 把 rax 中的返回值转换为结果, 未知的负值当作 ENOSYS
+    pub fn from_return(value : u64) -> Result<u64, Errno> {
+        let signed = value as i64;
+        if !(-4095..0).contains(&signed) {
+            return Ok(value);
+        }
+        Err(Errno::ALL.iter().copied().find(|errno| *errno as i64 == -signed).unwrap_or(Errno::ENOSYS))
+    }
+
+    /// 写回 rax 的返回值
+    pub fn to_return(result : Result<u64, Errno>) -> u64 {
+        match result {
+            Ok(value) => value,
+            Err(errno) => (-(errno as i64)) as u64,
+        }
+    }
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
@@ -0,0 +1,52 @@
+//! 用户程序发起系统调用的原始接口, 调用号和参数的含义见各调用号的说明
+use core::arch::asm;
+use crate::Errno;
+
+pub type SyscallResult = Result<u64, Errno>;
+
+/// # Safety
+/// 同 [`syscall6`]
+#[inline]
+pub unsafe fn syscall0(api_index : u64) -> SyscallResult {
+    syscall6(api_index, 0, 0, 0, 0, 0, 0)
+}
+
+/// # Safety
+/// 同 [`syscall6`]
+#[inline]
+pub unsafe fn syscall1(api_index : u64, arg0 : u64) -> SyscallResult {
+    syscall6(api_index, arg0, 0, 0, 0, 0, 0)
+}
+
+/// # Safety
+/// 同 [`syscall6`]
+#[inline]
+pub unsafe fn syscall2(api_index : u64, arg0 : u64, arg1 : u64) -> SyscallResult {
+    syscall6(api_index, arg0, arg1, 0, 0, 0, 0)
+}
+
+/// # Safety
+/// 同 [`syscall6`]
+#[inline]
+pub unsafe fn syscall3(api_index : u64, arg0 : u64, arg1 : u64, arg2 : u64) -> SyscallResult {
+    syscall6(api_index, arg0, arg1, arg2, 0, 0, 0)
+}
+
+/// # Safety
+/// 参数中的地址和长度必须符合 `api_index` 的约定, 内核会读写这些内存
+#[inline]
+pub unsafe fn syscall6(api_index : u64, arg0 : u64, arg1 : u64, arg2 : u64, arg3 : u64, arg4 : u64, arg5 : u64) -> SyscallResult {
+    let result : u64;
+    asm!(
+        "int 0x80",
+        inlateout("rax") api_index => result,
+        in("rdi") arg0,
+        in("rsi") arg1,
+        in("rdx") arg2,
+        in("r10") arg3,
+        in("r8") arg4,
+        in("r9") arg5,
+        options(nostack),
+    );
+    Errno::from_return(result)
+}
@@ -0,0 +1,91 @@
+//! 用户程序的堆分配器。
+//!
+//! 小的分配来自进程的堆: 堆从程序断点开始, 不够时通过 BRK 系统调用向上扩展;
+//! 较大的分配直接通过 HEAP_ALLOC/HEAP_FREE 向内核申请和归还匿名内存。
+//!
+//! ```ignore
+//! #[global_allocator]
+//! static ALLOCATOR: os64_alloc::Os64Allocator = os64_alloc::Os64Allocator::new();
+//! ```
+#![no_std]
+
+use core::{alloc::{GlobalAlloc, Layout}, ptr::{self, NonNull}};
+use linked_list_allocator::LockedHeap;
+use os64_abi::{OS64_API_BRK, OS64_API_HEAP_ALLOC, OS64_API_HEAP_FREE, syscall::{syscall1, syscall2}};
+
+const PAGE_SIZE : usize = 4096;
+/// 不小于这个大小的分配直接向内核申请匿名内存
+const LARGE_ALLOCATION : usize = 128 * 1024;
+/// 堆每次至少增长的字节数
+const HEAP_GROW_MIN : usize = 64 * 1024;
+
+/// 移动程序断点, 返回新的断点; `address` 为 0 时只返回当前断点
+fn brk(address : usize) -> usize {
+    unsafe { syscall1(OS64_API_BRK, address as u64).unwrap_or(0) as usize }
+}
+
+fn is_large(layout : &Layout) -> bool {
+    layout.size() >= LARGE_ALLOCATION && layout.align() <= PAGE_SIZE
+}
+
+pub struct Os64Allocator {
+    heap : LockedHeap,
+}
+
+impl Os64Allocator {
+    pub const fn new() -> Self {
+        Os64Allocator {
+            heap : LockedHeap::empty(),
+        }
+    }
+
+    /// 堆的大小和已使用的字节数
+    pub fn usage(&self) -> (usize, usize) {
+        let heap = self.heap.lock();
+        (heap.size(), heap.used())
+    }
+}
+
+unsafe impl GlobalAlloc for Os64Allocator {
+    unsafe fn alloc(&self, layout : Layout) -> *mut u8 {
+        if is_large(&layout) {
+            return match syscall1(OS64_API_HEAP_ALLOC, layout.size() as u64) {
+                Ok(address) => address as *mut u8,
+                Err(_) => ptr::null_mut(),
+            };
+        }
+
+        let mut heap = self.heap.lock();
+        if let Ok(block) = heap.allocate_first_fit(layout) {
+            return block.as_ptr();
+        }
+        // 堆不够, 向上移动程序断点
+        let grow = (layout.size() + layout.align()).max(HEAP_GROW_MIN);
+        let grow = (grow + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        if heap.size() == 0 {
+            let start = brk(0);
+            if brk(start + grow) < start + grow {
+                return ptr::null_mut();
+            }
+            heap.init(start, grow);
+        } else {
+            let top = heap.top();
+            if brk(top + grow) < top + grow {
+                return ptr::null_mut();
+            }
+            heap.extend(grow);
+        }
+        match heap.allocate_first_fit(layout) {
+            Ok(block) => block.as_ptr(),
+            Err(_) => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr : *mut u8, layout : Layout) {
+        if is_large(&layout) {
+            let _ = syscall2(OS64_API_HEAP_FREE, ptr as u64, layout.size() as u64);
+            return;
+        }
+        self.heap.lock().deallocate(NonNull::new_unchecked(ptr), layout);
+    }
+}
@@ -0,0 +1,142 @@
+//! 用户程序的运行时入口。
+//!
+//! 内核在进程的栈顶按 System V 的约定放好 argc、argv、envp 和辅助向量后跳到 `_start`,
+//! 这里记下它们的位置, 再调用程序定义的 `main`, 并以它的返回值结束进程。
+//!
+//! ```ignore
+//! #[no_mangle]
+//! pub extern "C" fn main(argc : usize, argv : *const *const u8, envp : *const *const u8) -> i32 {
+//!     for arg in os64_runtime::args() { /* ... */ }
+//!     0
+//! }
+//! ```
+#![no_std]
+
+use core::{arch::{asm, global_asm}, slice, str, sync::atomic::{AtomicPtr, AtomicUsize, Ordering}};
+
+use os64_abi::{OS64_API_EXIT, OS64_API_SIGRETURN};
+pub use os64_abi::{AT_NULL, AT_PHDR, AT_PHENT, AT_PHNUM, AT_PAGESZ, AT_ENTRY, AT_RANDOM};
+
+static ARGC: AtomicUsize = AtomicUsize::new(0);
+static ARGV: AtomicPtr<*const u8> = AtomicPtr::new(core::ptr::null_mut());
+static ENVP: AtomicPtr<*const u8> = AtomicPtr::new(core::ptr::null_mut());
+static AUXV: AtomicPtr<u64> = AtomicPtr::new(core::ptr::null_mut());
+
+extern "C" {
+    /// 由程序定义
+    fn main(argc : usize, argv : *const *const u8, envp : *const *const u8) -> i32;
+}
+
+// 进入时 rsp 指向 argc, 按 16 字节对齐; 把它作为参数传给 os64_runtime_start
+global_asm!(
+    ".global _start",
+    "_start:",
+    "mov rdi, rsp",
+    "and rsp, -16",
+    "call {start}",
+    "ud2",
+    start = sym os64_runtime_start,
+);
+
+// 信号处理函数返回到这里, 此时 rsp 指向内核保存的现场; 0x26 为 OS64_API_SIGRETURN
+global_asm!(
+    ".global os64_signal_return",
+    "os64_signal_return:",
+    "mov eax, 0x26",
+    "int 0x80",
+    "ud2",
+);
+
+const _ : () = assert!(OS64_API_SIGRETURN == 0x26);
+
+extern "C" {
+    fn os64_signal_return();
+}
+
+/// 信号处理函数的返回地址, 它发起 SIGRETURN 回到被信号打断的位置
+pub fn signal_trampoline() -> u64 {
+    os64_signal_return as *const () as u64
+}
+
+unsafe extern "C" fn os64_runtime_start(stack : *const u64) -> ! {
+    let argc = *stack as usize;
+    let argv = stack.add(1) as *mut *const u8;
+    let envp = argv.add(argc + 1);
+    let mut end = envp;
+    while !(*end).is_null() {
+        end = end.add(1);
+    }
+    ARGC.store(argc, Ordering::Relaxed);
+    ARGV.store(argv, Ordering::Relaxed);
+    ENVP.store(envp, Ordering::Relaxed);
+    AUXV.store(end.add(1) as *mut u64, Ordering::Relaxed);
+
+    let code = main(argc, argv, envp);
+    exit(code)
+}
+
+/// 结束进程
+pub fn exit(code : i32) -> ! {
+    unsafe {
+        asm!(
+            "int 0x80",
+            in("rax") OS64_API_EXIT,
+            in("rdi") code as i64,
+            options(noreturn),
+        );
+    }
+}
+
+/// 以 0 结尾的字符串, 不是 UTF-8 时为空
+unsafe fn c_str(pointer : *const u8) -> &'static str {
+    let mut length = 0;
+    while *pointer.add(length) != 0 {
+        length += 1;
+    }
+    str::from_utf8(slice::from_raw_parts(pointer, length)).unwrap_or("")
+}
+
+/// 以 0 结尾的指针数组中的字符串
+unsafe fn strings(list : *const *const u8) -> impl Iterator<Item = &'static str> {
+    let mut index = 0;
+    core::iter::from_fn(move || {
+        if list.is_null() || (*list.add(index)).is_null() {
+            return None;
+        }
+        index += 1;
+        Some(c_str(*list.add(index - 1)))
+    })
+}
+
+/// 程序的参数, 第一个为程序名
+pub fn args() -> impl Iterator<Item = &'static str> {
+    unsafe { strings(ARGV.load(Ordering::Relaxed)).take(ARGC.load(Ordering::Relaxed)) }
+}
+
+/// 所有环境变量, 即 `NAME=value` 拆成的名字和值
+pub fn vars() -> impl Iterator<Item = (&'static str, &'static str)> {
+    unsafe { strings(ENVP.load(Ordering::Relaxed)) }
+        .map(|var| var.split_once('=').unwrap_or((var, "")))
+}
+
+/// 环境变量 `name` 的值
+pub fn var(name : &str) -> Option<&'static str> {
+    vars().find(|(key, _)| *key == name).map(|(_, value)| value)
+}
+
+/// 辅助向量中 `kind` 的值
+pub fn aux(kind : u64) -> Option<u64> {
+    let mut entry = AUXV.load(Ordering::Relaxed) as *const u64;
+    if entry.is_null() {
+        return None;
+    }
+    unsafe {
+        while *entry != AT_NULL {
+            if *entry == kind {
+                return Some(*entry.add(1));
+            }
+            entry = entry.add(2);
+        }
+    }
+    None
+}
@@ -0,0 +1,142 @@
+//! 文件和目录
+use alloc::{string::String, vec::Vec};
+use core::mem::MaybeUninit;
+use os64_abi::*;
+use crate::{path_arguments, syscall::{syscall1, syscall2, syscall3, syscall6}};
+
+pub use os64_abi::{
+    FileStat, FILE_KIND_DIRECTORY, FILE_KIND_FILE, OPEN_APPEND, OPEN_CREATE, OPEN_EXISTING, OPEN_READ, OPEN_WRITE,
+    SEEK_CURRENT, SEEK_END, SEEK_START,
+};
+
+/// 打开的文件、目录或管道的一端, 释放时关闭
+#[derive(Debug)]
+pub struct File {
+    fd : u64,
+}
+
+impl File {
+    /// 以 `mode`(OPEN_* 的组合) 打开文件
+    pub fn open(path : &str, mode : u64) -> Result<File, Errno> {
+        let (path, length) = path_arguments(path)?;
+        let fd = unsafe { syscall3(OS64_API_OPEN, path, length, mode)? };
+        Ok(File { fd })
+    }
+
+    /// 创建文件, 已存在时清空
+    pub fn create(path : &str) -> Result<File, Errno> {
+        File::open(path, OPEN_EXISTING | OPEN_CREATE | OPEN_WRITE)
+    }
+
+    /// 接管描述符 `fd`, 例如管道的一端
+    pub fn from_fd(fd : u64) -> File {
+        File { fd }
+    }
+
+    pub fn fd(&self) -> u64 {
+        self.fd
+    }
+
+    /// 放弃所有权, 返回描述符, 之后不会自动关闭
+    pub fn into_fd(self) -> u64 {
+        let fd = self.fd;
+        core::mem::forget(self);
+        fd
+    }
+
+    /// 读到 `buffer`, 返回读到的字节数, 0 表示文件尾
+    pub fn read(&self, buffer : &mut [u8]) -> Result<usize, Errno> {
+        unsafe { syscall3(OS64_API_READ, self.fd, buffer.as_mut_ptr() as u64, buffer.len() as u64).map(|size| size as usize) }
+    }
+
+    /// 读到文件尾
+    pub fn read_to_end(&self, data : &mut Vec<u8>) -> Result<usize, Errno> {
+        let mut buffer = [0u8; 4096];
+        let mut total = 0;
+        loop {
+            let size = self.read(&mut buffer)?;
+            if size == 0 {
+                return Ok(total);
+            }
+            data.extend_from_slice(&buffer[..size]);
+            total += size;
+        }
+    }
+
+    pub fn write(&self, data : &[u8]) -> Result<usize, Errno> {
+        unsafe { syscall3(OS64_API_WRITE, self.fd, data.as_ptr() as u64, data.len() as u64).map(|size| size as usize) }
+    }
+
+    pub fn write_all(&self, data : &[u8]) -> Result<(), Errno> {
+        let mut written = 0;
+        while written < data.len() {
+            written += self.write(&data[written..])?;
+        }
+        Ok(())
+    }
+
+    /// 以 `whence`(SEEK_*) 为起点移动读写位置, 返回新的位置
+    pub fn seek(&self, offset : i64, whence : u64) -> Result<u64, Errno> {
+        unsafe { syscall3(OS64_API_SEEK, self.fd, offset as u64, whence) }
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        let _ = unsafe { syscall1(OS64_API_CLOSE, self.fd) };
+    }
+}
+
+/// 读取整个文件
+pub fn read(path : &str) -> Result<Vec<u8>, Errno> {
+    let mut data = Vec::new();
+    File::open(path, OPEN_EXISTING | OPEN_READ)?.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// 创建或清空文件并写入 `data`
+pub fn write(path : &str, data : &[u8]) -> Result<(), Errno> {
+    File::create(path)?.write_all(data)
+}
+
+pub fn stat(path : &str) -> Result<FileStat, Errno> {
+    let (path, length) = path_arguments(path)?;
+    let mut stat = MaybeUninit::<FileStat>::uninit();
+    unsafe {
+        syscall3(OS64_API_STAT, path, length, stat.as_mut_ptr() as u64)?;
+        Ok(stat.assume_init())
+    }
+}
+
+/// 目录中的所有项, 即文件类型(FILE_KIND_*)和文件名
+pub fn read_dir(path : &str) -> Result<Vec<(u8, String)>, Errno> {
+    let directory = File::open(path, OPEN_EXISTING | OPEN_READ)?;
+    let mut entries = Vec::new();
+    let mut buffer = [0u8; 1024];
+    loop {
+        let size = unsafe { syscall3(OS64_API_READDIR, directory.fd, buffer.as_mut_ptr() as u64, buffer.len() as u64)? } as usize;
+        if size == 0 {
+            return Ok(entries);
+        }
+        for entry in buffer[..size].split(|byte| *byte == 0).filter(|entry| !entry.is_empty()) {
+            entries.push((entry[0], String::from_utf8_lossy(&entry[1..]).into_owned()));
+        }
+    }
+}
+
+pub fn create_dir(path : &str) -> Result<(), Errno> {
+    let (path, length) = path_arguments(path)?;
+    unsafe { syscall2(OS64_API_MKDIR, path, length).map(|_| ()) }
+}
+
+/// 删除文件或空目录
+pub fn remove(path : &str) -> Result<(), Errno> {
+    let (path, length) = path_arguments(path)?;
+    unsafe { syscall2(OS64_API_UNLINK, path, length).map(|_| ()) }
+}
+
+pub fn rename(from : &str, to : &str) -> Result<(), Errno> {
+    let (from, from_length) = path_arguments(from)?;
+    let (to, to_length) = path_arguments(to)?;
+    unsafe { syscall6(OS64_API_RENAME, from, from_length, to, to_length, 0, 0).map(|_| ()) }
+}
@@ -0,0 +1,106 @@
+//! 标准输入、输出和错误输出
+//!
+//! 新进程的描述符 0、1、2 指向控制台(内核串口), 可以用 `process::dup2` 重定向到管道或文件。
+use alloc::{string::String, vec::Vec};
+use core::fmt;
+use os64_abi::{Errno, MAX_IO_SIZE, OS64_API_PIPE, OS64_API_READ, OS64_API_WRITE};
+use crate::{fs::File, syscall::{syscall1, syscall3}};
+
+pub use os64_abi::{STDERR, STDIN, STDOUT};
+
+fn write_all(fd : u64, mut data : &[u8]) -> Result<(), Errno> {
+    while !data.is_empty() {
+        let chunk = &data[..data.len().min(MAX_IO_SIZE as usize)];
+        let written = unsafe { syscall3(OS64_API_WRITE, fd, chunk.as_ptr() as u64, chunk.len() as u64)? };
+        data = &data[written as usize..];
+    }
+    Ok(())
+}
+
+/// 把字符串写到标准输出
+pub fn print(message : &str) -> Result<(), Errno> {
+    write_all(STDOUT, message.as_bytes())
+}
+
+/// 标准输出
+pub struct Stdout;
+
+impl fmt::Write for Stdout {
+    fn write_str(&mut self, s : &str) -> fmt::Result {
+        write_all(STDOUT, s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+/// 标准错误输出
+pub struct Stderr;
+
+impl fmt::Write for Stderr {
+    fn write_str(&mut self, s : &str) -> fmt::Result {
+        write_all(STDERR, s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+/// 标准输入
+pub struct Stdin;
+
+impl Stdin {
+    /// 读到 `buffer`, 返回读到的字节数, 0 表示输入已经结束; 没有输入时等待
+    pub fn read(&self, buffer : &mut [u8]) -> Result<usize, Errno> {
+        unsafe { syscall3(OS64_API_READ, STDIN, buffer.as_mut_ptr() as u64, buffer.len() as u64).map(|size| size as usize) }
+    }
+
+    /// 读取一行追加到 `line`, 包括结尾的 '\n', 返回读到的字节数, 0 表示输入已经结束
+    pub fn read_line(&self, line : &mut String) -> Result<usize, Errno> {
+        let mut bytes = Vec::new();
+        let mut byte = [0u8];
+        while self.read(&mut byte)? == 1 {
+            bytes.push(byte[0]);
+            if byte[0] == b'\n' {
+                break;
+            }
+        }
+        line.push_str(&String::from_utf8_lossy(&bytes));
+        Ok(bytes.len())
+    }
+}
+
+/// 创建管道, 返回读取端和写入端
+pub fn pipe() -> Result<(File, File), Errno> {
+    let mut fds = [0u64; 2];
+    unsafe { syscall1(OS64_API_PIPE, fds.as_mut_ptr() as u64)? };
+    Ok((File::from_fd(fds[0]), File::from_fd(fds[1])))
+}
+
+#[doc(hidden)]
+pub fn _print(args : fmt::Arguments) {
+    use core::fmt::Write;
+    let _ = Stdout.write_fmt(args);
+}
+
+#[doc(hidden)]
+pub fn _eprint(args : fmt::Arguments) {
+    use core::fmt::Write;
+    let _ = Stderr.write_fmt(args);
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::io::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! eprint {
+    ($($arg:tt)*) => ($crate::io::_eprint(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! eprintln {
+    () => ($crate::eprint!("\n"));
+    ($($arg:tt)*) => ($crate::eprint!("{}\n", format_args!($($arg)*)));
+}
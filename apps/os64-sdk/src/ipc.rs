@@ -0,0 +1,104 @@
+//! 进程间通信的通道和端口
+use os64_abi::*;
+use crate::{path_arguments, syscall::{syscall1, syscall2, syscall3}};
+
+pub use os64_abi::{IpcMessage, MAX_MESSAGE_HANDLES, MAX_MESSAGE_SIZE};
+
+/// 通道的一端, 释放时关闭
+#[derive(Debug)]
+pub struct Channel {
+    fd : u64,
+}
+
+fn message(data : &[u8], handles : &[u64]) -> IpcMessage {
+    IpcMessage {
+        data : data.as_ptr() as u64,
+        data_size : data.len() as u64,
+        handles : handles.as_ptr() as u64,
+        handle_count : handles.len() as u64,
+    }
+}
+
+impl Channel {
+    /// 创建通道, 返回它的两端
+    pub fn pair() -> Result<(Channel, Channel), Errno> {
+        let mut fds = [0u64; 2];
+        unsafe { syscall1(OS64_API_CHANNEL_CREATE, fds.as_mut_ptr() as u64)? };
+        Ok((Channel { fd : fds[0] }, Channel { fd : fds[1] }))
+    }
+
+    /// 接管描述符 `fd`, 例如从消息中收到的通道
+    pub fn from_fd(fd : u64) -> Channel {
+        Channel { fd }
+    }
+
+    pub fn fd(&self) -> u64 {
+        self.fd
+    }
+
+    /// 放弃所有权, 返回描述符, 之后不会自动关闭
+    pub fn into_fd(self) -> u64 {
+        let fd = self.fd;
+        core::mem::forget(self);
+        fd
+    }
+
+    fn send_with(&self, data : &[u8], handles : &[u64], flags : u64) -> Result<(), Errno> {
+        let message = message(data, handles);
+        unsafe { syscall3(OS64_API_SEND, self.fd, &message as *const IpcMessage as u64, flags).map(|_| ()) }
+    }
+
+    /// 发送数据和描述符, 对方得到描述符的副本; 对方队列满时阻塞
+    pub fn send(&self, data : &[u8], handles : &[u64]) -> Result<(), Errno> {
+        self.send_with(data, handles, 0)
+    }
+
+    /// 不阻塞地发送, 对方队列满时返回 EAGAIN
+    pub fn try_send(&self, data : &[u8], handles : &[u64]) -> Result<(), Errno> {
+        self.send_with(data, handles, IPC_NONBLOCK)
+    }
+
+    fn receive_with(&self, data : &mut [u8], handles : &mut [u64], flags : u64) -> Result<(usize, usize), Errno> {
+        let mut message = message(data, handles);
+        unsafe { syscall3(OS64_API_RECEIVE, self.fd, &mut message as *mut IpcMessage as u64, flags)? };
+        Ok((message.data_size as usize, message.handle_count as usize))
+    }
+
+    /// 接收一条消息, 返回数据的字节数和描述符的个数; 队列为空时阻塞, 缓冲区不够时返回 E2BIG
+    pub fn receive(&self, data : &mut [u8], handles : &mut [u64]) -> Result<(usize, usize), Errno> {
+        self.receive_with(data, handles, 0)
+    }
+
+    /// 不阻塞地接收, 队列为空时返回 EAGAIN
+    pub fn try_receive(&self, data : &mut [u8], handles : &mut [u64]) -> Result<(usize, usize), Errno> {
+        self.receive_with(data, handles, IPC_NONBLOCK)
+    }
+
+    /// 发送请求并等待回复, 返回回复的数据字节数和描述符个数
+    pub fn call(&self, request : &[u8], handles : &[u64], reply : &mut [u8], reply_handles : &mut [u64]) -> Result<(usize, usize), Errno> {
+        let request = message(request, handles);
+        let mut reply = message(reply, reply_handles);
+        unsafe {
+            syscall3(OS64_API_CALL, self.fd, &request as *const IpcMessage as u64, &mut reply as *mut IpcMessage as u64)?;
+        }
+        Ok((reply.data_size as usize, reply.handle_count as usize))
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        let _ = unsafe { syscall1(OS64_API_CLOSE, self.fd) };
+    }
+}
+
+/// 注册端口 `name`, 返回监听端; 每个连接的客户端以一条带一个通道描述符的空消息到达
+pub fn create_port(name : &str) -> Result<Channel, Errno> {
+    let (name, length) = path_arguments(name)?;
+    unsafe { syscall2(OS64_API_PORT_CREATE, name, length).map(Channel::from_fd) }
+}
+
+/// 连接到端口 `name`, 返回与服务通信的通道
+pub fn connect(name : &str) -> Result<Channel, Errno> {
+    let (name, length) = path_arguments(name)?;
+    unsafe { syscall2(OS64_API_PORT_CONNECT, name, length).map(Channel::from_fd) }
+}
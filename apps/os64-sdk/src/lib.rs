@@ -0,0 +1,94 @@
+//! OS64 用户程序的开发包。
+//!
+//! 包括运行时入口、panic 处理、全局分配器、`print!`/`println!`/`eprintln!` 以及每个系统调用的类型化封装,
+//! 调用号和错误码来自与内核共用的 os64-abi。
+//!
+//! ```ignore
+//! #![no_std]
+//! #![no_main]
+//!
+//! os64_sdk::entry!(main);
+//!
+//! fn main() -> i32 {
+//!     os64_sdk::println!("hello, {:?}", os64_sdk::args().collect::<alloc::vec::Vec<_>>());
+//!     0
+//! }
+//! ```
+#![no_std]
+
+pub extern crate alloc;
+
+pub mod fs;
+pub mod io;
+pub mod ipc;
+pub mod memory;
+pub mod process;
+pub mod signal;
+pub mod syscall;
+
+pub use os64_abi::Errno;
+pub use os64_runtime::{args, aux, var, vars};
+pub use process::exit;
+
+/// 把 `fn() -> T` 作为程序的入口, `T` 为 `()`、`i32` 或 `Result<(), E>`
+#[macro_export]
+macro_rules! entry {
+    ($main:path) => {
+        #[export_name = "main"]
+        pub extern "C" fn __os64_main(_argc : usize, _argv : *const *const u8, _envp : *const *const u8) -> i32 {
+            $crate::Termination::report($main())
+        }
+    };
+}
+
+/// 入口函数的返回值转换为退出码
+pub trait Termination {
+    fn report(self) -> i32;
+}
+
+impl Termination for () {
+    fn report(self) -> i32 {
+        0
+    }
+}
+
+impl Termination for i32 {
+    fn report(self) -> i32 {
+        self
+    }
+}
+
+impl<E : core::fmt::Display> Termination for Result<(), E> {
+    fn report(self) -> i32 {
+        match self {
+            Ok(()) => 0,
+            Err(error) => {
+                eprintln!("error: {}", error);
+                1
+            }
+        }
+    }
+}
+
+/// 文件名的地址和长度
+fn path_arguments(path : &str) -> Result<(u64, u64), Errno> {
+    if path.is_empty() || path.len() as u64 > os64_abi::MAX_PATH_SIZE {
+        return Err(Errno::EINVAL);
+    }
+    Ok((path.as_ptr() as u64, path.len() as u64))
+}
+
+#[cfg(feature = "allocator")]
+#[global_allocator]
+static ALLOCATOR : os64_alloc::Os64Allocator = os64_alloc::Os64Allocator::new();
+
+#[cfg(feature = "panic-handler")]
+#[panic_handler]
+fn panic(info : &core::panic::PanicInfo) -> ! {
+    eprintln!("{}", info);
+    exit(101)
+}
+
+#[cfg(feature = "panic-handler")]
+#[no_mangle]
+pub extern "C" fn eh_personality() {}
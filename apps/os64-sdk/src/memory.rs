@@ -0,0 +1,81 @@
+//! 内存相关的系统调用
+use os64_abi::*;
+use crate::{path_arguments, syscall::{syscall1, syscall2, syscall3}};
+
+/// 当前的程序断点
+pub fn current_brk() -> usize {
+    brk(0).unwrap_or(0)
+}
+
+/// 移动程序断点到 `address`, 返回新的断点; 无法移动时返回的仍是原来的断点
+pub fn brk(address : usize) -> Result<usize, Errno> {
+    unsafe { syscall1(OS64_API_BRK, address as u64).map(|address| address as usize) }
+}
+
+/// 分配 `size` 字节的匿名内存, 页在第一次访问时分配
+pub fn heap_alloc(size : usize) -> Result<*mut u8, Errno> {
+    unsafe { syscall1(OS64_API_HEAP_ALLOC, size as u64).map(|address| address as *mut u8) }
+}
+
+/// 释放 `heap_alloc` 分配的内存, 可以只释放其中的一部分
+///
+/// # Safety
+/// 释放后不能再访问这段内存
+pub unsafe fn heap_free(address : *mut u8, size : usize) -> Result<(), Errno> {
+    syscall2(OS64_API_HEAP_FREE, address as u64, size as u64).map(|_| ())
+}
+
+/// 映射 `size` 字节的匿名内存, `prot` 为 PROT_* 的组合, `flags` 为 0 或 MAP_SHARED
+pub fn mmap(size : usize, prot : u64, flags : u64) -> Result<*mut u8, Errno> {
+    unsafe { syscall3(OS64_API_MMAP, size as u64, prot, flags).map(|address| address as *mut u8) }
+}
+
+/// 解除 `[address, address + size)` 的映射
+///
+/// # Safety
+/// 解除后不能再访问这段内存
+pub unsafe fn munmap(address : *mut u8, size : usize) -> Result<(), Errno> {
+    syscall2(OS64_API_MUNMAP, address as u64, size as u64).map(|_| ())
+}
+
+/// 把 `[address, address + size)` 的访问权限改为 `prot`, `address` 必须按页对齐
+///
+/// # Safety
+/// 去掉权限后, 以原来的权限访问这段内存会使进程结束
+pub unsafe fn mprotect(address : *mut u8, size : usize, prot : u64) -> Result<(), Errno> {
+    syscall3(OS64_API_MPROTECT, address as u64, size as u64, prot).map(|_| ())
+}
+
+/// 当前进程的内存使用情况
+pub fn usage() -> Result<MemoryUsage, Errno> {
+    let mut usage = MemoryUsage::default();
+    unsafe { syscall1(OS64_API_MEMORY_USAGE, &mut usage as *mut MemoryUsage as u64)? };
+    Ok(usage)
+}
+
+/// 创建 `size` 字节的共享内存对象, 返回它的 id
+pub fn shm_create(size : usize) -> Result<u64, Errno> {
+    unsafe { syscall1(OS64_API_SHM_CREATE, size as u64) }
+}
+
+/// 把共享内存对象映射到当前进程, 返回起始地址
+pub fn shm_map(id : u64, writable : bool) -> Result<*mut u8, Errno> {
+    unsafe { syscall2(OS64_API_SHM_MAP, id, writable as u64).map(|address| address as *mut u8) }
+}
+
+/// 删除共享内存对象的 id, 已有的映射仍然有效
+pub fn shm_unlink(id : u64) -> Result<(), Errno> {
+    unsafe { syscall1(OS64_API_SHM_UNLINK, id).map(|_| ()) }
+}
+
+/// 映射文件的全部内容, 返回起始地址
+pub fn mmap_file(path : &str, writable : bool) -> Result<*mut u8, Errno> {
+    let (path, length) = path_arguments(path)?;
+    unsafe { syscall3(OS64_API_MMAP_FILE, path, length, writable as u64).map(|address| address as *mut u8) }
+}
+
+/// 全局分配器中堆的大小和已使用的字节数
+#[cfg(feature = "allocator")]
+pub fn heap_usage() -> (usize, usize) {
+    crate::ALLOCATOR.usage()
+}
@@ -0,0 +1,100 @@
+//! 进程相关的系统调用
+use alloc::vec::Vec;
+use os64_abi::*;
+use crate::{path_arguments, syscall::{syscall0, syscall2, syscall3, syscall6}};
+
+pub use os64_abi::{ExitStatus, WAIT_ANY};
+pub use os64_runtime::exit;
+
+/// FORK 的结果
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Fork {
+    /// 在父进程中, 带有子进程的 id
+    Parent(u64),
+    Child,
+}
+
+/// 让出处理器
+pub fn yield_now() {
+    let _ = unsafe { syscall0(OS64_API_YIELD) };
+}
+
+/// 复制当前进程, 子进程的内存写时复制
+pub fn fork() -> Result<Fork, Errno> {
+    match unsafe { syscall0(OS64_API_FORK)? } {
+        0 => Ok(Fork::Child),
+        id => Ok(Fork::Parent(id)),
+    }
+}
+
+/// 当前进程的 id
+pub fn id() -> u64 {
+    unsafe { syscall0(OS64_API_GETPID).unwrap_or(0) }
+}
+
+/// 父进程的 id, 不是由 fork 创建的进程为 0
+pub fn parent_id() -> u64 {
+    unsafe { syscall0(OS64_API_GETPPID).unwrap_or(0) }
+}
+
+fn waitpid(pid : u64, flags : u64) -> Result<Option<(u64, ExitStatus)>, Errno> {
+    let mut status = 0u64;
+    match unsafe { syscall3(OS64_API_WAITPID, pid, &mut status as *mut u64 as u64, flags)? } {
+        0 => Ok(None),
+        id => Ok(Some((id, ExitStatus::from_raw(status)))),
+    }
+}
+
+/// 等待子进程 `pid` 结束并回收它, `pid` 为 [`WAIT_ANY`] 时等待任意一个, 返回子进程的 id 和结束的原因
+///
+/// 没有这样的子进程时返回 ECHILD, 等待中收到信号时返回 EINTR。
+pub fn wait(pid : u64) -> Result<(u64, ExitStatus), Errno> {
+    waitpid(pid, 0)?.ok_or(Errno::ECHILD)
+}
+
+/// 同 [`wait`], 但子进程都还在运行时返回 `None`
+pub fn try_wait(pid : u64) -> Result<Option<(u64, ExitStatus)>, Errno> {
+    waitpid(pid, WAIT_NOHANG)
+}
+
+/// 让描述符 `new_fd` 引用与 `fd` 相同的对象, 原来的 `new_fd` 被关闭
+///
+/// 在 fork 出的子进程中 exec 之前调用, 可以重定向新程序的标准输入输出。
+pub fn dup2(fd : u64, new_fd : u64) -> Result<(), Errno> {
+    unsafe { syscall2(OS64_API_DUP2, fd, new_fd).map(|_| ()) }
+}
+
+/// 依次排列的以 0 结尾的字符串
+fn string_list(strings : &[&str]) -> Result<Vec<u8>, Errno> {
+    let mut list = Vec::new();
+    for string in strings {
+        if string.as_bytes().contains(&0) {
+            return Err(Errno::EINVAL);
+        }
+        list.extend_from_slice(string.as_bytes());
+        list.push(0);
+    }
+    if list.len() > MAX_ARGUMENT_SIZE {
+        return Err(Errno::E2BIG);
+    }
+    Ok(list)
+}
+
+/// 用文件 `path` 中的程序替换当前进程, 成功时不返回
+///
+/// `args` 为空时以 `path` 作为 argv[0]。
+pub fn exec(path : &str, args : &[&str], env : &[&str]) -> Errno {
+    let run = || -> Result<u64, Errno> {
+        let (path, length) = path_arguments(path)?;
+        let args = string_list(args)?;
+        let env = string_list(env)?;
+        unsafe {
+            syscall6(OS64_API_EXEC, path, length, args.as_ptr() as u64, args.len() as u64,
+                env.as_ptr() as u64, env.len() as u64)
+        }
+    };
+    match run() {
+        Ok(_) => Errno::ENOSYS,
+        Err(errno) => errno,
+    }
+}
@@ -0,0 +1,35 @@
+//! 信号
+//!
+//! 处理函数在进程下一次从系统调用返回时被调用, 可能打断任何代码, 其中只应访问原子变量等不需要加锁的数据。
+//! 正在阻塞的系统调用(读管道、等待子进程等)收到信号后返回 EINTR。
+use os64_abi::*;
+use crate::syscall::{syscall2, syscall3};
+
+pub use os64_abi::{NSIG, SIGILL, SIGINT, SIGKILL, SIGSEGV, SIGTERM};
+
+/// 信号的处理方式
+#[derive(Clone,Copy,Debug)]
+pub enum Handler {
+    /// 结束进程
+    Default,
+    Ignore,
+    /// 以信号为参数调用函数
+    Function(extern "C" fn(u64)),
+}
+
+/// 设置信号 `signal` 的处理方式
+///
+/// SIGKILL 不能改变; SIGSEGV 和 SIGILL 总是结束进程, 也不能改变。
+pub fn set_handler(signal : u64, handler : Handler) -> Result<(), Errno> {
+    let handler = match handler {
+        Handler::Default => SIG_DFL,
+        Handler::Ignore => SIG_IGN,
+        Handler::Function(function) => function as *const () as u64,
+    };
+    unsafe { syscall3(OS64_API_SIGNAL, signal, handler, os64_runtime::signal_trampoline()).map(|_| ()) }
+}
+
+/// 向进程 `pid` 发送信号 `signal`, `signal` 为 0 时只检查进程是否存在
+pub fn kill(pid : u64, signal : u64) -> Result<(), Errno> {
+    unsafe { syscall2(OS64_API_KILL, pid, signal).map(|_| ()) }
+}
@@ -0,0 +1,3 @@
+//! 原始的系统调用, 调用号和参数的含义见 os64-abi
+pub use os64_abi::*;
+pub use os64_abi::syscall::*;
@@ -0,0 +1,104 @@
+// 文件、目录和管道的系统调用
+// 文件描述符属于进程, 打开的文件由 device::disk::vfs 管理; 新进程的 0、1、2 指向控制台
+use os64_abi::{FileStat, MAX_IO_SIZE, SEEK_CURRENT, SEEK_END, SEEK_START};
+use crate::{device::disk::{file_system::{FileOpenMode, FilePosition}, vfs::{self, Descriptor}}, parallel::pipe};
+use super::{current_process, install_descriptors, read_user_path, user::{UserPtr, UserSlice}, Errno, SyscallResult};
+
+/// 以 `mode`(os64_abi 的 OPEN_*) 打开文件 `[path, path + length)`, 返回文件描述符
+pub fn open(path : u64, length : u64, mode : u64) -> SyscallResult {
+    let path = read_user_path(path, length)?;
+    let mode = FileOpenMode::from_bits(mode as u8).filter(|_| mode <= u8::MAX as u64).ok_or(Errno::EINVAL)?;
+    let process = current_process()?;
+    let handle = vfs::open(&path, mode)?;
+    process.with_files(|files| files.insert(handle))
+}
+
+pub fn close(fd : u64) -> SyscallResult {
+    current_process()?.with_files(|files| files.remove(fd))?;
+    Ok(0)
+}
+
+/// 读取最多 `size` 字节到 `buffer`, 返回读到的字节数, 0 表示文件尾
+///
+/// 读取会移动文件位置或取走管道中的数据, 所以先检查缓冲区可写, 地址错误时不读取。
+pub fn read(fd : u64, buffer : u64, size : u64) -> SyscallResult {
+    let descriptor = current_process()?.with_files(|files| files.get(fd))?;
+    let buffer = UserSlice::new(buffer, size.min(MAX_IO_SIZE));
+    buffer.check_writable()?;
+    let data = descriptor.read(buffer.len() as usize)?;
+    buffer.write(&data)?;
+    Ok(data.len() as u64)
+}
+
+/// 写入 `[buffer, buffer + size)`, 返回写入的字节数
+pub fn write(fd : u64, buffer : u64, size : u64) -> SyscallResult {
+    let descriptor = current_process()?.with_files(|files| files.get(fd))?;
+    let data = UserSlice::new(buffer, size.min(MAX_IO_SIZE)).read()?;
+    descriptor.write(&data).map(|written| written as u64)
+}
+
+/// 以 `whence`(SEEK_*) 为起点移动读写位置, 返回新的位置
+pub fn seek(fd : u64, offset : i64, whence : u64) -> SyscallResult {
+    let position = match whence {
+        SEEK_START if offset >= 0 => FilePosition::Start(offset as usize),
+        SEEK_CURRENT => FilePosition::Current(offset as isize),
+        SEEK_END => FilePosition::End(offset as isize),
+        _ => return Err(Errno::EINVAL),
+    };
+    match current_process()?.with_files(|files| files.get(fd))? {
+        Descriptor::File(handle) => vfs::seek(&handle, position).map(|position| position as u64),
+        Descriptor::PipeReader(_) | Descriptor::PipeWriter(_) | Descriptor::Console => Err(Errno::ESPIPE),
+        Descriptor::Channel(_) => Err(Errno::EBADF),
+    }
+}
+
+/// 把文件 `[path, path + length)` 的信息写到 `stat` 指向的 FileStat
+pub fn stat(path : u64, length : u64, stat : u64) -> SyscallResult {
+    let path = read_user_path(path, length)?;
+    let info = vfs::stat(&path)?;
+    UserPtr::<FileStat>::new(stat).write(&info)?;
+    Ok(0)
+}
+
+/// 从目录的当前位置读取目录项到 `[buffer, buffer + size)`, 返回字节数, 0 表示已经读完
+pub fn read_dir(fd : u64, buffer : u64, size : u64) -> SyscallResult {
+    let handle = current_process()?.with_files(|files| files.file(fd))?;
+    let buffer = UserSlice::new(buffer, size.min(MAX_IO_SIZE));
+    buffer.check_writable()?;
+    let entries = vfs::read_dir(&handle, buffer.len() as usize)?;
+    buffer.write(&entries)?;
+    Ok(entries.len() as u64)
+}
+
+pub fn mkdir(path : u64, length : u64) -> SyscallResult {
+    vfs::create_directory(&read_user_path(path, length)?).map(|_| 0)
+}
+
+/// 删除文件或空目录
+pub fn unlink(path : u64, length : u64) -> SyscallResult {
+    vfs::remove(&read_user_path(path, length)?).map(|_| 0)
+}
+
+pub fn rename(from : u64, from_length : u64, to : u64, to_length : u64) -> SyscallResult {
+    let from = read_user_path(from, from_length)?;
+    let to = read_user_path(to, to_length)?;
+    vfs::rename(&from, &to).map(|_| 0)
+}
+
+/// 创建管道, 读取端和写入端的描述符写到 `fds` 指向的两个 u64
+pub fn pipe(fds : u64) -> SyscallResult {
+    let process = current_process()?;
+    let (reader, writer) = pipe::pipe();
+    install_descriptors(&process, alloc::vec![reader.into(), writer.into()], fds)?;
+    Ok(0)
+}
+
+/// 让 `new_fd` 引用与 `fd` 相同的对象, 原来的 `new_fd` 被关闭, 返回 `new_fd`
+pub fn dup2(fd : u64, new_fd : u64) -> SyscallResult {
+    let replaced = current_process()?.with_files(|files| {
+        let descriptor = files.get(fd)?;
+        files.set(new_fd, descriptor)
+    })?;
+    drop(replaced);
+    Ok(new_fd)
+}
@@ -0,0 +1,106 @@
+// 进程间通信的系统调用, 通道见 parallel::ipc
+// 通道的两端和端口的监听端都是文件描述符, 用 CLOSE 关闭
+use alloc::vec::Vec;
+use os64_abi::{IpcMessage, IPC_NONBLOCK, MAX_MESSAGE_HANDLES, MAX_MESSAGE_SIZE};
+use crate::parallel::{ipc::{Endpoint, Message, Port}, process::Process};
+use super::{current_process, install_descriptors, read_user_path, user::{UserPtr, UserSlice}, Errno, SyscallResult};
+
+fn blocking(flags : u64) -> Result<bool, Errno> {
+    match flags {
+        0 => Ok(true),
+        IPC_NONBLOCK => Ok(false),
+        _ => Err(Errno::EINVAL),
+    }
+}
+
+/// 创建通道, 两端的描述符写到 `fds` 指向的两个 u64
+pub fn channel_create(fds : u64) -> SyscallResult {
+    let process = current_process()?;
+    let (first, second) = Endpoint::pair();
+    install_descriptors(&process, alloc::vec![first.into(), second.into()], fds)?;
+    Ok(0)
+}
+
+/// 从用户空间读出要发送的消息, 描述符复制一份
+fn read_message(process : &Process, message : u64) -> Result<Message, Errno> {
+    let header = UserPtr::<IpcMessage>::new(message).read()?;
+    if header.data_size > MAX_MESSAGE_SIZE || header.handle_count > MAX_MESSAGE_HANDLES {
+        return Err(Errno::E2BIG);
+    }
+    let data = UserSlice::new(header.data, header.data_size).read()?;
+    let fds = UserSlice::new(header.handles, header.handle_count * 8).read()?;
+    let handles = process.with_files(|files| {
+        fds.chunks_exact(8).map(|fd| {
+            files.get(u64::from_ne_bytes([fd[0], fd[1], fd[2], fd[3], fd[4], fd[5], fd[6], fd[7]]))
+        }).collect::<Result<Vec<_>, Errno>>()
+    })?;
+    Message::new(data, handles)
+}
+
+/// 接收消息到 `message` 描述的缓冲区, 并写回实际的大小
+///
+/// 先检查缓冲区都可写再取出消息, 地址错误时消息留在队列中。
+fn write_message(process : &Process, endpoint : &Endpoint, message : u64, blocking : bool) -> Result<(), Errno> {
+    let header = UserPtr::<IpcMessage>::new(message);
+    let mut buffer = header.read()?;
+    header.check_writable()?;
+    UserSlice::new(buffer.data, buffer.data_size).check_writable()?;
+    UserSlice::new(buffer.handles, buffer.handle_count.checked_mul(8).ok_or(Errno::EFAULT)?).check_writable()?;
+    let received = endpoint.receive_if(blocking, |message| {
+        match message.data.len() as u64 <= buffer.data_size && message.handles.len() as u64 <= buffer.handle_count {
+            true => Ok(()),
+            false => Err(Errno::E2BIG),
+        }
+    })?;
+    UserSlice::new(buffer.data, buffer.data_size).write(&received.data)?;
+    buffer.data_size = received.data.len() as u64;
+    buffer.handle_count = install_descriptors(process, received.handles, buffer.handles)?.len() as u64;
+    header.write(&buffer)
+}
+
+/// 通过通道 `fd` 发送 `message` 指向的 IpcMessage
+pub fn send(fd : u64, message : u64, flags : u64) -> SyscallResult {
+    let blocking = blocking(flags)?;
+    let process = current_process()?;
+    let endpoint = process.with_files(|files| files.channel(fd))?;
+    let message = read_message(&process, message)?;
+    endpoint.send(message, blocking)?;
+    Ok(0)
+}
+
+/// 从通道 `fd` 接收一条消息到 `message` 指向的 IpcMessage
+///
+/// 收到的描述符放不进进程的描述符表时返回 EMFILE, 这条消息被丢弃。
+pub fn receive(fd : u64, message : u64, flags : u64) -> SyscallResult {
+    let blocking = blocking(flags)?;
+    let process = current_process()?;
+    let endpoint = process.with_files(|files| files.channel(fd))?;
+    write_message(&process, &endpoint, message, blocking)?;
+    Ok(0)
+}
+
+/// 发送 `request` 并等待对方的下一条消息作为回复, 写到 `reply`
+pub fn call(fd : u64, request : u64, reply : u64) -> SyscallResult {
+    let process = current_process()?;
+    let endpoint = process.with_files(|files| files.channel(fd))?;
+    let message = read_message(&process, request)?;
+    endpoint.send(message, true)?;
+    write_message(&process, &endpoint, reply, true)?;
+    Ok(0)
+}
+
+/// 注册名为 `[name, name + length)` 的端口, 返回监听端的描述符
+pub fn port_create(name : u64, length : u64) -> SyscallResult {
+    let name = read_user_path(name, length)?;
+    let process = current_process()?;
+    let listener = Port::create(&name)?;
+    process.with_files(|files| files.insert(listener))
+}
+
+/// 连接到名为 `[name, name + length)` 的端口, 返回通道的描述符
+pub fn port_connect(name : u64, length : u64) -> SyscallResult {
+    let name = read_user_path(name, length)?;
+    let process = current_process()?;
+    let channel = Port::connect(&name)?;
+    process.with_files(|files| files.insert(channel))
+}
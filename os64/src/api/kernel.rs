@@ -1,4 +1,88 @@
-pub fn print() -> u64 {
-    serial_print!("Hello World From Sub Process!");
-    0
-}
\ No newline at end of file
+use alloc::{string::String, vec::Vec};
+use crate::{parallel::{process, scheduler}, serial_print};
+use os64_abi::{MAX_PRINT_SIZE, WAIT_ANY, WAIT_NOHANG};
+use super::{current_process, read_user_path, read_user_strings, user::{UserPtr, UserSlice}, Errno, SyscallFrame, SyscallResult};
+
+/// 结束当前进程
+pub fn exit(code : i64) -> SyscallResult {
+    process::exit_current(code)
+}
+
+pub fn yield_now() -> SyscallResult {
+    scheduler::yield_now();
+    Ok(0)
+}
+
+/// 把用户空间 `[address, address + size)` 中的字符串输出到串口, 返回输出的字节数
+pub fn print(address : u64, size : u64) -> SyscallResult {
+    let size = size.min(MAX_PRINT_SIZE);
+    let bytes = UserSlice::new(address, size).read()?;
+    serial_print!("{}", String::from_utf8_lossy(&bytes));
+    Ok(size)
+}
+
+/// 复制当前进程, 父进程得到子进程的 id, 子进程得到 0
+pub fn fork(frame : &SyscallFrame) -> SyscallResult {
+    let process = current_process()?;
+    process.fork(frame)
+        .map(|child| child.id().as_u64())
+        .map_err(|_| Errno::ENOMEM)
+}
+
+/// 用文件 `[path, path + length)` 中的程序替换当前进程的映像, 成功时不返回
+///
+/// `args` 和 `env` 是字符串列表, 没有参数时以程序名作为 argv[0]。
+pub fn exec(path : u64, length : u64, args : u64, args_length : u64, env : u64, env_length : u64) -> SyscallResult {
+    let filename = read_user_path(path, length)?;
+    let mut args = read_user_strings(args, args_length)?;
+    let env = read_user_strings(env, env_length)?;
+    if args.is_empty() {
+        args.push(filename.clone());
+    }
+    let process = current_process()?;
+    let (entry, stack_top) = {
+        let args : Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+        let env : Vec<&str> = env.iter().map(|s| s.as_str()).collect();
+        process.exec(&filename, &args, &env).map_err(|_| Errno::ENOENT)?
+    };
+    // 进入新映像后不会回到这里, 先释放局部变量
+    drop(process);
+    drop((filename, args, env));
+    process::enter_user_mode(entry, stack_top)
+}
+
+/// 等待子进程 `pid` 结束并回收它, `pid` 为 WAIT_ANY 时等待任意一个; 返回子进程的 id
+///
+/// `status` 不为 0 时写入 `ExitStatus::to_raw` 的结果。有 WAIT_NOHANG 且子进程都还在运行时返回 0。
+pub fn waitpid(pid : u64, status : u64, flags : u64) -> SyscallResult {
+    if flags & !WAIT_NOHANG != 0 {
+        return Err(Errno::EINVAL);
+    }
+    // 先检查能否写入, 以免回收了子进程却丢掉它的状态
+    if status != 0 {
+        UserPtr::<u64>::new(status).write(&0)?;
+    }
+    let pid = match pid {
+        WAIT_ANY => None,
+        pid => Some(pid),
+    };
+    let child = current_process()?.wait_child(pid, flags & WAIT_NOHANG == 0)?;
+    match child {
+        Some((id, exit_status)) => {
+            if status != 0 {
+                UserPtr::<u64>::new(status).write(&exit_status.to_raw())?;
+            }
+            Ok(id.as_u64())
+        },
+        None => Ok(0),
+    }
+}
+
+pub fn getpid() -> SyscallResult {
+    Ok(current_process()?.id().as_u64())
+}
+
+/// 父进程的 id, 没有父进程时为 0
+pub fn getppid() -> SyscallResult {
+    Ok(current_process()?.parent().map_or(0, |parent| parent.as_u64()))
+}
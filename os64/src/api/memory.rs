@@ -0,0 +1,147 @@
+use os64_abi::{MemoryUsage, MAP_SHARED, PROT_EXEC, PROT_READ, PROT_WRITE};
+use x86_64::VirtAddr;
+use crate::memory::{shared::{SharedMemory, SharedMemoryId}, vma::{is_user_address, VmaBacking, VmaFlags, VmaKind}};
+use super::{current_process, read_user_path, user::UserPtr, Errno, SyscallResult};
+
+const PAGE_SIZE : u64 = 4096;
+
+/// 移动程序断点到 `address`, 返回新的断点; `address` 为 0 或无法移动时返回当前断点
+pub fn brk(address : u64) -> SyscallResult {
+    let process = current_process()?;
+    process.with_address_space(|space| {
+        let current = space.program_break();
+        if address == 0 {
+            return Ok(current.as_u64());
+        }
+        let target = VirtAddr::try_new(address).map_err(|_| Errno::EINVAL)?;
+        Ok(space.set_program_break(target).unwrap_or(current).as_u64())
+    })
+}
+
+/// 分配 `size` 字节的匿名内存, 返回起始地址, 页在第一次访问时分配
+pub fn heap_alloc(size : u64) -> SyscallResult {
+    let process = current_process()?;
+    process.with_address_space(|space| space.map_anonymous(size, VmaFlags::READ | VmaFlags::WRITE))
+        .map(|address| address.as_u64())
+        .map_err(|_| Errno::ENOMEM)
+}
+
+/// 映射 `size` 字节的匿名内存, 访问权限为 `prot`, 返回起始地址
+///
+/// `flags` 含 MAP_SHARED 时映射一个没有 id 的共享内存对象, fork 后父子进程看到同样的内容。
+pub fn mmap(size : u64, prot : u64, flags : u64) -> SyscallResult {
+    let process = current_process()?;
+    let access = protection(prot)?;
+    if size == 0 || flags & !MAP_SHARED != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let result = if flags & MAP_SHARED != 0 {
+        let object = SharedMemory::create(size).map_err(|_| Errno::EINVAL)?;
+        let _ = SharedMemory::unlink(object.id());
+        process.with_address_space(|space| space.map_shared(object, access))
+    } else {
+        process.with_address_space(|space| space.map_anonymous(size, access))
+    };
+    result.map(|address| address.as_u64()).map_err(|_| Errno::ENOMEM)
+}
+
+/// 解除 `[address, address + size)` 的映射, 可以只解除一个区域的一部分
+pub fn munmap(address : u64, size : u64) -> SyscallResult {
+    let process = current_process()?;
+    let start = VirtAddr::try_new(address).map_err(|_| Errno::EINVAL)?;
+    process.with_address_space(|space| space.unmap(start, size))
+        .map(|_| 0)
+        .map_err(|_| Errno::EINVAL)
+}
+
+/// 把 `[address, address + size)` 的访问权限改为 `prot`, 范围必须已全部映射
+pub fn mprotect(address : u64, size : u64, prot : u64) -> SyscallResult {
+    let process = current_process()?;
+    let access = protection(prot)?;
+    let start = VirtAddr::try_new(address).map_err(|_| Errno::EINVAL)?;
+    let last = address.checked_add(size).and_then(|end| VirtAddr::try_new(end - 1).ok()).ok_or(Errno::EINVAL)?;
+    if !start.is_aligned(PAGE_SIZE) || size == 0 || !is_user_address(start) || !is_user_address(last) {
+        return Err(Errno::EINVAL);
+    }
+    process.with_address_space(|space| {
+        if !space.check_range(start, size, VmaFlags::empty()) {
+            return Err(Errno::ENOMEM);
+        }
+        space.protect(start, size, access).map_err(|_| Errno::EACCES)
+    })?;
+    Ok(0)
+}
+
+/// 把当前进程的内存使用情况写到 `usage` 指向的 MemoryUsage
+pub fn memory_usage(usage : u64) -> SyscallResult {
+    let process = current_process()?;
+    let info = process.with_address_space(|space| {
+        let mut info = MemoryUsage {
+            resident_size : space.resident_pages() as u64 * PAGE_SIZE,
+            ..MemoryUsage::default()
+        };
+        for vma in space.vmas() {
+            let size = vma.size();
+            info.virtual_size += size;
+            match vma.kind {
+                VmaKind::Heap => info.heap_size += size,
+                VmaKind::Stack => info.stack_size += size,
+                VmaKind::Mapped => info.mapped_size += size,
+                VmaKind::Code | VmaKind::Data => {},
+            }
+            if let VmaBacking::Shared { .. } = vma.backing {
+                info.shared_size += size;
+            }
+        }
+        info
+    });
+    UserPtr::<MemoryUsage>::new(usage).write(&info)?;
+    Ok(0)
+}
+
+/// 创建 `size` 字节的共享内存对象, 返回它的 id
+pub fn shm_create(size : u64) -> SyscallResult {
+    current_process()?;
+    SharedMemory::create(size)
+        .map(|object| object.id().as_u64())
+        .map_err(|_| Errno::EINVAL)
+}
+
+/// 把共享内存对象映射到当前进程, 返回起始地址
+pub fn shm_map(id : u64, writable : bool) -> SyscallResult {
+    let process = current_process()?;
+    let object = SharedMemory::open(SharedMemoryId::from_u64(id)).ok_or(Errno::ENOENT)?;
+    process.with_address_space(|space| space.map_shared(object, access_flags(writable)))
+        .map(|address| address.as_u64())
+        .map_err(|_| Errno::ENOMEM)
+}
+
+/// 删除共享内存对象的 id, 已有的映射仍然有效
+pub fn shm_unlink(id : u64) -> SyscallResult {
+    current_process()?;
+    SharedMemory::unlink(SharedMemoryId::from_u64(id))
+        .map(|_| 0)
+        .map_err(|_| Errno::ENOENT)
+}
+
+/// 映射文件 `[path, path + length)` 的全部内容, 同一个文件的映射共享页缓存; 路径和 open 一样从根目录解析
+pub fn mmap_file(path : u64, length : u64, writable : bool) -> SyscallResult {
+    let process = current_process()?;
+    let path = read_user_path(path, length)?;
+    let object = SharedMemory::for_file(&path).map_err(|_| Errno::ENOENT)?;
+    process.with_address_space(|space| space.map_shared(object, access_flags(writable)))
+        .map(|address| address.as_u64())
+        .map_err(|_| Errno::ENOMEM)
+}
+
+/// PROT_* 转换为 VMA 的访问权限
+fn protection(prot : u64) -> Result<VmaFlags, Errno> {
+    if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(Errno::EINVAL);
+    }
+    VmaFlags::from_bits(prot as u32).ok_or(Errno::EINVAL)
+}
+
+fn access_flags(writable : bool) -> VmaFlags {
+    if writable { VmaFlags::READ | VmaFlags::WRITE } else { VmaFlags::READ }
+}
@@ -0,0 +1,145 @@
+// 系统调用接口。
+// 调用号、错误码和调用约定定义在 os64-abi 中, 与用户程序共用。
+use alloc::{string::String, sync::Arc, vec::Vec};
+use crate::{device::disk::vfs::Descriptor, parallel::process::{self, Process}};
+use user::UserSlice;
+
+pub mod filesystem;
+pub mod ipc;
+pub mod kernel;
+pub mod memory;
+pub mod signal;
+pub mod user;
+
+pub use os64_abi::{
+    Errno, OS64_API_EXIT, OS64_API_YIELD, OS64_API_PRINT, OS64_API_HEAP_ALLOC, OS64_API_HEAP_FREE,
+    OS64_API_BRK, OS64_API_SHM_CREATE, OS64_API_SHM_MAP, OS64_API_SHM_UNLINK, OS64_API_MMAP_FILE,
+    OS64_API_FORK, OS64_API_EXEC, OS64_API_OPEN, OS64_API_CLOSE, OS64_API_READ, OS64_API_WRITE,
+    OS64_API_SEEK, OS64_API_STAT, OS64_API_READDIR, OS64_API_MKDIR, OS64_API_UNLINK, OS64_API_RENAME,
+    OS64_API_MMAP, OS64_API_MUNMAP, OS64_API_MPROTECT, OS64_API_MEMORY_USAGE, OS64_API_CHANNEL_CREATE,
+    OS64_API_SEND, OS64_API_RECEIVE, OS64_API_CALL, OS64_API_PORT_CREATE, OS64_API_PORT_CONNECT, OS64_API_PIPE,
+    OS64_API_DUP2, OS64_API_WAITPID, OS64_API_KILL, OS64_API_SIGNAL, OS64_API_SIGRETURN, OS64_API_GETPID,
+    OS64_API_GETPPID,
+};
+use os64_abi::{MAX_ARGUMENT_SIZE, MAX_PATH_SIZE};
+
+pub type SyscallResult = Result<u64, Errno>;
+
+/// `int 0x80` 入口保存的用户寄存器, 顺序与 `asm_system_call_entry` 压栈的顺序相反
+#[repr(C)]
+#[derive(Clone,Copy,Debug,Default)]
+pub struct SyscallFrame {
+    pub r15 : u64,
+    pub r14 : u64,
+    pub r13 : u64,
+    pub r12 : u64,
+    pub r11 : u64,
+    pub r10 : u64,
+    pub r9 : u64,
+    pub r8 : u64,
+    pub rbp : u64,
+    pub rdi : u64,
+    pub rsi : u64,
+    pub rdx : u64,
+    pub rcx : u64,
+    pub rbx : u64,
+    pub rax : u64,
+    // 以下由处理器压栈
+    pub rip : u64,
+    pub cs : u64,
+    pub rflags : u64,
+    pub rsp : u64,
+    pub ss : u64,
+}
+
+/// 系统调用的分发, 由 `asm_system_call_entry` 调用
+pub(crate) extern "C" fn system_call(frame : &mut SyscallFrame) {
+    let (a0, a1, a2, a3, a4, a5) = (frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9);
+    let result = match frame.rax {
+        OS64_API_EXIT => kernel::exit(a0 as i64),
+        OS64_API_YIELD => kernel::yield_now(),
+        OS64_API_PRINT => kernel::print(a0, a1),
+        OS64_API_HEAP_ALLOC => memory::heap_alloc(a0),
+        OS64_API_HEAP_FREE => memory::munmap(a0, a1),
+        OS64_API_BRK => memory::brk(a0),
+        OS64_API_SHM_CREATE => memory::shm_create(a0),
+        OS64_API_SHM_MAP => memory::shm_map(a0, a1 != 0),
+        OS64_API_SHM_UNLINK => memory::shm_unlink(a0),
+        OS64_API_MMAP_FILE => memory::mmap_file(a0, a1, a2 != 0),
+        OS64_API_FORK => kernel::fork(frame),
+        OS64_API_EXEC => kernel::exec(a0, a1, a2, a3, a4, a5),
+        OS64_API_OPEN => filesystem::open(a0, a1, a2),
+        OS64_API_CLOSE => filesystem::close(a0),
+        OS64_API_READ => filesystem::read(a0, a1, a2),
+        OS64_API_WRITE => filesystem::write(a0, a1, a2),
+        OS64_API_SEEK => filesystem::seek(a0, a1 as i64, a2),
+        OS64_API_STAT => filesystem::stat(a0, a1, a2),
+        OS64_API_READDIR => filesystem::read_dir(a0, a1, a2),
+        OS64_API_MKDIR => filesystem::mkdir(a0, a1),
+        OS64_API_UNLINK => filesystem::unlink(a0, a1),
+        OS64_API_RENAME => filesystem::rename(a0, a1, a2, a3),
+        OS64_API_MMAP => memory::mmap(a0, a1, a2),
+        OS64_API_MUNMAP => memory::munmap(a0, a1),
+        OS64_API_MPROTECT => memory::mprotect(a0, a1, a2),
+        OS64_API_MEMORY_USAGE => memory::memory_usage(a0),
+        OS64_API_CHANNEL_CREATE => ipc::channel_create(a0),
+        OS64_API_SEND => ipc::send(a0, a1, a2),
+        OS64_API_RECEIVE => ipc::receive(a0, a1, a2),
+        OS64_API_CALL => ipc::call(a0, a1, a2),
+        OS64_API_PORT_CREATE => ipc::port_create(a0, a1),
+        OS64_API_PORT_CONNECT => ipc::port_connect(a0, a1),
+        OS64_API_PIPE => filesystem::pipe(a0),
+        OS64_API_DUP2 => filesystem::dup2(a0, a1),
+        OS64_API_WAITPID => kernel::waitpid(a0, a1, a2),
+        OS64_API_KILL => signal::kill(a0, a1),
+        OS64_API_SIGNAL => signal::signal(a0, a1, a2),
+        OS64_API_SIGRETURN => signal::sigreturn(frame),
+        OS64_API_GETPID => kernel::getpid(),
+        OS64_API_GETPPID => kernel::getppid(),
+        _ => Err(Errno::ENOSYS),
+    };
+    frame.rax = Errno::to_return(result);
+    signal::deliver(frame);
+}
+
+/// 发起系统调用的进程, 内核线程没有进程
+fn current_process() -> Result<Arc<Process>, Errno> {
+    process::current().ok_or(Errno::ESRCH)
+}
+
+/// 把描述符放进当前进程的表中并写到用户空间的 `address`, 写入失败时撤销
+fn install_descriptors(process : &Process, descriptors : Vec<Descriptor>, address : u64) -> Result<Vec<u64>, Errno> {
+    let fds = process.with_files(|files| files.insert_all(descriptors))?;
+    let bytes : Vec<u8> = fds.iter().flat_map(|fd| fd.to_ne_bytes()).collect();
+    if let Err(errno) = UserSlice::new(address, bytes.len() as u64).write(&bytes) {
+        let removed : Vec<_> = process.with_files(|files| fds.iter().filter_map(|fd| files.remove(*fd).ok()).collect());
+        drop(removed);
+        return Err(errno);
+    }
+    Ok(fds)
+}
+
+/// 读取用户空间 `[address, address + length)` 中的文件名
+fn read_user_path(address : u64, length : u64) -> Result<String, Errno> {
+    if length == 0 || length > MAX_PATH_SIZE {
+        return Err(Errno::EINVAL);
+    }
+    String::from_utf8(UserSlice::new(address, length).read()?).map_err(|_| Errno::EINVAL)
+}
+
+/// 读取用户空间 `[address, address + length)` 中以 0 结尾的字符串列表, `length` 为 0 时为空
+fn read_user_strings(address : u64, length : u64) -> Result<Vec<String>, Errno> {
+    if length == 0 {
+        return Ok(Vec::new());
+    }
+    if length > MAX_ARGUMENT_SIZE as u64 {
+        return Err(Errno::E2BIG);
+    }
+    let bytes = UserSlice::new(address, length).read()?;
+    if bytes.last() != Some(&0) {
+        return Err(Errno::EINVAL);
+    }
+    bytes[..bytes.len() - 1].split(|byte| *byte == 0)
+        .map(|string| String::from_utf8(string.to_vec()).map_err(|_| Errno::EINVAL))
+        .collect()
+}
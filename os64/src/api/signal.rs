@@ -0,0 +1,103 @@
+// 信号的处理。
+// 处理函数在进程从系统调用返回时调用: 内核把系统调用的现场(SyscallFrame)保存到用户栈上, 再压入用户给出的
+// trampoline 作为返回地址, 让进程从处理函数开始执行, rdi 为信号。处理函数返回到 trampoline,
+// 由它发起 SIGRETURN, 内核从用户栈上恢复原来的现场, 原来的系统调用像没有被打断一样返回。
+//see also: https://man7.org/linux/man-pages/man7/signal.7.html
+use core::mem::size_of;
+use os64_abi::{SIG_DFL, SIG_IGN, SIGSEGV};
+use x86_64::{registers::rflags::RFlags, VirtAddr};
+use crate::{memory::vma::is_user_address, parallel::process::{self, SignalAction}};
+use super::{current_process, user::UserPtr, Errno, SyscallFrame, SyscallResult};
+
+/// System V ABI 中函数可以直接使用的栈顶以下的区域, 保存现场时要跳过
+const RED_ZONE_SIZE : u64 = 128;
+
+/// SIGRETURN 可以恢复的标志, 其它标志保持内核的设置
+fn user_flags() -> RFlags {
+    RFlags::CARRY_FLAG | RFlags::PARITY_FLAG | RFlags::AUXILIARY_CARRY_FLAG | RFlags::ZERO_FLAG
+        | RFlags::SIGN_FLAG | RFlags::DIRECTION_FLAG | RFlags::OVERFLOW_FLAG
+}
+
+/// 地址可以作为用户态的 rip 或 rsp, iretq 到非规范的地址会在内核中引起保护异常
+fn is_user_pointer(address : u64) -> bool {
+    VirtAddr::try_new(address).map_or(false, is_user_address)
+}
+
+/// 设置信号 `signal` 的处理函数, 返回原来的处理函数
+///
+/// `handler` 为 SIG_DFL、SIG_IGN 或函数地址, 有处理函数时 `trampoline` 是它返回的地址。
+pub fn signal(signal : u64, handler : u64, trampoline : u64) -> SyscallResult {
+    let action = match handler {
+        SIG_DFL => SignalAction::Default,
+        SIG_IGN => SignalAction::Ignore,
+        handler if is_user_pointer(handler) && is_user_pointer(trampoline) => SignalAction::Handler { handler, trampoline },
+        _ => return Err(Errno::EFAULT),
+    };
+    let old = current_process()?.set_signal_action(signal, action)?;
+    Ok(match old {
+        SignalAction::Default => SIG_DFL,
+        SignalAction::Ignore => SIG_IGN,
+        SignalAction::Handler { handler, .. } => handler,
+    })
+}
+
+/// 向进程 `pid` 发送信号 `signal`
+pub fn kill(pid : u64, signal : u64) -> SyscallResult {
+    let target = process::find(pid).ok_or(Errno::ESRCH)?;
+    target.kill(signal).map(|_| 0)
+}
+
+/// 从处理函数返回, 恢复进入处理函数之前保存的现场, 返回值是原来的 rax
+///
+/// 段寄存器保持不变, rflags 只恢复运算结果的标志和方向标志。
+pub fn sigreturn(frame : &mut SyscallFrame) -> SyscallResult {
+    // trampoline 被 ret 弹出后 rsp 指向保存的现场
+    let saved = UserPtr::<SyscallFrame>::new(frame.rsp).read()?;
+    if !is_user_pointer(saved.rip) || !is_user_pointer(saved.rsp) {
+        return Err(Errno::EFAULT);
+    }
+    let rflags = (frame.rflags & !user_flags().bits()) | (saved.rflags & user_flags().bits());
+    *frame = SyscallFrame { cs : frame.cs, ss : frame.ss, rflags, ..saved };
+    Ok(saved.rax)
+}
+
+/// 处理当前进程未处理的信号, 在系统调用返回用户态之前调用
+///
+/// 按默认方式处理的信号结束进程; 遇到有处理函数的信号时修改 `frame` 进入处理函数,
+/// 其余信号留到它 SIGRETURN 之后。
+pub fn deliver(frame : &mut SyscallFrame) {
+    let process = match process::current() {
+        Some(process) => process,
+        None => return,
+    };
+    while let Some((signal, action)) = process.take_signal() {
+        match action {
+            SignalAction::Ignore => {},
+            SignalAction::Default => {
+                drop(process);
+                process::terminate_current(signal, "killed");
+            },
+            SignalAction::Handler { handler, trampoline } => {
+                drop(process);
+                if enter_handler(frame, signal, handler, trampoline).is_err() {
+                    process::terminate_current(SIGSEGV, "cannot save the signal frame");
+                }
+                return;
+            },
+        }
+    }
+}
+
+/// 在用户栈上保存现场并压入返回地址, 让 `frame` 返回到 `handler(signal)`
+///
+/// 保存的现场按 16 字节对齐, 进入处理函数时与函数调用后的栈一样, rsp + 8 按 16 字节对齐。
+fn enter_handler(frame : &mut SyscallFrame, signal : u64, handler : u64, trampoline : u64) -> Result<(), Errno> {
+    let saved = frame.rsp.checked_sub(RED_ZONE_SIZE + size_of::<SyscallFrame>() as u64 + 8).ok_or(Errno::EFAULT)? & !15;
+    UserPtr::<SyscallFrame>::new(saved).write(frame)?;
+    let return_address = saved - 8;
+    UserPtr::<u64>::new(return_address).write(&trampoline)?;
+    frame.rsp = return_address;
+    frame.rip = handler;
+    frame.rdi = signal;
+    Ok(())
+}
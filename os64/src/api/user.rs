@@ -0,0 +1,184 @@
+// 系统调用参数中的用户空间指针。
+// 用户传来的地址在访问前先检查是否落在当前进程有相应权限的 VMA 中, 内核地址一律返回 EFAULT;
+// 复制时仍可能因为其它线程解除映射等原因缺页, 缺页处理程序会让复制提前结束, 同样返回 EFAULT。
+//
+// 开启 SMAP/SMEP 后内核不能直接读写或执行用户页, 只有这里的复制函数在复制期间临时允许访问。
+//see also: Intel SDM Vol.3 4.6 Access Rights
+use core::{arch::{asm, x86_64::__cpuid_count}, marker::PhantomData, mem::{size_of, MaybeUninit}, sync::atomic::{AtomicBool, Ordering}};
+use alloc::vec::Vec;
+use os64_abi::{FileStat, IpcMessage, MemoryUsage};
+use x86_64::{registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags}, VirtAddr};
+use crate::{architecture::x86_64_asm::asm_copy_user, memory::vma::{is_user_address, VmaFlags}};
+use super::{current_process, Errno, SyscallFrame};
+
+/// 是否开启了 SMAP, 开启后复制前后需要 stac/clac; 系统调用入口也读取它
+pub(crate) static SMAP_ENABLED : AtomicBool = AtomicBool::new(false);
+
+/// 在当前 CPU 上开启写保护和 CPU 支持的 SMEP/SMAP, 每个 CPU 都要调用
+///
+/// 写保护使内核写入写时复制的用户页时也会缺页。
+pub fn init_cpu() {
+    let features = unsafe { __cpuid_count(7, 0).ebx };
+    let mut flags = Cr4Flags::empty();
+    if features & (1 << 7) != 0 {
+        flags |= Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION;
+    }
+    if features & (1 << 20) != 0 {
+        flags |= Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION;
+        SMAP_ENABLED.store(true, Ordering::Relaxed);
+    }
+    unsafe {
+        Cr0::update(|cr0| *cr0 |= Cr0Flags::WRITE_PROTECT);
+        Cr4::update(|cr4| *cr4 |= flags);
+    }
+}
+
+/// 清除 EFLAGS.AC, 让 SMAP 重新生效; 每个异常和中断处理程序首先调用
+///
+/// 用户态可以设置 AC 后进入内核, `copy_user` 中发生的缺页和中断进入时 AC 也是 1。
+/// 被打断时的 RFLAGS 保存在中断帧中, iretq 时恢复。
+#[inline(always)]
+pub fn clear_access_flag() {
+    if SMAP_ENABLED.load(Ordering::Relaxed) {
+        unsafe { asm!("clac", options(nomem, nostack)) };
+    }
+}
+
+/// 复制时可能缺页, 调用者不能持有当前进程地址空间的锁
+unsafe fn copy_user(destination : *mut u8, source : *const u8, size : usize) -> Result<(), Errno> {
+    let smap = SMAP_ENABLED.load(Ordering::Relaxed);
+    if smap {
+        asm!("stac", options(nomem, nostack));
+    }
+    let remaining = asm_copy_user(destination, source, size);
+    if smap {
+        asm!("clac", options(nomem, nostack));
+    }
+    match remaining {
+        0 => Ok(()),
+        _ => Err(Errno::EFAULT),
+    }
+}
+
+/// 用户空间中的一段内存 `[address, address + size)`
+#[derive(Clone,Copy,Debug)]
+pub struct UserSlice {
+    address : u64,
+    size : u64,
+}
+
+impl UserSlice {
+    pub fn new(address : u64, size : u64) -> UserSlice {
+        UserSlice { address, size }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// 检查整个范围都在当前进程的用户空间中, 且所在的 VMA 允许 `flags` 的访问
+    fn check(&self, flags : VmaFlags) -> Result<VirtAddr, Errno> {
+        let start = VirtAddr::try_new(self.address).map_err(|_| Errno::EFAULT)?;
+        if self.size == 0 {
+            return Ok(start);
+        }
+        let last = self.address.checked_add(self.size - 1)
+            .and_then(|last| VirtAddr::try_new(last).ok())
+            .ok_or(Errno::EFAULT)?;
+        if !is_user_address(start) || !is_user_address(last) {
+            return Err(Errno::EFAULT);
+        }
+        let process = current_process()?;
+        if !process.with_address_space(|space| space.check_range(start, self.size, flags)) {
+            return Err(Errno::EFAULT);
+        }
+        Ok(start)
+    }
+
+    /// 检查整个范围都可以写入, 用于在取走要写入的数据之前发现错误的地址
+    pub fn check_writable(&self) -> Result<(), Errno> {
+        self.check(VmaFlags::WRITE).map(|_| ())
+    }
+
+    /// 复制全部内容
+    pub fn read(&self) -> Result<Vec<u8>, Errno> {
+        let start = self.check(VmaFlags::READ)?;
+        let mut data = Vec::with_capacity(self.size as usize);
+        unsafe {
+            copy_user(data.as_mut_ptr(), start.as_ptr(), self.size as usize)?;
+            data.set_len(self.size as usize);
+        }
+        Ok(data)
+    }
+
+    /// 把 `data` 复制到开头, `data` 不能比这段内存长
+    pub fn write(&self, data : &[u8]) -> Result<(), Errno> {
+        if data.len() as u64 > self.size {
+            return Err(Errno::EINVAL);
+        }
+        let start = UserSlice::new(self.address, data.len() as u64).check(VmaFlags::WRITE)?;
+        unsafe { copy_user(start.as_mut_ptr(), data.as_ptr(), data.len()) }
+    }
+}
+
+/// 可以按字节在内核与用户空间之间复制的类型: 没有填充字节, 任意的字节都是合法的值
+///
+/// # Safety
+/// 实现的类型必须满足上面的条件, 否则会泄露内核栈上的数据或构造出非法的值。
+pub unsafe trait Plain : Copy {}
+
+unsafe impl Plain for u8 {}
+unsafe impl Plain for u32 {}
+unsafe impl Plain for u64 {}
+unsafe impl Plain for i64 {}
+unsafe impl Plain for FileStat {}
+unsafe impl Plain for MemoryUsage {}
+unsafe impl Plain for IpcMessage {}
+unsafe impl Plain for SyscallFrame {}
+
+/// 用户空间中的一个 `T`
+#[derive(Debug)]
+pub struct UserPtr<T : Plain> {
+    address : u64,
+    _type : PhantomData<*mut T>,
+}
+
+impl<T : Plain> Clone for UserPtr<T> {
+    fn clone(&self) -> UserPtr<T> {
+        *self
+    }
+}
+
+impl<T : Plain> Copy for UserPtr<T> {}
+
+impl<T : Plain> UserPtr<T> {
+    pub fn new(address : u64) -> UserPtr<T> {
+        UserPtr { address, _type : PhantomData }
+    }
+
+    fn slice(&self) -> UserSlice {
+        UserSlice::new(self.address, size_of::<T>() as u64)
+    }
+
+    pub fn read(&self) -> Result<T, Errno> {
+        let start = self.slice().check(VmaFlags::READ)?;
+        let mut value = MaybeUninit::<T>::uninit();
+        unsafe {
+            copy_user(value.as_mut_ptr() as *mut u8, start.as_ptr(), size_of::<T>())?;
+            Ok(value.assume_init())
+        }
+    }
+
+    pub fn check_writable(&self) -> Result<(), Errno> {
+        self.slice().check_writable()
+    }
+
+    pub fn write(&self, value : &T) -> Result<(), Errno> {
+        let start = self.slice().check(VmaFlags::WRITE)?;
+        unsafe { copy_user(start.as_mut_ptr(), value as *const T as *const u8, size_of::<T>()) }
+    }
+}
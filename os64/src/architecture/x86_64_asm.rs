@@ -1,4 +1,4 @@
-use core::arch::asm;
+use core::arch::{asm, global_asm};
 
 // #[cfg(all(target_arch = "x86", target_os = "interix"))] 
 #[inline(always)] 
@@ -32,6 +32,13 @@ pub fn asm_nop() {
     unsafe { asm!("nop"); }
 }
 
+/// 64 位随机数, 处理器不支持 RDRAND 时退回到时间戳计数器
+pub fn asm_random_u64() -> u64 {
+    x86_64::instructions::random::RdRand::new()
+        .and_then(|rdrand| rdrand.get_u64())
+        .unwrap_or_else(|| unsafe { core::arch::x86_64::_rdtsc() })
+}
+
 pub unsafe fn asm_in_u16(port : u16,buffer : *mut u16, size : usize){
     asm!(
         "cld",          // 清方向标志,用于字符串操作
@@ -67,3 +74,168 @@ pub unsafe fn asm_out_u32(port : u16, buffer : *const u32, size : usize){
         options(nostack, preserves_flags)
     );
 }
+
+// 内核线程切换：保存被调用者保存的寄存器和 rflags, 把当前 rsp 写入 *old_rsp, 再切换到 new_rsp。
+// 新线程的栈上预先放好这些寄存器，ret 时进入 thread_entry_trampoline, r12 中保存线程参数。
+global_asm!(
+    ".global asm_switch_context",
+    "asm_switch_context:",
+    "pushfq",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "popfq",
+    "ret",
+    "",
+    ".global asm_thread_entry_trampoline",
+    "asm_thread_entry_trampoline:",
+    "mov rdi, r12",
+    "call {entry}",
+    "ud2",
+    entry = sym crate::parallel::scheduler::thread_main,
+);
+
+extern "C" {
+    /// 保存当前上下文到 `*old_rsp` 并切换到 `new_rsp` 所指的上下文
+    pub fn asm_switch_context(old_rsp : *mut u64, new_rsp : u64);
+    /// 新线程第一次被调度时的入口
+    pub fn asm_thread_entry_trampoline();
+}
+
+// 系统调用入口(int 0x80): 保存所有通用寄存器, 以 api::SyscallFrame 的形式交给 api::system_call,
+// 返回值由它写入帧中的 rax。进入时处理器已对齐栈并压入 5 个字, 再压 15 个字后调用时栈按 16 字节对齐。
+// 开启 SMAP 时先清除用户态可能设置的 AC, 用户的 RFLAGS 已在帧中, iretq 时恢复。
+global_asm!(
+    ".global asm_system_call_entry",
+    "asm_system_call_entry:",
+    "cmp byte ptr [rip + {smap}], 0",
+    "je 2f",
+    "clac",
+    "2:",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "call {dispatch}",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    "iretq",
+    dispatch = sym crate::api::system_call,
+    smap = sym crate::api::user::SMAP_ENABLED,
+);
+
+extern "C" {
+    /// `int 0x80` 的中断处理入口
+    pub fn asm_system_call_entry();
+}
+
+// 从保存的系统调用帧返回用户态, 用于 fork 出的子进程: 帧的布局与 asm_system_call_entry 压栈的相同,
+// 直接把栈指针指向它, 依次弹出通用寄存器后 iretq。
+global_asm!(
+    ".global asm_return_to_user",
+    "asm_return_to_user:",
+    "mov rsp, rdi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    "iretq",
+);
+
+extern "C" {
+    /// 以 `*frame` 中的寄存器返回用户态, 帧必须在调用者的栈上
+    pub fn asm_return_to_user(frame : *const crate::api::SyscallFrame) -> !;
+}
+
+// 在内核与用户空间之间复制 rdx 字节: rdi 为目的地址, rsi 为源地址, 返回未复制的字节数。
+// asm_copy_user_copy 处的 rep movsb 缺页且无法处理时, 缺页处理程序让它从 asm_copy_user_fault 继续,
+// 此时 rcx 正好是剩余的字节数。
+global_asm!(
+    ".global asm_copy_user",
+    ".global asm_copy_user_copy",
+    ".global asm_copy_user_fault",
+    "asm_copy_user:",
+    "mov rcx, rdx",
+    "asm_copy_user_copy:",
+    "rep movsb",
+    "asm_copy_user_fault:",
+    "mov rax, rcx",
+    "ret",
+);
+
+extern "C" {
+    /// 从 `source` 复制 `size` 字节到 `destination`, 返回因缺页而未复制的字节数
+    pub fn asm_copy_user(destination : *mut u8, source : *const u8, size : usize) -> usize;
+    /// 可能缺页的复制指令
+    pub fn asm_copy_user_copy();
+    /// 缺页后继续执行的位置
+    pub fn asm_copy_user_fault();
+}
+
+/// 通过 iretq 进入用户态，从 `entry` 开始执行, 栈指针为 `stack_top`, 并打开中断
+///
+/// 调用前须设置好用户页表和 TSS 中的 RSP0。
+pub unsafe fn asm_enter_user_mode(entry : u64, stack_top : u64, code_selector : u16, data_selector : u16) -> ! {
+    asm!(
+        "mov ds, {data:x}",
+        "mov es, {data:x}",
+        "push {data}",      // ss
+        "push {stack}",     // rsp
+        "push 0x202",       // rflags: IF
+        "push {code}",      // cs
+        "push {entry}",     // rip
+        "iretq",
+        data = in(reg) data_selector as u64,
+        stack = in(reg) stack_top,
+        code = in(reg) code_selector as u64,
+        entry = in(reg) entry,
+        options(noreturn),
+    );
+}
@@ -0,0 +1,84 @@
+//see also: https://wiki.osdev.org/AML
+//see also: https://forum.osdev.org/viewtopic.php?t=16990
+// 极简的 AML 扫描，只用来取得 \_S5 对象中的 SLP_TYPa/SLP_TYPb, 不是完整的解释器。
+// DSDT 中 \_S5 通常编码为:
+//   NameOp(08) ['\'] "_S5_" PackageOp(12) PkgLength NumElements 元素...
+// 元素是整数常量: BytePrefix(0A) 字节 / WordPrefix(0B) / DWordPrefix(0C) / ZeroOp(00) / OneOp(01)
+use core::slice;
+use x86_64::PhysAddr;
+use crate::memory::phys_to_virt;
+use super::{SdtHeader, read_header, tables};
+
+const NAME_OP           : u8 = 0x08;
+const ROOT_CHAR         : u8 = b'\\';
+const PACKAGE_OP        : u8 = 0x12;
+const ZERO_OP           : u8 = 0x00;
+const ONE_OP            : u8 = 0x01;
+const BYTE_PREFIX       : u8 = 0x0A;
+const WORD_PREFIX       : u8 = 0x0B;
+const DWORD_PREFIX      : u8 = 0x0C;
+const ONES_OP           : u8 = 0xFF;
+
+/// 返回一张 AML 表(DSDT/SSDT)去掉表头后的字节
+fn aml_bytes(address : PhysAddr) -> Option<&'static [u8]> {
+    let header = read_header(address)?;
+    let offset = core::mem::size_of::<SdtHeader>();
+    let length = header.length as usize - offset;
+    Some(unsafe { slice::from_raw_parts(phys_to_virt(address + offset as u64).as_ptr::<u8>(), length) })
+}
+
+/// 读取一个整数常量，返回 (值, 占用的字节数)
+fn read_integer(bytes : &[u8]) -> Option<(u64, usize)> {
+    let value = |n : usize| -> Option<u64> {
+        let data = bytes.get(1..1 + n)?;
+        Some(data.iter().rev().fold(0u64, |v, b| v << 8 | *b as u64))
+    };
+    match *bytes.first()? {
+        ZERO_OP => Some((0, 1)),
+        ONE_OP => Some((1, 1)),
+        ONES_OP => Some((u64::MAX, 1)),
+        BYTE_PREFIX => Some((value(1)?, 2)),
+        WORD_PREFIX => Some((value(2)?, 3)),
+        DWORD_PREFIX => Some((value(4)?, 5)),
+        _ => None,
+    }
+}
+
+/// 在 `bytes` 中找到名为 `name` 的 Package, 返回它的前两个整数元素
+fn find_package_pair(bytes : &[u8], name : &[u8; 4]) -> Option<(u64, u64)> {
+    let mut i = 0;
+    while i + 4 < bytes.len() {
+        if &bytes[i..i + 4] != name {
+            i += 1;
+            continue;
+        }
+        // 名字前面必须是 NameOp, 或者 NameOp 加根前缀
+        let defined = (i >= 1 && bytes[i - 1] == NAME_OP)
+            || (i >= 2 && bytes[i - 1] == ROOT_CHAR && bytes[i - 2] == NAME_OP);
+        let mut p = i + 4;
+        if !defined || bytes.get(p) != Some(&PACKAGE_OP) {
+            i += 1;
+            continue;
+        }
+        p += 1;
+        // PkgLength: 首字节的高 2 位是后续字节数
+        let lead = *bytes.get(p)?;
+        p += 1 + (lead >> 6) as usize;
+        // NumElements
+        p += 1;
+        let (first, used) = read_integer(bytes.get(p..)?)?;
+        p += used;
+        let (second, _) = read_integer(bytes.get(p..)?)?;
+        return Some((first, second));
+    }
+    None
+}
+
+/// 在 DSDT 及所有 SSDT 中查找 \_S5, 返回 (SLP_TYPa, SLP_TYPb)
+pub fn find_s5(dsdt : PhysAddr) -> Option<(u8, u8)> {
+    let ssdts = tables().into_iter().flat_map(|t| t.find_tables(b"SSDT"));
+    core::iter::once(dsdt).chain(ssdts)
+        .filter_map(aml_bytes)
+        .find_map(|bytes| find_package_pair(bytes, b"_S5_"))
+        .map(|(a, b)| (a as u8, b as u8))
+}
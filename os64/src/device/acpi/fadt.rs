@@ -0,0 +1,163 @@
+//see also: https://wiki.osdev.org/FADT
+// FADT: Fixed ACPI Description Table,签名为 "FACP"
+// 给出电源管理寄存器(PM1 控制块等)、SCI 中断、复位寄存器以及 DSDT 的地址。
+// ACPI 1.0 的 FADT 只有 116 字节，之后的版本逐步增加了 64 位的扩展字段。
+use core::mem;
+use conquer_once::spin::OnceCell;
+use x86_64::PhysAddr;
+use super::{GenericAddress, SdtHeader, read_header, read_phys, tables};
+
+pub const FADT_SIGNATURE : &[u8; 4] = b"FACP";
+
+/// Flags 的第 10 位: 支持复位寄存器
+pub const FADT_FLAG_RESET_REG_SUP : u32 = 1 << 10;
+/// IAPC_BOOT_ARCH 的第 1 位: 存在 8042 键盘控制器
+pub const BOOT_ARCH_8042 : u16 = 1 << 1;
+/// RESET_VALUE 在表中的偏移, 表长超过它时复位寄存器和复位值才完整
+const FADT_RESET_VALUE_OFFSET : usize = 128;
+
+/// FADT 的原始布局，到 X_PM1b_CNT_BLK 为止
+#[repr(packed)]
+#[derive(Clone,Copy)]
+#[allow(dead_code)]
+struct Fadt {
+    header : SdtHeader,
+    firmware_control : u32,
+    dsdt : u32,
+    reserved0 : u8,
+    preferred_pm_profile : u8,
+    sci_interrupt : u16,
+    smi_command_port : u32,
+    acpi_enable : u8,
+    acpi_disable : u8,
+    s4bios_request : u8,
+    pstate_control : u8,
+    pm1a_event_block : u32,
+    pm1b_event_block : u32,
+    pm1a_control_block : u32,
+    pm1b_control_block : u32,
+    pm2_control_block : u32,
+    pm_timer_block : u32,
+    gpe0_block : u32,
+    gpe1_block : u32,
+    pm1_event_length : u8,
+    pm1_control_length : u8,
+    pm2_control_length : u8,
+    pm_timer_length : u8,
+    gpe0_length : u8,
+    gpe1_length : u8,
+    gpe1_base : u8,
+    cstate_control : u8,
+    worst_c2_latency : u16,
+    worst_c3_latency : u16,
+    flush_size : u16,
+    flush_stride : u16,
+    duty_offset : u8,
+    duty_width : u8,
+    day_alarm : u8,
+    month_alarm : u8,
+    century : u8,
+    boot_architecture_flags : u16,
+    reserved1 : u8,
+    flags : u32,
+    reset_register : GenericAddress,
+    reset_value : u8,
+    arm_boot_architecture_flags : u16,
+    minor_version : u8,
+    x_firmware_control : u64,
+    x_dsdt : u64,
+    x_pm1a_event_block : GenericAddress,
+    x_pm1b_event_block : GenericAddress,
+    x_pm1a_control_block : GenericAddress,
+    x_pm1b_control_block : GenericAddress,
+}
+
+/// 解析后的 FADT
+#[derive(Clone,Copy,Debug)]
+pub struct FadtInfo {
+    pub revision : u8,
+    pub dsdt : PhysAddr,
+    pub sci_interrupt : u16,
+    /// 写入 `acpi_enable` 可把系统从传统模式切换到 ACPI 模式, 为 0 表示已在 ACPI 模式
+    pub smi_command_port : u32,
+    pub acpi_enable : u8,
+    pub acpi_disable : u8,
+    pub pm1a_control : GenericAddress,
+    /// 可能不存在，这时 `is_null()` 为真
+    pub pm1b_control : GenericAddress,
+    pub pm_timer_block : u32,
+    /// CMOS 中世纪的寄存器号, 0 表示不支持
+    pub century : u8,
+    pub boot_architecture_flags : u16,
+    pub flags : u32,
+    /// 只有 flags 中设置了 RESET_REG_SUP 才有效
+    pub reset_register : Option<GenericAddress>,
+    pub reset_value : u8,
+}
+
+impl FadtInfo {
+    pub fn has_8042(&self) -> bool {
+        // ACPI 1.0 没有这个字段，这时假定存在
+        self.revision < 2 || self.boot_architecture_flags & BOOT_ARCH_8042 != 0
+    }
+}
+
+static FADT: OnceCell<FadtInfo> = OnceCell::uninit();
+
+/// 解析 FADT, 表不存在或 ACPI 尚未初始化时返回 `None`
+pub fn parse() -> Option<FadtInfo> {
+    let address = tables()?.find_table(FADT_SIGNATURE)?;
+    let header = read_header(address)?;
+
+    // 旧版本的表较短，缺少的字段按 0 处理
+    let mut raw = [0u8; mem::size_of::<Fadt>()];
+    let length = (header.length as usize).min(raw.len());
+    for (i, byte) in raw[..length].iter_mut().enumerate() {
+        *byte = unsafe { read_phys(address + i as u64) };
+    }
+    let fadt : Fadt = unsafe { core::ptr::read_unaligned(raw.as_ptr() as *const Fadt) };
+
+    let x_dsdt = fadt.x_dsdt;
+    let dsdt = if x_dsdt != 0 { x_dsdt } else { fadt.dsdt as u64 };
+    let pm1a_control = if fadt.x_pm1a_control_block.is_null() {
+        GenericAddress::io_port(fadt.pm1a_control_block, fadt.pm1_control_length * 8)
+    } else {
+        fadt.x_pm1a_control_block
+    };
+    let pm1b_control = if fadt.x_pm1b_control_block.is_null() {
+        GenericAddress::io_port(fadt.pm1b_control_block, fadt.pm1_control_length * 8)
+    } else {
+        fadt.x_pm1b_control_block
+    };
+    let flags = fadt.flags;
+    let reset_supported = length > FADT_RESET_VALUE_OFFSET && flags & FADT_FLAG_RESET_REG_SUP != 0 && !fadt.reset_register.is_null();
+
+    Some(FadtInfo {
+        revision : header.revision,
+        dsdt : PhysAddr::new(dsdt),
+        sci_interrupt : fadt.sci_interrupt,
+        smi_command_port : fadt.smi_command_port,
+        acpi_enable : fadt.acpi_enable,
+        acpi_disable : fadt.acpi_disable,
+        pm1a_control,
+        pm1b_control,
+        pm_timer_block : fadt.pm_timer_block,
+        century : fadt.century,
+        boot_architecture_flags : fadt.boot_architecture_flags,
+        flags,
+        reset_register : if reset_supported { Some(fadt.reset_register) } else { None },
+        reset_value : fadt.reset_value,
+    })
+}
+
+/// 解析一次 FADT 并保存
+pub(crate) fn init() -> Option<&'static FadtInfo> {
+    let fadt = parse()?;
+    let _ = FADT.try_init_once(|| fadt);
+    get()
+}
+
+/// 已解析的 FADT
+pub fn get() -> Option<&'static FadtInfo> {
+    FADT.try_get().ok()
+}
@@ -0,0 +1,135 @@
+//see also: https://wiki.osdev.org/HPET
+// HPET: High Precision Event Timer, 签名为 "HPET"
+// 表中给出 HPET 寄存器的物理地址；主计数器以固定周期(飞秒)递增，可用作高精度的时间源。
+use core::{mem, sync::atomic::{AtomicU64, Ordering}};
+use x86_64::{PhysAddr, VirtAddr, structures::paging::{FrameAllocator, Mapper, Size2MiB, Size4KiB, Translate}};
+use crate::{memory::map_physical_region, serial_println};
+use super::{GenericAddress, SdtHeader, ADDRESS_SPACE_SYSTEM_MEMORY, read_header, read_phys, tables};
+
+pub const HPET_SIGNATURE : &[u8; 4] = b"HPET";
+
+// HPET 寄存器偏移
+const HPET_CAPABILITIES     : u64 = 0x000;
+const HPET_CONFIGURATION    : u64 = 0x010;
+const HPET_MAIN_COUNTER     : u64 = 0x0F0;
+
+/// 配置寄存器的第 0 位: 主计数器开始计数
+const HPET_ENABLE_CNF       : u64 = 1;
+
+const FEMTOSECONDS_PER_NANOSECOND : u64 = 1_000_000;
+
+/// HPET 表在表头之后的部分
+#[repr(packed)]
+#[derive(Clone,Copy)]
+#[allow(dead_code)]
+struct HpetTable {
+    event_timer_block_id : u32,
+    base_address : GenericAddress,
+    hpet_number : u8,
+    minimum_tick : u16,
+    page_protection : u8,
+}
+
+/// 解析后的 HPET 表
+#[derive(Clone,Copy,Debug)]
+pub struct HpetInfo {
+    pub address : PhysAddr,
+    pub hardware_revision : u8,
+    /// 比较器(定时器)个数
+    pub comparator_count : u8,
+    pub counter_64bit : bool,
+    pub legacy_replacement : bool,
+    pub pci_vendor_id : u16,
+    pub hpet_number : u8,
+    /// 周期模式下的最小间隔(主计数器的计数)
+    pub minimum_tick : u16,
+}
+
+/// 解析 HPET 表，表不存在或 ACPI 尚未初始化时返回 `None`
+pub fn parse() -> Option<HpetInfo> {
+    let address = tables()?.find_table(HPET_SIGNATURE)?;
+    let header = read_header(address)?;
+    if (header.length as usize) < mem::size_of::<SdtHeader>() + mem::size_of::<HpetTable>() {
+        return None;
+    }
+    let table : HpetTable = unsafe { read_phys(address + mem::size_of::<SdtHeader>() as u64) };
+    if table.base_address.address_space != ADDRESS_SPACE_SYSTEM_MEMORY {
+        return None;
+    }
+
+    let id = table.event_timer_block_id;
+    Some(HpetInfo {
+        address : PhysAddr::new(table.base_address.address),
+        hardware_revision : id as u8,
+        comparator_count : ((id >> 8) & 0x1F) as u8 + 1,
+        counter_64bit : id & (1 << 13) != 0,
+        legacy_replacement : id & (1 << 15) != 0,
+        pci_vendor_id : (id >> 16) as u16,
+        hpet_number : table.hpet_number,
+        minimum_tick : table.minimum_tick,
+    })
+}
+
+/// HPET 寄存器的虚拟地址，0 表示尚未初始化
+static HPET_BASE: AtomicU64 = AtomicU64::new(0);
+/// 主计数器的周期(飞秒)
+static HPET_PERIOD: AtomicU64 = AtomicU64::new(0);
+
+unsafe fn read_register(base : VirtAddr, register : u64) -> u64 {
+    (base + register).as_ptr::<u64>().read_volatile()
+}
+
+unsafe fn write_register(base : VirtAddr, register : u64, value : u64) {
+    (base + register).as_mut_ptr::<u64>().write_volatile(value)
+}
+
+/// 映射 HPET 寄存器并启动主计数器, 须在 `device::acpi::init` 之后调用
+pub fn init(
+    mapper : &mut (impl Mapper<Size4KiB> + Mapper<Size2MiB> + Translate),
+    frame_allocator : &mut impl FrameAllocator<Size4KiB>,
+) -> Result<HpetInfo, &'static str> {
+    let info = parse().ok_or("HPET not found")?;
+    let base = map_physical_region(info.address, 1024, mapper, frame_allocator)
+        .map_err(|_| "failed to map HPET")?;
+
+    unsafe {
+        let period = read_register(base, HPET_CAPABILITIES) >> 32;
+        if period == 0 {
+            return Err("invalid HPET period");
+        }
+        let configuration = read_register(base, HPET_CONFIGURATION);
+        write_register(base, HPET_CONFIGURATION, configuration | HPET_ENABLE_CNF);
+        HPET_PERIOD.store(period, Ordering::Relaxed);
+    }
+    HPET_BASE.store(base.as_u64(), Ordering::Release);
+
+    serial_println!("HPET: address = 0x{:08x}, {} comparators, period = {} fs",
+        info.address.as_u64(), info.comparator_count, HPET_PERIOD.load(Ordering::Relaxed));
+    Ok(info)
+}
+
+/// 主计数器的当前值，HPET 未初始化时返回 `None`
+pub fn counter() -> Option<u64> {
+    match HPET_BASE.load(Ordering::Acquire) {
+        0 => None,
+        base => Some(unsafe { read_register(VirtAddr::new(base), HPET_MAIN_COUNTER) }),
+    }
+}
+
+/// 主计数器启动以来经过的纳秒数
+pub fn nanoseconds() -> Option<u64> {
+    let period = HPET_PERIOD.load(Ordering::Relaxed);
+    counter().map(|count| (count as u128 * period as u128 / FEMTOSECONDS_PER_NANOSECOND as u128) as u64)
+}
+
+/// 用 HPET 忙等待指定的微秒数，HPET 未初始化时立即返回 `false`
+pub fn busy_wait(microseconds : u64) -> bool {
+    let start = match nanoseconds() {
+        Some(start) => start,
+        None => return false,
+    };
+    while nanoseconds().unwrap_or(u64::MAX) - start < microseconds * 1000 {
+        core::hint::spin_loop();
+    }
+    true
+}
@@ -0,0 +1,187 @@
+//see also: https://wiki.osdev.org/MADT
+// MADT: Multiple APIC Description Table,签名为 "APIC"
+// 描述本机所有处理器的 Local APIC、所有 IO-APIC，以及 ISA 中断到全局中断号(GSI)的重定向关系
+use core::mem;
+use alloc::vec::Vec;
+use x86_64::PhysAddr;
+use super::{SdtHeader, read_header, read_phys, tables};
+
+pub const MADT_SIGNATURE : &[u8; 4] = b"APIC";
+
+/// MADT 中 flags 的第 0 位：同时装有 8259 PIC
+pub const MADT_FLAG_PCAT_COMPAT : u32 = 1;
+
+/// Local APIC flags 的第 0 位：处理器可用
+pub const LOCAL_APIC_ENABLED : u32 = 1;
+/// Local APIC flags 的第 1 位：处理器可以被启用
+pub const LOCAL_APIC_ONLINE_CAPABLE : u32 = 2;
+
+/// 中断极性 (flags 的 0~1 位)
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Polarity {
+    /// 遵循总线规范, ISA 为高电平有效
+    Conforming  = 0,
+    ActiveHigh  = 1,
+    ActiveLow   = 3,
+}
+
+/// 触发方式 (flags 的 2~3 位)
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum TriggerMode {
+    /// 遵循总线规范, ISA 为边沿触发
+    Conforming  = 0,
+    Edge        = 1,
+    Level       = 3,
+}
+
+fn polarity_from(flags : u16) -> Polarity {
+    match flags & 0x3 {
+        1 => Polarity::ActiveHigh,
+        3 => Polarity::ActiveLow,
+        _ => Polarity::Conforming,
+    }
+}
+
+fn trigger_mode_from(flags : u16) -> TriggerMode {
+    match (flags >> 2) & 0x3 {
+        1 => TriggerMode::Edge,
+        3 => TriggerMode::Level,
+        _ => TriggerMode::Conforming,
+    }
+}
+
+#[derive(Clone,Copy,Debug)]
+pub struct ProcessorLocalApic {
+    pub processor_id : u8,
+    pub apic_id : u8,
+    pub flags : u32,
+}
+
+impl ProcessorLocalApic {
+    pub fn usable(&self) -> bool {
+        self.flags & (LOCAL_APIC_ENABLED | LOCAL_APIC_ONLINE_CAPABLE) != 0
+    }
+}
+
+#[derive(Clone,Copy,Debug)]
+pub struct IoApicEntry {
+    pub id : u8,
+    pub address : PhysAddr,
+    /// 该 IO-APIC 第一个输入引脚对应的 GSI
+    pub gsi_base : u32,
+}
+
+/// ISA 中断重定向，如 QEMU 中 IRQ0(PIT) 接在 GSI 2 上
+#[derive(Clone,Copy,Debug)]
+pub struct InterruptSourceOverride {
+    pub bus : u8,
+    pub irq : u8,
+    pub gsi : u32,
+    pub polarity : Polarity,
+    pub trigger_mode : TriggerMode,
+}
+
+/// 接到 Local APIC LINT0/LINT1 的 NMI, processor_id 为 0xFF 表示所有处理器
+#[derive(Clone,Copy,Debug)]
+pub struct LocalApicNmi {
+    pub processor_id : u8,
+    pub lint : u8,
+    pub polarity : Polarity,
+    pub trigger_mode : TriggerMode,
+}
+
+/// 解析后的 MADT
+#[derive(Clone,Debug)]
+pub struct MadtInfo {
+    pub local_apic_address : PhysAddr,
+    pub flags : u32,
+    pub processors : Vec<ProcessorLocalApic>,
+    pub io_apics : Vec<IoApicEntry>,
+    pub overrides : Vec<InterruptSourceOverride>,
+    pub nmis : Vec<LocalApicNmi>,
+}
+
+impl MadtInfo {
+    /// 是否同时存在 8259 PIC, 如存在则启用 APIC 前需要屏蔽它
+    pub fn has_legacy_pic(&self) -> bool {
+        self.flags & MADT_FLAG_PCAT_COMPAT != 0
+    }
+
+    /// 将 ISA 中断号转换为 GSI 及其极性和触发方式
+    pub fn isa_irq_to_gsi(&self, irq : u8) -> (u32, Polarity, TriggerMode) {
+        match self.overrides.iter().find(|o| o.bus == 0 && o.irq == irq) {
+            Some(o) => (o.gsi, o.polarity, o.trigger_mode),
+            None => (irq as u32, Polarity::Conforming, TriggerMode::Conforming),
+        }
+    }
+
+    /// 找到负责给定 GSI 的 IO-APIC
+    pub fn io_apic_for_gsi(&self, gsi : u32) -> Option<&IoApicEntry> {
+        self.io_apics.iter().filter(|io| io.gsi_base <= gsi).max_by_key(|io| io.gsi_base)
+    }
+}
+
+/// 解析 MADT,表不存在或 ACPI 尚未初始化时返回 `None`
+pub fn parse() -> Option<MadtInfo> {
+    let address = tables()?.find_table(MADT_SIGNATURE)?;
+    let header = read_header(address)?;
+
+    // 表头之后是 Local APIC 地址(u32) 和 flags(u32), 再之后是变长的条目
+    let body = address + mem::size_of::<SdtHeader>() as u64;
+    let mut info = MadtInfo {
+        local_apic_address : PhysAddr::new(unsafe { read_phys::<u32>(body) } as u64),
+        flags : unsafe { read_phys::<u32>(body + 4u64) },
+        processors : Vec::new(),
+        io_apics : Vec::new(),
+        overrides : Vec::new(),
+        nmis : Vec::new(),
+    };
+
+    let end = address + header.length as u64;
+    let mut entry = body + 8u64;
+    while entry + 2u64 <= end {
+        let kind : u8 = unsafe { read_phys(entry) };
+        let length : u8 = unsafe { read_phys(entry + 1u64) };
+        if length < 2 {
+            break;
+        }
+        unsafe {
+            match kind {
+                0 => info.processors.push(ProcessorLocalApic {
+                    processor_id : read_phys(entry + 2u64),
+                    apic_id : read_phys(entry + 3u64),
+                    flags : read_phys(entry + 4u64),
+                }),
+                1 => info.io_apics.push(IoApicEntry {
+                    id : read_phys(entry + 2u64),
+                    address : PhysAddr::new(read_phys::<u32>(entry + 4u64) as u64),
+                    gsi_base : read_phys(entry + 8u64),
+                }),
+                2 => {
+                    let flags : u16 = read_phys(entry + 8u64);
+                    info.overrides.push(InterruptSourceOverride {
+                        bus : read_phys(entry + 2u64),
+                        irq : read_phys(entry + 3u64),
+                        gsi : read_phys(entry + 4u64),
+                        polarity : polarity_from(flags),
+                        trigger_mode : trigger_mode_from(flags),
+                    })
+                },
+                4 => {
+                    let flags : u16 = read_phys(entry + 3u64);
+                    info.nmis.push(LocalApicNmi {
+                        processor_id : read_phys(entry + 2u64),
+                        lint : read_phys(entry + 5u64),
+                        polarity : polarity_from(flags),
+                        trigger_mode : trigger_mode_from(flags),
+                    })
+                },
+                5 => info.local_apic_address = PhysAddr::new(read_phys::<u64>(entry + 4u64)),
+                _ => {},
+            }
+        }
+        entry += length as u64;
+    }
+
+    Some(info)
+}
@@ -0,0 +1,65 @@
+//see also: https://wiki.osdev.org/PCI_Express
+// MCFG: PCI Express memory mapped configuration space base address description table
+// 每个条目描述一个 PCI 段组中一段总线号的 ECAM(增强配置访问机制)区域：
+// 设备的配置空间位于 base + (bus - start_bus) << 20 | device << 15 | function << 12。
+use core::mem;
+use alloc::vec::Vec;
+use x86_64::PhysAddr;
+use super::{SdtHeader, read_header, read_phys, tables};
+
+pub const MCFG_SIGNATURE : &[u8; 4] = b"MCFG";
+
+/// 一个 ECAM 区域
+#[derive(Clone,Copy,Debug)]
+pub struct McfgEntry {
+    pub base_address : PhysAddr,
+    pub segment_group : u16,
+    pub start_bus : u8,
+    pub end_bus : u8,
+}
+
+impl McfgEntry {
+    pub fn contains(&self, segment_group : u16, bus : u8) -> bool {
+        self.segment_group == segment_group && bus >= self.start_bus && bus <= self.end_bus
+    }
+
+    /// 指定设备的配置空间的物理地址
+    pub fn config_address(&self, bus : u8, device : u8, function : u8) -> PhysAddr {
+        self.base_address
+            + (((bus - self.start_bus) as u64) << 20 | (device as u64) << 15 | (function as u64) << 12)
+    }
+
+    /// 这个区域的字节数
+    pub fn size(&self) -> usize {
+        (self.end_bus as usize - self.start_bus as usize + 1) << 20
+    }
+}
+
+/// 解析 MCFG, 表不存在(如只有传统 PCI 的机器)时返回空表
+pub fn parse() -> Vec<McfgEntry> {
+    let mut entries = Vec::new();
+    let address = match tables().and_then(|t| t.find_table(MCFG_SIGNATURE)) {
+        Some(address) => address,
+        None => return entries,
+    };
+    let header = match read_header(address) {
+        Some(header) => header,
+        None => return entries,
+    };
+
+    // 表头之后有 8 字节保留, 之后每个条目 16 字节
+    let end = address + header.length as u64;
+    let mut entry = address + mem::size_of::<SdtHeader>() as u64 + 8u64;
+    while entry + 16u64 <= end {
+        unsafe {
+            entries.push(McfgEntry {
+                base_address : PhysAddr::new(read_phys(entry)),
+                segment_group : read_phys(entry + 8u64),
+                start_bus : read_phys(entry + 10u64),
+                end_bus : read_phys(entry + 11u64),
+            });
+        }
+        entry += 16u64;
+    }
+    entries
+}
@@ -0,0 +1,279 @@
+//see also: https://wiki.osdev.org/RSDP
+//see also: https://wiki.osdev.org/RSDT
+// ACPI: Advanced Configuration and Power Interface,高级配置与电源接口。
+// 固件在内存中留下一组描述硬件的表，入口是 RSDP，它指向 RSDT(32位指针) 或 XSDT(64位指针)，
+// 再由它们指向 MADT(APIC)、FADT(电源)、HPET、MCFG(PCIe) 等具体的表。
+use core::{mem, slice};
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use x86_64::{PhysAddr, instructions::port::Port};
+use crate::{memory::phys_to_virt, serial_println};
+
+pub mod madt;
+pub mod fadt;
+pub mod hpet;
+pub mod mcfg;
+pub mod aml;
+pub mod power;
+
+pub use power::{shutdown, reboot};
+
+/// EBDA 段地址保存在 BIOS 数据区的这个位置
+const EBDA_SEGMENT_POINTER  : u64 = 0x40E;
+/// BIOS 只读区，RSDP 可能出现在这里的任意 16 字节边界上
+const BIOS_AREA_START       : u64 = 0xE0000;
+const BIOS_AREA_END         : u64 = 0x100000;
+
+pub const RSDP_SIGNATURE    : &[u8; 8] = b"RSD PTR ";
+
+/// Root System Description Pointer, ACPI 1.0 部分 20 字节，2.0 起扩展到 36 字节
+#[repr(packed)]
+#[derive(Clone,Copy,Debug)]
+pub struct Rsdp {
+    pub signature : [u8; 8],
+    pub checksum : u8,
+    pub oem_id : [u8; 6],
+    pub revision : u8,
+    pub rsdt_address : u32,
+    // 以下字段仅在 revision >= 2 时有效
+    pub length : u32,
+    pub xsdt_address : u64,
+    pub extended_checksum : u8,
+    pub reserved : [u8; 3],
+}
+
+/// 所有 ACPI 表(RSDP 除外)共有的表头，36 字节
+#[repr(packed)]
+#[derive(Clone,Copy,Debug)]
+pub struct SdtHeader {
+    pub signature : [u8; 4],
+    pub length : u32,
+    pub revision : u8,
+    pub checksum : u8,
+    pub oem_id : [u8; 6],
+    pub oem_table_id : [u8; 8],
+    pub oem_revision : u32,
+    pub creator_id : u32,
+    pub creator_revision : u32,
+}
+
+/// Generic Address Structure 的地址空间
+pub const ADDRESS_SPACE_SYSTEM_MEMORY   : u8 = 0;
+pub const ADDRESS_SPACE_SYSTEM_IO       : u8 = 1;
+pub const ADDRESS_SPACE_PCI_CONFIG      : u8 = 2;
+
+/// Generic Address Structure, 12 字节, 描述一个位于内存、IO 端口或 PCI 配置空间中的寄存器
+#[repr(packed)]
+#[derive(Clone,Copy,Debug,Default)]
+pub struct GenericAddress {
+    pub address_space : u8,
+    pub bit_width : u8,
+    pub bit_offset : u8,
+    /// 1 = byte, 2 = word, 3 = dword, 4 = qword, 0 = 未指定
+    pub access_size : u8,
+    pub address : u64,
+}
+
+impl GenericAddress {
+    /// 由 ACPI 1.0 的 32 位 IO 端口构造
+    pub fn io_port(port : u32, bit_width : u8) -> GenericAddress {
+        GenericAddress {
+            address_space : ADDRESS_SPACE_SYSTEM_IO,
+            bit_width,
+            bit_offset : 0,
+            access_size : 0,
+            address : port as u64,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        let address = self.address;
+        address == 0
+    }
+
+    /// 访问宽度(位), 优先使用 access_size
+    fn width(&self) -> u8 {
+        match self.access_size {
+            1 => 8,
+            2 => 16,
+            3 => 32,
+            4 => 64,
+            _ => if self.bit_width == 0 { 8 } else { self.bit_width },
+        }
+    }
+
+    pub unsafe fn read(&self) -> u64 {
+        let address = self.address;
+        match (self.address_space, self.width()) {
+            (ADDRESS_SPACE_SYSTEM_IO, 8) => Port::<u8>::new(address as u16).read() as u64,
+            (ADDRESS_SPACE_SYSTEM_IO, 16) => Port::<u16>::new(address as u16).read() as u64,
+            (ADDRESS_SPACE_SYSTEM_IO, _) => Port::<u32>::new(address as u16).read() as u64,
+            (ADDRESS_SPACE_SYSTEM_MEMORY, width) => {
+                let pointer = phys_to_virt(PhysAddr::new(address));
+                match width {
+                    8 => pointer.as_ptr::<u8>().read_volatile() as u64,
+                    16 => pointer.as_ptr::<u16>().read_volatile() as u64,
+                    32 => pointer.as_ptr::<u32>().read_volatile() as u64,
+                    _ => pointer.as_ptr::<u64>().read_volatile(),
+                }
+            },
+            _ => 0,
+        }
+    }
+
+    pub unsafe fn write(&self, value : u64) {
+        let address = self.address;
+        match (self.address_space, self.width()) {
+            (ADDRESS_SPACE_SYSTEM_IO, 8) => Port::<u8>::new(address as u16).write(value as u8),
+            (ADDRESS_SPACE_SYSTEM_IO, 16) => Port::<u16>::new(address as u16).write(value as u16),
+            (ADDRESS_SPACE_SYSTEM_IO, _) => Port::<u32>::new(address as u16).write(value as u32),
+            (ADDRESS_SPACE_SYSTEM_MEMORY, width) => {
+                let pointer = phys_to_virt(PhysAddr::new(address));
+                match width {
+                    8 => pointer.as_mut_ptr::<u8>().write_volatile(value as u8),
+                    16 => pointer.as_mut_ptr::<u16>().write_volatile(value as u16),
+                    32 => pointer.as_mut_ptr::<u32>().write_volatile(value as u32),
+                    _ => pointer.as_mut_ptr::<u64>().write_volatile(value),
+                }
+            },
+            // 总线 0 上的 PCI 配置空间: 地址为 设备 << 32 | 功能 << 16 | 偏移
+            (ADDRESS_SPACE_PCI_CONFIG, _) => {
+                let device = (address >> 32) as u32 & 0x1F;
+                let function = (address >> 16) as u32 & 0x7;
+                let offset = address as u32 & 0xFF;
+                let mut config_address : Port<u32> = Port::new(0xCF8);
+                let mut config_data : Port<u8> = Port::new(0xCFC + (offset & 3) as u16);
+                config_address.write(0x8000_0000 | device << 11 | function << 8 | (offset & 0xFC));
+                config_data.write(value as u8);
+            },
+            _ => {},
+        }
+    }
+}
+
+/// 已找到的 ACPI 表
+pub struct AcpiTables {
+    pub revision : u8,
+    pub rsdp_address : PhysAddr,
+    /// (签名, 物理地址)
+    pub tables : Vec<([u8; 4], PhysAddr)>,
+}
+
+impl AcpiTables {
+    /// 按签名查找表，返回第一个校验通过的表的物理地址
+    pub fn find_table(&self, signature : &[u8; 4]) -> Option<PhysAddr> {
+        self.tables.iter().find(|(s, _)| s == signature).map(|(_, address)| *address)
+    }
+
+    /// 按签名查找所有的表，如多个 SSDT
+    pub fn find_tables<'a>(&'a self, signature : &'a [u8; 4]) -> impl Iterator<Item = PhysAddr> + 'a {
+        self.tables.iter().filter(move |(s, _)| s == signature).map(|(_, address)| *address)
+    }
+}
+
+static ACPI_TABLES: OnceCell<AcpiTables> = OnceCell::uninit();
+
+/// 所有字节相加为 0 时校验通过
+fn checksum_ok(address : PhysAddr, length : usize) -> bool {
+    let bytes = unsafe { slice::from_raw_parts(phys_to_virt(address).as_ptr::<u8>(), length) };
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+/// 读取物理地址处的一个结构
+pub(crate) unsafe fn read_phys<T : Copy>(address : PhysAddr) -> T {
+    core::ptr::read_unaligned(phys_to_virt(address).as_ptr::<T>())
+}
+
+fn search_rsdp(start : u64, end : u64) -> Option<PhysAddr> {
+    let mut address = start;
+    while address + 20 <= end {
+        let candidate = PhysAddr::new(address);
+        let signature : [u8; 8] = unsafe { read_phys(candidate) };
+        if &signature == RSDP_SIGNATURE && checksum_ok(candidate, 20) {
+            return Some(candidate);
+        }
+        address += 16;
+    }
+    None
+}
+
+/// 在 EBDA 的前 1KB 及 BIOS 只读区中查找 RSDP
+pub fn find_rsdp() -> Option<PhysAddr> {
+    let ebda_segment : u16 = unsafe { read_phys(PhysAddr::new(EBDA_SEGMENT_POINTER)) };
+    let ebda = (ebda_segment as u64) << 4;
+    if ebda != 0 {
+        if let Some(rsdp) = search_rsdp(ebda, ebda + 1024) {
+            return Some(rsdp);
+        }
+    }
+    search_rsdp(BIOS_AREA_START, BIOS_AREA_END)
+}
+
+/// 读取表头，并检查整张表的校验和
+pub fn read_header(address : PhysAddr) -> Option<SdtHeader> {
+    let header : SdtHeader = unsafe { read_phys(address) };
+    if (header.length as usize) < mem::size_of::<SdtHeader>() || !checksum_ok(address, header.length as usize) {
+        None
+    } else {
+        Some(header)
+    }
+}
+
+/// 遍历 RSDT/XSDT, 记录每张表的地址
+fn walk_root_table(rsdp : &Rsdp) -> Vec<([u8; 4], PhysAddr)> {
+    let mut tables = Vec::new();
+    let use_xsdt = rsdp.revision >= 2 && rsdp.xsdt_address != 0;
+    let root = PhysAddr::new(if use_xsdt { rsdp.xsdt_address } else { rsdp.rsdt_address as u64 });
+    let header = match read_header(root) {
+        Some(header) => header,
+        None => {
+            serial_println!("ACPI: bad root table at {:?}", root);
+            return tables;
+        }
+    };
+
+    let entry_size = if use_xsdt { 8 } else { 4 };
+    let count = (header.length as usize - mem::size_of::<SdtHeader>()) / entry_size;
+    for i in 0..count {
+        let entry = root + mem::size_of::<SdtHeader>() as u64 + (i * entry_size) as u64;
+        let address = if use_xsdt {
+            unsafe { read_phys::<u64>(entry) }
+        } else {
+            unsafe { read_phys::<u32>(entry) as u64 }
+        };
+        let address = PhysAddr::new(address);
+        match read_header(address) {
+            Some(header) => tables.push((header.signature, address)),
+            None => serial_println!("ACPI: skip table with bad checksum at {:?}", address),
+        }
+    }
+    tables
+}
+
+/// 查找并解析 ACPI 根表，只需要调用一次
+pub fn init() -> Result<(), &'static str> {
+    let rsdp_address = find_rsdp().ok_or("RSDP not found")?;
+    let rsdp : Rsdp = unsafe { read_phys(rsdp_address) };
+    if rsdp.revision >= 2 && !checksum_ok(rsdp_address, rsdp.length as usize) {
+        return Err("RSDP extended checksum error");
+    }
+
+    let tables = walk_root_table(&rsdp);
+    for (signature, address) in tables.iter() {
+        serial_println!("ACPI: {} at 0x{:08x}", core::str::from_utf8(signature).unwrap_or("????"), address.as_u64());
+    }
+
+    ACPI_TABLES
+        .try_init_once(|| AcpiTables { revision: rsdp.revision, rsdp_address, tables })
+        .map_err(|_| "ACPI already initialized")?;
+
+    if let Err(e) = power::init() {
+        serial_println!("ACPI: power management unavailable: {}", e);
+    }
+    Ok(())
+}
+
+/// 返回已解析的 ACPI 表，`init` 之前调用返回 `None`
+pub fn tables() -> Option<&'static AcpiTables> {
+    ACPI_TABLES.try_get().ok()
+}
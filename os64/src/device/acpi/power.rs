@@ -0,0 +1,116 @@
+//see also: https://wiki.osdev.org/Shutdown
+//see also: https://wiki.osdev.org/Reboot
+// 关机和重启。
+// 关机: 向 PM1a(/PM1b) 控制寄存器写入 \_S5 的 SLP_TYP 和 SLP_EN, 进入 S5(soft off) 状态。
+// 重启: 优先使用 FADT 的复位寄存器，其次是 8042 键盘控制器，最后触发三重错误。
+use conquer_once::spin::OnceCell;
+use x86_64::{VirtAddr, instructions::{interrupts, port::Port, tables::{lidt, DescriptorTablePointer}}};
+use crate::serial_println;
+use super::{aml, fadt::{self, FadtInfo}};
+
+/// PM1 控制寄存器的位
+const PM1_SCI_EN        : u64 = 1;
+const PM1_SLP_TYP_SHIFT : u64 = 10;
+const PM1_SLP_EN        : u64 = 1 << 13;
+
+/// 8042 键盘控制器
+const KBC_STATUS_PORT   : u16 = 0x64;
+const KBC_INPUT_FULL    : u8 = 1 << 1;
+const KBC_PULSE_RESET   : u8 = 0xFE;
+/// 等待 8042 输入缓冲区变空时读取状态的次数
+const KBC_RETRIES       : usize = 100_000;
+
+/// 等待 SCI_EN 生效的最大次数
+const ACPI_ENABLE_RETRIES : usize = 1_000_000;
+
+/// \_S5 中的 (SLP_TYPa, SLP_TYPb)
+static S5: OnceCell<(u8, u8)> = OnceCell::uninit();
+
+/// 解析 FADT 及 \_S5, 由 `device::acpi::init` 调用
+pub(super) fn init() -> Result<(), &'static str> {
+    let fadt = fadt::init().ok_or("FADT not found")?;
+    serial_println!("ACPI: FADT revision {}, SCI = {}, PM1a_CNT = 0x{:x}, reset register: {}",
+        fadt.revision, fadt.sci_interrupt, { fadt.pm1a_control.address }, fadt.reset_register.is_some());
+
+    let s5 = aml::find_s5(fadt.dsdt).ok_or("\\_S5 not found in DSDT")?;
+    serial_println!("ACPI: \\_S5 SLP_TYPa = {}, SLP_TYPb = {}", s5.0, s5.1);
+    let _ = S5.try_init_once(|| s5);
+    Ok(())
+}
+
+/// 如果还处于传统模式，通过 SMI 命令端口切换到 ACPI 模式
+unsafe fn enable_acpi_mode(fadt : &FadtInfo) {
+    if fadt.pm1a_control.read() & PM1_SCI_EN != 0 {
+        return;
+    }
+    if fadt.smi_command_port == 0 || fadt.acpi_enable == 0 {
+        return;
+    }
+    Port::<u8>::new(fadt.smi_command_port as u16).write(fadt.acpi_enable);
+    for _ in 0..ACPI_ENABLE_RETRIES {
+        if fadt.pm1a_control.read() & PM1_SCI_EN != 0 {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// 进入 S5 状态, 成功时不会返回
+unsafe fn enter_s5(fadt : &FadtInfo, (slp_typ_a, slp_typ_b) : (u8, u8)) {
+    enable_acpi_mode(fadt);
+
+    let value = fadt.pm1a_control.read() & !(0x7 << PM1_SLP_TYP_SHIFT);
+    fadt.pm1a_control.write(value | (slp_typ_a as u64) << PM1_SLP_TYP_SHIFT | PM1_SLP_EN);
+    if !fadt.pm1b_control.is_null() {
+        let value = fadt.pm1b_control.read() & !(0x7 << PM1_SLP_TYP_SHIFT);
+        fadt.pm1b_control.write(value | (slp_typ_b as u64) << PM1_SLP_TYP_SHIFT | PM1_SLP_EN);
+    }
+}
+
+/// 关闭电源
+///
+/// ACPI 不可用时尝试 QEMU(0x604)和 Bochs/旧版 QEMU(0xB004)的 PM1a 端口，仍然失败则停机。
+pub fn shutdown() -> ! {
+    serial_println!("shutting down ...");
+    interrupts::disable();
+    unsafe {
+        if let (Some(fadt), Ok(s5)) = (fadt::get(), S5.try_get()) {
+            enter_s5(fadt, *s5);
+        }
+        Port::<u16>::new(0x604).write(0x2000);
+        Port::<u16>::new(0xB004).write(0x2000);
+    }
+    serial_println!("shutdown failed, halting");
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// 重启计算机
+pub fn reboot() -> ! {
+    serial_println!("rebooting ...");
+    interrupts::disable();
+    unsafe {
+        if let Some(fadt) = fadt::get() {
+            if let Some(reset) = fadt.reset_register {
+                reset.write(fadt.reset_value as u64);
+            }
+        }
+
+        if fadt::get().map(|f| f.has_8042()).unwrap_or(true) {
+            // 输入缓冲区一直不空时放弃, 改用三重错误
+            let mut status : Port<u8> = Port::new(KBC_STATUS_PORT);
+            if (0..KBC_RETRIES).any(|_| status.read() & KBC_INPUT_FULL == 0) {
+                status.write(KBC_PULSE_RESET);
+            }
+        }
+
+        // 加载一个空的 IDT, 任何中断都会导致三重错误而复位
+        let empty = DescriptorTablePointer { limit : 0, base : VirtAddr::new(0) };
+        lidt(&empty);
+        x86_64::instructions::interrupts::int3();
+    }
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
@@ -0,0 +1,63 @@
+// 控制台: 进程默认的标准输入、输出和错误输出
+// 输出写到串口 COM1; 输入来自键盘和 COM1 的中断, 放在固定大小的队列中, 读取时等待到至少有一个字节。
+// 进程读取时由控制台回显输入; 执行器中的 shell 用 `read_byte` 读取原始的字节, 自己处理回显。
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use os64_abi::Errno;
+use crate::parallel::{process, sync::{AsyncWaitQueue, WaitQueue}};
+use super::serial::COM1;
+
+/// 输入队列的大小, 满了之后新的输入被丢弃
+const INPUT_CAPACITY : usize = 1024;
+
+static INPUT: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static READERS: WaitQueue = WaitQueue::new();
+static READER_TASKS: AsyncWaitQueue = AsyncWaitQueue::new();
+
+fn input() -> &'static ArrayQueue<u8> {
+    let _ = INPUT.try_init_once(|| ArrayQueue::new(INPUT_CAPACITY));
+    INPUT.try_get().expect("console input queue not initialized")
+}
+
+/// 加入输入的字节, 由中断处理程序调用, 不能阻塞或分配内存
+///
+/// 第一次读取之前的输入被丢弃。
+pub fn push_input(bytes : &[u8]) {
+    if let Ok(queue) = INPUT.try_get() {
+        for byte in bytes {
+            let _ = queue.push(*byte);
+        }
+        READERS.notify_all();
+        READER_TASKS.notify_all();
+    }
+}
+
+/// 读取最多 `size` 字节的输入, 没有输入时等待, 等待中进程收到信号则返回 EINTR
+///
+/// 回车转换为换行, 读到的输入回显到串口。
+pub fn read(size : usize) -> Result<Vec<u8>, Errno> {
+    if size == 0 {
+        return Ok(Vec::new());
+    }
+    let queue = input();
+    let data = process::wait_interruptible(&READERS, || {
+        let data : Vec<u8> = core::iter::from_fn(|| queue.pop().ok())
+            .map(|byte| if byte == b'\r' { b'\n' } else { byte })
+            .take(size).collect();
+        if data.is_empty() { None } else { Some(Ok(data)) }
+    })?;
+    write(&data);
+    Ok(data)
+}
+
+/// 异步地读取一个字节的原始输入, 不回显
+pub async fn read_byte() -> u8 {
+    let queue = input();
+    READER_TASKS.wait_until(|| queue.pop().ok()).await
+}
+
+/// 原样输出到串口 COM1, 返回输出的字节数
+pub fn write(data : &[u8]) -> usize {
+    COM1.write_bytes(data)
+}
@@ -24,12 +24,12 @@
 //      0xFF0 - 0xFF6 保留  0xFF7 坏簇   0xFF8 - 0xFFF 文件的最后一个簇
 //  
 
-use core::{slice, cell::RefCell, borrow::{Borrow, BorrowMut}};
+use core::{slice, cell::{Cell, RefCell}, borrow::{Borrow, BorrowMut}};
 use bitfield::size_of;
 use bitflags::bitflags;
 use alloc::{boxed::Box, rc::{Rc, Weak}, vec::Vec, string::{String, ToString}};
 use crate::{serial_println, serial_print};
-use super::{disk::{DiskDriver, SECTOR_SIZE, SECTOR_BYTES}, file_system::{Time, Date, FileSystem, SuperBlock, IndexNode, Directory, File, DateTime, FileOpenMode, FilePosition}};
+use super::{disk::{DiskDriver, SECTOR_SIZE, SECTOR_BYTES}, file_system::{Time, Date, FileSystem, SuperBlock, IndexNode, Directory, File, DateTime, FileOpenMode, FilePosition, add_offset}};
 
 bitflags! { 
     ///目录项属性
@@ -296,7 +296,8 @@ impl FAT16Fats {
         let mut ret = Vec::new();
         let mut index = index as usize;
         ret.push(index as u16);
-        while self.data[index] < FAT16_END_FLAG {
+        // 损坏的 FAT 中簇链可能越界或成环
+        while index < self.data.len() && self.data[index] < FAT16_END_FLAG && ret.len() <= self.total_clusters {
             let temp = self.data[index];
             ret.push(temp);
             index = temp as usize;
@@ -346,12 +347,12 @@ pub struct FAT16SuperBlock {
 
 impl FAT16SuperBlock {
     pub fn new(driver : Rc<dyn DiskDriver>, sector0 : Rc<Fat16BootSector>) -> FAT16SuperBlock {
-        let cluster0_sector_index =  sector0.reserved_sectors as usize; 
+        let cluster0_sector_index =  sector0.reserved_sectors as usize;
         let clusters_count = sector0.get_totel_sectors() / (sector0.sectors_per_cluster as usize);
-        let root = Rc::new(FAT16SuperBlock::ReadRoot(&driver, &sector0));
         let mut fats = FAT16Fats::new(&driver, &sector0);
         fats.init(&driver);
         let fats = Rc::new(fats);
+        let root = Rc::new(FAT16SuperBlock::ReadRoot(&driver, &sector0, &fats));
 
         FAT16SuperBlock {
             driver,
@@ -364,32 +365,29 @@ impl FAT16SuperBlock {
     }
 
     ///读取根目录数据
-    pub fn ReadRoot(driver : &Rc<dyn DiskDriver>, boot_sector : &Fat16BootSector) -> FAT16Directory {
+    pub fn ReadRoot(driver : &Rc<dyn DiskDriver>, boot_sector : &Rc<Fat16BootSector>, fats : &Rc<FAT16Fats>) -> FAT16Directory {
         let root_sectors = (boot_sector.root_entries * 32 / boot_sector.bytes_per_sector) as usize;
         let root_bytes = root_sectors * boot_sector.bytes_per_sector as usize;
 
         let mut data: Vec<u8> = Vec::with_capacity(root_bytes);
         unsafe{data.set_len(root_bytes);}
 
-        let mut root_buffer  : Vec<Fat16DirectoryItem> = Vec::with_capacity(boot_sector.root_entries as usize);
-        unsafe{root_buffer.set_len(boot_sector.root_entries as usize);}
         let temp: &mut [u32] =  unsafe { slice::from_raw_parts_mut(data.as_mut_ptr() as *mut u32, root_bytes / 4) };
         let root_start_sectors = boot_sector.reserved_sectors  as u64 + boot_sector.fats as u64 * boot_sector.sectors_per_fat as u64;
         let _ = driver.read(root_start_sectors, root_sectors, temp);
         serial_println!("root_start_sectors = {}, root_sectors = {}", root_start_sectors, root_sectors);
-        // for i in 0..128 {
-        //     serial_print!("{:08x} ",data[i]);
-        // }
-        // serial_print!("");
-        serial_println!("root_buffer.len() = {}", root_buffer.len());
-        let mut root_item = Fat16DirectoryItem::root();
+        let root_item = Fat16DirectoryItem::root();
 
         FAT16Directory {
-            children_data : Rc::new(data), 
-            bytes : root_bytes, 
-            parent : None, 
+            children_data : Rc::new(data),
+            bytes : root_bytes,
+            parent : None,
             data : root_item,
+            name : u8_11_to_string(&root_item.name),
             clusters_index: Vec::new(),
+            driver : driver.clone(),
+            sector0 : boot_sector.clone(),
+            fats : fats.clone(),
         }
     }
 
@@ -409,28 +407,40 @@ impl SuperBlock for FAT16SuperBlock {
 ///
 #[derive(Clone)]
 pub struct FAT16IndexNode {
-    ///父目录
+    ///父目录, 根目录的父目录是它自己
     parent : Rc<FAT16Directory>,
-    index : usize,
-    longname_indexes : Vec<usize>,
+    ///目录项的副本
+    item : Fat16DirectoryItem,
+    ///有长文件名时为长文件名, 否则为 8.3 名字
+    name : String,
 }
 
 impl FAT16IndexNode {
     fn new(parent : Rc<FAT16Directory>, index : usize, longname_indexex: Vec<usize>) -> FAT16IndexNode {
+        let item = parent.get_child_item(index);
+        let name = long_name(&parent.get_children_longname(longname_indexex)).unwrap_or_else(|| u8_11_to_string(&item.name));
         FAT16IndexNode {
             parent,
-            index,
-            longname_indexes: longname_indexex,
+            item,
+            name,
         }
     }
 
     #[inline(always)]
     fn get_item(&self) -> Fat16DirectoryItem {
-        self.parent.get_child_item(self.index)
+        self.item
     }
 }
 
 impl IndexNode for FAT16IndexNode {
+    fn get_id(&self) -> u64 {
+        self.get_item().cluster_index as u64
+    }
+
+    fn is_directory(&self) -> bool {
+        self.get_item().attributes.contains(Attributes::DIRECTORY)
+    }
+
     fn get_parent(&self) -> Rc<dyn Directory> {
         self.parent.clone()
     }
@@ -440,7 +450,7 @@ impl IndexNode for FAT16IndexNode {
     }
 
     fn get_name(&self) -> String {
-        u8_11_to_string(&self.get_item().name)
+        self.name.clone()
     }
 
     fn set_name(&self, name : &str, super_block : &Rc<dyn SuperBlock>) {
@@ -477,8 +487,14 @@ pub struct FAT16Directory {
     parent : Option<Rc<FAT16Directory>>,
     ///数据
     data : Fat16DirectoryItem,
+    ///目录名
+    name : String,
     ///目录簇编号
     clusters_index : Vec<u16>,
+    ///读取子目录和文件用
+    driver : Rc<dyn DiskDriver>,
+    sector0 : Rc<Fat16BootSector>,
+    fats : Rc<FAT16Fats>,
 }
 
 impl FAT16Directory {
@@ -537,80 +553,203 @@ impl FAT16Directory {
     }
 
     pub fn open_file(&self, super_block : &Rc<FAT16SuperBlock>, index_node : Rc<FAT16IndexNode>) -> Result<Rc<FAT16File>,&'static str> {
-        let item = self.get_child_item(index_node.index);
-        let all_clusters = super_block.fats.get_all_clusters(item.cluster_index);
+        let all_clusters = self.clusters_of(index_node.get_item().cluster_index);
         let ret = Rc::new(FAT16File::new(Rc::new(self.clone()), index_node,RefCell::new(all_clusters)));
         Ok(ret)
     }
+
+    /// 目录中的文件和子目录, 跳过卷标、已删除的项和 "." ".."
+    fn entries(&self) -> Vec<Rc<FAT16IndexNode>> {
+        let parent = Rc::new(self.clone());
+        let count = self.bytes / size_of::<Fat16DirectoryItem>();
+        let mut ret = Vec::new();
+        let mut longname: Vec<usize> = Vec::new();
+        for i in 0..count {
+            let item = self.get_child_item(i);
+            match item.name[0] {
+                // 之后没有目录项了
+                0x00 => break,
+                // 已删除
+                0xE5 => {
+                    longname.clear();
+                    continue;
+                },
+                _ => {},
+            }
+            if item.attributes.contains(Attributes::LONG_NAME) {
+                longname.push(i);
+                continue;
+            }
+            let indexes = core::mem::take(&mut longname);
+            if item.attributes.contains(Attributes::VOLUME_ID) || item.name[0] == b'.' {
+                continue;
+            }
+            ret.push(Rc::new(FAT16IndexNode::new(parent.clone(), i, indexes)));
+        }
+        ret
+    }
+
+    /// 名字不区分大小写
+    fn child(&self, name : &str) -> Option<Rc<FAT16IndexNode>> {
+        self.entries().into_iter().find(|node| node.name.eq_ignore_ascii_case(name))
+    }
+
+    /// 以 `cluster` 开始的簇链, 空文件的起始簇为 0, 没有簇
+    fn clusters_of(&self, cluster : u16) -> Vec<u16> {
+        match cluster < 2 {
+            true => Vec::new(),
+            false => self.fats.get_all_clusters(cluster),
+        }
+    }
+
+    /// 读取一个簇到 `buffer`, 它的长度是每簇的字节数 / 4
+    fn read_cluster(&self, cluster : u16, buffer : &mut [u32]) -> Result<(), &'static str> {
+        if cluster < 2 {
+            return Err("invalid cluster");
+        }
+        let sector_index = self.sector0.get_sector_index(cluster as usize) as u64;
+        self.driver.read(sector_index, self.sector0.sectors_per_cluster as usize, buffer).map_err(|_| "disk read error")
+    }
+
+    /// 根目录, 目录项中的起始簇 0 表示它
+    fn root(&self) -> FAT16Directory {
+        let mut directory = self.clone();
+        while let Some(parent) = directory.parent.clone() {
+            directory = (*parent).clone();
+        }
+        directory
+    }
 }
 
 impl Directory for FAT16Directory {
     fn get_node(&self) -> Rc<dyn IndexNode> {
-        todo!()
+        Rc::new(FAT16IndexNode {
+            parent : self.parent.clone().unwrap_or_else(|| Rc::new(self.clone())),
+            item : self.data,
+            name : self.name.clone(),
+        })
     }
 
     fn get_children(&self) -> Vec<Rc<dyn IndexNode>> {
-        todo!()
+        self.entries().into_iter().map(|node| node as Rc<dyn IndexNode>).collect()
     }
 
     fn get_files(&self) -> Vec<Rc<dyn IndexNode>> {
-        todo!()
+        self.get_children().into_iter().filter(|node| !node.is_directory()).collect()
     }
 
     fn get_directories(&self) -> Vec<Rc<dyn IndexNode>> {
-        todo!()
+        self.get_children().into_iter().filter(|node| node.is_directory()).collect()
+    }
+
+    fn find(&self, name : &str) -> Option<Rc<dyn IndexNode>> {
+        self.child(name).map(|node| node as Rc<dyn IndexNode>)
     }
 
     /// open the file
-    fn open_file(&self, node : Rc<dyn IndexNode>, super_block : &Rc<dyn SuperBlock>) -> Rc<dyn File> {
-        todo!()
+    fn open_file(&self, node : Rc<dyn IndexNode>, mode : FileOpenMode, super_block : &Rc<dyn SuperBlock>) -> Rc<dyn File> {
+        let clusters = self.clusters_of(node.get_id() as u16);
+        let mut file = FAT16File::new(Rc::new(self.clone()), node, RefCell::new(clusters));
+        file.mode = mode;
+        Rc::new(file)
     }
 
     /// get the sub directory
     fn load_directory(&self, node : Rc<dyn IndexNode>, super_block : &Rc<dyn SuperBlock>) -> Rc<dyn Directory> {
-        todo!()
+        let child = match self.child(&node.get_name()) {
+            Some(child) if child.is_directory() && child.get_item().cluster_index >= 2 => child,
+            _ => return Rc::new(self.root()),
+        };
+        let clusters = self.clusters_of(child.get_item().cluster_index);
+        let bytes_per_cluster = self.sector0.get_bytes_per_cluster();
+        let mut data : Vec<u32> = alloc::vec![0; clusters.len() * bytes_per_cluster / size_of::<u32>()];
+        for (i, cluster) in clusters.iter().enumerate() {
+            let words = bytes_per_cluster / size_of::<u32>();
+            if self.read_cluster(*cluster, &mut data[i * words..(i + 1) * words]).is_err() {
+                break;
+            }
+        }
+        let bytes : Vec<u8> = data.iter().flat_map(|word| word.to_le_bytes()).collect();
+        Rc::new(FAT16Directory {
+            bytes : bytes.len(),
+            children_data : Rc::new(bytes),
+            parent : Some(Rc::new(self.clone())),
+            data : child.get_item(),
+            name : child.name.clone(),
+            clusters_index : clusters,
+            driver : self.driver.clone(),
+            sector0 : self.sector0.clone(),
+            fats : self.fats.clone(),
+        })
     }
 
-    fn create_directory(&self, super_block : &Rc<dyn SuperBlock>) -> Rc<dyn Directory> {
-        todo!()
+    fn create_file(&self, _name : &str, _super_block : &Rc<dyn SuperBlock>) -> Result<Rc<dyn IndexNode>, &'static str> {
+        Err("FAT16 is read-only")
+    }
+
+    fn create_directory(&self, _name : &str, _super_block : &Rc<dyn SuperBlock>) -> Result<Rc<dyn Directory>, &'static str> {
+        Err("FAT16 is read-only")
     }
 
     fn delete_directory(&self, super_block : &Rc<dyn SuperBlock>) {
         todo!()
     }
+
+    fn remove(&self, _name : &str, _super_block : &Rc<dyn SuperBlock>) -> Result<(), &'static str> {
+        Err("FAT16 is read-only")
+    }
+
+    fn rename(&self, _name : &str, _target : &Rc<dyn Directory>, _new_name : &str, _super_block : &Rc<dyn SuperBlock>) -> Result<(), &'static str> {
+        Err("FAT16 is read-only")
+    }
 }
 
 pub struct FAT16File  {
     // pub driver : Rc<dyn DiskDriver>,
     pub path : Rc<FAT16Directory>,
-    pub node : Rc<FAT16IndexNode>,
+    pub node : Rc<dyn IndexNode>,
     pub indexes : RefCell<Vec<u16>>,
-    pub pos : usize,
+    pub pos : Cell<usize>,
     pub mode : FileOpenMode,
 }
 
 impl FAT16File {
-    pub fn new(path : Rc<FAT16Directory>, node : Rc<FAT16IndexNode>, indexes : RefCell<Vec<u16>>) -> FAT16File {
-        FAT16File { path, node, indexes, pos: 0, mode: FileOpenMode::empty() }
+    pub fn new(path : Rc<FAT16Directory>, node : Rc<dyn IndexNode>, indexes : RefCell<Vec<u16>>) -> FAT16File {
+        FAT16File { path, node, indexes, pos: Cell::new(0), mode: FileOpenMode::empty() }
     }
 
     pub fn read_all_text(&self, super_block : &Rc<FAT16SuperBlock>) -> Rc<String> {
         Rc::new(String::from_utf8_lossy(&self.read_all_bytes(&super_block)).to_string())
     }
 
-    pub fn read_all_bytes(&self, super_block : &Rc<FAT16SuperBlock>) -> Vec<u8> {
-        let item = self.path.get_child_item(self.node.index);
-        let all_clusters_index = super_block.fats.get_all_clusters(item.cluster_index);
-        let bytes_per_cluster = super_block.sector0.get_bytes_per_cluster();
-        let buffer_size = bytes_per_cluster * all_clusters_index.len();
-        let mut ret : Vec<u8> = Vec::with_capacity(buffer_size);
-        let size = item.file_size as usize;
-        unsafe{ret.set_len(size);}
-        let ptr = ret.as_mut_ptr();
-        for i in 0..all_clusters_index.len() {
-            let sector_index = super_block.sector0.get_sector_index(all_clusters_index[i] as usize) as u64;
-            let data = unsafe { slice::from_raw_parts_mut(ptr.add(i * bytes_per_cluster) as *mut u32, bytes_per_cluster / size_of::<u32>()) };
-            let _ = super_block.driver.read(sector_index, super_block.sector0.sectors_per_cluster as usize, data);
+    pub fn read_all_bytes(&self, _super_block : &Rc<FAT16SuperBlock>) -> Vec<u8> {
+        let position = self.pos.replace(0);
+        let ret = self.read_at(0, self.node.get_size());
+        self.pos.set(position);
+        ret
+    }
+
+    /// 从 `position` 读取最多 `len` 字节, 每次读取一个簇, 读盘出错时返回已读到的部分
+    fn read_at(&self, position : usize, len : usize) -> Vec<u8> {
+        let end = self.node.get_size().min(position.saturating_add(len));
+        let bytes_per_cluster = self.path.sector0.get_bytes_per_cluster();
+        let mut ret = Vec::with_capacity(end.saturating_sub(position));
+        let mut buffer : Vec<u32> = alloc::vec![0; bytes_per_cluster / size_of::<u32>()];
+        let clusters = self.indexes.borrow();
+        let mut offset = position;
+        while offset < end {
+            let cluster = match clusters.get(offset / bytes_per_cluster) {
+                Some(cluster) => *cluster,
+                None => break,
+            };
+            if self.path.read_cluster(cluster, &mut buffer).is_err() {
+                break;
+            }
+            let data = unsafe { slice::from_raw_parts(buffer.as_ptr() as *const u8, bytes_per_cluster) };
+            let start = offset % bytes_per_cluster;
+            let count = (bytes_per_cluster - start).min(end - offset);
+            ret.extend_from_slice(&data[start..start + count]);
+            offset += count;
         }
         ret
     }
@@ -626,40 +765,29 @@ impl File for FAT16File {
     }
 
     fn get_position(&self) -> usize {
-        self.pos
+        self.pos.get()
     }
 
-    fn set_position(&mut self, pos : FilePosition) {
-        self.pos = match pos {
-            FilePosition::Start(o) => o ,
-            FilePosition::End(o) => ( self.node.get_size() as isize + o ) as usize,
-            FilePosition::Current(o) => ( self.pos as isize + o ) as usize,
-        }
+    fn set_position(&self, pos : FilePosition) -> Result<usize, &str> {
+        let pos = match pos {
+            FilePosition::Start(o) => Some(o),
+            FilePosition::End(o) => add_offset(self.node.get_size(), o),
+            FilePosition::Current(o) => add_offset(self.pos.get(), o),
+        };
+        let pos = pos.ok_or("position out of range")?;
+        self.pos.set(pos);
+        Ok(pos)
     }
 
-    fn read(&self, super_block : &Rc<dyn SuperBlock>, len : usize) -> RefCell<Vec<u8>> {
-        let mut len = len;
-        if len > self.node.get_size() - self.pos {
-            len = self.node.get_size() - self.pos;
-        }
-        let mut ret = RefCell::new(Vec::new());
-        ret.borrow_mut().resize(len, 0);
-        // super_block.get_disk.read();
-        // self.pos += len;
-        ret
+    fn read(&self, _super_block : &Rc<dyn SuperBlock>, len : usize) -> RefCell<Vec<u8>> {
+        let position = self.pos.get();
+        let ret = self.read_at(position, len);
+        self.pos.set(position + ret.len());
+        RefCell::new(ret)
     }
 
-    fn write(&self, super_block : &Rc<dyn SuperBlock>, data : &[u8]) -> Result<usize, &str> {
-        // let mut len = len;
-        // if len > self.node.get_size() - self.pos {
-        //     len = self.node.get_size() - self.pos;
-        // }
-        // let mut ret = RefCell::new(Vec::new());
-        // ret.borrow_mut().resize(len, 0);
-        // // super_block.get_disk.read();
-        // ret
-        // self.pos += len;
-        Ok(0)
+    fn write(&self, _super_block : &Rc<dyn SuperBlock>, _data : &[u8]) -> Result<usize, &str> {
+        Err("FAT16 is read-only")
     }
 
     fn flush(&self, super_block : &Rc<dyn SuperBlock>) -> Result<(), &str> {
@@ -672,15 +800,40 @@ impl File for FAT16File {
 }
 
 impl FileSystem for FAT16SuperBlock {
-    fn super_block(driver : Rc<dyn DiskDriver>) -> Rc<dyn SuperBlock> {
+    fn super_block(driver : Rc<dyn DiskDriver>) -> Result<Rc<dyn SuperBlock>, &'static str> {
         let mut data : [u32;SECTOR_SIZE] = [0;SECTOR_SIZE];
         //读取启动扇区
-        driver.read(0, 1, &mut data);
+        driver.read(0, 1, &mut data).map_err(|_| "cannot read the boot sector")?;
         let boot_sector = unsafe {*(data.as_mut_ptr() as *mut Fat16BootSector)};
-        Rc::new(FAT16SuperBlock::new(driver.clone(), Rc::new(boot_sector)))
+        //FAT16Fats::new 要求 FAT 项为 16 位
+        let clusters = boot_sector.get_totel_sectors() / (boot_sector.sectors_per_cluster.max(1) as usize);
+        let fat_bytes = boot_sector.sectors_per_fat as usize * boot_sector.bytes_per_sector as usize;
+        if boot_sector.magic != 0xAA55 || boot_sector.bytes_per_sector as usize != SECTOR_BYTES
+            || boot_sector.sectors_per_cluster == 0 || boot_sector.fats == 0
+            || clusters == 0 || fat_bytes * 8 / clusters != 16 {
+            return Err("not a FAT16 volume");
+        }
+        Ok(Rc::new(FAT16SuperBlock::new(driver.clone(), Rc::new(boot_sector))))
     }
 }
 
+/// 把长文件名目录项(按在目录中的顺序, 即倒序)拼成名字
+fn long_name(entries : &[FatDirectoryItemLongName]) -> Option<String> {
+    if entries.is_empty() {
+        return None;
+    }
+    let mut units : Vec<u16> = Vec::new();
+    for entry in entries.iter().rev() {
+        let (name1, name2, name3) = (entry.name1, entry.name2, entry.name3);
+        units.extend(name1.iter().chain(name2.iter()).chain(name3.iter()));
+    }
+    let end = units.iter().position(|unit| *unit == 0x0000 || *unit == 0xFFFF).unwrap_or(units.len());
+    let name : String = core::char::decode_utf16(units[..end].iter().copied())
+        .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+        .collect();
+    if name.is_empty() { None } else { Some(name) }
+}
+
 pub fn name_ext_to_u8_11(name : &str, ext : &str) -> [u8;11] {
     let mut ret : [u8;11] = [' ' as u8;11];
     let name = &name[0..8].to_uppercase();
@@ -94,10 +94,13 @@ bitflags! {
         //以写入方式打开文件（如文件已存在，首先会清空文件，文件指针在文件头）
         const   WRITE   = 0x08;
         //以追加方式打开文件（无论文件是否存在，文件指针在文件尾）
-        const   APPEND  = 0x04;
+        const   APPEND  = 0x10;
     }
 }
 
+/// 写入超过文件系统允许的最大文件时 `File::write` 返回的错误
+pub const FILE_TOO_LARGE : &str = "file too large";
+
 #[derive(Clone,Copy,Debug)]
 pub enum FilePosition {
     Start(usize),
@@ -105,6 +108,14 @@ pub enum FilePosition {
     Current(isize),
 }
 
+/// `base` 加上有符号的偏移, 结果为负或溢出时返回 `None`
+pub fn add_offset(base : usize, offset : isize) -> Option<usize> {
+    match offset < 0 {
+        true => base.checked_sub(offset.unsigned_abs()),
+        false => base.checked_add(offset as usize),
+    }
+}
+
 // 以下是文件系统需要实现的部分：
 
 pub trait SuperBlock {
@@ -113,6 +124,9 @@ pub trait SuperBlock {
 }
 
 pub trait IndexNode {
+    /// 在文件系统内唯一的编号
+    fn get_id(&self) -> u64;
+    fn is_directory(&self) -> bool;
     fn get_parent(&self) -> Rc<dyn Directory>;
     fn get_size(&self) -> usize;
 
@@ -139,17 +153,31 @@ pub trait Directory {
     /// get directories
 	fn get_directories(&self) -> Vec<Rc<dyn IndexNode>>;
 
+    /// find a child by name
+    fn find(&self, name : &str) -> Option<Rc<dyn IndexNode>> {
+        self.get_children().into_iter().find(|node| node.get_name() == name)
+    }
+
     /// open the file
-    fn open_file(&self, node : Rc<dyn IndexNode>, super_block : &Rc<dyn SuperBlock>) -> Rc<dyn File>;
+    fn open_file(&self, node : Rc<dyn IndexNode>, mode : FileOpenMode, super_block : &Rc<dyn SuperBlock>) -> Rc<dyn File>;
 
     /// get the sub directory
     fn load_directory(&self, node : Rc<dyn IndexNode>, super_block : &Rc<dyn SuperBlock>) -> Rc<dyn Directory>;
 
+    /// create an empty file
+    fn create_file(&self, name : &str, super_block : &Rc<dyn SuperBlock>) -> Result<Rc<dyn IndexNode>, &'static str>;
+
     /// create directory
-    fn create_directory(&self, super_block : &Rc<dyn SuperBlock>) -> Rc<dyn Directory>;
+    fn create_directory(&self, name : &str, super_block : &Rc<dyn SuperBlock>) -> Result<Rc<dyn Directory>, &'static str>;
 
     /// delete directory
     fn delete_directory(&self, super_block : &Rc<dyn SuperBlock>);
+
+    /// remove a file or an empty directory
+    fn remove(&self, name : &str, super_block : &Rc<dyn SuperBlock>) -> Result<(), &'static str>;
+
+    /// move the child `name` into `target` as `new_name`, replacing an existing file
+    fn rename(&self, name : &str, target : &Rc<dyn Directory>, new_name : &str, super_block : &Rc<dyn SuperBlock>) -> Result<(), &'static str>;
 }
 
 ///已经打开或创建的文件
@@ -159,7 +187,8 @@ pub trait File {
     fn get_mode(&self) -> FileOpenMode;
 
     fn get_position(&self) -> usize;
-    fn set_position(&mut self, pos : FilePosition);
+    /// 返回新的位置, 不能移到文件头之前
+    fn set_position(&self, pos : FilePosition) -> Result<usize, &str>;
 
     fn read(&self, super_block : &Rc<dyn SuperBlock>, len : usize) -> RefCell<Vec<u8>>;
     fn write(&self, super_block : &Rc<dyn SuperBlock>, data : &[u8]) -> Result<usize, &str>;
@@ -169,13 +198,10 @@ pub trait File {
 }
 
 pub trait FileSystem {
-    fn super_block(driver : Rc<dyn DiskDriver>) -> Rc<dyn SuperBlock>;
+    /// 读取 `driver` 上的文件系统, 不是这种文件系统时返回错误
+    fn super_block(driver : Rc<dyn DiskDriver>) -> Result<Rc<dyn SuperBlock>, &'static str>;
 }
 
-// pub fn mount<T : FileSystem>(directory : &'static str,  device : &'static str, file_system : &T ) -> Result<(),&'static str> {
-//     Ok(())
-// }
-
 // pub fn unmount<T : FileSystem>(directory : &'static str ) -> Result<(),&'static str> {
 //     Ok(())
 // }
\ No newline at end of file
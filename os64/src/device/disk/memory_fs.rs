@@ -0,0 +1,394 @@
+// 内存文件系统: 所有文件和目录都保存在内核堆中, 重启后丢失
+// 作为 vfs 的根文件系统, 实现 file_system 中的各个 trait
+use core::cell::{Cell, RefCell};
+use alloc::{collections::BTreeMap, rc::Rc, string::{String, ToString}, vec::Vec};
+use crate::device::clock::real_time_clock;
+use super::file_system::{DateTime, Directory, File, FileOpenMode, FilePosition, IndexNode, SuperBlock, FILE_TOO_LARGE, add_offset};
+
+/// 根目录的编号, 它的父目录是自己
+const ROOT_ID : u64 = 1;
+/// 文件的最大长度, 文件保存在内核堆中, 不能让一次写入或移动位置耗尽内存
+pub const MAX_FILE_SIZE : usize = 1024 * 1024;
+
+enum InodeData {
+    File(Vec<u8>),
+    /// 文件名到编号
+    Directory(BTreeMap<String, u64>),
+}
+
+struct Inode {
+    parent : u64,
+    name : String,
+    attribute : u64,
+    write_datetime : DateTime,
+    data : InodeData,
+}
+
+/// 所有节点, 由超级块、节点、目录和打开的文件共享
+struct Inodes {
+    inodes : RefCell<BTreeMap<u64, Inode>>,
+    next_id : Cell<u64>,
+}
+
+impl Inodes {
+    /// 访问节点 `id`, 它已被删除时返回 None
+    fn with<R>(&self, id : u64, f : impl FnOnce(&mut Inode) -> R) -> Option<R> {
+        self.inodes.borrow_mut().get_mut(&id).map(f)
+    }
+
+    fn children(&self, id : u64) -> Vec<u64> {
+        self.with(id, |inode| match &inode.data {
+            InodeData::Directory(children) => children.values().copied().collect(),
+            InodeData::File(_) => Vec::new(),
+        }).unwrap_or_default()
+    }
+
+    fn child(&self, id : u64, name : &str) -> Option<u64> {
+        self.with(id, |inode| match &inode.data {
+            InodeData::Directory(children) => children.get(name).copied(),
+            InodeData::File(_) => None,
+        }).flatten()
+    }
+
+    fn is_directory(&self, id : u64) -> bool {
+        self.with(id, |inode| matches!(inode.data, InodeData::Directory(_))).unwrap_or(false)
+    }
+
+    /// 在目录 `parent` 中创建 `name`
+    fn create(&self, parent : u64, name : &str, data : InodeData) -> Result<u64, &'static str> {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err("invalid file name");
+        }
+        if !self.is_directory(parent) {
+            return Err("not a directory");
+        }
+        if self.child(parent, name).is_some() {
+            return Err("file exists");
+        }
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        let mut inodes = self.inodes.borrow_mut();
+        inodes.insert(id, Inode {
+            parent,
+            name : name.to_string(),
+            attribute : 0,
+            write_datetime : real_time_clock::get_datetime(),
+            data,
+        });
+        if let Some(InodeData::Directory(children)) = inodes.get_mut(&parent).map(|inode| &mut inode.data) {
+            children.insert(name.to_string(), id);
+        }
+        Ok(id)
+    }
+
+    /// 从父目录中删除 `id` 及其数据, 目录必须为空
+    fn remove(&self, id : u64) -> Result<(), &'static str> {
+        if id == ROOT_ID {
+            return Err("cannot remove the root directory");
+        }
+        if !self.children(id).is_empty() {
+            return Err("directory not empty");
+        }
+        let mut inodes = self.inodes.borrow_mut();
+        let inode = inodes.remove(&id).ok_or("file not found")?;
+        if let Some(InodeData::Directory(children)) = inodes.get_mut(&inode.parent).map(|parent| &mut parent.data) {
+            children.remove(&inode.name);
+        }
+        Ok(())
+    }
+
+    /// `ancestor` 是否为 `id` 本身或它的上级目录
+    fn is_ancestor(&self, ancestor : u64, id : u64) -> bool {
+        let mut id = id;
+        loop {
+            if id == ancestor {
+                return true;
+            }
+            if id == ROOT_ID {
+                return false;
+            }
+            match self.with(id, |inode| inode.parent) {
+                Some(parent) => id = parent,
+                None => return false,
+            }
+        }
+    }
+}
+
+pub struct MemorySuperBlock {
+    inodes : Rc<Inodes>,
+}
+
+impl MemorySuperBlock {
+    /// 只有一个空的根目录
+    pub fn new() -> MemorySuperBlock {
+        let mut inodes = BTreeMap::new();
+        inodes.insert(ROOT_ID, Inode {
+            parent : ROOT_ID,
+            name : String::new(),
+            attribute : 0,
+            write_datetime : real_time_clock::get_datetime(),
+            data : InodeData::Directory(BTreeMap::new()),
+        });
+        MemorySuperBlock {
+            inodes : Rc::new(Inodes { inodes : RefCell::new(inodes), next_id : Cell::new(ROOT_ID + 1) }),
+        }
+    }
+}
+
+impl Default for MemorySuperBlock {
+    fn default() -> Self {
+        MemorySuperBlock::new()
+    }
+}
+
+impl SuperBlock for MemorySuperBlock {
+    fn write(&self) {}
+
+    fn get_root(&self) -> Rc<dyn Directory> {
+        Rc::new(MemoryDirectory { inodes : self.inodes.clone(), id : ROOT_ID })
+    }
+}
+
+pub struct MemoryIndexNode {
+    inodes : Rc<Inodes>,
+    id : u64,
+}
+
+impl IndexNode for MemoryIndexNode {
+    fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    fn is_directory(&self) -> bool {
+        self.inodes.is_directory(self.id)
+    }
+
+    fn get_parent(&self) -> Rc<dyn Directory> {
+        let parent = self.inodes.with(self.id, |inode| inode.parent).unwrap_or(ROOT_ID);
+        Rc::new(MemoryDirectory { inodes : self.inodes.clone(), id : parent })
+    }
+
+    /// 文件的字节数, 目录的项数
+    fn get_size(&self) -> usize {
+        self.inodes.with(self.id, |inode| match &inode.data {
+            InodeData::File(data) => data.len(),
+            InodeData::Directory(children) => children.len(),
+        }).unwrap_or(0)
+    }
+
+    fn get_name(&self) -> String {
+        self.inodes.with(self.id, |inode| inode.name.clone()).unwrap_or_default()
+    }
+
+    fn set_name(&self, name : &str, super_block : &Rc<dyn SuperBlock>) {
+        let parent = self.get_parent();
+        let _ = parent.rename(&self.get_name(), &parent, name, super_block);
+    }
+
+    fn get_attribute(&self) -> u64 {
+        self.inodes.with(self.id, |inode| inode.attribute).unwrap_or(0)
+    }
+
+    fn set_attribute(&mut self, value : u64, _super_block : &Rc<dyn SuperBlock>) {
+        self.inodes.with(self.id, |inode| inode.attribute = value);
+    }
+
+    fn set_write_datetime(&self, value : DateTime, _super_block : &Rc<dyn SuperBlock>) {
+        self.inodes.with(self.id, |inode| inode.write_datetime = value);
+    }
+
+    fn get_write_datetime(&self) -> DateTime {
+        self.inodes.with(self.id, |inode| inode.write_datetime).unwrap_or_else(real_time_clock::get_datetime)
+    }
+}
+
+pub struct MemoryDirectory {
+    inodes : Rc<Inodes>,
+    id : u64,
+}
+
+impl MemoryDirectory {
+    fn node(&self, id : u64) -> Rc<dyn IndexNode> {
+        Rc::new(MemoryIndexNode { inodes : self.inodes.clone(), id })
+    }
+}
+
+impl Directory for MemoryDirectory {
+    fn get_node(&self) -> Rc<dyn IndexNode> {
+        self.node(self.id)
+    }
+
+    fn get_children(&self) -> Vec<Rc<dyn IndexNode>> {
+        self.inodes.children(self.id).into_iter().map(|id| self.node(id)).collect()
+    }
+
+    fn get_files(&self) -> Vec<Rc<dyn IndexNode>> {
+        self.get_children().into_iter().filter(|node| !node.is_directory()).collect()
+    }
+
+    fn get_directories(&self) -> Vec<Rc<dyn IndexNode>> {
+        self.get_children().into_iter().filter(|node| node.is_directory()).collect()
+    }
+
+    fn find(&self, name : &str) -> Option<Rc<dyn IndexNode>> {
+        self.inodes.child(self.id, name).map(|id| self.node(id))
+    }
+
+    /// 以 WRITE 打开时清空文件, 以 APPEND 打开时位置在文件尾
+    fn open_file(&self, node : Rc<dyn IndexNode>, mode : FileOpenMode, _super_block : &Rc<dyn SuperBlock>) -> Rc<dyn File> {
+        let id = node.get_id();
+        let mut position = 0;
+        self.inodes.with(id, |inode| {
+            if let InodeData::File(data) = &mut inode.data {
+                if mode.contains(FileOpenMode::APPEND) {
+                    position = data.len();
+                } else if mode.contains(FileOpenMode::WRITE) {
+                    data.clear();
+                    inode.write_datetime = real_time_clock::get_datetime();
+                }
+            }
+        });
+        Rc::new(MemoryFile { inodes : self.inodes.clone(), id, mode, position : Cell::new(position) })
+    }
+
+    fn load_directory(&self, node : Rc<dyn IndexNode>, _super_block : &Rc<dyn SuperBlock>) -> Rc<dyn Directory> {
+        Rc::new(MemoryDirectory { inodes : self.inodes.clone(), id : node.get_id() })
+    }
+
+    fn create_file(&self, name : &str, _super_block : &Rc<dyn SuperBlock>) -> Result<Rc<dyn IndexNode>, &'static str> {
+        let id = self.inodes.create(self.id, name, InodeData::File(Vec::new()))?;
+        Ok(self.node(id))
+    }
+
+    fn create_directory(&self, name : &str, _super_block : &Rc<dyn SuperBlock>) -> Result<Rc<dyn Directory>, &'static str> {
+        let id = self.inodes.create(self.id, name, InodeData::Directory(BTreeMap::new()))?;
+        Ok(Rc::new(MemoryDirectory { inodes : self.inodes.clone(), id }))
+    }
+
+    fn delete_directory(&self, _super_block : &Rc<dyn SuperBlock>) {
+        let _ = self.inodes.remove(self.id);
+    }
+
+    fn remove(&self, name : &str, _super_block : &Rc<dyn SuperBlock>) -> Result<(), &'static str> {
+        let id = self.inodes.child(self.id, name).ok_or("file not found")?;
+        self.inodes.remove(id)
+    }
+
+    /// 目标已存在时: 文件被替换, 空目录只能被目录替换, 非空目录不能被替换
+    fn rename(&self, name : &str, target : &Rc<dyn Directory>, new_name : &str, _super_block : &Rc<dyn SuperBlock>) -> Result<(), &'static str> {
+        let id = self.inodes.child(self.id, name).ok_or("file not found")?;
+        let target_id = target.get_node().get_id();
+        if new_name.is_empty() || new_name == "." || new_name == ".." || new_name.contains('/') {
+            return Err("invalid file name");
+        }
+        if !self.inodes.is_directory(target_id) {
+            return Err("not a directory");
+        }
+        if self.inodes.is_directory(id) && self.inodes.is_ancestor(id, target_id) {
+            return Err("cannot move a directory into itself");
+        }
+        if let Some(existing) = self.inodes.child(target_id, new_name) {
+            if existing == id {
+                return Ok(());
+            }
+            match (self.inodes.is_directory(id), self.inodes.is_directory(existing)) {
+                (false, true) => return Err("is a directory"),
+                (true, false) => return Err("not a directory"),
+                _ => self.inodes.remove(existing)?,
+            }
+        }
+        let mut inodes = self.inodes.inodes.borrow_mut();
+        if let Some(InodeData::Directory(children)) = inodes.get_mut(&self.id).map(|inode| &mut inode.data) {
+            children.remove(name);
+        }
+        if let Some(InodeData::Directory(children)) = inodes.get_mut(&target_id).map(|inode| &mut inode.data) {
+            children.insert(new_name.to_string(), id);
+        }
+        if let Some(inode) = inodes.get_mut(&id) {
+            inode.parent = target_id;
+            inode.name = new_name.to_string();
+        }
+        Ok(())
+    }
+}
+
+/// 打开的文件, 有自己的读写位置
+pub struct MemoryFile {
+    inodes : Rc<Inodes>,
+    id : u64,
+    mode : FileOpenMode,
+    position : Cell<usize>,
+}
+
+impl File for MemoryFile {
+    fn get_node(&self) -> Rc<dyn IndexNode> {
+        Rc::new(MemoryIndexNode { inodes : self.inodes.clone(), id : self.id })
+    }
+
+    fn get_mode(&self) -> FileOpenMode {
+        self.mode
+    }
+
+    fn get_position(&self) -> usize {
+        self.position.get()
+    }
+
+    fn set_position(&self, pos : FilePosition) -> Result<usize, &str> {
+        let pos = match pos {
+            FilePosition::Start(offset) => Some(offset),
+            FilePosition::End(offset) => add_offset(self.get_node().get_size(), offset),
+            FilePosition::Current(offset) => add_offset(self.position.get(), offset),
+        };
+        match pos {
+            Some(pos) if pos <= MAX_FILE_SIZE => {
+                self.position.set(pos);
+                Ok(pos)
+            },
+            Some(_) => Err("position beyond the maximum file size"),
+            None => Err("position before the start of file"),
+        }
+    }
+
+    /// 从当前位置读取最多 `len` 字节, 在文件尾时返回空
+    fn read(&self, _super_block : &Rc<dyn SuperBlock>, len : usize) -> RefCell<Vec<u8>> {
+        let position = self.position.get();
+        let data = self.inodes.with(self.id, |inode| match &inode.data {
+            InodeData::File(data) if position < data.len() => {
+                data[position..data.len().min(position.saturating_add(len))].to_vec()
+            },
+            _ => Vec::new(),
+        }).unwrap_or_default();
+        self.position.set(position + data.len());
+        RefCell::new(data)
+    }
+
+    /// 写到当前位置, 以 APPEND 打开时总是写到文件尾; 位置超过文件尾时中间补 0
+    fn write(&self, _super_block : &Rc<dyn SuperBlock>, data : &[u8]) -> Result<usize, &str> {
+        let append = self.mode.contains(FileOpenMode::APPEND);
+        let position = self.position.get();
+        let end = self.inodes.with(self.id, |inode| match &mut inode.data {
+            InodeData::File(content) => {
+                let start = if append { content.len() } else { position };
+                let end = start.checked_add(data.len()).filter(|end| *end <= MAX_FILE_SIZE).ok_or(FILE_TOO_LARGE)?;
+                if content.len() < end {
+                    content.resize(end, 0);
+                }
+                content[start..end].copy_from_slice(data);
+                inode.write_datetime = real_time_clock::get_datetime();
+                Ok(end)
+            },
+            InodeData::Directory(_) => Err("is a directory"),
+        }).ok_or("file removed")??;
+        self.position.set(end);
+        Ok(data.len())
+    }
+
+    fn flush(&self, _super_block : &Rc<dyn SuperBlock>) -> Result<(), &str> {
+        Ok(())
+    }
+
+    fn close(&self, _super_block : &Rc<dyn SuperBlock>) -> Result<(), &str> {
+        Ok(())
+    }
+}
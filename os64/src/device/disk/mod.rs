@@ -3,3 +3,5 @@ pub mod ide;
 pub mod sata;
 pub mod file_system;
 pub mod fat;
+pub mod memory_fs;
+pub mod vfs;
@@ -0,0 +1,507 @@
+// 虚拟文件系统: 按路径在挂载的文件系统中查找文件, 并记录所有打开的文件
+// 路径总是从根目录开始, 以 '/' 分隔, 忽略空的部分和 ".", ".." 按名字回到上一级
+// 根目录是内存文件系统, 其它文件系统用 `mount` 挂载到它的目录上, 如数据盘挂载在 DISK_MOUNT_POINT。
+//
+// file_system 中的对象使用 Rc, 不能在线程间共享, 所以它们只在 VFS 的锁内创建、使用和释放;
+// 进程通过 FileHandle 引用打开的文件, 文件描述符表中还可以有通道、管道和控制台。
+use alloc::{collections::BTreeMap, rc::Rc, string::{String, ToString}, sync::Arc, vec::Vec};
+use os64_abi::{Errno, FileStat, FILE_KIND_DIRECTORY, FILE_KIND_FILE, MAX_OPEN_FILES, STDERR, STDIN, STDOUT};
+use crate::{device::console, parallel::{ipc::Endpoint, pipe::{PipeReader, PipeWriter}, sync::Mutex}};
+use super::{disk::DiskDriver, file_system::{Directory, File, FileOpenMode, FilePosition, FileSystem, IndexNode, SuperBlock, FILE_TOO_LARGE}, memory_fs::MemorySuperBlock};
+
+/// 数据盘(FAT16)的挂载点, 程序从这里加载
+pub const DISK_MOUNT_POINT : &str = "/disk";
+/// `read_all` 每次读取的字节数
+const READ_CHUNK_SIZE : usize = 64 * 1024;
+
+enum OpenFile {
+    /// 文件和它所在的文件系统
+    File { file : Rc<dyn File>, super_block : Rc<dyn SuperBlock> },
+    /// 目录的读取位置是下一项的序号
+    Directory { directory : Rc<dyn Directory>, position : usize },
+}
+
+/// 挂载在某个目录上的文件系统
+struct Mount {
+    /// 挂载点的各级名字, 根文件系统为空
+    names : Vec<String>,
+    super_block : Rc<dyn SuperBlock>,
+    read_only : bool,
+}
+
+struct Vfs {
+    /// 挂载点深的在前, 最后一个是根文件系统
+    mounts : Vec<Mount>,
+    open_files : BTreeMap<u64, OpenFile>,
+    next_handle : u64,
+}
+
+// 只在 VFS 的锁内访问
+unsafe impl Send for Vfs {}
+
+/// 第一次使用时以内存文件系统为根
+///
+/// 磁盘读写在锁内进行, 所以拿不到时阻塞而不是关中断自旋; 不能在中断处理程序中或持有 IrqSpinLock 时使用。
+static VFS: Mutex<Option<Vfs>> = Mutex::new(None);
+
+fn with_vfs<R>(f : impl FnOnce(&mut Vfs) -> R) -> R {
+    let mut vfs = VFS.lock();
+    f(vfs.get_or_insert_with(|| Vfs {
+        mounts : alloc::vec![Mount { names : Vec::new(), super_block : Rc::new(MemorySuperBlock::new()), read_only : false }],
+        open_files : BTreeMap::new(),
+        next_handle : 1,
+    }))
+}
+
+/// 打开的文件, 最后一个引用释放时关闭
+#[derive(Debug)]
+pub struct FileHandle(u64);
+
+impl Drop for FileHandle {
+    fn drop(&mut self) {
+        with_vfs(|vfs| {
+            if let Some(OpenFile::File { file, super_block }) = vfs.open_files.remove(&self.0) {
+                let _ = file.close(&super_block);
+            }
+        });
+    }
+}
+
+/// 路径的各级名字, 已经处理了 "." 和 ".."
+fn components(path : &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    for name in path.split('/') {
+        match name {
+            "" | "." => {},
+            ".." => {
+                names.pop();
+            },
+            name => names.push(name),
+        }
+    }
+    names
+}
+
+impl Vfs {
+    /// `names` 所在的文件系统和在其中的路径
+    fn mount<'a, 'b>(&'a self, names : &'b [&'b str]) -> (&'a Mount, &'b [&'b str]) {
+        let mount = self.mounts.iter()
+            .find(|mount| mount.names.len() <= names.len() && mount.names.iter().zip(names).all(|(a, b)| a == b))
+            .expect("the root file system is always mounted");
+        (mount, &names[mount.names.len()..])
+    }
+
+    fn walk(&self, names : &[&str]) -> Result<(&Mount, Rc<dyn IndexNode>), Errno> {
+        let (mount, names) = self.mount(names);
+        let mut node = mount.super_block.get_root().get_node();
+        for name in names {
+            if !node.is_directory() {
+                return Err(Errno::ENOTDIR);
+            }
+            node = directory(&mount.super_block, &node).find(name).ok_or(Errno::ENOENT)?;
+        }
+        Ok((mount, node))
+    }
+
+    fn lookup(&self, path : &str) -> Result<(&Mount, Rc<dyn IndexNode>), Errno> {
+        self.walk(&components(path))
+    }
+
+    /// 路径所在的文件系统、目录和最后一部分的名字, 根目录和挂载点不能作为最后一部分
+    fn lookup_parent<'a>(&self, path : &'a str) -> Result<(&Mount, Rc<dyn Directory>, &'a str), Errno> {
+        let names = components(path);
+        if self.mounts.iter().any(|mount| mount.names.iter().eq(names.iter())) {
+            return Err(if names.is_empty() { Errno::EINVAL } else { Errno::EBUSY });
+        }
+        let (name, parent) = names.split_last().ok_or(Errno::EINVAL)?;
+        let (mount, parent) = self.walk(parent)?;
+        if !parent.is_directory() {
+            return Err(Errno::ENOTDIR);
+        }
+        Ok((mount, directory(&mount.super_block, &parent), name))
+    }
+
+    /// 同 `lookup_parent`, 文件系统只读时返回 EROFS
+    fn lookup_writable_parent<'a>(&self, path : &'a str) -> Result<(&Mount, Rc<dyn Directory>, &'a str), Errno> {
+        let (mount, directory, name) = self.lookup_parent(path)?;
+        if mount.read_only {
+            return Err(Errno::EROFS);
+        }
+        Ok((mount, directory, name))
+    }
+
+    fn insert(&mut self, file : OpenFile) -> FileHandle {
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        self.open_files.insert(handle, file);
+        FileHandle(handle)
+    }
+
+    fn open(&mut self, path : &str, mode : FileOpenMode) -> Result<FileHandle, Errno> {
+        let (read_only, super_block, node) = match self.lookup(path) {
+            Ok(_) if !mode.contains(FileOpenMode::OPEN) => return Err(Errno::EEXIST),
+            Ok((mount, node)) => (mount.read_only, mount.super_block.clone(), node),
+            Err(Errno::ENOENT) if mode.contains(FileOpenMode::CREATE) => {
+                let (mount, directory, name) = self.lookup_writable_parent(path)?;
+                let node = directory.create_file(name, &mount.super_block).map_err(|_| Errno::EINVAL)?;
+                (mount.read_only, mount.super_block.clone(), node)
+            },
+            Err(errno) => return Err(errno),
+        };
+        let writing = mode.intersects(FileOpenMode::WRITE | FileOpenMode::APPEND);
+        if node.is_directory() {
+            if writing {
+                return Err(Errno::EISDIR);
+            }
+            let directory = directory(&super_block, &node);
+            return Ok(self.insert(OpenFile::Directory { directory, position : 0 }));
+        }
+        if writing && read_only {
+            return Err(Errno::EROFS);
+        }
+        let file = node.get_parent().open_file(node, mode, &super_block);
+        Ok(self.insert(OpenFile::File { file, super_block }))
+    }
+}
+
+/// 目录节点对应的目录
+fn directory(super_block : &Rc<dyn SuperBlock>, node : &Rc<dyn IndexNode>) -> Rc<dyn Directory> {
+    node.get_parent().load_directory(node.clone(), super_block)
+}
+
+/// 按 `mode` 打开文件或目录, 目录只能读
+pub fn open(path : &str, mode : FileOpenMode) -> Result<FileHandle, Errno> {
+    if !mode.intersects(FileOpenMode::OPEN | FileOpenMode::CREATE)
+        || !mode.intersects(FileOpenMode::READ | FileOpenMode::WRITE | FileOpenMode::APPEND) {
+        return Err(Errno::EINVAL);
+    }
+    with_vfs(|vfs| vfs.open(path, mode))
+}
+
+/// 把 `driver` 上的文件系统 `F` 挂载到已存在的目录 `path`, 之后 `path` 之下的路径都在这个文件系统中查找
+///
+/// `read_only` 时不能在其中创建、写入、删除或移动文件。
+pub fn mount<F : FileSystem>(path : &str, driver : impl DiskDriver + 'static, read_only : bool) -> Result<(), Errno> {
+    with_vfs(|vfs| {
+        let names : Vec<String> = components(path).iter().map(|name| name.to_string()).collect();
+        if vfs.mounts.iter().any(|mount| mount.names == names) {
+            return Err(Errno::EBUSY);
+        }
+        if !vfs.lookup(path)?.1.is_directory() {
+            return Err(Errno::ENOTDIR);
+        }
+        let super_block = F::super_block(Rc::new(driver)).map_err(|_| Errno::EINVAL)?;
+        vfs.mounts.push(Mount { names, super_block, read_only });
+        vfs.mounts.sort_by(|a, b| b.names.len().cmp(&a.names.len()));
+        Ok(())
+    })
+}
+
+/// 读取文件的全部内容
+pub fn read_all(path : &str) -> Result<Vec<u8>, Errno> {
+    let handle = open(path, FileOpenMode::OPEN | FileOpenMode::READ)?;
+    let mut data = Vec::new();
+    loop {
+        let chunk = read(&handle, READ_CHUNK_SIZE)?;
+        if chunk.is_empty() {
+            return Ok(data);
+        }
+        data.extend_from_slice(&chunk);
+    }
+}
+
+/// 从当前位置读取最多 `size` 字节
+pub fn read(handle : &FileHandle, size : usize) -> Result<Vec<u8>, Errno> {
+    with_vfs(|vfs| match vfs.open_files.get(&handle.0) {
+        Some(OpenFile::File { file, super_block }) if file.get_mode().contains(FileOpenMode::READ) => {
+            Ok(file.read(super_block, size).into_inner())
+        },
+        Some(OpenFile::File { .. }) => Err(Errno::EBADF),
+        Some(OpenFile::Directory { .. }) => Err(Errno::EISDIR),
+        None => Err(Errno::EBADF),
+    })
+}
+
+/// 从 `offset` 读取最多 `size` 字节, 不改变读写位置
+pub fn read_at(handle : &FileHandle, offset : usize, size : usize) -> Result<Vec<u8>, Errno> {
+    with_vfs(|vfs| match vfs.open_files.get(&handle.0) {
+        Some(OpenFile::File { file, super_block }) if file.get_mode().contains(FileOpenMode::READ) => {
+            let position = file.get_position();
+            file.set_position(FilePosition::Start(offset)).map_err(|_| Errno::EINVAL)?;
+            let data = file.read(super_block, size).into_inner();
+            let _ = file.set_position(FilePosition::Start(position));
+            Ok(data)
+        },
+        Some(OpenFile::File { .. }) => Err(Errno::EBADF),
+        Some(OpenFile::Directory { .. }) => Err(Errno::EISDIR),
+        None => Err(Errno::EBADF),
+    })
+}
+
+/// 写到当前位置, 返回写入的字节数
+pub fn write(handle : &FileHandle, data : &[u8]) -> Result<usize, Errno> {
+    with_vfs(|vfs| match vfs.open_files.get(&handle.0) {
+        Some(OpenFile::File { file, super_block }) if file.get_mode().intersects(FileOpenMode::WRITE | FileOpenMode::APPEND) => {
+            file.write(super_block, data).map_err(|e| match e {
+                FILE_TOO_LARGE => Errno::EFBIG,
+                _ => Errno::EINVAL,
+            })
+        },
+        Some(OpenFile::File { .. }) => Err(Errno::EBADF),
+        Some(OpenFile::Directory { .. }) => Err(Errno::EISDIR),
+        None => Err(Errno::EBADF),
+    })
+}
+
+/// 移动读写位置, 返回新的位置; 目录只能回到开头
+pub fn seek(handle : &FileHandle, position : FilePosition) -> Result<usize, Errno> {
+    with_vfs(|vfs| match vfs.open_files.get_mut(&handle.0) {
+        Some(OpenFile::File { file, .. }) => file.set_position(position).map_err(|_| Errno::EINVAL),
+        Some(OpenFile::Directory { position : current, .. }) => match position {
+            FilePosition::Start(0) => {
+                *current = 0;
+                Ok(0)
+            },
+            _ => Err(Errno::EINVAL),
+        },
+        None => Err(Errno::EBADF),
+    })
+}
+
+/// 从当前位置读取目录项, 总长度不超过 `capacity`, 格式见 os64_abi::FileStat
+pub fn read_dir(handle : &FileHandle, capacity : usize) -> Result<Vec<u8>, Errno> {
+    with_vfs(|vfs| match vfs.open_files.get_mut(&handle.0) {
+        Some(OpenFile::Directory { directory, position }) => {
+            let mut entries = Vec::new();
+            for node in directory.get_children().iter().skip(*position) {
+                let name = node.get_name();
+                if entries.len() + name.len() + 2 > capacity {
+                    if entries.is_empty() {
+                        return Err(Errno::EINVAL);
+                    }
+                    break;
+                }
+                entries.push(if node.is_directory() { FILE_KIND_DIRECTORY } else { FILE_KIND_FILE });
+                entries.extend_from_slice(name.as_bytes());
+                entries.push(0);
+                *position += 1;
+            }
+            Ok(entries)
+        },
+        Some(OpenFile::File { .. }) => Err(Errno::ENOTDIR),
+        None => Err(Errno::EBADF),
+    })
+}
+
+pub fn stat(path : &str) -> Result<FileStat, Errno> {
+    with_vfs(|vfs| {
+        let (_, node) = vfs.lookup(path)?;
+        let datetime = node.get_write_datetime();
+        let date = if datetime.0.0 < 1980 { 0 } else { datetime.0.to_u16() };
+        Ok(FileStat {
+            id : node.get_id(),
+            kind : if node.is_directory() { FILE_KIND_DIRECTORY } else { FILE_KIND_FILE } as u64,
+            size : node.get_size() as u64,
+            write_time : (date as u64) << 16 | datetime.1.to_u16() as u64,
+        })
+    })
+}
+
+pub fn create_directory(path : &str) -> Result<(), Errno> {
+    with_vfs(|vfs| {
+        let (mount, directory, name) = vfs.lookup_writable_parent(path)?;
+        if directory.find(name).is_some() {
+            return Err(Errno::EEXIST);
+        }
+        directory.create_directory(name, &mount.super_block).map(|_| ()).map_err(|_| Errno::EINVAL)
+    })
+}
+
+/// 删除文件或空目录, 已打开的文件之后读不到数据
+pub fn remove(path : &str) -> Result<(), Errno> {
+    with_vfs(|vfs| {
+        let (mount, directory, name) = vfs.lookup_writable_parent(path)?;
+        let node = directory.find(name).ok_or(Errno::ENOENT)?;
+        if node.is_directory() && node.get_size() > 0 {
+            return Err(Errno::ENOTEMPTY);
+        }
+        directory.remove(name, &mount.super_block).map_err(|_| Errno::EINVAL)
+    })
+}
+
+/// 移动文件或目录, 已存在的目标被替换
+pub fn rename(from : &str, to : &str) -> Result<(), Errno> {
+    with_vfs(|vfs| {
+        let (mount, source, name) = vfs.lookup_writable_parent(from)?;
+        let node = source.find(name).ok_or(Errno::ENOENT)?;
+        let (target_mount, target, new_name) = vfs.lookup_writable_parent(to)?;
+        if !core::ptr::eq(mount, target_mount) {
+            return Err(Errno::EXDEV);
+        }
+        if let Some(existing) = target.find(new_name) {
+            match (node.is_directory(), existing.is_directory()) {
+                (false, true) => return Err(Errno::EISDIR),
+                (true, false) => return Err(Errno::ENOTDIR),
+                (true, true) if existing.get_id() != node.get_id() && existing.get_size() > 0 => return Err(Errno::ENOTEMPTY),
+                _ => {},
+            }
+        }
+        // 目录不能移到自己之下
+        let mut ancestor = target.get_node();
+        loop {
+            if ancestor.get_id() == node.get_id() {
+                return Err(Errno::EINVAL);
+            }
+            let parent = ancestor.get_parent().get_node();
+            if parent.get_id() == ancestor.get_id() {
+                break;
+            }
+            ancestor = parent;
+        }
+        source.rename(name, &target, new_name, &mount.super_block).map_err(|_| Errno::EINVAL)
+    })
+}
+
+/// 文件描述符引用的对象
+#[derive(Clone)]
+pub enum Descriptor {
+    File(Arc<FileHandle>),
+    /// 进程间通信的通道一端
+    Channel(Arc<Endpoint>),
+    PipeReader(Arc<PipeReader>),
+    PipeWriter(Arc<PipeWriter>),
+    /// 控制台, 可读可写
+    Console,
+}
+
+impl Descriptor {
+    /// 读取最多 `size` 字节, 管道和控制台没有数据时等待, 等待中进程收到信号则返回 EINTR
+    pub fn read(&self, size : usize) -> Result<Vec<u8>, Errno> {
+        match self {
+            Descriptor::File(handle) => read(handle, size),
+            Descriptor::PipeReader(reader) => reader.read(size, true),
+            Descriptor::Console => console::read(size),
+            Descriptor::Channel(_) | Descriptor::PipeWriter(_) => Err(Errno::EBADF),
+        }
+    }
+
+    /// 写入 `data`, 返回写入的字节数, 管道已满时等待
+    pub fn write(&self, data : &[u8]) -> Result<usize, Errno> {
+        match self {
+            Descriptor::File(handle) => write(handle, data),
+            Descriptor::PipeWriter(writer) => writer.write(data, true),
+            Descriptor::Console => Ok(console::write(data)),
+            Descriptor::Channel(_) | Descriptor::PipeReader(_) => Err(Errno::EBADF),
+        }
+    }
+}
+
+impl From<FileHandle> for Descriptor {
+    fn from(handle : FileHandle) -> Descriptor {
+        Descriptor::File(Arc::new(handle))
+    }
+}
+
+impl From<Arc<Endpoint>> for Descriptor {
+    fn from(endpoint : Arc<Endpoint>) -> Descriptor {
+        Descriptor::Channel(endpoint)
+    }
+}
+
+impl From<PipeReader> for Descriptor {
+    fn from(reader : PipeReader) -> Descriptor {
+        Descriptor::PipeReader(Arc::new(reader))
+    }
+}
+
+impl From<PipeWriter> for Descriptor {
+    fn from(writer : PipeWriter) -> Descriptor {
+        Descriptor::PipeWriter(Arc::new(writer))
+    }
+}
+
+/// 进程的文件描述符表
+///
+/// fork 时复制, 父子进程共享打开的文件和读写位置。
+#[derive(Clone, Default)]
+pub struct FileTable {
+    files : BTreeMap<u64, Descriptor>,
+}
+
+impl FileTable {
+    pub fn new() -> FileTable {
+        FileTable::default()
+    }
+
+    /// 标准输入、输出和错误输出都指向控制台的表, 新进程使用
+    pub fn with_stdio() -> FileTable {
+        let mut table = FileTable::new();
+        for fd in [STDIN, STDOUT, STDERR].iter() {
+            table.files.insert(*fd, Descriptor::Console);
+        }
+        table
+    }
+
+    /// 使用最小的空闲描述符
+    pub fn insert(&mut self, descriptor : impl Into<Descriptor>) -> Result<u64, Errno> {
+        if self.files.len() >= MAX_OPEN_FILES {
+            return Err(Errno::EMFILE);
+        }
+        let fd = (0..).find(|fd| !self.files.contains_key(fd)).unwrap_or(0);
+        self.files.insert(fd, descriptor.into());
+        Ok(fd)
+    }
+
+    /// 一次插入多个描述符, 空闲的描述符不够时一个也不插入
+    pub fn insert_all(&mut self, descriptors : Vec<Descriptor>) -> Result<Vec<u64>, Errno> {
+        if self.files.len() + descriptors.len() > MAX_OPEN_FILES {
+            return Err(Errno::EMFILE);
+        }
+        descriptors.into_iter().map(|descriptor| self.insert(descriptor)).collect()
+    }
+
+    pub fn get(&self, fd : u64) -> Result<Descriptor, Errno> {
+        self.files.get(&fd).cloned().ok_or(Errno::EBADF)
+    }
+
+    /// 描述符引用的文件, 其它对象返回 EBADF
+    pub fn file(&self, fd : u64) -> Result<Arc<FileHandle>, Errno> {
+        match self.files.get(&fd) {
+            Some(Descriptor::File(handle)) => Ok(handle.clone()),
+            _ => Err(Errno::EBADF),
+        }
+    }
+
+    /// 描述符引用的通道, 其它对象返回 EBADF
+    pub fn channel(&self, fd : u64) -> Result<Arc<Endpoint>, Errno> {
+        match self.files.get(&fd) {
+            Some(Descriptor::Channel(endpoint)) => Ok(endpoint.clone()),
+            _ => Err(Errno::EBADF),
+        }
+    }
+
+    /// 让 `fd` 引用 `descriptor`, 返回原来的对象
+    pub fn set(&mut self, fd : u64, descriptor : impl Into<Descriptor>) -> Result<Option<Descriptor>, Errno> {
+        if fd >= MAX_OPEN_FILES as u64 || (!self.files.contains_key(&fd) && self.files.len() >= MAX_OPEN_FILES) {
+            return Err(Errno::EBADF);
+        }
+        Ok(self.files.insert(fd, descriptor.into()))
+    }
+
+    pub fn remove(&mut self, fd : u64) -> Result<Descriptor, Errno> {
+        self.files.remove(&fd).ok_or(Errno::EBADF)
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+}
+
+/// 打开的文件和目录的个数
+pub fn open_files() -> usize {
+    with_vfs(|vfs| vfs.open_files.len())
+}
@@ -1,13 +1,16 @@
+pub mod acpi;
 pub mod clock;
+pub mod console;
 pub mod disk;
 pub mod graphics;
 pub mod network;
+pub mod pci;
 pub mod printer;
 pub mod serial;
 pub mod usb;
 
 use crate::Error;
-use self::disk::{ide::IDE_DISKS, disk::init_disks};
+use self::disk::{ide::IDE_DISKS, disk::init_disks, fat::FAT16SuperBlock, vfs::{self, DISK_MOUNT_POINT}};
 
 pub trait Device {
     fn open(&self) -> Result<(), Error>;
@@ -28,7 +31,19 @@ pub trait BlockDevice: Device {
 }
 
 pub fn devices_init() {
+    serial::init();
     init_disks();
+    mount_disks();
+    pci::probe_drivers();
     // let _ =IDE_DISKS[0].init();
     // IDE_DISKS[1].init();
 }
+
+/// 把数据盘只读挂载到 DISK_MOUNT_POINT
+fn mount_disks() {
+    let result = vfs::create_directory(DISK_MOUNT_POINT)
+        .and_then(|_| vfs::mount::<FAT16SuperBlock>(DISK_MOUNT_POINT, IDE_DISKS[1], true));
+    if let Err(errno) = result {
+        crate::serial_println!("cannot mount the data disk at {}: {}", DISK_MOUNT_POINT, errno);
+    }
+}
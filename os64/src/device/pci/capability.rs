@@ -0,0 +1,149 @@
+//see also: https://wiki.osdev.org/PCI#Message_Signaled_Interrupts
+// PCI 能力链表。状态寄存器第 4 位置位时，偏移 0x34 处是第一个能力的位置，
+// 每个能力的第 0 字节是 ID, 第 1 字节是下一个能力的偏移。
+// MSI/MSI-X 让设备通过写内存(LAPIC 地址 0xFEE00000)来发出中断，不再经过 IO-APIC。
+use alloc::vec::Vec;
+use x86_64::PhysAddr;
+use super::config::{self, PciAddress};
+
+pub const CAPABILITY_POWER_MANAGEMENT   : u8 = 0x01;
+pub const CAPABILITY_MSI                : u8 = 0x05;
+pub const CAPABILITY_VENDOR_SPECIFIC    : u8 = 0x09;
+pub const CAPABILITY_PCI_EXPRESS        : u8 = 0x10;
+pub const CAPABILITY_MSIX               : u8 = 0x11;
+
+const STATUS_CAPABILITIES_LIST  : u16 = 1 << 4;
+const CAPABILITIES_POINTER      : u16 = 0x34;
+
+/// MSI 消息的目标地址: 0xFEE00000 | 目标 APIC ID << 12
+const MSI_ADDRESS_BASE          : u64 = 0xFEE0_0000;
+
+#[derive(Clone,Copy,Debug)]
+pub struct Capability {
+    pub id : u8,
+    pub offset : u16,
+}
+
+/// 读取能力链表
+pub fn read_capabilities(address : PciAddress) -> Vec<Capability> {
+    let mut capabilities = Vec::new();
+    if config::read_u16(address, 0x06) & STATUS_CAPABILITIES_LIST == 0 {
+        return capabilities;
+    }
+    let mut offset = (config::read_u8(address, CAPABILITIES_POINTER) & 0xFC) as u16;
+    // 最多 48 个能力，防止损坏的链表形成环
+    while offset >= 0x40 && capabilities.len() < 48 {
+        let id = config::read_u8(address, offset);
+        capabilities.push(Capability { id, offset });
+        offset = (config::read_u8(address, offset + 1) & 0xFC) as u16;
+    }
+    capabilities
+}
+
+/// 组成 MSI 的地址和数据: 以固定模式、边沿触发把 `vector` 送到 `apic_id`
+pub fn msi_message(apic_id : u8, vector : u8) -> (PhysAddr, u32) {
+    (PhysAddr::new(MSI_ADDRESS_BASE | (apic_id as u64) << 12), vector as u32)
+}
+
+/// MSI 能力
+#[derive(Clone,Copy,Debug)]
+pub struct MsiCapability {
+    pub address : PciAddress,
+    pub offset : u16,
+    pub is_64bit : bool,
+    pub per_vector_masking : bool,
+    /// 设备请求的消息数(2 的幂)
+    pub multiple_message_capable : u8,
+}
+
+impl MsiCapability {
+    pub fn new(address : PciAddress, capability : &Capability) -> Option<MsiCapability> {
+        if capability.id != CAPABILITY_MSI {
+            return None;
+        }
+        let control = config::read_u16(address, capability.offset + 2);
+        Some(MsiCapability {
+            address,
+            offset : capability.offset,
+            is_64bit : control & (1 << 7) != 0,
+            per_vector_masking : control & (1 << 8) != 0,
+            multiple_message_capable : 1 << ((control >> 1) & 0x7),
+        })
+    }
+
+    /// 设置消息并启用 MSI, 只使用一个向量
+    pub fn enable(&self, apic_id : u8, vector : u8) {
+        let (message_address, data) = msi_message(apic_id, vector);
+        let offset = self.offset;
+        config::write_u32(self.address, offset + 4, message_address.as_u64() as u32);
+        if self.is_64bit {
+            config::write_u32(self.address, offset + 8, (message_address.as_u64() >> 32) as u32);
+            config::write_u16(self.address, offset + 12, data as u16);
+        } else {
+            config::write_u16(self.address, offset + 8, data as u16);
+        }
+        // Multiple Message Enable = 0(一个向量), MSI Enable = 1
+        let control = config::read_u16(self.address, offset + 2) & !(0x7 << 4);
+        config::write_u16(self.address, offset + 2, control | 1);
+    }
+
+    pub fn disable(&self) {
+        let control = config::read_u16(self.address, self.offset + 2);
+        config::write_u16(self.address, self.offset + 2, control & !1);
+    }
+}
+
+/// MSI-X 能力，向量表和 PBA 位于设备的某个 BAR 中
+#[derive(Clone,Copy,Debug)]
+pub struct MsixCapability {
+    pub address : PciAddress,
+    pub offset : u16,
+    /// 向量表的条目数
+    pub table_size : u16,
+    pub table_bar : u8,
+    pub table_offset : u32,
+    pub pba_bar : u8,
+    pub pba_offset : u32,
+}
+
+impl MsixCapability {
+    pub fn new(address : PciAddress, capability : &Capability) -> Option<MsixCapability> {
+        if capability.id != CAPABILITY_MSIX {
+            return None;
+        }
+        let control = config::read_u16(address, capability.offset + 2);
+        let table = config::read_u32(address, capability.offset + 4);
+        let pba = config::read_u32(address, capability.offset + 8);
+        Some(MsixCapability {
+            address,
+            offset : capability.offset,
+            table_size : (control & 0x7FF) + 1,
+            table_bar : (table & 0x7) as u8,
+            table_offset : table & !0x7,
+            pba_bar : (pba & 0x7) as u8,
+            pba_offset : pba & !0x7,
+        })
+    }
+
+    /// 启用 MSI-X。向量表须由驱动映射后通过 `set_entry` 设置
+    pub fn enable(&self) {
+        let control = config::read_u16(self.address, self.offset + 2);
+        // MSI-X Enable = 1, Function Mask = 0
+        config::write_u16(self.address, self.offset + 2, (control | 1 << 15) & !(1 << 14));
+    }
+
+    pub fn disable(&self) {
+        let control = config::read_u16(self.address, self.offset + 2);
+        config::write_u16(self.address, self.offset + 2, control & !(1 << 15));
+    }
+
+    /// 设置向量表中的一项，`table` 为已映射的向量表的虚拟地址
+    pub unsafe fn set_entry(table : *mut u32, index : u16, apic_id : u8, vector : u8, masked : bool) {
+        let (message_address, data) = msi_message(apic_id, vector);
+        let entry = table.add(index as usize * 4);
+        entry.write_volatile(message_address.as_u64() as u32);
+        entry.add(1).write_volatile((message_address.as_u64() >> 32) as u32);
+        entry.add(2).write_volatile(data);
+        entry.add(3).write_volatile(masked as u32);
+    }
+}
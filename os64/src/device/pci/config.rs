@@ -0,0 +1,154 @@
+//see also: https://wiki.osdev.org/PCI#Configuration_Space_Access_Mechanism_.231
+//see also: https://wiki.osdev.org/PCI_Express#Enhanced_Configuration_Mechanism
+// PCI 配置空间的访问方式:
+//   1. 传统方式：向 0xCF8 写入 总线/设备/功能/偏移，再从 0xCFC 读写，只能访问前 256 字节；
+//   2. ECAM: ACPI MCFG 给出的内存映射区域，每个功能 4KB, 可以访问完整的扩展配置空间。
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use spin::Mutex;
+use x86_64::{VirtAddr, instructions::port::Port, structures::paging::{FrameAllocator, Mapper, Size2MiB, Size4KiB, Translate}};
+use crate::{device::acpi::mcfg::{self, McfgEntry}, memory::map_physical_region, serial_println};
+
+const CONFIG_ADDRESS_PORT   : u16 = 0xCF8;
+const CONFIG_DATA_PORT      : u16 = 0xCFC;
+const CONFIG_ENABLE         : u32 = 1 << 31;
+
+/// 一个 PCI 功能的地址
+#[derive(Clone,Copy,Debug,PartialEq,Eq,PartialOrd,Ord)]
+pub struct PciAddress {
+    pub segment : u16,
+    pub bus : u8,
+    pub device : u8,
+    pub function : u8,
+}
+
+impl PciAddress {
+    pub fn new(segment : u16, bus : u8, device : u8, function : u8) -> PciAddress {
+        PciAddress { segment, bus, device, function }
+    }
+}
+
+impl core::fmt::Display for PciAddress {
+    fn fmt(&self, f : &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{:04x}:{:02x}:{:02x}.{}", self.segment, self.bus, self.device, self.function)
+    }
+}
+
+/// 已映射的 ECAM 区域
+struct EcamRegion {
+    entry : McfgEntry,
+    base : VirtAddr,
+}
+
+struct ConfigSpace {
+    ecam : Vec<EcamRegion>,
+}
+
+static CONFIG_SPACE: OnceCell<ConfigSpace> = OnceCell::uninit();
+/// 0xCF8/0xCFC 必须成对访问
+static LEGACY_PORTS: Mutex<()> = Mutex::new(());
+
+/// 映射 MCFG 中的 ECAM 区域, 没有 MCFG 时只使用传统端口
+pub fn init(
+    mapper : &mut (impl Mapper<Size4KiB> + Mapper<Size2MiB> + Translate),
+    frame_allocator : &mut impl FrameAllocator<Size4KiB>,
+) {
+    let mut ecam = Vec::new();
+    for entry in mcfg::parse() {
+        match map_physical_region(entry.base_address, entry.size(), mapper, frame_allocator) {
+            Ok(base) => {
+                serial_println!("PCI: ECAM segment {} bus {}-{} at 0x{:x}",
+                    entry.segment_group, entry.start_bus, entry.end_bus, entry.base_address.as_u64());
+                ecam.push(EcamRegion { entry, base });
+            },
+            Err(_) => serial_println!("PCI: failed to map ECAM at 0x{:x}", entry.base_address.as_u64()),
+        }
+    }
+    let _ = CONFIG_SPACE.try_init_once(|| ConfigSpace { ecam });
+}
+
+/// 配置空间中某个功能的虚拟地址, 没有对应的 ECAM 区域时返回 `None`
+fn ecam_address(address : PciAddress) -> Option<VirtAddr> {
+    let config = CONFIG_SPACE.try_get().ok()?;
+    let region = config.ecam.iter().find(|r| r.entry.contains(address.segment, address.bus))?;
+    let offset = region.entry.config_address(address.bus, address.device, address.function) - region.entry.base_address;
+    Some(region.base + offset)
+}
+
+fn legacy_address(address : PciAddress, offset : u16) -> u32 {
+    CONFIG_ENABLE
+        | (address.bus as u32) << 16
+        | (address.device as u32) << 11
+        | (address.function as u32) << 8
+        | (offset as u32 & 0xFC)
+}
+
+/// 读取配置空间中的一个双字, `offset` 须 4 字节对齐
+pub fn read_u32(address : PciAddress, offset : u16) -> u32 {
+    if let Some(base) = ecam_address(address) {
+        return unsafe { (base + offset as u64).as_ptr::<u32>().read_volatile() };
+    }
+    if address.segment != 0 || offset >= 256 {
+        return u32::MAX;
+    }
+    let _guard = LEGACY_PORTS.lock();
+    unsafe {
+        Port::<u32>::new(CONFIG_ADDRESS_PORT).write(legacy_address(address, offset));
+        Port::<u32>::new(CONFIG_DATA_PORT).read()
+    }
+}
+
+/// 写入配置空间中的一个双字, `offset` 须 4 字节对齐
+pub fn write_u32(address : PciAddress, offset : u16, value : u32) {
+    if let Some(base) = ecam_address(address) {
+        unsafe { (base + offset as u64).as_mut_ptr::<u32>().write_volatile(value) };
+        return;
+    }
+    if address.segment != 0 || offset >= 256 {
+        return;
+    }
+    let _guard = LEGACY_PORTS.lock();
+    unsafe {
+        Port::<u32>::new(CONFIG_ADDRESS_PORT).write(legacy_address(address, offset));
+        Port::<u32>::new(CONFIG_DATA_PORT).write(value);
+    }
+}
+
+pub fn read_u16(address : PciAddress, offset : u16) -> u16 {
+    (read_u32(address, offset & !3) >> ((offset & 2) * 8)) as u16
+}
+
+pub fn read_u8(address : PciAddress, offset : u16) -> u8 {
+    (read_u32(address, offset & !3) >> ((offset & 3) * 8)) as u8
+}
+
+/// 16 位和 8 位写入不能读出整个双字再写回: 与命令寄存器同一双字的状态寄存器是写 1 清除的
+pub fn write_u16(address : PciAddress, offset : u16, value : u16) {
+    if let Some(base) = ecam_address(address) {
+        unsafe { (base + (offset & !1) as u64).as_mut_ptr::<u16>().write_volatile(value) };
+        return;
+    }
+    if address.segment != 0 || offset >= 256 {
+        return;
+    }
+    let _guard = LEGACY_PORTS.lock();
+    unsafe {
+        Port::<u32>::new(CONFIG_ADDRESS_PORT).write(legacy_address(address, offset));
+        Port::<u16>::new(CONFIG_DATA_PORT + (offset & 2)).write(value);
+    }
+}
+
+pub fn write_u8(address : PciAddress, offset : u16, value : u8) {
+    if let Some(base) = ecam_address(address) {
+        unsafe { (base + offset as u64).as_mut_ptr::<u8>().write_volatile(value) };
+        return;
+    }
+    if address.segment != 0 || offset >= 256 {
+        return;
+    }
+    let _guard = LEGACY_PORTS.lock();
+    unsafe {
+        Port::<u32>::new(CONFIG_ADDRESS_PORT).write(legacy_address(address, offset));
+        Port::<u8>::new(CONFIG_DATA_PORT + (offset & 3)).write(value);
+    }
+}
@@ -0,0 +1,399 @@
+//see also: https://wiki.osdev.org/PCI
+//see also: https://wiki.osdev.org/PCI_Express
+// PCI 总线枚举。
+// 从总线 0 开始递归扫描：每条总线 32 个设备，每个设备最多 8 个功能；
+// 遇到 PCI-PCI 桥时继续扫描它的二级总线。找到的设备记录在 PCI_DEVICES 中，
+// 驱动通过 `register_driver` 注册，`probe_drivers` 按厂商/设备 ID 或类别匹配后调用驱动的 probe。
+use alloc::{vec::Vec, string::String};
+use spin::Mutex;
+use x86_64::{PhysAddr, structures::paging::{FrameAllocator, Mapper, Size2MiB, Size4KiB, Translate}};
+use crate::serial_println;
+
+pub mod config;
+pub mod capability;
+
+pub use config::PciAddress;
+use capability::{Capability, MsiCapability, MsixCapability};
+
+/// 不存在的设备读出的厂商 ID
+const INVALID_VENDOR        : u16 = 0xFFFF;
+
+// 配置空间头部的偏移
+const VENDOR_ID             : u16 = 0x00;
+const DEVICE_ID             : u16 = 0x02;
+const COMMAND               : u16 = 0x04;
+const REVISION_ID           : u16 = 0x08;
+const PROG_IF               : u16 = 0x09;
+const SUBCLASS              : u16 = 0x0A;
+const CLASS_CODE            : u16 = 0x0B;
+const HEADER_TYPE           : u16 = 0x0E;
+const BAR0                  : u16 = 0x10;
+const SECONDARY_BUS         : u16 = 0x19;
+const SUBSYSTEM_VENDOR_ID   : u16 = 0x2C;
+const SUBSYSTEM_ID          : u16 = 0x2E;
+const INTERRUPT_LINE        : u16 = 0x3C;
+const INTERRUPT_PIN         : u16 = 0x3D;
+
+// 命令寄存器的位
+pub const COMMAND_IO_SPACE          : u16 = 1 << 0;
+pub const COMMAND_MEMORY_SPACE      : u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER        : u16 = 1 << 2;
+pub const COMMAND_INTERRUPT_DISABLE : u16 = 1 << 10;
+
+const HEADER_TYPE_MULTI_FUNCTION    : u8 = 0x80;
+const HEADER_TYPE_GENERAL           : u8 = 0x00;
+const HEADER_TYPE_PCI_BRIDGE        : u8 = 0x01;
+
+pub const CLASS_MASS_STORAGE        : u8 = 0x01;
+pub const CLASS_NETWORK             : u8 = 0x02;
+pub const CLASS_DISPLAY             : u8 = 0x03;
+pub const CLASS_MULTIMEDIA          : u8 = 0x04;
+pub const CLASS_BRIDGE              : u8 = 0x06;
+pub const CLASS_SERIAL_BUS          : u8 = 0x0C;
+
+/// 基址寄存器(BAR)
+#[derive(Clone,Copy,Debug)]
+pub enum Bar {
+    Memory {
+        address : PhysAddr,
+        size : u64,
+        prefetchable : bool,
+        is_64bit : bool,
+    },
+    Io {
+        port : u16,
+        size : u32,
+    },
+}
+
+impl Bar {
+    pub fn size(&self) -> u64 {
+        match self {
+            Bar::Memory { size, .. } => *size,
+            Bar::Io { size, .. } => *size as u64,
+        }
+    }
+}
+
+/// 一个 PCI 功能
+#[derive(Clone,Debug)]
+pub struct PciDevice {
+    pub address : PciAddress,
+    pub vendor_id : u16,
+    pub device_id : u16,
+    pub subsystem_vendor_id : u16,
+    pub subsystem_id : u16,
+    pub class : u8,
+    pub subclass : u8,
+    pub prog_if : u8,
+    pub revision : u8,
+    pub header_type : u8,
+    pub interrupt_line : u8,
+    pub interrupt_pin : u8,
+    pub bars : [Option<Bar>; 6],
+    pub capabilities : Vec<Capability>,
+    /// 已接管该设备的驱动
+    pub driver : Option<&'static str>,
+}
+
+impl PciDevice {
+    pub fn find_capability(&self, id : u8) -> Option<&Capability> {
+        self.capabilities.iter().find(|c| c.id == id)
+    }
+
+    pub fn msi(&self) -> Option<MsiCapability> {
+        MsiCapability::new(self.address, self.find_capability(capability::CAPABILITY_MSI)?)
+    }
+
+    pub fn msix(&self) -> Option<MsixCapability> {
+        MsixCapability::new(self.address, self.find_capability(capability::CAPABILITY_MSIX)?)
+    }
+
+    pub fn command(&self) -> u16 {
+        config::read_u16(self.address, COMMAND)
+    }
+
+    pub fn set_command(&self, command : u16) {
+        config::write_u16(self.address, COMMAND, command);
+    }
+
+    /// 打开 IO/内存译码及总线主控(DMA)
+    pub fn enable(&self, bus_master : bool) {
+        let mut command = self.command() | COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE;
+        if bus_master {
+            command |= COMMAND_BUS_MASTER;
+        }
+        self.set_command(command);
+    }
+
+    pub fn class_name(&self) -> &'static str {
+        class_name(self.class, self.subclass)
+    }
+}
+
+/// 驱动的匹配规则
+#[derive(Clone,Copy,Debug)]
+pub enum PciMatch {
+    Id { vendor_id : u16, device_id : u16 },
+    Class { class : u8, subclass : u8, prog_if : Option<u8> },
+}
+
+impl PciMatch {
+    pub fn matches(&self, device : &PciDevice) -> bool {
+        match *self {
+            PciMatch::Id { vendor_id, device_id } =>
+                device.vendor_id == vendor_id && device.device_id == device_id,
+            PciMatch::Class { class, subclass, prog_if } =>
+                device.class == class && device.subclass == subclass
+                    && prog_if.map(|p| p == device.prog_if).unwrap_or(true),
+        }
+    }
+}
+
+/// PCI 设备驱动
+pub trait PciDriver: Sync {
+    fn name(&self) -> &'static str;
+    /// 支持的设备
+    fn id_table(&self) -> &[PciMatch];
+    /// 接管设备, 返回错误表示不支持这个设备
+    fn probe(&self, device : &PciDevice) -> Result<(), &'static str>;
+}
+
+static PCI_DEVICES: Mutex<Vec<PciDevice>> = Mutex::new(Vec::new());
+static PCI_DRIVERS: Mutex<Vec<&'static dyn PciDriver>> = Mutex::new(Vec::new());
+
+/// 读取 BAR 并测量其大小。测量时暂时关闭设备的译码
+fn read_bars(address : PciAddress, count : usize) -> [Option<Bar>; 6] {
+    let mut bars = [None; 6];
+    let command = config::read_u16(address, COMMAND);
+    config::write_u16(address, COMMAND, command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE));
+
+    let mut index = 0;
+    while index < count {
+        let offset = BAR0 + index as u16 * 4;
+        let value = config::read_u32(address, offset);
+        config::write_u32(address, offset, u32::MAX);
+        let mask = config::read_u32(address, offset);
+        config::write_u32(address, offset, value);
+
+        if value & 1 == 1 {
+            // 异常的设备可能读出全 1 或全 0 的掩码, 用回绕运算避免溢出
+            let size = (!(mask & !0x3)).wrapping_add(1) & 0xFFFF;
+            if mask & !0x3 != 0 {
+                bars[index] = Some(Bar::Io { port : (value & !0x3) as u16, size });
+            }
+            index += 1;
+            continue;
+        }
+
+        let is_64bit = (value >> 1) & 0x3 == 0x2;
+        let prefetchable = value & 0x8 != 0;
+        // 64 位 BAR 的大小可能在 4GB 以上, 这时低双字的掩码为 0, 要检查合起来的掩码
+        let wide = is_64bit && index + 1 < count;
+        let (base, mask) = if wide {
+            let high_offset = offset + 4;
+            let high = config::read_u32(address, high_offset);
+            config::write_u32(address, high_offset, u32::MAX);
+            let high_mask = config::read_u32(address, high_offset);
+            config::write_u32(address, high_offset, high);
+
+            ((high as u64) << 32 | (value & !0xF) as u64, (high_mask as u64) << 32 | (mask & !0xF) as u64)
+        } else {
+            ((value & !0xF) as u64, (mask & !0xF) as u64)
+        };
+        if mask != 0 {
+            let size = match wide {
+                true => (!mask).wrapping_add(1),
+                false => (!(mask as u32)).wrapping_add(1) as u64,
+            };
+            bars[index] = Some(Bar::Memory { address : PhysAddr::new(base), size, prefetchable, is_64bit });
+        }
+        index += if is_64bit { 2 } else { 1 };
+    }
+
+    config::write_u16(address, COMMAND, command);
+    bars
+}
+
+fn read_device(address : PciAddress) -> PciDevice {
+    let header_type = config::read_u8(address, HEADER_TYPE);
+    let bar_count = match header_type & !HEADER_TYPE_MULTI_FUNCTION {
+        HEADER_TYPE_GENERAL => 6,
+        HEADER_TYPE_PCI_BRIDGE => 2,
+        _ => 0,
+    };
+    let general = header_type & !HEADER_TYPE_MULTI_FUNCTION == HEADER_TYPE_GENERAL;
+    PciDevice {
+        address,
+        vendor_id : config::read_u16(address, VENDOR_ID),
+        device_id : config::read_u16(address, DEVICE_ID),
+        subsystem_vendor_id : if general { config::read_u16(address, SUBSYSTEM_VENDOR_ID) } else { 0 },
+        subsystem_id : if general { config::read_u16(address, SUBSYSTEM_ID) } else { 0 },
+        class : config::read_u8(address, CLASS_CODE),
+        subclass : config::read_u8(address, SUBCLASS),
+        prog_if : config::read_u8(address, PROG_IF),
+        revision : config::read_u8(address, REVISION_ID),
+        header_type,
+        interrupt_line : config::read_u8(address, INTERRUPT_LINE),
+        interrupt_pin : config::read_u8(address, INTERRUPT_PIN),
+        bars : read_bars(address, bar_count),
+        capabilities : capability::read_capabilities(address),
+        driver : None,
+    }
+}
+
+fn scan_function(address : PciAddress, devices : &mut Vec<PciDevice>, visited : &mut [bool; 256]) {
+    let device = read_device(address);
+    let is_bridge = device.header_type & !HEADER_TYPE_MULTI_FUNCTION == HEADER_TYPE_PCI_BRIDGE;
+    devices.push(device);
+    if is_bridge {
+        let secondary = config::read_u8(address, SECONDARY_BUS);
+        scan_bus(address.segment, secondary, devices, visited);
+    }
+}
+
+fn scan_bus(segment : u16, bus : u8, devices : &mut Vec<PciDevice>, visited : &mut [bool; 256]) {
+    if visited[bus as usize] {
+        return;
+    }
+    visited[bus as usize] = true;
+
+    for device in 0..32 {
+        let address = PciAddress::new(segment, bus, device, 0);
+        if config::read_u16(address, VENDOR_ID) == INVALID_VENDOR {
+            continue;
+        }
+        scan_function(address, devices, visited);
+        if config::read_u8(address, HEADER_TYPE) & HEADER_TYPE_MULTI_FUNCTION == 0 {
+            continue;
+        }
+        for function in 1..8 {
+            let address = PciAddress::new(segment, bus, device, function);
+            if config::read_u16(address, VENDOR_ID) != INVALID_VENDOR {
+                scan_function(address, devices, visited);
+            }
+        }
+    }
+}
+
+/// 映射 ECAM 区域并枚举所有 PCI 设备; ACPI 可用时须在 `device::acpi::init` 之后调用, 不可用时只使用传统端口
+pub fn init(
+    mapper : &mut (impl Mapper<Size4KiB> + Mapper<Size2MiB> + Translate),
+    frame_allocator : &mut impl FrameAllocator<Size4KiB>,
+) -> usize {
+    config::init(mapper, frame_allocator);
+
+    let mut devices = Vec::new();
+    let mut visited = [false; 256];
+    let host = PciAddress::new(0, 0, 0, 0);
+    if config::read_u8(host, HEADER_TYPE) & HEADER_TYPE_MULTI_FUNCTION == 0 {
+        scan_bus(0, 0, &mut devices, &mut visited);
+    } else {
+        // 有多个主桥时, 主桥的功能号就是它负责的总线号
+        for function in 0..8 {
+            let address = PciAddress::new(0, 0, 0, function);
+            if config::read_u16(address, VENDOR_ID) != INVALID_VENDOR {
+                scan_bus(0, function, &mut devices, &mut visited);
+            }
+        }
+    }
+
+    for device in devices.iter() {
+        serial_println!("PCI {} {:04x}:{:04x} class {:02x}.{:02x}.{:02x} {}",
+            device.address, device.vendor_id, device.device_id,
+            device.class, device.subclass, device.prog_if, device.class_name());
+    }
+    let count = devices.len();
+    *PCI_DEVICES.lock() = devices;
+    count
+}
+
+/// 注册驱动，并立即尝试接管已枚举出的设备
+pub fn register_driver(driver : &'static dyn PciDriver) {
+    PCI_DRIVERS.lock().push(driver);
+    probe_driver(driver);
+}
+
+/// 在锁外调用驱动的 probe, 这样 probe 中可以查询设备或注册别的驱动
+fn probe_driver(driver : &'static dyn PciDriver) {
+    let candidates : Vec<PciDevice> = PCI_DEVICES.lock().iter()
+        .filter(|d| d.driver.is_none() && driver.id_table().iter().any(|m| m.matches(d)))
+        .cloned()
+        .collect();
+    for device in candidates {
+        if let Err(e) = driver.probe(&device) {
+            serial_println!("PCI {}: {} probe failed: {}", device.address, driver.name(), e);
+            continue;
+        }
+        let mut devices = PCI_DEVICES.lock();
+        match devices.iter_mut().find(|d| d.address == device.address) {
+            // probe 期间另一个驱动已经接管了它
+            Some(entry) if entry.driver.is_some() => {
+                serial_println!("PCI {}: already bound to {}", device.address, entry.driver.unwrap_or_default());
+            },
+            Some(entry) => {
+                serial_println!("PCI {}: bound to {}", device.address, driver.name());
+                entry.driver = Some(driver.name());
+            },
+            None => {},
+        }
+    }
+}
+
+/// 为所有尚无驱动的设备匹配已注册的驱动
+pub fn probe_drivers() {
+    let drivers = PCI_DRIVERS.lock().clone();
+    for driver in drivers {
+        probe_driver(driver);
+    }
+}
+
+/// 所有已枚举的设备
+pub fn devices() -> Vec<PciDevice> {
+    PCI_DEVICES.lock().clone()
+}
+
+/// 按厂商/设备 ID 查找
+pub fn find_device(vendor_id : u16, device_id : u16) -> Option<PciDevice> {
+    PCI_DEVICES.lock().iter().find(|d| d.vendor_id == vendor_id && d.device_id == device_id).cloned()
+}
+
+/// 按类别查找
+pub fn find_by_class(class : u8, subclass : u8) -> Vec<PciDevice> {
+    PCI_DEVICES.lock().iter().filter(|d| d.class == class && d.subclass == subclass).cloned().collect()
+}
+
+/// 设备类别的名称
+pub fn class_name(class : u8, subclass : u8) -> &'static str {
+    match (class, subclass) {
+        (0x01, 0x01) => "IDE controller",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "NVMe controller",
+        (0x01, _) => "mass storage controller",
+        (0x02, 0x00) => "ethernet controller",
+        (0x02, _) => "network controller",
+        (0x03, 0x00) => "VGA compatible controller",
+        (0x03, _) => "display controller",
+        (0x04, _) => "multimedia controller",
+        (0x05, _) => "memory controller",
+        (0x06, 0x00) => "host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI-to-PCI bridge",
+        (0x06, _) => "bridge",
+        (0x07, _) => "communication controller",
+        (0x08, _) => "system peripheral",
+        (0x09, _) => "input device controller",
+        (0x0C, 0x03) => "USB controller",
+        (0x0C, 0x05) => "SMBus controller",
+        (0x0C, _) => "serial bus controller",
+        (0x0D, _) => "wireless controller",
+        _ => "unknown device",
+    }
+}
+
+/// 设备的一行描述, 如 `0000:00:01.1 8086:7010 IDE controller [driver]`
+pub fn describe(device : &PciDevice) -> String {
+    alloc::format!("{} {:04x}:{:04x} {}{}", device.address, device.vendor_id, device.device_id,
+        device.class_name(),
+        device.driver.map(|d| alloc::format!(" [{}]", d)).unwrap_or_default())
+}
@@ -1,27 +1,468 @@
-// in src/serial.rs
-use uart_16550::SerialPort;
-use spin::Mutex;
-use lazy_static::lazy_static;
-
-lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
-        serial_port.init();
-        Mutex::new(serial_port)
-    };
+//see also: https://wiki.osdev.org/Serial_Ports
+//see also: https://www.lammertbies.nl/comm/info/serial-uart
+// 16550 UART 串口驱动, 支持 COM1 和 COM2
+// 打开收发 FIFO, 接收由中断驱动: 中断处理程序取出收到的字节, COM1 是控制台, 字节交给 console,
+// 其它端口放进自己的接收缓冲, 由线程(`CharacterDevice::read`)或执行器中的任务(`stream`)读取。
+// 发送先放进发送缓冲, 发送保持寄存器空的中断到来时再填满硬件 FIFO;
+// 调试输出(serial_print!)同步写出, 之前先送出缓冲中的数据, 保证输出的顺序。
+// 缓冲都是固定大小的数组, 中断处理程序和堆初始化之前的调试输出都不会分配内存。
+use core::{convert::TryFrom, fmt, pin::Pin, task::{Context, Poll}};
+use futures_util::{stream::Stream, task::AtomicWaker};
+use x86_64::instructions::port::Port;
+use crate::{Error, parallel::sync::{IrqSpinLock, IrqSpinLockGuard, WaitQueue, SERIAL_LOCK_LEVEL}};
+use super::{console, CharacterDevice, Device};
+
+/// 任何地方都可能输出调试信息, 串口总是最后获取的锁
+pub static COM1: SerialPort = SerialPort::new("COM1", 0x3F8, true);
+pub static COM2: SerialPort = SerialPort::new("COM2", 0x2F8, false);
+
+/// UART 的输入时钟为 1.8432 MHz, 16 分频后为最高波特率, 波特率 = 115200 / 除数
+pub const MAX_BAUD_RATE : u32 = 115200;
+pub const DEFAULT_BAUD_RATE : u32 = 38400;
+
+/// `Device::control` 的命令: 设置波特率, 参数为波特率, 须能整除 `MAX_BAUD_RATE`
+pub const CONTROL_BAUD_RATE : u32 = 1;
+
+/// 硬件收发 FIFO 的大小
+const FIFO_SIZE : usize = 16;
+const RECEIVE_BUFFER_SIZE : usize = 1024;
+const TRANSMIT_BUFFER_SIZE : usize = 4096;
+
+// 寄存器相对于基址的偏移; 线路控制寄存器的 DLAB 位为 1 时, 前两个寄存器是波特率除数的低、高字节
+const DATA : u16 = 0;
+const INTERRUPT_ENABLE : u16 = 1;
+/// 读取时为中断标识, 写入时为 FIFO 控制
+const INTERRUPT_ID : u16 = 2;
+const FIFO_CONTROL : u16 = 2;
+const LINE_CONTROL : u16 = 3;
+const MODEM_CONTROL : u16 = 4;
+const LINE_STATUS : u16 = 5;
+
+const ENABLE_RECEIVED_DATA : u8 = 0x01;
+const ENABLE_TRANSMITTER_EMPTY : u8 = 0x02;
+/// 中断标识的最低位为 1 表示没有待处理的中断
+const NO_INTERRUPT_PENDING : u8 = 0x01;
+/// 打开并清空收发 FIFO, 收到 14 字节时中断
+const FIFO_ENABLE_CLEAR_14 : u8 = 0xC7;
+const DIVISOR_LATCH : u8 = 0x80;
+/// 8 个数据位, 无校验, 1 个停止位
+const EIGHT_N_ONE : u8 = 0x03;
+/// DTR、RTS 和 OUT2, OUT2 连接着中断线
+const DTR_RTS_OUT2 : u8 = 0x0B;
+/// 检测时使用的回环模式
+const LOOPBACK : u8 = 0x1E;
+const DATA_READY : u8 = 0x01;
+const TRANSMITTER_EMPTY : u8 = 0x20;
+
+// 错误码, 见 crate::Error
+const ERROR_MODULE : u8 = 0x10;
+const ERROR_NOT_PRESENT : u8 = 0x01;
+const ERROR_INVALID_ARGUMENT : u8 = 0x02;
+const ERROR_UNSUPPORTED : u8 = 0x03;
+const FUNCTION_OPEN : u8 = 0x01;
+const FUNCTION_CONTROL : u8 = 0x03;
+const FUNCTION_READ : u8 = 0x04;
+
+fn error(class_code : u8, function_code : u8, message : &'static str) -> Error<'static> {
+    Error::new(0x00, ERROR_MODULE, class_code, function_code, message)
+}
+
+/// 固定大小的字节队列
+struct ByteQueue<const N : usize> {
+    data : [u8; N],
+    head : usize,
+    len : usize,
+}
+
+impl<const N : usize> ByteQueue<N> {
+    const fn new() -> Self {
+        ByteQueue { data : [0; N], head : 0, len : 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// 满了时返回 false, 字节被丢弃
+    fn push(&mut self, byte : u8) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        self.data[(self.head + self.len) % N] = byte;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+        let byte = self.data[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+/// UART 的寄存器和发送缓冲, 第一次使用时初始化
+struct Uart {
+    base : u16,
+    initialized : bool,
+    /// 回环检测通过
+    present : bool,
+    baud_rate : u32,
+    /// 中断使能寄存器的当前值
+    interrupts : u8,
+    transmit : ByteQueue<TRANSMIT_BUFFER_SIZE>,
+}
+
+impl Uart {
+    const fn new(base : u16) -> Uart {
+        Uart { base, initialized : false, present : false, baud_rate : DEFAULT_BAUD_RATE, interrupts : 0,
+            transmit : ByteQueue::new() }
+    }
+
+    fn read(&self, register : u16) -> u8 {
+        unsafe { Port::new(self.base + register).read() }
+    }
+
+    fn write(&mut self, register : u16, value : u8) {
+        unsafe { Port::new(self.base + register).write(value) }
+    }
+
+    /// 检测 UART 是否存在, 设置波特率、数据格式和 FIFO, 打开接收中断
+    fn init(&mut self) {
+        self.initialized = true;
+        self.write(INTERRUPT_ENABLE, 0);
+        self.set_baud_rate(self.baud_rate);
+        self.write(LINE_CONTROL, EIGHT_N_ONE);
+        self.write(FIFO_CONTROL, FIFO_ENABLE_CLEAR_14);
+        self.write(MODEM_CONTROL, LOOPBACK);
+        self.write(DATA, 0xAE);
+        self.present = self.read(DATA) == 0xAE;
+        if !self.present {
+            return;
+        }
+        self.write(MODEM_CONTROL, DTR_RTS_OUT2);
+        // 丢弃回环时收到的字节
+        while self.read(LINE_STATUS) & DATA_READY != 0 {
+            self.read(DATA);
+        }
+        self.set_interrupts(ENABLE_RECEIVED_DATA);
+    }
+
+    fn set_interrupts(&mut self, interrupts : u8) {
+        if self.interrupts != interrupts {
+            self.interrupts = interrupts;
+            self.write(INTERRUPT_ENABLE, interrupts);
+        }
+    }
+
+    /// `baud_rate` 须能整除 `MAX_BAUD_RATE`
+    fn set_baud_rate(&mut self, baud_rate : u32) {
+        let divisor = (MAX_BAUD_RATE / baud_rate) as u16;
+        let line_control = self.read(LINE_CONTROL);
+        self.write(LINE_CONTROL, line_control | DIVISOR_LATCH);
+        self.write(DATA, divisor as u8);
+        self.write(INTERRUPT_ENABLE, (divisor >> 8) as u8);
+        self.write(LINE_CONTROL, line_control & !DIVISOR_LATCH);
+        self.baud_rate = baud_rate;
+    }
+
+    /// 等到发送保持寄存器空后发送一个字节
+    fn send_now(&mut self, byte : u8) {
+        while self.read(LINE_STATUS) & TRANSMITTER_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        self.write(DATA, byte);
+    }
+
+    /// 同步送出发送缓冲中的所有字节
+    fn flush(&mut self) {
+        while let Some(byte) = self.transmit.pop() {
+            self.send_now(byte);
+        }
+    }
+
+    /// 硬件 FIFO 空了时从发送缓冲填满它; 缓冲中还有数据时打开发送中断, 否则关闭
+    fn start_transmit(&mut self) {
+        if self.read(LINE_STATUS) & TRANSMITTER_EMPTY != 0 {
+            for _ in 0..FIFO_SIZE {
+                match self.transmit.pop() {
+                    Some(byte) => self.write(DATA, byte),
+                    None => break,
+                }
+            }
+        }
+        let interrupts = match self.transmit.is_empty() {
+            true => self.interrupts & !ENABLE_TRANSMITTER_EMPTY,
+            false => self.interrupts | ENABLE_TRANSMITTER_EMPTY,
+        };
+        self.set_interrupts(interrupts);
+    }
+
+    /// 取出已经收到的字节, 返回个数
+    fn receive(&mut self, buffer : &mut [u8]) -> usize {
+        let mut count = 0;
+        while count < buffer.len() && self.read(LINE_STATUS) & DATA_READY != 0 {
+            buffer[count] = self.read(DATA);
+            count += 1;
+        }
+        count
+    }
+}
+
+/// 同步输出, 用于调试信息
+impl fmt::Write for Uart {
+    fn write_str(&mut self, s : &str) -> fmt::Result {
+        if self.present {
+            s.bytes().for_each(|byte| self.send_now(byte));
+        }
+        Ok(())
+    }
+}
+
+pub struct SerialPort {
+    name : &'static str,
+    /// 收到的字节交给控制台, 而不是放进自己的接收缓冲
+    console : bool,
+    uart : IrqSpinLock<Uart>,
+    input : IrqSpinLock<ByteQueue<RECEIVE_BUFFER_SIZE>>,
+    readers : WaitQueue,
+    /// `SerialStream` 的 Waker
+    waker : AtomicWaker,
+}
+
+impl SerialPort {
+    const fn new(name : &'static str, base : u16, console : bool) -> SerialPort {
+        SerialPort {
+            name,
+            console,
+            uart : IrqSpinLock::with_level(Uart::new(base), SERIAL_LOCK_LEVEL),
+            input : IrqSpinLock::new(ByteQueue::new()),
+            readers : WaitQueue::new(),
+            waker : AtomicWaker::new(),
+        }
+    }
+
+    fn uart(&self) -> IrqSpinLockGuard<'_, Uart> {
+        let mut uart = self.uart.lock();
+        if !uart.initialized {
+            uart.init();
+        }
+        uart
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// I/O 端口基址
+    pub fn base(&self) -> u16 {
+        self.uart.lock().base
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.uart().present
+    }
+
+    pub fn baud_rate(&self) -> u32 {
+        self.uart().baud_rate
+    }
+
+    /// 是否作为控制台
+    pub fn is_console(&self) -> bool {
+        self.console
+    }
+
+    /// 设置波特率, 须能整除 `MAX_BAUD_RATE`; 发送缓冲中的数据先按原来的波特率送出
+    pub fn set_baud_rate(&self, baud_rate : u32) -> Result<(), Error<'static>> {
+        if baud_rate == 0 || MAX_BAUD_RATE % baud_rate != 0 {
+            return Err(error(ERROR_INVALID_ARGUMENT, FUNCTION_CONTROL, "unsupported baud rate"));
+        }
+        let mut uart = self.uart();
+        if !uart.present {
+            return Err(error(ERROR_NOT_PRESENT, FUNCTION_CONTROL, "serial port not present"));
+        }
+        uart.flush();
+        uart.set_baud_rate(baud_rate);
+        Ok(())
+    }
+
+    /// 放进发送缓冲, 由中断送出; 缓冲满时同步送出缓冲中的数据。端口不存在时丢弃, 总是返回 `data` 的长度
+    pub fn write_bytes(&self, data : &[u8]) -> usize {
+        let mut uart = self.uart();
+        if !uart.present {
+            return data.len();
+        }
+        for byte in data {
+            if uart.transmit.is_full() {
+                uart.flush();
+            }
+            uart.transmit.push(*byte);
+        }
+        uart.start_transmit();
+        data.len()
+    }
+
+    /// 同步输出, 之前先送出发送缓冲中的数据
+    pub fn write_fmt(&self, args : fmt::Arguments) -> fmt::Result {
+        let mut uart = self.uart();
+        uart.flush();
+        fmt::Write::write_fmt(&mut *uart, args)
+    }
+
+    /// 取出接收缓冲中的字节, 不等待
+    pub fn try_read(&self, buffer : &mut [u8]) -> usize {
+        let mut input = self.input.lock();
+        let mut count = 0;
+        while count < buffer.len() {
+            match input.pop() {
+                Some(byte) => buffer[count] = byte,
+                None => break,
+            }
+            count += 1;
+        }
+        count
+    }
+
+    /// 收到的字节组成的异步流, 同时只应有一个任务读取
+    pub fn stream(&'static self) -> SerialStream {
+        SerialStream { port : self }
+    }
+
+    /// 中断处理: 取出收到的字节, 继续发送缓冲中的数据
+    pub fn handle_interrupt(&self) {
+        let mut received = [0u8; FIFO_SIZE];
+        loop {
+            let (count, pending) = {
+                let mut uart = self.uart();
+                if !uart.present {
+                    return;
+                }
+                let count = uart.receive(&mut received);
+                uart.start_transmit();
+                (count, uart.read(INTERRUPT_ID) & NO_INTERRUPT_PENDING == 0)
+            };
+            self.deliver(&received[..count]);
+            if !pending {
+                break;
+            }
+        }
+    }
+
+    /// 收到的字节交给控制台或放进接收缓冲, 缓冲满时丢弃
+    fn deliver(&self, bytes : &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        if self.console {
+            console::push_input(bytes);
+            return;
+        }
+        {
+            let mut input = self.input.lock();
+            for byte in bytes {
+                input.push(*byte);
+            }
+        }
+        self.readers.notify_all();
+        self.waker.wake();
+    }
+}
+
+impl Device for SerialPort {
+    fn open(&self) -> Result<(), Error<'_>> {
+        match self.is_present() {
+            true => Ok(()),
+            false => Err(error(ERROR_NOT_PRESENT, FUNCTION_OPEN, "serial port not present")),
+        }
+    }
+
+    /// 送出发送缓冲中的数据
+    fn close(&self) -> Result<(), Error<'_>> {
+        self.uart().flush();
+        Ok(())
+    }
+
+    fn control(&self, code : u32, value : usize) -> Result<(), Error<'_>> {
+        match code {
+            CONTROL_BAUD_RATE => {
+                let baud_rate = u32::try_from(value)
+                    .map_err(|_| error(ERROR_INVALID_ARGUMENT, FUNCTION_CONTROL, "unsupported baud rate"))?;
+                self.set_baud_rate(baud_rate)
+            },
+            _ => Err(error(ERROR_UNSUPPORTED, FUNCTION_CONTROL, "unknown control code")),
+        }
+    }
+}
+
+impl CharacterDevice for SerialPort {
+    /// 等到至少收到一个字节; 作为控制台的端口收到的字节要从 console 读取
+    fn read(&self, buffer : &mut [u8]) -> Result<usize, Error<'_>> {
+        if self.console {
+            return Err(error(ERROR_UNSUPPORTED, FUNCTION_READ, "input goes to the console"));
+        }
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        Ok(self.readers.wait_until(|| match self.try_read(buffer) {
+            0 => None,
+            count => Some(count),
+        }))
+    }
+
+    fn write(&self, buffer : &[u8]) -> Result<usize, Error<'_>> {
+        Ok(self.write_bytes(buffer))
+    }
+}
+
+/// 串口收到的字节, 与 keyboard::ScancodeStream 相同, 用 AtomicWaker 唤醒读取的任务
+pub struct SerialStream {
+    port : &'static SerialPort,
+}
+
+impl Stream for SerialStream {
+    type Item = u8;
+
+    fn poll_next(self : Pin<&mut Self>, context : &mut Context) -> Poll<Option<u8>> {
+        let port = self.port;
+        let mut byte = [0u8];
+        if port.try_read(&mut byte) == 1 {
+            return Poll::Ready(Some(byte[0]));
+        }
+        port.waker.register(context.waker());
+        match port.try_read(&mut byte) {
+            1 => {
+                port.waker.take();
+                Poll::Ready(Some(byte[0]))
+            },
+            _ => Poll::Pending,
+        }
+    }
+}
+
+/// 所有串口
+pub fn ports() -> [&'static SerialPort; 2] {
+    [&COM1, &COM2]
+}
+
+/// 初始化所有串口, 之后才会有它们的中断
+pub fn init() {
+    for port in ports().iter() {
+        if port.is_present() {
+            crate::serial_println!("{}: 16550 UART at {:#x}, {} baud", port.name(), port.base(), port.baud_rate());
+        }
+    }
 }
 
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
-    use core::fmt::Write;
-    use x86_64::instructions::interrupts;       // new
-
-    interrupts::without_interrupts(|| {         // new
-        SERIAL1
-            .lock()
-            .write_fmt(args)
-            .expect("Printing to serial failed");
-    });
+    COM1.write_fmt(args).expect("Printing to serial failed");
 }
 
 /// Prints to the host through the serial interface.
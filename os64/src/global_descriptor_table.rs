@@ -1,44 +1,100 @@
+use alloc::{boxed::Box, vec};
 use lazy_static::lazy_static;
 use x86_64::{VirtAddr, structures::{gdt::{GlobalDescriptorTable, Descriptor,SegmentSelector}, tss::TaskStateSegment}};
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+/// 每个处理器的 double fault 栈大小
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5;
+
+/// 启动处理器(BSP)的 TSS 与 double fault 栈，在堆可用之前就要加载，所以是静态的
+static mut BSP_TSS: TaskStateSegment = TaskStateSegment::new();
+static mut BSP_DOUBLE_FAULT_STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
+
 lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+    static ref GDT: CpuTables = unsafe { CpuTables::new(&mut BSP_TSS, &mut BSP_DOUBLE_FAULT_STACK) };
+}
 
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
+pub struct Selectors {
+    pub code_selector: SegmentSelector,
+    pub tss_selector: SegmentSelector,
+    /// 用户态的代码段和数据段(栈段), RPL 为 3
+    pub user_code_selector: SegmentSelector,
+    pub user_data_selector: SegmentSelector,
 }
 
-lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
+/// 一个处理器的 GDT 及其 TSS
+///
+/// 每个处理器必须有自己的 TSS: TSS 描述符被加载后会被标记为 busy，
+/// 而且 double fault 栈和特权级栈也不能在处理器之间共享。
+pub struct CpuTables {
+    pub gdt: GlobalDescriptorTable,
+    pub selectors: Selectors,
+    tss: *mut TaskStateSegment,
+}
+
+// TSS 只会被所属的处理器修改
+unsafe impl Sync for CpuTables {}
+unsafe impl Send for CpuTables {}
+
+impl CpuTables {
+    fn new(tss: &'static mut TaskStateSegment, double_fault_stack: &'static mut [u8]) -> CpuTables {
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+            let stack_start = VirtAddr::from_ptr(double_fault_stack.as_ptr());
+            stack_start + double_fault_stack.len()
+        };
+
+        let tss_pointer = tss as *mut TaskStateSegment;
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors { code_selector, tss_selector })
-    };
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*tss_pointer }));
+        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+        CpuTables {
+            gdt,
+            selectors: Selectors { code_selector, tss_selector, user_code_selector, user_data_selector },
+            tss: tss_pointer,
+        }
+    }
+
+    /// 为应用处理器(AP)分配新的 GDT 和 TSS, 须在堆初始化之后调用
+    pub fn allocate() -> &'static CpuTables {
+        let tss = Box::leak(Box::new(TaskStateSegment::new()));
+        let stack = Box::leak(vec![0u8; DOUBLE_FAULT_STACK_SIZE].into_boxed_slice());
+        Box::leak(Box::new(CpuTables::new(tss, stack)))
+    }
+
+    /// 在当前处理器上加载 GDT、代码段和 TSS
+    ///
+    /// 数据段寄存器置为空选择子: 之前的值(如 AP 跳板的 0x10)在这个 GDT 中可能是 TSS 描述符,
+    /// 留在 SS 中会让之后的 iretq 产生 #GP。
+    pub fn load(&'static self) {
+        use x86_64::instructions::tables::load_tss;
+        use x86_64::instructions::segmentation::{CS, DS, ES, SS, Segment};
+
+        self.gdt.load();
+        unsafe {
+            CS::set_reg(self.selectors.code_selector);
+            SS::set_reg(SegmentSelector(0));
+            DS::set_reg(SegmentSelector(0));
+            ES::set_reg(SegmentSelector(0));
+            load_tss(self.selectors.tss_selector);
+        }
+    }
+
+    /// 设置从用户态进入内核时使用的栈(RSP0)
+    ///
+    /// 只能在拥有该 TSS 的处理器上、关中断时调用。
+    pub unsafe fn set_kernel_stack(&self, stack_top: VirtAddr) {
+        (*self.tss).privilege_stack_table[0] = stack_top;
+    }
 }
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+/// 启动处理器的 GDT 和 TSS
+pub fn bsp_tables() -> &'static CpuTables {
+    &GDT
 }
 
 pub fn init() {
-    use x86_64::instructions::tables::load_tss;
-    use x86_64::instructions::segmentation::{CS, Segment};
-    
-    GDT.0.load();
-    unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
-    }
+    GDT.load();
 }
@@ -1,6 +1,7 @@
 #![no_std]
 #![feature(const_mut_refs)]
 #![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
 #![cfg_attr(test, no_main)]
 #![feature(custom_test_frameworks)]
 #![test_runner(crate::test_runner)]
@@ -9,11 +10,13 @@
 extern crate alloc;
 use core::panic::PanicInfo;
 
+pub mod api;
 pub mod architecture;
 pub mod parallel;
 pub mod device;
 pub mod memory;
 pub mod global_descriptor_table;
+pub mod shell;
 
 pub fn hlt_loop() -> ! {
     loop {
@@ -76,10 +79,20 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
 pub fn init() {
     global_descriptor_table::init();
     parallel::interrupts::init_interrupt_descriptor_table();
+    api::user::init_cpu();
     unsafe { parallel::interrupts::PICS.lock().initialize() }; // new    
     x86_64::instructions::interrupts::enable();     // new
 }
 
+/// 内存分配失败时报告堆和物理内存的使用情况
+#[alloc_error_handler]
+fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
+    if let Some(stats) = memory::frame_stats() {
+        serial_println!("physical memory: {}", stats);
+    }
+    panic!("allocation error: {:?}, heap: {}", layout, memory::allocator::heap_stats());
+}
+
 #[test_case]
 fn test_breakpoint_exception() {
     // invoke a breakpoint exception
@@ -103,6 +116,7 @@ fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
     hlt_loop();
 }
 
+#[derive(Debug, Clone, Copy)]
 pub struct Error<'a> {
     ///such as: kernel = 0x00
     system_code : u8, 
@@ -115,3 +129,20 @@ pub struct Error<'a> {
     //
     message : &'a str,
 }
+
+impl<'a> Error<'a> {
+    pub const fn new(system_code : u8, module_code : u8, class_code : u8, function_code : u8, message : &'a str) -> Error<'a> {
+        Error { system_code, module_code, class_code, function_code, message }
+    }
+
+    pub fn message(&self) -> &'a str {
+        self.message
+    }
+}
+
+impl core::fmt::Display for Error<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{} ({:02x}:{:02x}:{:02x}:{:02x})", self.message,
+            self.system_code, self.module_code, self.class_code, self.function_code)
+    }
+}
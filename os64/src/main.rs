@@ -21,7 +21,7 @@ extern crate alloc;
 // use os64::parallel::{executor::Executor, Task, keyboard};
 use bootloader::{BootInfo, entry_point, bootinfo};
 use x86_64::VirtAddr;
-use os64::{device::{serial::_print, graphics::{GraphicsDriver, drawing::{canvas::{ScreenCanvas, Canvas}, windows::{widget_base::{add_child, Widget}, win31_style::{create_cursor_widget, create_window, BorderKind, create_desktop}}, colors}, vga::modes::{Graphics640x480x16, ALLCOLOR4COLOR}, Rect, Point, Size}, devices_init}, memory::{self, BootInfoFrameAllocator}, parallel::{mouse::{self}, process::Process}};
+use os64::{device::{serial::_print, devices_init}, memory::{self, frame_allocator::BitmapFrameAllocator}, parallel::{apic, cpu, executor, scheduler, smp, mouse::{self}, task::Task}, shell};
 
 #[cfg(test)]
 fn test_runner(tests: &[&dyn Fn()]) {
@@ -82,16 +82,49 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    let mut frame_allocator = unsafe { BitmapFrameAllocator::init(&boot_info.memory_map) };
+
+    // AP 的跳板须在 1MB 以下，要在堆占用低端内存之前预留
+    let trampoline = smp::reserve_trampoline(&mut frame_allocator);
+
+    // 之后的分配都经过全局帧分配器, 缺页处理也使用它
+    memory::init_frame_allocator(frame_allocator);
+    let mut frame_allocator = memory::GlobalFrameAllocator;
+    if let Some(stats) = memory::frame_stats() {
+        serial_println!("physical memory: {}", stats);
+    }
 
     os64::memory::allocator::init_heap(&mut mapper, &mut frame_allocator)
         .expect("heap initialization failed");
 
+    match os64::device::acpi::init() {
+        Ok(()) => {
+            if let Err(e) = os64::parallel::apic::init(&mut mapper, &mut frame_allocator) {
+                serial_println!("APIC initialization failed: {}, keep using 8259 PIC", e);
+            }
+            if let Err(e) = os64::device::acpi::hpet::init(&mut mapper, &mut frame_allocator) {
+                serial_println!("HPET initialization failed: {}", e);
+            }
+        },
+        Err(e) => serial_println!("ACPI initialization failed: {}", e),
+    }
+    // 没有 MCFG 时通过 0xCF8/0xCFC 端口枚举
+    let count = os64::device::pci::init(&mut mapper, &mut frame_allocator);
+    serial_println!("PCI: {} functions found", count);
+
+    cpu::init_bsp();
+    scheduler::init_cpu();
+    if apic::is_enabled() && trampoline.is_some() {
+        if let Err(e) = smp::init(&mut mapper, &mut frame_allocator) {
+            serial_println!("SMP initialization failed: {}", e);
+        }
+    }
+
     os64::device::clock::real_time_clock::get_datetime();
     devices_init();
-    vga_test();
 
-    let process = Process::read("test",boot_info.physical_memory_offset);
+    // 程序由 shell 的 run 命令启动
+    executor::global().spawn(Task::new(shell::run()));
 
     // unsafe{ 
     //     asm!("int 0x80");
@@ -111,10 +144,7 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     serial_println!("It did not crash!");
 
-    loop {
-        x86_64::instructions::hlt();
-        // os64::hlt_loop();
-    }
+    executor::global().run();
 
 }
 
@@ -123,41 +153,6 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 //     loop {}
 // }
 
-fn vga_test() {
-    let mut vga = Graphics640x480x16::new();
-    // let mut vga = graphics_320x200x256::Graphics320x200x256::new();
-    // let mut vga = graphics_320x240x256::Graphics320x240x256::new();
-    GraphicsDriver::init(&mut vga);
-    GraphicsDriver::clear_screen(&mut vga, colors::SILVER);
-
-    let mut canvas = ScreenCanvas::from_driver(&mut vga);
-    let screen_rect = canvas.rect;
-    
-    let desktop = create_desktop(screen_rect);
-    
-    let rect = Rect { left_top: Point { x: 160, y: 100 }, size: Size { w:320, h: 240 } };
-    let welcome_window = create_window("Welcome to OS64", rect, BorderKind::Resizable);
-
-    add_child(&desktop, welcome_window);
-
-    let center = Point{ x : screen_rect.width() as isize / 2, y : screen_rect.height() as isize / 2 };
-    let mut cursor = create_cursor_widget(center);
-    add_child(&desktop, cursor);
-
-    desktop.repaint(&mut canvas, screen_rect.left_top, screen_rect);
-
-    // canvas.draw_text(0, 0, "Hello World!", colors::BLACK, colors::TRANSPARENT);
-    // for i in 0..20 {
-    //     canvas.set_pixel(i,i,colors::RED);
-    // }
-
-    canvas.draw_x_line(0, 447, 640, colors::BLACK);
-    for i in 0..16 {
-        canvas.fill_rectangle(i*40, 448, 40, 16, ALLCOLOR4COLOR[(i as usize + 8) % 16]);
-        canvas.fill_rectangle(i*40, 464, 40, 16, ALLCOLOR4COLOR[i as usize]);
-    }
-}
-
 // fn test_alloc() {
 //     // allocate a number on the heap
 //     let heap_value = Box::new(41);
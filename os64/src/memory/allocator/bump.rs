@@ -1,4 +1,4 @@
-use super::{align_up, Locked};
+use super::{align_up, HeapAllocator, Locked};
 use core::alloc::{GlobalAlloc, Layout};
 use core::ptr;
 
@@ -31,6 +31,16 @@ impl BumpAllocator {
     }
 }
 
+impl HeapAllocator for BumpAllocator {
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        BumpAllocator::init(self, heap_start, heap_size);
+    }
+
+    fn usage(&self) -> (usize, usize) {
+        (self.heap_end - self.heap_start, self.next - self.heap_start)
+    }
+}
+
 unsafe impl GlobalAlloc for Locked<BumpAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut bump = self.lock(); // get a mutable reference
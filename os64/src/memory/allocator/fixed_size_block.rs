@@ -1,4 +1,4 @@
-use super::Locked;
+use super::{HeapAllocator, Locked};
 use core::alloc::{GlobalAlloc, Layout};
 use core::{
     mem,
@@ -48,12 +48,35 @@ impl FixedSizeBlockAllocator {
     }
 
     /// Allocates using the fallback allocator.
+    ///
+    /// Grows the heap when the fallback allocator runs out of memory.
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        if let Ok(ptr) = self.fallback_allocator.allocate_first_fit(layout) {
+            return ptr.as_ptr();
+        }
+        let required = layout.size() + layout.align();
+        let grown = super::grow_heap(self.fallback_allocator.top(), required);
+        if grown == 0 {
+            return ptr::null_mut();
+        }
+        unsafe { self.fallback_allocator.extend(grown) };
         match self.fallback_allocator.allocate_first_fit(layout) {
             Ok(ptr) => ptr.as_ptr(),
             Err(_) => ptr::null_mut(),
         }
     }
+
+}
+
+impl HeapAllocator for FixedSizeBlockAllocator {
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        FixedSizeBlockAllocator::init(self, heap_start, heap_size);
+    }
+
+    /// Blocks cached in the free lists count as used.
+    fn usage(&self) -> (usize, usize) {
+        (self.fallback_allocator.size(), self.fallback_allocator.used())
+    }
 }
 
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
@@ -1,4 +1,4 @@
-use super::{align_up, Locked};
+use super::{align_up, HeapAllocator, Locked};
 use core::alloc::{GlobalAlloc, Layout};
 use core::{mem, ptr};
 
@@ -24,6 +24,7 @@ impl ListNode {
 
 pub struct LinkedListAllocator {
     head: ListNode,
+    heap_size: usize,
 }
 
 impl LinkedListAllocator {
@@ -31,6 +32,7 @@ impl LinkedListAllocator {
     pub const fn new() -> Self {
         Self {
             head: ListNode::new(0),
+            heap_size: 0,
         }
     }
 
@@ -40,6 +42,7 @@ impl LinkedListAllocator {
     /// heap bounds are valid and that the heap is unused. This method must be
     /// called only once.
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap_size = heap_size;
         self.add_free_region(heap_start, heap_size);
     }
 
@@ -119,6 +122,22 @@ impl LinkedListAllocator {
     }
 }
 
+impl HeapAllocator for LinkedListAllocator {
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        LinkedListAllocator::init(self, heap_start, heap_size);
+    }
+
+    fn usage(&self) -> (usize, usize) {
+        let mut free = 0;
+        let mut current = &self.head;
+        while let Some(ref region) = current.next {
+            free += region.size;
+            current = region;
+        }
+        (self.heap_size, self.heap_size - free)
+    }
+}
+
 unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         // perform layout adjustments
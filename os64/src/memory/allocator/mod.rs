@@ -1,5 +1,8 @@
 use core::alloc::{GlobalAlloc, Layout};
+use core::fmt;
 use core::ptr::null_mut;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::{parallel::sync::{IrqSpinLock, IrqSpinLockGuard, ALLOCATOR_LOCK_LEVEL}, serial_println};
 
 use x86_64::{
     structures::paging::{
@@ -10,21 +13,51 @@ use x86_64::{
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
 // pub const HEAP_SIZE: usize = 100 * 1024; // 100 KB
-pub const HEAP_SIZE: usize = 2 * 1024 * 1024; // 1 GB
+/// 堆的初始大小，不够用时按需增长
+pub const HEAP_SIZE: usize = 2 * 1024 * 1024; // 2 MB
+/// 堆默认最多可以增长到的大小，可以用 `set_heap_limit` 修改
+pub const HEAP_MAX_SIZE: usize = 256 * 1024 * 1024; // 256 MB
+/// 每次增长的最小字节数，避免频繁映射
+const HEAP_GROW_MIN: usize = 256 * 1024;
+
+/// 堆的上限
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
+/// 从 `HEAP_START` 开始已经映射的字节数
+static HEAP_MAPPED: AtomicUsize = AtomicUsize::new(0);
 
 pub mod bump;
 pub mod linked_list;
 pub mod fixed_size_block;
+pub mod slab;
+
+// 全局分配器由 Cargo feature 选择，见 Cargo.toml 的 [features]。
+// 同时打开多个时按 slab、fixed-size-block、linked-list、bump 的顺序选择第一个。
+#[cfg(feature = "slab-allocator")]
+#[global_allocator]
+pub static ALLOCATOR: Locked<slab::SlabAllocator> = Locked::new(slab::SlabAllocator::new());
+
+#[cfg(all(not(feature = "slab-allocator"), feature = "fixed-size-block-allocator"))]
+#[global_allocator]
+pub static ALLOCATOR: Locked<fixed_size_block::FixedSizeBlockAllocator> =
+    Locked::new(fixed_size_block::FixedSizeBlockAllocator::new());
 
-// use bump::BumpAllocator;
-// #[global_allocator]
-// static ALLOCATOR: Locked<BumpAllocator> = Locked::new(BumpAllocator::new());
-// use linked_list::LinkedListAllocator;
-// #[global_allocator]
-// static ALLOCATOR: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
-use fixed_size_block::FixedSizeBlockAllocator;
+#[cfg(all(not(feature = "slab-allocator"), not(feature = "fixed-size-block-allocator"),
+    feature = "linked-list-allocator"))]
 #[global_allocator]
-pub static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
+pub static ALLOCATOR: Locked<linked_list::LinkedListAllocator> = Locked::new(linked_list::LinkedListAllocator::new());
+
+#[cfg(all(not(feature = "slab-allocator"), not(feature = "fixed-size-block-allocator"),
+    not(feature = "linked-list-allocator")))]
+#[global_allocator]
+pub static ALLOCATOR: Locked<bump::BumpAllocator> = Locked::new(bump::BumpAllocator::new());
+
+/// 可以作为内核全局分配器的堆分配器
+pub trait HeapAllocator {
+    /// 用给定的堆区域初始化，只能调用一次
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize);
+    /// 返回 (分配器管理的总字节数, 已使用的字节数)
+    fn usage(&self) -> (usize, usize);
+}
 
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
@@ -46,13 +79,94 @@ pub fn init_heap(
         unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
     }
 
+    HEAP_MAPPED.store(HEAP_SIZE, Ordering::Relaxed);
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        HeapAllocator::init(&mut *ALLOCATOR.lock(), HEAP_START, HEAP_SIZE);
     }
 
     Ok(())
 }
 
+/// 设置堆的上限(字节)，小于当前已映射的大小时取当前大小
+pub fn set_heap_limit(limit: usize) {
+    let mapped = HEAP_MAPPED.load(Ordering::Relaxed);
+    HEAP_LIMIT.store(limit.max(mapped).max(HEAP_SIZE), Ordering::Relaxed);
+}
+
+pub fn heap_limit() -> usize {
+    HEAP_LIMIT.load(Ordering::Relaxed)
+}
+
+/// 从 `heap_top` 开始映射新的页，使堆至少增加 `required` 字节。
+///
+/// 返回实际增加的字节数；到达上限或物理内存耗尽时返回 0。
+/// 堆所在的 4 级表项在初始化时已经存在，进程页表与内核共享它，所以新映射的页对所有地址空间可见。
+fn grow_heap(heap_top: usize, required: usize) -> usize {
+    use crate::memory::{GlobalFrameAllocator, kernel_mapper};
+
+    let limit = HEAP_START + heap_limit();
+    let size = align_up(required.max(HEAP_GROW_MIN), 4096).min(limit.saturating_sub(heap_top));
+    if size < required {
+        return 0;
+    }
+
+    let mut mapper = unsafe { kernel_mapper() };
+    let mut frame_allocator = GlobalFrameAllocator;
+    let start = Page::<Size4KiB>::containing_address(VirtAddr::new(heap_top as u64));
+    let mut grown = 0;
+    for page in Page::range(start, start + (size / 4096) as u64) {
+        let frame = match frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => break,
+        };
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        match unsafe { mapper.map_to(page, frame, flags, &mut frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(_) => {
+                unsafe { crate::memory::deallocate_frame(frame) };
+                break;
+            },
+        }
+        grown += 4096;
+    }
+    HEAP_MAPPED.fetch_add(grown, Ordering::Relaxed);
+    grown
+}
+
+/// 堆的使用情况
+#[derive(Clone,Copy,Debug)]
+pub struct HeapStats {
+    /// 当前已映射的大小
+    pub size: usize,
+    pub used: usize,
+    pub free: usize,
+    pub limit: usize,
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} KB used, {} KB free, {} KB mapped, limit {} KB",
+            self.used / 1024, self.free / 1024, self.size / 1024, self.limit / 1024)
+    }
+}
+
+pub fn heap_stats() -> HeapStats {
+    let (size, used) = ALLOCATOR.lock().usage();
+    HeapStats {
+        size,
+        used,
+        free: size - used,
+        limit: heap_limit(),
+    }
+}
+
+/// 通过串口打印堆的使用情况, slab 分配器还会打印每个缓存的统计
+pub fn report() {
+    serial_println!("heap: {}", heap_stats());
+    #[cfg(feature = "slab-allocator")]
+    ALLOCATOR.lock().report();
+}
+
 pub struct Dummy;
 
 unsafe impl GlobalAlloc for Dummy {
@@ -65,19 +179,21 @@ unsafe impl GlobalAlloc for Dummy {
     }
 }
 
-/// A wrapper around spin::Mutex to permit trait implementations.
+/// A wrapper around IrqSpinLock to permit trait implementations.
+///
+/// 持有期间关闭中断, 中断处理程序中分配内存时不会与被它打断的分配死锁。
 pub struct Locked<A> {
-    inner: spin::Mutex<A>,
+    inner: IrqSpinLock<A>,
 }
 
 impl<A> Locked<A> {
     pub const fn new(inner: A) -> Self {
         Locked {
-            inner: spin::Mutex::new(inner),
+            inner: IrqSpinLock::with_level(inner, ALLOCATOR_LOCK_LEVEL),
         }
     }
 
-    pub fn lock(&self) -> spin::MutexGuard<A> {
+    pub fn lock(&self) -> IrqSpinLockGuard<A> {
         self.inner.lock()
     }
 }
@@ -0,0 +1,349 @@
+//see also: https://www.kernel.org/doc/gorman/html/understand/understand011.html
+// Slab 分配器。
+// 每种对象大小有一个缓存(cache)，缓存由若干 slab 组成，一个 slab 就是一个 4K 物理帧，
+// 通过物理内存窗口访问，开头是 slab 头，其余部分切成同样大小的对象，空闲对象串成链表。
+// 对象全部释放后 slab 的帧归还给帧分配器(每个缓存保留一个空 slab 以免反复分配)。
+// 超过最大对象大小的分配，以及帧分配器尚不可用时的分配，交给后备的 linked_list_allocator 堆。
+use super::{HeapAllocator, Locked, HEAP_START, heap_limit};
+use core::alloc::{GlobalAlloc, Layout};
+use core::{mem, ptr::{self, NonNull}};
+use x86_64::{PhysAddr, structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame}};
+use crate::{memory::{GlobalFrameAllocator, phys_to_virt, physical_memory_offset}, serial_println};
+
+/// 各缓存的对象大小, 必须是 2 的幂, 同时作为对象的对齐
+const SLAB_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+const SLAB_PAGE_SIZE: usize = 4096;
+
+/// 选择能容纳 `layout` 的缓存
+fn cache_index(layout: &Layout) -> Option<usize> {
+    let required = layout.size().max(layout.align());
+    SLAB_SIZES.iter().position(|&s| s >= required)
+}
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// 位于每个 slab 页开头
+struct SlabHeader {
+    prev: *mut SlabHeader,
+    next: *mut SlabHeader,
+    free_list: *mut FreeObject,
+    in_use: usize,
+}
+
+/// 一个缓存的统计信息
+#[derive(Clone,Copy,Debug,Default)]
+pub struct CacheStats {
+    pub object_size: usize,
+    pub slabs: usize,
+    pub objects_in_use: usize,
+    pub peak_objects: usize,
+    /// 累计的分配和释放次数
+    pub allocations: u64,
+    pub frees: u64,
+    /// 调用者实际请求的字节数, 与对象大小之差为内部碎片
+    pub requested_bytes: usize,
+}
+
+impl CacheStats {
+    pub fn free_objects(&self, capacity: usize) -> usize {
+        self.slabs * capacity - self.objects_in_use
+    }
+}
+
+struct SlabCache {
+    object_size: usize,
+    /// 包含空闲对象的 slab, 双向链表
+    partial: *mut SlabHeader,
+    /// 保留的一个空 slab
+    empty: *mut SlabHeader,
+    stats: CacheStats,
+}
+
+impl SlabCache {
+    const fn new(object_size: usize) -> SlabCache {
+        SlabCache {
+            object_size,
+            partial: ptr::null_mut(),
+            empty: ptr::null_mut(),
+            stats: CacheStats {
+                object_size,
+                slabs: 0,
+                objects_in_use: 0,
+                peak_objects: 0,
+                allocations: 0,
+                frees: 0,
+                requested_bytes: 0,
+            },
+        }
+    }
+
+    /// 第一个对象在页内的偏移
+    fn first_object_offset(&self) -> usize {
+        super::align_up(mem::size_of::<SlabHeader>(), self.object_size)
+    }
+
+    fn capacity(&self) -> usize {
+        (SLAB_PAGE_SIZE - self.first_object_offset()) / self.object_size
+    }
+
+    unsafe fn push_partial(&mut self, slab: *mut SlabHeader) {
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = self.partial;
+        if !self.partial.is_null() {
+            (*self.partial).prev = slab;
+        }
+        self.partial = slab;
+    }
+
+    unsafe fn remove_partial(&mut self, slab: *mut SlabHeader) {
+        if !(*slab).prev.is_null() {
+            (*(*slab).prev).next = (*slab).next;
+        } else {
+            self.partial = (*slab).next;
+        }
+        if !(*slab).next.is_null() {
+            (*(*slab).next).prev = (*slab).prev;
+        }
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = ptr::null_mut();
+    }
+
+    /// 从帧分配器取一页，初始化成 slab
+    unsafe fn new_slab(&mut self) -> *mut SlabHeader {
+        let frame = match GlobalFrameAllocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return ptr::null_mut(),
+        };
+        let page: *mut u8 = phys_to_virt(frame.start_address()).as_mut_ptr();
+        let capacity = self.capacity();
+        let first = self.first_object_offset();
+
+        let mut free_list: *mut FreeObject = ptr::null_mut();
+        for i in (0..capacity).rev() {
+            let object = page.add(first + i * self.object_size) as *mut FreeObject;
+            (*object).next = free_list;
+            free_list = object;
+        }
+        let slab = page as *mut SlabHeader;
+        slab.write(SlabHeader {
+            prev: ptr::null_mut(),
+            next: ptr::null_mut(),
+            free_list,
+            in_use: 0,
+        });
+        self.stats.slabs += 1;
+        slab
+    }
+
+    unsafe fn free_slab(&mut self, slab: *mut SlabHeader) {
+        let address = PhysAddr::new(slab as u64 - physical_memory_offset().as_u64());
+        GlobalFrameAllocator.deallocate_frame(PhysFrame::containing_address(address));
+        self.stats.slabs -= 1;
+    }
+
+    unsafe fn alloc(&mut self, requested: usize) -> *mut u8 {
+        if self.partial.is_null() {
+            let slab = if !self.empty.is_null() {
+                mem::replace(&mut self.empty, ptr::null_mut())
+            } else {
+                self.new_slab()
+            };
+            if slab.is_null() {
+                return ptr::null_mut();
+            }
+            self.push_partial(slab);
+        }
+
+        let slab = self.partial;
+        let object = (*slab).free_list;
+        (*slab).free_list = (*object).next;
+        (*slab).in_use += 1;
+        if (*slab).free_list.is_null() {
+            // slab 已满，离开 partial 链表，释放其中的对象时再放回来
+            self.remove_partial(slab);
+        }
+
+        self.stats.allocations += 1;
+        self.stats.objects_in_use += 1;
+        self.stats.peak_objects = self.stats.peak_objects.max(self.stats.objects_in_use);
+        self.stats.requested_bytes += requested;
+        object as *mut u8
+    }
+
+    unsafe fn dealloc(&mut self, object: *mut u8, requested: usize) {
+        let slab = (object as usize & !(SLAB_PAGE_SIZE - 1)) as *mut SlabHeader;
+        let was_full = (*slab).free_list.is_null();
+        let node = object as *mut FreeObject;
+        (*node).next = (*slab).free_list;
+        (*slab).free_list = node;
+        (*slab).in_use -= 1;
+
+        self.stats.frees += 1;
+        self.stats.objects_in_use -= 1;
+        self.stats.requested_bytes -= requested;
+
+        if was_full {
+            self.push_partial(slab);
+        }
+        if (*slab).in_use == 0 {
+            // 回收空 slab: 保留一个，其余的页还给帧分配器
+            self.remove_partial(slab);
+            if self.empty.is_null() {
+                self.empty = slab;
+            } else {
+                self.free_slab(slab);
+            }
+        }
+    }
+}
+
+pub struct SlabAllocator {
+    caches: [SlabCache; SLAB_SIZES.len()],
+    fallback_allocator: linked_list_allocator::Heap,
+    /// 当前分配出去的字节数(按对象大小计算)及其峰值
+    bytes_in_use: usize,
+    peak_bytes: usize,
+}
+
+// slab 只由持有锁的一方访问
+unsafe impl Send for SlabAllocator {}
+
+impl SlabAllocator {
+    pub const fn new() -> Self {
+        SlabAllocator {
+            caches: [
+                SlabCache::new(8), SlabCache::new(16), SlabCache::new(32),
+                SlabCache::new(64), SlabCache::new(128), SlabCache::new(256),
+                SlabCache::new(512), SlabCache::new(1024), SlabCache::new(2048),
+            ],
+            fallback_allocator: linked_list_allocator::Heap::empty(),
+            bytes_in_use: 0,
+            peak_bytes: 0,
+        }
+    }
+
+    /// 后备堆中的分配, 不够时扩展堆
+    fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        if let Ok(ptr) = self.fallback_allocator.allocate_first_fit(layout) {
+            return ptr.as_ptr();
+        }
+        let grown = super::grow_heap(self.fallback_allocator.top(), layout.size() + layout.align());
+        if grown == 0 {
+            return ptr::null_mut();
+        }
+        unsafe { self.fallback_allocator.extend(grown) };
+        match self.fallback_allocator.allocate_first_fit(layout) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(_) => ptr::null_mut(),
+        }
+    }
+
+    fn record_alloc(&mut self, size: usize) {
+        self.bytes_in_use += size;
+        self.peak_bytes = self.peak_bytes.max(self.bytes_in_use);
+    }
+
+    /// 各缓存的统计信息
+    pub fn cache_stats(&self) -> impl Iterator<Item = CacheStats> + '_ {
+        self.caches.iter().map(|cache| cache.stats)
+    }
+
+    pub fn peak_bytes(&self) -> usize {
+        self.peak_bytes
+    }
+
+    /// 通过串口打印每个缓存的分配情况、碎片和峰值
+    pub fn report(&self) {
+        serial_println!("slab  objsize  slabs  in-use   free   peak      allocs       frees  waste");
+        let mut slab_bytes = 0;
+        let mut object_bytes = 0;
+        let mut requested_bytes = 0;
+        for cache in self.caches.iter() {
+            let stats = &cache.stats;
+            let capacity = cache.capacity();
+            let waste = stats.objects_in_use * stats.object_size - stats.requested_bytes;
+            serial_println!("      {:>7}  {:>5}  {:>6}  {:>5}  {:>5}  {:>10}  {:>10}  {:>5}",
+                stats.object_size, stats.slabs, stats.objects_in_use, stats.free_objects(capacity),
+                stats.peak_objects, stats.allocations, stats.frees, waste);
+            slab_bytes += (stats.slabs + (!cache.empty.is_null()) as usize) * SLAB_PAGE_SIZE;
+            object_bytes += stats.objects_in_use * stats.object_size;
+            requested_bytes += stats.requested_bytes;
+        }
+        // 碎片: slab 页中没有被调用者实际使用的部分
+        let fragmentation = if slab_bytes == 0 { 0 } else { 100 - requested_bytes * 100 / slab_bytes };
+        serial_println!("slab pages: {} KB, objects: {} KB, requested: {} KB, fragmentation: {}%",
+            slab_bytes / 1024, object_bytes / 1024, requested_bytes / 1024, fragmentation);
+        serial_println!("fallback heap: {} KB used of {} KB; in use: {} KB, peak: {} KB",
+            self.fallback_allocator.used() / 1024, self.fallback_allocator.size() / 1024,
+            self.bytes_in_use / 1024, self.peak_bytes / 1024);
+    }
+}
+
+/// 地址是否属于后备堆
+fn in_fallback_heap(ptr: *mut u8) -> bool {
+    let address = ptr as usize;
+    address >= HEAP_START && address < HEAP_START + heap_limit()
+}
+
+impl HeapAllocator for SlabAllocator {
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.fallback_allocator.init(heap_start, heap_size);
+    }
+
+    fn usage(&self) -> (usize, usize) {
+        let slab_bytes: usize = self.caches.iter()
+            .map(|cache| (cache.stats.slabs + (!cache.empty.is_null()) as usize) * SLAB_PAGE_SIZE)
+            .sum();
+        (self.fallback_allocator.size() + slab_bytes, self.fallback_allocator.used() + slab_bytes)
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<SlabAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut allocator = self.lock();
+        match cache_index(&layout) {
+            Some(index) => {
+                let ptr = allocator.caches[index].alloc(layout.size());
+                if !ptr.is_null() {
+                    allocator.record_alloc(SLAB_SIZES[index]);
+                    return ptr;
+                }
+                // 帧分配器不可用时退回到后备堆
+                let block = Layout::from_size_align(SLAB_SIZES[index], SLAB_SIZES[index]).unwrap();
+                let ptr = allocator.fallback_alloc(block);
+                if !ptr.is_null() {
+                    allocator.record_alloc(SLAB_SIZES[index]);
+                }
+                ptr
+            }
+            None => {
+                let ptr = allocator.fallback_alloc(layout);
+                if !ptr.is_null() {
+                    allocator.record_alloc(layout.size());
+                }
+                ptr
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut allocator = self.lock();
+        match cache_index(&layout) {
+            Some(index) if !in_fallback_heap(ptr) => {
+                allocator.caches[index].dealloc(ptr, layout.size());
+                allocator.bytes_in_use -= SLAB_SIZES[index];
+            }
+            Some(index) => {
+                let block = Layout::from_size_align(SLAB_SIZES[index], SLAB_SIZES[index]).unwrap();
+                allocator.fallback_allocator.deallocate(NonNull::new(ptr).unwrap(), block);
+                allocator.bytes_in_use -= SLAB_SIZES[index];
+            }
+            None => {
+                allocator.fallback_allocator.deallocate(NonNull::new(ptr).unwrap(), layout);
+                allocator.bytes_in_use -= layout.size();
+            }
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//see also: https://wiki.osdev.org/Page_Frame_Allocation
+// 基于位图的物理帧分配器。
+// 每个 4K 物理帧对应位图中的一位，1 表示已使用。位图本身放在第一个足够大的可用内存区域中，
+// 通过物理内存窗口访问，因此在堆初始化之前就可以使用。
+// 单帧分配从上次分配的位置向后查找第一个空闲帧；连续分配(供 DMA 使用)逐位查找足够长的空闲区间。
+use core::fmt;
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::{PhysAddr, structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB}};
+use crate::serial_println;
+use super::phys_to_virt;
+
+const FRAME_SIZE : u64 = 4096;
+const BITS_PER_WORD : usize = 64;
+
+/// 物理内存的使用情况
+#[derive(Clone,Copy,Debug)]
+pub struct FrameStats {
+    /// 位图管理的可用帧数
+    pub total_frames : usize,
+    pub free_frames : usize,
+}
+
+impl FrameStats {
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.total_frames as u64 * FRAME_SIZE
+    }
+
+    pub fn free_bytes(&self) -> u64 {
+        self.free_frames as u64 * FRAME_SIZE
+    }
+
+    pub fn used_bytes(&self) -> u64 {
+        self.used_frames() as u64 * FRAME_SIZE
+    }
+}
+
+impl fmt::Display for FrameStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} KB used, {} KB free, {} KB total",
+            self.used_bytes() / 1024, self.free_bytes() / 1024, self.total_bytes() / 1024)
+    }
+}
+
+pub struct BitmapFrameAllocator {
+    /// 每一位对应一个帧, 1 表示已使用
+    bitmap : &'static mut [u64],
+    /// 位图覆盖的帧数, 即最大可用物理地址 / 4K
+    frame_count : usize,
+    total_frames : usize,
+    free_frames : usize,
+    /// 下次开始查找的字
+    next_word : usize,
+}
+
+impl BitmapFrameAllocator {
+    /// 从 bootloader 的内存地图中创建分配器
+    ///
+    /// 这个函数是不安全的，调用者必须保证内存地图中标记为可用的帧确实未被使用，
+    /// 且物理内存窗口已经由 `memory::init` 设置好。
+    pub unsafe fn init(memory_map : &'static MemoryMap) -> Self {
+        let usable = || memory_map.iter().filter(|r| r.region_type == MemoryRegionType::Usable);
+        let end = usable().map(|r| r.range.end_addr()).max().unwrap_or(0);
+        let frame_count = (end / FRAME_SIZE) as usize;
+        let words = (frame_count + BITS_PER_WORD - 1) / BITS_PER_WORD;
+        let bitmap_bytes = (words * 8) as u64;
+        let bitmap_frames = (bitmap_bytes + FRAME_SIZE - 1) / FRAME_SIZE;
+
+        // 位图放在第一个足够大的可用区域的开头
+        let region = usable()
+            .find(|r| r.range.end_frame_number - r.range.start_frame_number >= bitmap_frames)
+            .expect("no memory for the frame bitmap");
+        let bitmap_start = PhysAddr::new(region.range.start_addr());
+        let bitmap = core::slice::from_raw_parts_mut(phys_to_virt(bitmap_start).as_mut_ptr::<u64>(), words);
+        bitmap.iter_mut().for_each(|word| *word = u64::MAX);
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            frame_count,
+            total_frames : 0,
+            free_frames : 0,
+            next_word : 0,
+        };
+        for region in usable() {
+            for frame in region.range.start_frame_number..region.range.end_frame_number {
+                allocator.clear(frame as usize);
+                allocator.total_frames += 1;
+            }
+        }
+
+        let first = (bitmap_start.as_u64() / FRAME_SIZE) as usize;
+        for frame in first..first + bitmap_frames as usize {
+            allocator.set(frame);
+        }
+        // 物理地址 0 不分配出去，避免和空指针混淆
+        if !allocator.is_used(0) {
+            allocator.set(0);
+        }
+        allocator
+    }
+
+    fn is_used(&self, frame : usize) -> bool {
+        self.bitmap[frame / BITS_PER_WORD] & (1 << (frame % BITS_PER_WORD)) != 0
+    }
+
+    fn set(&mut self, frame : usize) {
+        self.bitmap[frame / BITS_PER_WORD] |= 1 << (frame % BITS_PER_WORD);
+        self.free_frames -= 1;
+    }
+
+    fn clear(&mut self, frame : usize) {
+        self.bitmap[frame / BITS_PER_WORD] &= !(1 << (frame % BITS_PER_WORD));
+        self.free_frames += 1;
+    }
+
+    fn frame_number(frame : PhysFrame) -> usize {
+        (frame.start_address().as_u64() / FRAME_SIZE) as usize
+    }
+
+    fn frame_at(number : usize) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(number as u64 * FRAME_SIZE))
+    }
+
+    /// 分配 `count` 个物理地址连续的帧，起始帧号按 `align` 个帧对齐
+    pub fn allocate_contiguous(&mut self, count : usize, align : usize) -> Option<PhysFrame> {
+        if count == 0 || self.free_frames < count {
+            return None;
+        }
+        let align = align.max(1);
+        let mut start = 0;
+        while start + count <= self.frame_count {
+            match (start..start + count).rev().find(|frame| self.is_used(*frame)) {
+                // 跳过已使用的帧，从它之后的下一个对齐位置继续
+                Some(used) => start = (used + 1 + align - 1) / align * align,
+                None => {
+                    for frame in start..start + count {
+                        self.set(frame);
+                    }
+                    return Some(Self::frame_at(start));
+                },
+            }
+        }
+        None
+    }
+
+    /// 释放 `allocate_contiguous` 分配的帧
+    ///
+    /// 调用者必须保证这些帧不再被使用。
+    pub unsafe fn deallocate_contiguous(&mut self, start : PhysFrame, count : usize) {
+        let first = Self::frame_number(start);
+        for frame in first..first + count {
+            self.deallocate_frame(Self::frame_at(frame));
+        }
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            total_frames : self.total_frames,
+            free_frames : self.free_frames,
+        }
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if self.free_frames == 0 {
+            return None;
+        }
+        let words = self.bitmap.len();
+        for i in 0..words {
+            let index = (self.next_word + i) % words;
+            let word = self.bitmap[index];
+            if word == u64::MAX {
+                continue;
+            }
+            let frame = index * BITS_PER_WORD + (!word).trailing_zeros() as usize;
+            if frame >= self.frame_count {
+                continue;
+            }
+            self.set(frame);
+            self.next_word = index;
+            return Some(Self::frame_at(frame));
+        }
+        None
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame : PhysFrame) {
+        let number = Self::frame_number(frame);
+        if number >= self.frame_count {
+            return;
+        }
+        if !self.is_used(number) {
+            serial_println!("frame 0x{:x} freed twice", frame.start_address().as_u64());
+            return;
+        }
+        self.clear(number);
+        // 让下一次分配优先使用低地址的帧
+        self.next_word = self.next_word.min(number / BITS_PER_WORD);
+    }
+}
@@ -1,19 +1,110 @@
 use x86_64::{
     VirtAddr,
     PhysAddr,
-    structures::paging::*
+    structures::paging::{*, mapper::MapToError}
 };
 use bootloader::bootinfo::MemoryMap;
 use bootloader::bootinfo::MemoryRegionType;
+use core::sync::atomic::{AtomicU64, Ordering};
+use alloc::collections::BTreeMap;
+use x86_64::registers::control::Cr3;
+use crate::parallel::sync::{IrqSpinLock, FRAME_ALLOCATOR_LOCK_LEVEL};
 
 pub mod allocator;
+pub mod frame_allocator;
+pub mod shared;
+pub mod vma;
+
+use frame_allocator::{BitmapFrameAllocator, FrameStats};
+
+/// 2MiB 大页的大小
+pub const HUGE_PAGE_SIZE: u64 = 0x20_0000;
+/// 一个 2MiB 大页包含的 4K 帧数
+pub const HUGE_PAGE_FRAMES: usize = 512;
+
+/// 物理内存在虚拟地址空间中的偏移量，由 `init` 设置。
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+/// 内核页表(启动时的 4 级页表)的物理地址，由 `init` 设置。
+static KERNEL_PAGE_TABLE: AtomicU64 = AtomicU64::new(0);
 
 /// 初始化一个新的OffsetPageTable。
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    KERNEL_PAGE_TABLE.store(Cr3::read().0.start_address().as_u64(), Ordering::Relaxed);
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
+/// 操作内核页表的 Mapper，用于在堆增长等场合修改内核映射。
+///
+/// 调用者必须保证不会同时修改同一部分映射。
+pub unsafe fn kernel_mapper() -> OffsetPageTable<'static> {
+    let table = &mut *(phys_to_virt(kernel_page_table().start_address()).as_mut_ptr::<PageTable>());
+    OffsetPageTable::new(table, physical_memory_offset())
+}
+
+/// 内核页表，内核线程和没有进程的处理器使用它。
+pub fn kernel_page_table() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_PAGE_TABLE.load(Ordering::Relaxed)))
+}
+
+/// 返回物理内存窗口的起始虚拟地址。
+pub fn physical_memory_offset() -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+}
+
+/// 返回给定物理地址在物理内存窗口中的虚拟地址。
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    physical_memory_offset() + addr.as_u64()
+}
+
+/// 将一段物理内存(如 APIC 的寄存器、显卡的帧缓冲)映射到物理内存窗口中，并返回其虚拟地址。
+///
+/// bootloader 只映射了内存地图中出现的物理内存，位于内存顶端之上的 MMIO 区域需要
+/// 在使用前单独映射。已经映射过的页保持不变，新映射的页不使用缓存。
+/// 区域中按 2MiB 对齐且完整的部分使用大页映射，以减少页表和 TLB 的占用。
+pub fn map_physical_region(
+    phys: PhysAddr,
+    size: usize,
+    mapper: &mut (impl Mapper<Size4KiB> + Mapper<Size2MiB> + Translate),
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    use x86_64::structures::paging::PageTableFlags as Flags;
+
+    let end = phys + size.max(1) as u64;
+    let flags = Flags::PRESENT | Flags::WRITABLE | Flags::NO_CACHE | Flags::WRITE_THROUGH;
+
+    let mut address = phys.align_down(4096u64);
+    while address < end {
+        let virt = phys_to_virt(address);
+        if mapper.translate_addr(virt).is_some() {
+            address += 4096u64;
+            continue;
+        }
+        if address.is_aligned(HUGE_PAGE_SIZE) && virt.is_aligned(HUGE_PAGE_SIZE) && end - address >= HUGE_PAGE_SIZE {
+            let page = Page::<Size2MiB>::containing_address(virt);
+            let frame = PhysFrame::<Size2MiB>::containing_address(address);
+            match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+                Ok(flush) => {
+                    flush.flush();
+                    address += HUGE_PAGE_SIZE;
+                    continue;
+                },
+                // 这 2MiB 中已经有 4K 的映射，退回逐页映射
+                Err(MapToError::PageAlreadyMapped(_)) => {},
+                Err(MapToError::FrameAllocationFailed) => return Err(MapToError::FrameAllocationFailed),
+                Err(MapToError::ParentEntryHugePage) => return Err(MapToError::ParentEntryHugePage),
+            }
+        }
+        let page = Page::<Size4KiB>::containing_address(virt);
+        let frame = PhysFrame::<Size4KiB>::containing_address(address);
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+        address += 4096u64;
+    }
+
+    Ok(phys_to_virt(phys))
+}
+
 /// 返回一个对活动的4级表的可变引用。
 pub unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
     -> &'static mut PageTable
@@ -52,7 +143,7 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr)
     let mut frame = level_4_table_frame;
 
     // 遍历多级页表
-    for &index in &table_indexes {
+    for (level, &index) in table_indexes.iter().enumerate() {
         // 将该框架转换为页表参考
         let virt = physical_memory_offset + frame.start_address().as_u64();
         let table_ptr: *const PageTable = virt.as_ptr();
@@ -63,7 +154,11 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr)
         frame = match entry.frame() {
             Ok(frame) => frame,
             Err(FrameError::FrameNotPresent) => return None,
-            Err(FrameError::HugeFrame) => panic!("huge pages not supported"),
+            // 3 级表中的 1GiB 页或 2 级表中的 2MiB 页，剩下的地址位都是页内偏移
+            Err(FrameError::HugeFrame) => {
+                let offset_bits = 12 + 9 * (3 - level);
+                return Some(entry.addr() + (addr.as_u64() & ((1u64 << offset_bits) - 1)));
+            },
         };
     }
 
@@ -98,6 +193,98 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
     }
 }
 
+/// 全局的物理帧分配器，由 `init_frame_allocator` 设置。
+///
+/// 缺页处理等运行时的分配都通过它进行，因此锁持有期间关闭中断。
+static FRAME_ALLOCATOR: IrqSpinLock<Option<BitmapFrameAllocator>> = IrqSpinLock::with_level(None, FRAME_ALLOCATOR_LOCK_LEVEL);
+
+/// 把启动时创建的帧分配器交给全局使用，之后通过 `GlobalFrameAllocator` 分配。
+pub fn init_frame_allocator(frame_allocator: BitmapFrameAllocator) {
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+}
+
+/// 全局帧分配器的句柄, 可以在任何需要 `FrameAllocator` 的地方使用。
+pub struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        FRAME_ALLOCATOR.lock().as_mut()?.allocate_frame()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        if let Some(allocator) = FRAME_ALLOCATOR.lock().as_mut() {
+            allocator.deallocate_frame(frame);
+        }
+    }
+}
+
+/// 释放一个物理帧，调用者必须保证它不再被使用。
+pub unsafe fn deallocate_frame(frame: PhysFrame) {
+    GlobalFrameAllocator.deallocate_frame(frame)
+}
+
+/// 被多个地址空间共同引用(写时复制)的帧的额外引用数, 不在表中的帧只有一个引用。
+static FRAME_REFERENCES: IrqSpinLock<BTreeMap<PhysFrame, usize>> = IrqSpinLock::new(BTreeMap::new());
+
+/// 增加一个对 `frame` 的引用，如 fork 时父子进程共享同一页。
+pub fn share_frame(frame: PhysFrame) {
+    *FRAME_REFERENCES.lock().entry(frame).or_insert(0) += 1;
+}
+
+/// `frame` 被引用的次数。
+pub fn frame_references(frame: PhysFrame) -> usize {
+    1 + FRAME_REFERENCES.lock().get(&frame).copied().unwrap_or(0)
+}
+
+/// 释放一个对 `frame` 的引用，最后一个引用释放时归还给帧分配器。
+///
+/// 调用者必须保证自己不再使用这个帧。
+pub unsafe fn release_frame(frame: PhysFrame) {
+    let shared = {
+        let mut references = FRAME_REFERENCES.lock();
+        match references.get_mut(&frame) {
+            Some(count) => {
+                *count -= 1;
+                if *count == 0 {
+                    references.remove(&frame);
+                }
+                true
+            },
+            None => false,
+        }
+    };
+    if !shared {
+        deallocate_frame(frame);
+    }
+}
+
+/// 分配 `count` 个物理地址连续的帧(如 DMA 缓冲区)，起始地址按 `align` 个帧对齐。
+pub fn allocate_contiguous(count: usize, align: usize) -> Option<PhysFrame> {
+    FRAME_ALLOCATOR.lock().as_mut()?.allocate_contiguous(count, align)
+}
+
+/// 释放 `allocate_contiguous` 分配的帧。
+pub unsafe fn deallocate_contiguous(start: PhysFrame, count: usize) {
+    if let Some(allocator) = FRAME_ALLOCATOR.lock().as_mut() {
+        allocator.deallocate_contiguous(start, count);
+    }
+}
+
+/// 物理内存的使用情况，全局帧分配器尚未设置时返回 `None`。
+pub fn frame_stats() -> Option<FrameStats> {
+    FRAME_ALLOCATOR.lock().as_ref().map(|a| a.stats())
+}
+
+/// 分配一个清零的物理帧。
+pub fn allocate_zeroed_frame() -> Option<PhysFrame> {
+    let frame = GlobalFrameAllocator.allocate_frame()?;
+    let page: *mut u8 = phys_to_virt(frame.start_address()).as_mut_ptr();
+    unsafe { core::ptr::write_bytes(page, 0, 4096) };
+    Some(frame)
+}
+
 /// 一个FrameAllocator，从bootloader的内存地图中返回可用的 frames。
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
@@ -0,0 +1,150 @@
+//see also: https://man7.org/linux/man-pages/man7/shm_overview.7.html
+// 共享内存对象和文件的页缓存。
+// 共享内存对象是一组按页索引的物理帧，可以同时映射到多个地址空间中(见 vma::VmaBacking::Shared)。
+// 帧在第一次缺页时才分配，只有对象本身被释放(所有映射和引用都已消失)时才归还给帧分配器。
+// 文件映射也是共享内存对象：同一个文件的所有映射共用一个对象, 对象打开着文件,
+// 页在缺页时才从文件中读出这一页来填充, 写入对其它映射可见，但不会写回磁盘。
+use core::sync::atomic::{AtomicU64, Ordering};
+use alloc::{collections::BTreeMap, string::String, sync::{Arc, Weak}, vec::Vec};
+use os64_abi::FILE_KIND_FILE;
+use x86_64::structures::paging::PhysFrame;
+use crate::{device::disk::{file_system::FileOpenMode, vfs::{self, FileHandle}}, parallel::sync::IrqSpinLock};
+use super::{allocate_zeroed_frame, deallocate_frame, phys_to_virt};
+
+const PAGE_SIZE : u64 = 4096;
+/// 一个共享内存对象的最大大小
+pub const MAX_SHARED_SIZE : u64 = 1 << 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SharedMemoryId(u64);
+
+impl SharedMemoryId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        SharedMemoryId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn from_u64(id : u64) -> Self {
+        SharedMemoryId(id)
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+pub struct SharedMemory {
+    id : SharedMemoryId,
+    size : u64,
+    /// 每页的物理帧, 第一次访问时分配
+    pages : IrqSpinLock<Vec<Option<PhysFrame>>>,
+    /// 文件映射的路径和打开的文件
+    file : Option<(String, FileHandle)>,
+}
+
+/// 用 `create` 创建、尚未 `unlink` 的对象
+static SHARED_OBJECTS: IrqSpinLock<BTreeMap<SharedMemoryId, Arc<SharedMemory>>> = IrqSpinLock::new(BTreeMap::new());
+/// 文件的页缓存, 文件的完整路径为键; 只要还有映射, 同一个文件就使用同一个对象
+static PAGE_CACHE: IrqSpinLock<BTreeMap<String, Weak<SharedMemory>>> = IrqSpinLock::new(BTreeMap::new());
+
+impl SharedMemory {
+    fn new(size : u64, file : Option<(String, FileHandle)>) -> Result<SharedMemory, &'static str> {
+        if size == 0 || size > MAX_SHARED_SIZE {
+            return Err("invalid shared memory size");
+        }
+        let pages = ((size + PAGE_SIZE - 1) / PAGE_SIZE) as usize;
+        Ok(SharedMemory {
+            id : SharedMemoryId::new(),
+            size,
+            pages : IrqSpinLock::new(alloc::vec![None; pages]),
+            file,
+        })
+    }
+
+    /// 创建 `size` 字节的共享内存对象, 其它进程可以通过 id 找到它, 直到 `unlink`
+    pub fn create(size : u64) -> Result<Arc<SharedMemory>, &'static str> {
+        let object = Arc::new(SharedMemory::new(size, None)?);
+        SHARED_OBJECTS.lock().insert(object.id, object.clone());
+        Ok(object)
+    }
+
+    /// 通过 id 找到共享内存对象
+    pub fn open(id : SharedMemoryId) -> Option<Arc<SharedMemory>> {
+        SHARED_OBJECTS.lock().get(&id).cloned()
+    }
+
+    /// 删除 id, 已有的映射不受影响, 都解除后对象被释放
+    pub fn unlink(id : SharedMemoryId) -> Result<(), &'static str> {
+        SHARED_OBJECTS.lock().remove(&id)
+            .map(|_| ())
+            .ok_or("no such shared memory")
+    }
+
+    /// 文件 `path` 的页缓存对象, 缓存中没有时打开文件, 大小为打开时文件的大小
+    pub fn for_file(path : &str) -> Result<Arc<SharedMemory>, &'static str> {
+        let key = String::from(path);
+        let cached = PAGE_CACHE.lock().get(&key).and_then(|object| object.upgrade());
+        if let Some(object) = cached {
+            return Ok(object);
+        }
+        let stat = vfs::stat(path).map_err(|_| "file not found")?;
+        if stat.kind != FILE_KIND_FILE as u64 {
+            return Err("not a file");
+        }
+        let handle = vfs::open(path, FileOpenMode::OPEN | FileOpenMode::READ).map_err(|_| "file not found")?;
+        let object = Arc::new(SharedMemory::new(stat.size, Some((key.clone(), handle)))?);
+        PAGE_CACHE.lock().insert(key, Arc::downgrade(&object));
+        Ok(object)
+    }
+
+    pub fn id(&self) -> SharedMemoryId {
+        self.id
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// 已分配的页数
+    pub fn resident_pages(&self) -> usize {
+        self.pages.lock().iter().filter(|page| page.is_some()).count()
+    }
+
+    /// 第 `index` 页的物理帧, 还没有时分配并填充
+    ///
+    /// 文件在锁外读取; 同时有别的线程填充了这一页时使用它的帧, 释放自己读出的。
+    pub fn frame(&self, index : usize) -> Option<PhysFrame> {
+        if let Some(frame) = *self.pages.lock().get(index)? {
+            return Some(frame);
+        }
+        let frame = allocate_zeroed_frame()?;
+        // 读出的部分之外(文件尾之后或读取出错)保持为 0
+        if let Some((_, handle)) = &self.file {
+            let data = vfs::read_at(handle, index * PAGE_SIZE as usize, PAGE_SIZE as usize).unwrap_or_default();
+            let target = phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+            unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), target, data.len()) };
+        }
+        let existing = {
+            let mut pages = self.pages.lock();
+            *pages[index].get_or_insert(frame)
+        };
+        if existing != frame {
+            unsafe { deallocate_frame(frame) };
+        }
+        Some(existing)
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        for frame in self.pages.lock().iter().flatten() {
+            unsafe { deallocate_frame(*frame) };
+        }
+        if let Some((key, _)) = &self.file {
+            let mut cache = PAGE_CACHE.lock();
+            if cache.get(key).map_or(false, |object| object.strong_count() == 0) {
+                cache.remove(key);
+            }
+        }
+    }
+}
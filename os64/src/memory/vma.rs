@@ -0,0 +1,833 @@
+//see also: https://wiki.osdev.org/Paging#Page_Faults
+// 进程的虚拟内存区域(VMA)和地址空间。
+// 每个进程有自己的 4 级页表：内核已使用的 4 级表项原样复制(共享下级页表)，
+// 用户空间独占第 USER_L4_INDEX 个 4 级表项，共 512GB。
+// 建立 VMA 时并不分配内存，第一次访问时由缺页处理分配清零的页，来自映像的页再复制文件内容；
+// 栈 VMA 在其下方发生缺页时向下增长。
+// 缺页所在的 2MiB 区域完整地落在一个(非栈) VMA 中时，优先分配连续的 512 帧用一个大页映射。
+// 堆 VMA 紧跟在映像之后，由 brk 调整大小；mmap 的匿名内存从 MMAP_START 开始向上查找空闲区间。
+// 共享内存的页属于共享内存对象，页表项用 SHARED_PAGE 标记，解除映射时不释放。
+// fork 时私有的页由父子进程共享、去掉可写位并用 COW_PAGE 标记，写入时在缺页处理中复制；
+// 帧的引用数由 memory::share_frame / release_frame 维护。
+use core::fmt;
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use bitflags::bitflags;
+use x86_64::{
+    PhysAddr, VirtAddr,
+    instructions::tlb,
+    structures::{idt::PageFaultErrorCode, paging::{
+        FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size2MiB, Size4KiB, Translate,
+        mapper::{MapToError, TranslateResult}, page_table::{FrameError, PageTableEntry},
+    }},
+};
+use super::{
+    shared::SharedMemory,
+    GlobalFrameAllocator, HUGE_PAGE_FRAMES, HUGE_PAGE_SIZE, allocate_contiguous, allocate_zeroed_frame,
+    deallocate_contiguous, deallocate_frame, frame_references, kernel_page_table, phys_to_virt,
+    physical_memory_offset, release_frame, share_frame,
+};
+
+/// 用户空间使用的 4 级表项
+pub const USER_L4_INDEX : usize = 128;
+/// 用户空间的起始地址, 即第 USER_L4_INDEX 个 4 级表项所管理的区域
+pub const USER_SPACE_START : u64 = (USER_L4_INDEX as u64) << 39;
+/// 用户空间的结束地址(不含)
+pub const USER_SPACE_END : u64 = (USER_L4_INDEX as u64 + 1) << 39;
+
+pub const PAGE_SIZE : u64 = 4096;
+/// 栈 VMA 最多可以增长到的大小
+pub const MAX_STACK_SIZE : u64 = 8 * 1024 * 1024;
+/// mmap 区域的起始地址, 即用户空间的中间
+pub const MMAP_START : u64 = USER_SPACE_START + (1 << 38);
+/// mmap 区域的结束地址, 其上为栈可以增长到的区域及保护页
+pub const MMAP_END : u64 = USER_SPACE_END - MAX_STACK_SIZE - PAGE_SIZE;
+/// 页表项的可用位: 页属于共享内存对象, 不由地址空间释放
+pub const SHARED_PAGE : PageTableFlags = PageTableFlags::BIT_9;
+/// 页表项的可用位: 写时复制的页, 可写位已被去掉
+pub const COW_PAGE : PageTableFlags = PageTableFlags::BIT_10;
+
+bitflags! {
+    /// VMA 的访问权限
+    pub struct VmaFlags: u32 {
+        const READ          = 1 << 0;
+        const WRITE         = 1 << 1;
+        const EXECUTE       = 1 << 2;
+        /// 栈: 在下方缺页时向下增长
+        const GROWS_DOWN    = 1 << 3;
+    }
+}
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum VmaKind {
+    Code,
+    Data,
+    Heap,
+    Stack,
+    /// mmap 映射的区域
+    Mapped,
+}
+
+impl fmt::Display for VmaKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmaKind::Code => write!(f, "code"),
+            VmaKind::Data => write!(f, "data"),
+            VmaKind::Heap => write!(f, "heap"),
+            VmaKind::Stack => write!(f, "stack"),
+            VmaKind::Mapped => write!(f, "mmap"),
+        }
+    }
+}
+
+/// VMA 中的页第一次被访问时的内容来源
+#[derive(Clone)]
+pub enum VmaBacking {
+    /// 清零的页
+    Anonymous,
+    /// 从映像中复制: 从虚拟地址 `address` 开始的 `size` 字节来自 `data[offset..]`, 其余部分为 0
+    Image {
+        data : Arc<Vec<u8>>,
+        offset : usize,
+        address : u64,
+        size : usize,
+    },
+    /// 共享内存对象: 虚拟地址 `address` 对应对象的开头
+    Shared {
+        object : Arc<SharedMemory>,
+        address : u64,
+    },
+}
+
+/// 一个虚拟内存区域, 起止地址按页对齐
+#[derive(Clone)]
+pub struct Vma {
+    pub start : VirtAddr,
+    pub end : VirtAddr,
+    pub kind : VmaKind,
+    pub flags : VmaFlags,
+    pub backing : VmaBacking,
+}
+
+impl Vma {
+    /// 创建覆盖 `[start, end)` 的 VMA, 起止地址分别向下、向上对齐到页
+    pub fn new(start : u64, end : u64, kind : VmaKind, flags : VmaFlags, backing : VmaBacking) -> Vma {
+        Vma {
+            start : VirtAddr::new(start).align_down(PAGE_SIZE),
+            end : VirtAddr::new(end).align_up(PAGE_SIZE),
+            kind,
+            flags,
+            backing,
+        }
+    }
+
+    pub fn contains(&self, address : VirtAddr) -> bool {
+        self.start <= address && address < self.end
+    }
+
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    /// 映射该区域的页时使用的页表标志
+    fn page_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if self.flags.contains(VmaFlags::WRITE) {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !self.flags.contains(VmaFlags::EXECUTE) {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+/// 缺页的访问方式
+#[derive(Clone,Copy,Debug)]
+pub struct FaultAccess {
+    pub write : bool,
+    pub execute : bool,
+    /// 页已经存在, 即权限错误
+    pub present : bool,
+    pub user : bool,
+}
+
+impl From<PageFaultErrorCode> for FaultAccess {
+    fn from(error_code : PageFaultErrorCode) -> FaultAccess {
+        FaultAccess {
+            write : error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE),
+            execute : error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH),
+            present : error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION),
+            user : error_code.contains(PageFaultErrorCode::USER_MODE),
+        }
+    }
+}
+
+/// 无法处理的缺页
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum FaultError {
+    /// 地址不在用户空间
+    NotUserSpace,
+    /// 地址不属于任何 VMA
+    NoMapping,
+    /// 访问方式不被 VMA 允许
+    AccessViolation,
+    OutOfMemory,
+}
+
+impl fmt::Display for FaultError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FaultError::NotUserSpace => write!(f, "address outside user space"),
+            FaultError::NoMapping => write!(f, "address not mapped"),
+            FaultError::AccessViolation => write!(f, "access violation"),
+            FaultError::OutOfMemory => write!(f, "out of memory"),
+        }
+    }
+}
+
+pub fn is_user_address(address : VirtAddr) -> bool {
+    (USER_SPACE_START..USER_SPACE_END).contains(&address.as_u64())
+}
+
+/// 进程的地址空间
+pub struct AddressSpace {
+    /// 4 级页表
+    page_table : PhysFrame,
+    /// 以起始地址为键的 VMA
+    vmas : BTreeMap<u64, Vma>,
+    /// 已分配的页数
+    resident_pages : usize,
+}
+
+impl AddressSpace {
+    /// 创建只包含内核映射的地址空间
+    pub fn new() -> Result<AddressSpace, &'static str> {
+        let kernel = unsafe { &*(phys_to_virt(kernel_page_table().start_address()).as_ptr::<PageTable>()) };
+        if !kernel[USER_L4_INDEX].is_unused() {
+            return Err("user space overlaps kernel mappings");
+        }
+        let page_table = allocate_zeroed_frame().ok_or("out of memory")?;
+        let table = unsafe { &mut *(phys_to_virt(page_table.start_address()).as_mut_ptr::<PageTable>()) };
+        // 之后内核在已有 4 级表项下新增的映射会自动共享，在新的 4 级表项中的则不会
+        for (index, entry) in kernel.iter().enumerate() {
+            if index != USER_L4_INDEX {
+                table[index] = entry.clone();
+            }
+        }
+        Ok(AddressSpace {
+            page_table,
+            vmas : BTreeMap::new(),
+            resident_pages : 0,
+        })
+    }
+
+    /// 4 级页表的物理帧, 切换到该地址空间时写入 CR3
+    pub fn page_table(&self) -> PhysFrame {
+        self.page_table
+    }
+
+    fn mapper(&mut self) -> OffsetPageTable<'_> {
+        let table = unsafe { &mut *(phys_to_virt(self.page_table.start_address()).as_mut_ptr::<PageTable>()) };
+        unsafe { OffsetPageTable::new(table, physical_memory_offset()) }
+    }
+
+    /// 加入一个 VMA, 它必须位于用户空间且不与已有的 VMA 重叠
+    pub fn add_vma(&mut self, vma : Vma) -> Result<(), &'static str> {
+        if vma.start.as_u64() < USER_SPACE_START || vma.end.as_u64() > USER_SPACE_END || vma.start > vma.end {
+            return Err("VMA outside user space");
+        }
+        let overlaps = self.vmas.contains_key(&vma.start.as_u64())
+            || self.vmas.values().any(|v| v.start < vma.end && vma.start < v.end);
+        if overlaps {
+            return Err("VMA overlaps an existing one");
+        }
+        self.vmas.insert(vma.start.as_u64(), vma);
+        Ok(())
+    }
+
+    /// 包含 `address` 的 VMA
+    pub fn find_vma(&self, address : VirtAddr) -> Option<&Vma> {
+        self.vmas.range(..=address.as_u64()).next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| vma.contains(address))
+    }
+
+    pub fn vmas(&self) -> impl Iterator<Item = &Vma> {
+        self.vmas.values()
+    }
+
+    pub fn resident_pages(&self) -> usize {
+        self.resident_pages
+    }
+
+    /// 地址 `address` 是否已映射
+    pub fn is_mapped(&mut self, address : VirtAddr) -> bool {
+        self.mapper().translate_addr(address).is_some()
+    }
+
+    /// 把 `data` 写到该地址空间的 `address` 处, 页不存在或写时复制时先按缺页处理
+    ///
+    /// 通过物理内存窗口写入，地址空间不必是当前使用的，如加载程序时在新进程的栈上放入参数。
+    pub fn write_bytes(&mut self, address : VirtAddr, data : &[u8]) -> Result<(), FaultError> {
+        let mut written = 0;
+        while written < data.len() {
+            let current = address + written as u64;
+            let physical = match self.mapper().translate(current) {
+                TranslateResult::Mapped { frame, offset, flags } if flags.contains(PageTableFlags::WRITABLE) => {
+                    frame.start_address() + offset
+                },
+                result => {
+                    let present = matches!(result, TranslateResult::Mapped { .. });
+                    self.handle_page_fault(current, FaultAccess { write : true, execute : false, present, user : true })?;
+                    continue;
+                },
+            };
+            let length = ((PAGE_SIZE - current.as_u64() % PAGE_SIZE) as usize).min(data.len() - written);
+            unsafe {
+                core::ptr::copy_nonoverlapping(data[written..].as_ptr(), phys_to_virt(physical).as_mut_ptr::<u8>(), length);
+            }
+            written += length;
+        }
+        Ok(())
+    }
+
+    /// `[start, start + size)` 是否完全位于允许 `flags` 访问的 VMA 中
+    pub fn check_range(&self, start : VirtAddr, size : u64, flags : VmaFlags) -> bool {
+        let end = match start.as_u64().checked_add(size) {
+            Some(end) if end <= USER_SPACE_END => end,
+            _ => return false,
+        };
+        let mut address = start.as_u64();
+        while address < end {
+            match self.find_vma(VirtAddr::new(address)) {
+                Some(vma) if vma.flags.contains(flags) => address = vma.end.as_u64(),
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    /// 当前的程序断点, 即堆 VMA 的结束地址
+    pub fn program_break(&self) -> VirtAddr {
+        self.vmas.values().find(|vma| vma.kind == VmaKind::Heap)
+            .map(|vma| vma.end)
+            .unwrap_or(VirtAddr::new(USER_SPACE_START))
+    }
+
+    /// 把程序断点移动到 `address`(向上对齐到页), 缩小时释放多出的页
+    pub fn set_program_break(&mut self, address : VirtAddr) -> Result<VirtAddr, &'static str> {
+        let heap = self.vmas.values().find(|vma| vma.kind == VmaKind::Heap).cloned().ok_or("no heap")?;
+        if address < heap.start {
+            return Err("break below the heap");
+        }
+        let new_end = address.align_up(PAGE_SIZE);
+        if new_end > heap.end {
+            let next = self.vmas.range(heap.start.as_u64() + 1..).next().map(|(_, vma)| vma.start.as_u64());
+            if new_end.as_u64() > next.unwrap_or(MMAP_END).min(MMAP_END) {
+                return Err("heap overlaps another mapping");
+            }
+        } else if new_end < heap.end {
+            self.unmap_pages(new_end, heap.end)?;
+        }
+        if let Some(vma) = self.vmas.get_mut(&heap.start.as_u64()) {
+            vma.end = new_end;
+        }
+        Ok(new_end)
+    }
+
+    /// 在 mmap 区域中建立 `size` 字节的匿名映射, 返回起始地址
+    ///
+    /// 不小于 2MiB 的映射按 2MiB 对齐，以便使用大页。
+    pub fn map_anonymous(&mut self, size : u64, flags : VmaFlags) -> Result<VirtAddr, &'static str> {
+        if size == 0 {
+            return Err("empty mapping");
+        }
+        let size = size.checked_add(PAGE_SIZE - 1).ok_or("mapping too large")? & !(PAGE_SIZE - 1);
+        let align = if size >= HUGE_PAGE_SIZE { HUGE_PAGE_SIZE } else { PAGE_SIZE };
+        let start = self.find_free_range(size, align).ok_or("out of address space")?;
+        self.add_vma(Vma::new(start, start + size, VmaKind::Mapped, flags, VmaBacking::Anonymous))?;
+        Ok(VirtAddr::new(start))
+    }
+
+    /// 把共享内存对象整个映射到 mmap 区域中, 返回起始地址
+    pub fn map_shared(&mut self, object : Arc<SharedMemory>, flags : VmaFlags) -> Result<VirtAddr, &'static str> {
+        let size = (object.size() + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let start = self.find_free_range(size, PAGE_SIZE).ok_or("out of address space")?;
+        let backing = VmaBacking::Shared { object, address : start };
+        self.add_vma(Vma::new(start, start + size, VmaKind::Mapped, flags, backing))?;
+        Ok(VirtAddr::new(start))
+    }
+
+    /// 在 mmap 区域中查找 `size` 字节的空闲区间
+    fn find_free_range(&self, size : u64, align : u64) -> Option<u64> {
+        let mut start = MMAP_START;
+        for vma in self.vmas.values() {
+            if vma.end.as_u64() <= start {
+                continue;
+            }
+            if vma.start.as_u64() >= start + size {
+                break;
+            }
+            start = (vma.end.as_u64() + align - 1) & !(align - 1);
+        }
+        if start + size <= MMAP_END { Some(start) } else { None }
+    }
+
+    /// 解除 `[start, start + size)` 中 mmap 的映射, 可以只解除一个 VMA 的一部分
+    pub fn unmap(&mut self, start : VirtAddr, size : u64) -> Result<(), &'static str> {
+        if !start.is_aligned(PAGE_SIZE) || size == 0 {
+            return Err("unaligned range");
+        }
+        let end = start.as_u64().checked_add(size).ok_or("range out of user space")?;
+        let end = VirtAddr::new((end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1));
+        let affected : Vec<Vma> = self.vmas.values()
+            .filter(|vma| vma.start < end && start < vma.end)
+            .cloned()
+            .collect();
+        if affected.iter().any(|vma| vma.kind != VmaKind::Mapped) {
+            return Err("not an mmap region");
+        }
+        for vma in affected {
+            self.vmas.remove(&vma.start.as_u64());
+            if vma.start < start {
+                self.vmas.insert(vma.start.as_u64(), Vma { end : start, ..vma.clone() });
+            }
+            if end < vma.end {
+                self.vmas.insert(end.as_u64(), Vma { start : end, ..vma.clone() });
+            }
+            self.unmap_pages(vma.start.max(start), vma.end.min(end))?;
+        }
+        Ok(())
+    }
+
+    /// 把 `[start, start + size)` 的访问权限改为 `flags`, 范围必须完全被 VMA 覆盖, 部分覆盖的 VMA 被拆分
+    ///
+    /// 只读的共享映射不能改为可写, 否则可以绕过共享内存和文件映射的权限。
+    pub fn protect(&mut self, start : VirtAddr, size : u64, flags : VmaFlags) -> Result<(), &'static str> {
+        if !start.is_aligned(PAGE_SIZE) || size == 0 {
+            return Err("unaligned range");
+        }
+        let end = start.as_u64().checked_add(size).filter(|end| *end <= USER_SPACE_END).ok_or("range out of user space")?;
+        let end = VirtAddr::new((end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1));
+        if !self.check_range(start, end - start, VmaFlags::empty()) {
+            return Err("range not mapped");
+        }
+        let flags = flags & (VmaFlags::READ | VmaFlags::WRITE | VmaFlags::EXECUTE);
+        let affected : Vec<Vma> = self.vmas.values()
+            .filter(|vma| vma.start < end && start < vma.end)
+            .cloned()
+            .collect();
+        let upgrades_shared = |vma : &Vma| matches!(vma.backing, VmaBacking::Shared { .. })
+            && flags.contains(VmaFlags::WRITE) && !vma.flags.contains(VmaFlags::WRITE);
+        if affected.iter().any(upgrades_shared) {
+            return Err("read-only shared mapping");
+        }
+        for vma in affected {
+            self.vmas.remove(&vma.start.as_u64());
+            if vma.start < start {
+                self.vmas.insert(vma.start.as_u64(), Vma { end : start, ..vma.clone() });
+            }
+            if end < vma.end {
+                self.vmas.insert(end.as_u64(), Vma { start : end, ..vma.clone() });
+            }
+            let middle = Vma {
+                start : vma.start.max(start),
+                end : vma.end.min(end),
+                flags : (vma.flags & VmaFlags::GROWS_DOWN) | flags,
+                ..vma
+            };
+            self.protect_pages(&middle)?;
+            self.vmas.insert(middle.start.as_u64(), middle);
+        }
+        Ok(())
+    }
+
+    /// 按 `vma` 的权限修改其中已映射的页, 私有的帧还被其它地址空间引用时改为写时复制
+    fn protect_pages(&mut self, vma : &Vma) -> Result<(), &'static str> {
+        let kept = SHARED_PAGE | COW_PAGE | PageTableFlags::ACCESSED | PageTableFlags::DIRTY;
+        let mut address = vma.start;
+        while address < vma.end {
+            let entry = match self.level2_table(address) {
+                Some(table) => table[address.p2_index()].clone(),
+                None => {
+                    address = (address + 1u64).align_up(HUGE_PAGE_SIZE * 512);
+                    continue;
+                },
+            };
+            if entry.is_unused() {
+                address = (address + 1u64).align_up(HUGE_PAGE_SIZE);
+                continue;
+            }
+            if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                if address.is_aligned(HUGE_PAGE_SIZE) && address + HUGE_PAGE_SIZE <= vma.end {
+                    if let Some(table) = self.level2_table(address) {
+                        table[address.p2_index()].set_flags(vma.page_flags() | PageTableFlags::HUGE_PAGE | (entry.flags() & kept));
+                    }
+                    tlb::flush(address);
+                    address += HUGE_PAGE_SIZE;
+                    continue;
+                }
+                self.split_huge_page(address)?;
+            }
+            if let Some(entry) = self.level1_entry(address) {
+                if let Ok(frame) = entry.frame() {
+                    let old = entry.flags();
+                    let mut flags = vma.page_flags() | (old & kept);
+                    let private = !old.contains(SHARED_PAGE);
+                    if flags.contains(PageTableFlags::WRITABLE) && private && (old.contains(COW_PAGE) || frame_references(frame) > 1) {
+                        flags = (flags - PageTableFlags::WRITABLE) | COW_PAGE;
+                    }
+                    entry.set_flags(flags);
+                    tlb::flush(address);
+                }
+            }
+            address += PAGE_SIZE;
+        }
+        Ok(())
+    }
+
+    /// 为 fork 复制地址空间: VMA 原样复制，私有的页由父子共享并改为写时复制，共享内存的页仍然共享
+    ///
+    /// 大页先拆分成 4K 页, 这样写入时只需复制一页。调用者必须正在使用这个地址空间，
+    /// 返回前会刷新整个 TLB, 使父进程的页也变为只读。
+    pub fn fork(&mut self) -> Result<AddressSpace, &'static str> {
+        let mut child = AddressSpace::new()?;
+        child.vmas = self.vmas.clone();
+        let ranges : Vec<(VirtAddr, VirtAddr)> = self.vmas.values().map(|vma| (vma.start, vma.end)).collect();
+        let result = ranges.into_iter().try_for_each(|(start, end)| self.fork_range(&mut child, start, end));
+        tlb::flush_all();
+        result.map(|_| child)
+    }
+
+    /// 把 `[start, end)` 中已映射的页复制到 `child` 中
+    fn fork_range(&mut self, child : &mut AddressSpace, start : VirtAddr, end : VirtAddr) -> Result<(), &'static str> {
+        let parent_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        let mut address = start;
+        while address < end {
+            let entry = match self.level2_table(address) {
+                Some(table) => table[address.p2_index()].clone(),
+                None => {
+                    address = (address + 1u64).align_up(HUGE_PAGE_SIZE * 512);
+                    continue;
+                },
+            };
+            if entry.is_unused() {
+                address = (address + 1u64).align_up(HUGE_PAGE_SIZE);
+                continue;
+            }
+            if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                self.split_huge_page(address)?;
+            }
+            if let Some(entry) = self.level1_entry(address) {
+                if let Ok(frame) = entry.frame() {
+                    let mut flags = entry.flags();
+                    if !flags.contains(SHARED_PAGE) && flags.intersects(PageTableFlags::WRITABLE | COW_PAGE) {
+                        flags = (flags - PageTableFlags::WRITABLE) | COW_PAGE;
+                    }
+                    let page = Page::<Size4KiB>::containing_address(address);
+                    unsafe {
+                        child.mapper().map_to_with_table_flags(page, frame, flags, parent_flags, &mut GlobalFrameAllocator)
+                    }.map_err(|_| "out of memory")?.ignore();
+                    child.resident_pages += 1;
+                    if !flags.contains(SHARED_PAGE) {
+                        share_frame(frame);
+                    }
+                    entry.set_flags(flags);
+                }
+            }
+            address += PAGE_SIZE;
+        }
+        Ok(())
+    }
+
+    /// `address` 所在的 2 级页表, 还没有时返回 None
+    fn level2_table(&self, address : VirtAddr) -> Option<&mut PageTable> {
+        let mut frame = self.page_table;
+        for index in [address.p4_index(), address.p3_index()] {
+            let table = unsafe { &*(phys_to_virt(frame.start_address()).as_ptr::<PageTable>()) };
+            frame = table[index].frame().ok()?;
+        }
+        Some(unsafe { &mut *(phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>()) })
+    }
+
+    /// `address` 的 1 级页表项, 没有 1 级页表(未映射或大页)时返回 None
+    fn level1_entry(&self, address : VirtAddr) -> Option<&mut PageTableEntry> {
+        let frame = self.level2_table(address)?[address.p2_index()].frame().ok()?;
+        let table = unsafe { &mut *(phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>()) };
+        Some(&mut table[address.p1_index()])
+    }
+
+    /// 释放 `[start, end)` 中已映射的页, 只覆盖了一部分的大页先拆分成 4K 页
+    fn unmap_pages(&mut self, start : VirtAddr, end : VirtAddr) -> Result<(), &'static str> {
+        let mut address = start;
+        while address < end {
+            let entry = match self.level2_table(address) {
+                Some(table) => table[address.p2_index()].clone(),
+                // 整个 1GiB 都没有映射
+                None => {
+                    address = (address + 1u64).align_up(HUGE_PAGE_SIZE * 512);
+                    continue;
+                },
+            };
+            if entry.is_unused() {
+                address = (address + 1u64).align_up(HUGE_PAGE_SIZE);
+                continue;
+            }
+            if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                if address.is_aligned(HUGE_PAGE_SIZE) && address + HUGE_PAGE_SIZE <= end {
+                    if let Some(table) = self.level2_table(address) {
+                        table[address.p2_index()].set_unused();
+                    }
+                    tlb::flush(address);
+                    unsafe { deallocate_contiguous(PhysFrame::containing_address(entry.addr()), HUGE_PAGE_FRAMES) };
+                    self.resident_pages -= HUGE_PAGE_FRAMES;
+                    address += HUGE_PAGE_SIZE;
+                    continue;
+                }
+                self.split_huge_page(address)?;
+            }
+            let page = Page::<Size4KiB>::containing_address(address);
+            let shared = matches!(self.mapper().translate(address), TranslateResult::Mapped { flags, .. } if flags.contains(SHARED_PAGE));
+            if let Ok((frame, flush)) = self.mapper().unmap(page) {
+                flush.flush();
+                if !shared {
+                    unsafe { release_frame(frame) };
+                }
+                self.resident_pages -= 1;
+            }
+            address += PAGE_SIZE;
+        }
+        Ok(())
+    }
+
+    /// 把 `address` 所在的 2MiB 大页拆分成指向同样物理内存的 512 个 4K 页
+    fn split_huge_page(&mut self, address : VirtAddr) -> Result<(), &'static str> {
+        let table = self.level2_table(address).ok_or("not mapped")?;
+        let entry = &mut table[address.p2_index()];
+        if !entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return Ok(());
+        }
+        let frame = allocate_zeroed_frame().ok_or("out of memory")?;
+        let level1 = unsafe { &mut *(phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>()) };
+        let flags = entry.flags() - PageTableFlags::HUGE_PAGE;
+        for (i, page) in level1.iter_mut().enumerate() {
+            page.set_addr(entry.addr() + i as u64 * PAGE_SIZE, flags);
+        }
+        entry.set_addr(frame.start_address(),
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE);
+        tlb::flush(address.align_down(HUGE_PAGE_SIZE));
+        Ok(())
+    }
+
+    /// 处理用户空间中的缺页: 必要时增长栈，然后分配并映射该页
+    pub fn handle_page_fault(&mut self, address : VirtAddr, access : FaultAccess) -> Result<(), FaultError> {
+        if !is_user_address(address) {
+            return Err(FaultError::NotUserSpace);
+        }
+        let key = match self.find_vma(address) {
+            Some(vma) => vma.start.as_u64(),
+            None => self.grow_stack(address).ok_or(FaultError::NoMapping)?,
+        };
+        let vma = self.vmas[&key].clone();
+
+        if access.write && !vma.flags.contains(VmaFlags::WRITE) {
+            return Err(FaultError::AccessViolation);
+        }
+        if access.execute && !vma.flags.contains(VmaFlags::EXECUTE) {
+            return Err(FaultError::AccessViolation);
+        }
+        let page = Page::<Size4KiB>::containing_address(address);
+        // 页存在却仍然缺页，说明页表的权限比 VMA 更严格, 只有写时复制的页可以处理
+        if access.present {
+            return match access.write {
+                true => self.copy_on_write(page),
+                false => Err(FaultError::AccessViolation),
+            };
+        }
+        self.populate(page, &vma)
+    }
+
+    /// `address` 所在的共享内存(含文件映射)页: 对象和页在其中的序号
+    pub fn shared_page(&self, address : VirtAddr) -> Option<(Arc<SharedMemory>, usize)> {
+        match &self.find_vma(address)?.backing {
+            VmaBacking::Shared { object, address : start } =>
+                Some((object.clone(), ((address.align_down(PAGE_SIZE).as_u64() - start) / PAGE_SIZE) as usize)),
+            _ => None,
+        }
+    }
+
+    /// 写入写时复制的页: 帧还被其它地址空间引用时复制一份，否则直接恢复可写
+    fn copy_on_write(&mut self, page : Page<Size4KiB>) -> Result<(), FaultError> {
+        let entry = self.level1_entry(page.start_address()).ok_or(FaultError::AccessViolation)?;
+        let frame = entry.frame().map_err(|_| FaultError::AccessViolation)?;
+        if !entry.flags().contains(COW_PAGE) {
+            return Err(FaultError::AccessViolation);
+        }
+        let flags = (entry.flags() | PageTableFlags::WRITABLE) - COW_PAGE;
+        if frame_references(frame) > 1 {
+            let copy = GlobalFrameAllocator.allocate_frame().ok_or(FaultError::OutOfMemory)?;
+            unsafe {
+                core::ptr::copy_nonoverlapping(phys_to_virt(frame.start_address()).as_ptr::<u8>(),
+                    phys_to_virt(copy.start_address()).as_mut_ptr::<u8>(), PAGE_SIZE as usize);
+            }
+            entry.set_addr(copy.start_address(), flags);
+            unsafe { release_frame(frame) };
+        } else {
+            entry.set_flags(flags);
+        }
+        tlb::flush(page.start_address());
+        Ok(())
+    }
+
+    /// 把栈 VMA 向下扩展到包含 `address`, 返回扩展后的键
+    ///
+    /// 栈的大小不超过 MAX_STACK_SIZE, 且与下方的 VMA 之间至少保留一个保护页。
+    fn grow_stack(&mut self, address : VirtAddr) -> Option<u64> {
+        let new_start = address.align_down(PAGE_SIZE);
+        let (key, stack) = self.vmas.range(address.as_u64()..).next()
+            .filter(|(_, vma)| vma.flags.contains(VmaFlags::GROWS_DOWN))
+            .map(|(key, vma)| (*key, vma.clone()))?;
+        if stack.end - new_start > MAX_STACK_SIZE {
+            return None;
+        }
+        let below = self.vmas.range(..key).next_back().map(|(_, vma)| vma.end);
+        if let Some(end) = below {
+            if end + PAGE_SIZE > new_start {
+                return None;
+            }
+        }
+        self.vmas.remove(&key);
+        self.vmas.insert(new_start.as_u64(), Vma { start : new_start, ..stack });
+        Some(new_start.as_u64())
+    }
+
+    /// 分配一页, 按 VMA 的来源填充内容并映射
+    fn populate(&mut self, page : Page<Size4KiB>, vma : &Vma) -> Result<(), FaultError> {
+        if let VmaBacking::Shared { object, address } = &vma.backing {
+            let index = ((page.start_address().as_u64() - address) / PAGE_SIZE) as usize;
+            let frame = object.frame(index).ok_or(FaultError::OutOfMemory)?;
+            return self.map_page(page, frame, vma.page_flags() | SHARED_PAGE);
+        }
+        if self.populate_huge(page.start_address(), vma) {
+            return Ok(());
+        }
+        let frame = allocate_zeroed_frame().ok_or(FaultError::OutOfMemory)?;
+        fill(frame.start_address(), page.start_address(), PAGE_SIZE, vma);
+        self.map_page(page, frame, vma.page_flags())
+    }
+
+    /// 映射一页, 失败时释放不属于共享内存的帧
+    fn map_page(&mut self, page : Page<Size4KiB>, frame : PhysFrame, flags : PageTableFlags) -> Result<(), FaultError> {
+        let release = |frame| if !flags.contains(SHARED_PAGE) { unsafe { deallocate_frame(frame) } };
+        let parent_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        let result = unsafe {
+            self.mapper().map_to_with_table_flags(page, frame, flags, parent_flags, &mut GlobalFrameAllocator)
+        };
+        match result {
+            Ok(flush) => flush.flush(),
+            // 另一个线程已经映射了这一页
+            Err(MapToError::PageAlreadyMapped(_)) | Err(MapToError::ParentEntryHugePage) => {
+                release(frame);
+                return Ok(());
+            },
+            Err(_) => {
+                release(frame);
+                return Err(FaultError::OutOfMemory);
+            },
+        }
+        self.resident_pages += 1;
+        Ok(())
+    }
+
+    /// `address` 对应的 2 级表项是否未使用(既没有大页也没有 1 级页表)
+    fn l2_entry_unused(&self, address : VirtAddr) -> bool {
+        self.level2_table(address).map_or(true, |table| table[address.p2_index()].is_unused())
+    }
+
+    /// 尝试用一个 2MiB 大页映射 `address` 所在的区域, 失败时返回 false 由调用者逐页映射
+    ///
+    /// 要求整个 2MiB 区域都在 VMA 中、区域中还没有 4K 的映射，且有连续的物理内存。
+    fn populate_huge(&mut self, address : VirtAddr, vma : &Vma) -> bool {
+        let start = address.align_down(HUGE_PAGE_SIZE);
+        if vma.flags.contains(VmaFlags::GROWS_DOWN) || matches!(vma.backing, VmaBacking::Shared { .. })
+            || start < vma.start || start + HUGE_PAGE_SIZE > vma.end {
+            return false;
+        }
+        if !self.l2_entry_unused(start) {
+            return false;
+        }
+        let first = match allocate_contiguous(HUGE_PAGE_FRAMES, HUGE_PAGE_FRAMES) {
+            Some(frame) => frame,
+            None => return false,
+        };
+        unsafe { core::ptr::write_bytes(phys_to_virt(first.start_address()).as_mut_ptr::<u8>(), 0, HUGE_PAGE_SIZE as usize) };
+        fill(first.start_address(), start, HUGE_PAGE_SIZE, vma);
+
+        let page = Page::<Size2MiB>::containing_address(start);
+        let frame = PhysFrame::<Size2MiB>::containing_address(first.start_address());
+        let parent_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        let result = unsafe {
+            self.mapper().map_to_with_table_flags(page, frame, vma.page_flags(), parent_flags, &mut GlobalFrameAllocator)
+        };
+        match result {
+            Ok(flush) => {
+                flush.flush();
+                self.resident_pages += HUGE_PAGE_FRAMES;
+                true
+            },
+            Err(_) => {
+                unsafe { deallocate_contiguous(first, HUGE_PAGE_FRAMES) };
+                false
+            },
+        }
+    }
+}
+
+/// 把 VMA 来源中落在 [`page_start`, `page_start + length`) 的内容复制到以 `target` 开始的已清零的物理内存
+fn fill(target : PhysAddr, page_start : VirtAddr, length : u64, vma : &Vma) {
+    if let VmaBacking::Image { data, offset, address, size } = &vma.backing {
+        let page_start = page_start.as_u64();
+        let copy_start = page_start.max(*address);
+        let copy_end = (page_start + length).min(*address + *size as u64);
+        if copy_start < copy_end {
+            let source = offset + (copy_start - address) as usize;
+            let length = (copy_end - copy_start) as usize;
+            let target = phys_to_virt(target) + (copy_start - page_start);
+            unsafe {
+                core::ptr::copy_nonoverlapping(data[source..source + length].as_ptr(), target.as_mut_ptr::<u8>(), length);
+            }
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    /// 释放用户空间的所有页、页表以及 4 级页表
+    ///
+    /// 调用者必须保证没有处理器还在使用这个地址空间。
+    fn drop(&mut self) {
+        let table = unsafe { &*(phys_to_virt(self.page_table.start_address()).as_ptr::<PageTable>()) };
+        if let Ok(frame) = table[USER_L4_INDEX].frame() {
+            unsafe { free_table(frame, 3) };
+        }
+        unsafe { deallocate_frame(self.page_table) };
+    }
+}
+
+/// 释放 `level` 级页表 `frame` 以及它下面所有的页和页表
+unsafe fn free_table(frame : PhysFrame, level : u8) {
+    let table = &*(phys_to_virt(frame.start_address()).as_ptr::<PageTable>());
+    for entry in table.iter() {
+        match entry.frame() {
+            Ok(child) if level > 1 => free_table(child, level - 1),
+            Ok(_) if entry.flags().contains(SHARED_PAGE) => {},
+            Ok(child) => release_frame(child),
+            // 大页由连续的帧组成
+            Err(FrameError::HugeFrame) => {
+                let count = HUGE_PAGE_FRAMES.pow(level as u32 - 1);
+                deallocate_contiguous(PhysFrame::containing_address(entry.addr()), count);
+            },
+            Err(FrameError::FrameNotPresent) => {},
+        }
+    }
+    deallocate_frame(frame);
+}
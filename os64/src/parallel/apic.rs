@@ -1,316 +1,609 @@
-
-
-// pub struct pt_regs {
-// 	r15 : u64,
-// 	r14 : u64,
-// 	r13 : u64,
-// 	r12 : u64,
-// 	r11 : u64,
-// 	r10 : u64,
-// 	r9 : u64,
-// 	r8 : u64,
-// 	rbx : u64,
-// 	rcx : u64,
-// 	rdx : u64,
-// 	rsi : u64,
-// 	rdi : u64,
-// 	rbp : u64,
-// 	ds : u64,
-// 	es : u64,
-// 	rax : u64,
-// 	func : u64,
-// 	errcode : u64,
-// 	rip : u64,
-// 	cs : u64,
-// 	rflags : u64,
-// 	rsp : u64,
-// 	ss : u64,
-// }
-
-// use bitfield::bitfield;
-
-// bitfield!{
-//     pub struct IO_APIC_RET_entry(MSB0 [u8]);
-//     u32;
-//     get_vector, set_vector: 7, 0;
-//     get_deliver_mode, set_deliver_mode: 10, 8;
-//     get_dest_mode, set_dest_mode: 11, 11;
-//     get_deliver_status, set_deliver_status : 12, 12;    
-//     get_polarity,set_polarity: 13, 13;
-//     get_irr,set_irr: 14, 14;
-//     get_trigger,set_trigger: 15, 15;
-//     get_mask,set_mask: 16, 16;
-//     get_reserved,set_reserved: 31, 17;
-//     get_reserved1,set_reserved1: 55, 32;
-//     get_dest,set_dest:63,56;
-// }
-
-// pub const APIC_ICR_IOAPIC_FIXED : u8 = 0;	//LAPIC	IOAPIC 	ICR
-// pub const IOAPIC_ICR_LOWEST_PRIORITY : u8 = 1;	//	IOAPIC 	ICR
-// pub const APIC_ICR_IOAPIC_SMI : u8 = 2;	//LAPIC	IOAPIC 	ICR
-
-// pub const APIC_ICR_IOAPIC_NMI : u8 = 4;	//LAPIC	IOAPIC 	ICR
-// pub const APIC_ICR_IOAPIC_INIT : u8 =  5;	//LAPIC	IOAPIC 	ICR
-// pub const ICR_START_UP : u8 = 6;	//		ICR
-// pub const IOAPIC_EXT_INT : u8 = 7;	//	IOAPIC
-
-// //timer mode
-// pub const APIC_LVT_TIMER_ONE_SHOT : u8 = 0;
-// pub const APIC_LVT_TIMER_PERIODIC : u8 = 1;
-// pub const APIC_LVT_TIMER_TSC_DEADLINE : u8 = 2;
-
-// //mask
-// pub const APIC_ICR_IOAPIC_MASKED : u8 = 1;
-// pub const APIC_ICR_IOAPIC_UN_MASKED : u8 = 0;
-
-// //trigger mode
-// pub const APIC_ICR_IOAPIC_EDGE : u8 = 0;
-// pub const APIC_ICR_IOAPIC_LEVEL : u8 = 1;
-
-// //delivery status
-// pub const APIC_ICR_IOAPIC_IDLE : u8 = 0;
-// pub const APIC_ICR_IOAPIC_SEND_PENDING : u8 = 1;
-
-// //destination shorthand
-// pub const ICR_NO_SHORTHAND : u8 = 0;
-// pub const ICR_SELF : u8 = 1;
-// pub const ICR_ALL_INCLUDE_SELF : u8 = 2;
-// pub const ICR_ALL_EXCLUDE_SELF : u8 = 3;
-
-// //destination mode
-// pub const ICR_IOAPIC_DELV_PHYSICAL : u8 = 0;
-// pub const ICR_IOAPIC_DELV_LOGIC : u8 = 1;
-
-// //level
-// pub const ICR_LEVEL_DE_ASSERT : u8 = 0;
-// pub const ICR_LEVLE_ASSERT : u8 = 1;
-
-// //remote irr
-// pub const APIC_IOAPIC_IRR_RESET : u8 = 0;
-// pub const APIC_IOAPIC_IRR_ACCEPT : u8 = 1;
-
-// //pin polarity
-// pub const APIC_IOAPIC_POLARITY_HIGH : u8 = 0;
-// pub const APIC_IOAPIC_POLARITY_LOW : u8 = 1;
-
-// /*
-
-// 0~255 IDT
-
-// 0   ~   31	trap fault abort for system
-// 	0	devide error
-// 	1	debug
-// 	2	NMI
-// 	3	breakpoint
-// 	4	overflow
-// 	5	bound range
-// 	6	undefined opcode
-// 	7	device	not available
-// 	8	double fault
-// 	9	coprocessor segment overrun
-// 	10	invalid TSS
-// 	11	segment not present
-// 	12	stack segment fault
-// 	13	general protection
-// 	14	page fault
-// 	15	
-// 	16	x87 FPU error
-// 	17	alignment check
-// 	18	machine check
-// 	19	SIMD exception
-// 	20	virtualization exception
-// 21  ~   31	Do not use
-
-// 32  ~   55	I/O APIC
-// 	32	8259A
-// 	33	keyboard
-// 	34	HPET timer 0,8254 counter 0
-// 	35	serial port A
-// 	36	serial port B
-// 	37	parallel port
-// 	38	floppy
-// 	39	parallel port
-// 	40	RTC,HPET timer 1
-// 	41	Generic
-// 	42	Generic
-// 	43	HPET timer 2
-// 	44	HPET timer 3
-// 	45	FERR#
-// 	46	SATA primary
-// 	47	SATA secondary
-// 	48	PIRQA
-// 	49	PIRQB
-// 	50	PIRQC
-// 	51	PIRQD
-// 	52	PIRQE
-// 	53	PIRQF
-// 	54	PIRQG
-// 	55	PIRQH
-	
-	
-// 0x80		system call
-
-// 150 ~   200	Local APIC
-// 	150	CMCI
-// 	151	Timer
-// 	152	Thermal Monitor
-// 	153	Performance Counter
-// 	154	LINT0
-// 	155	LINT1
-// 	156	Error
-
-// 200 ~   255	MP IPI
-
-// */
-
-// /*
-
-// 1:	LVT	CMCI
-// 2:	LVT	Timer
-// 3:	LVT	Thermal Monitor
-// 4:	LVT	Performace Counter
-// 5:	LVT	LINT0
-// 6:	LVT	LINT1
-// 7:	LVT	Error
-
-// */
-
-// /*
-// 	LVT
-// */
-
-// // struct APIC_LVT
-// // {
-// // 	unsigned int 	vector	:8,	//0~7	ALL
-// // 		deliver_mode	:3,	//8~10	      CMCI LINT0 LINT1 PerformCounter ThermalSensor
-// // 			res_1	:1,	//11
-// // 		deliver_status	:1,	//12	ALL
-// // 			polarity:1,	//13	           LINT0 LINT1
-// // 			irr	:1,	//14	           LINT0 LINT1
-// // 			trigger	:1,	//15	           LINT0 LINT1
-// // 			mask	:1,	//16	ALL
-// // 		timer_mode	:2,	//17~18	Timer
-// // 			res_2	:13;	//19~31
-// // }__attribute__((packed));		//disable align in struct
-
-// /*
-// 	ICR
-// */
-
-// // struct INT_CMD_REG
-// // {
-// // 	unsigned int 	vector	:8,	//0~7
-// // 		deliver_mode	:3,	//8~10
-// // 		dest_mode	:1,	//11
-// // 		deliver_status	:1,	//12
-// // 			res_1	:1,	//13
-// // 			level	:1,	//14
-// // 			trigger	:1,	//15
-// // 			res_2	:2,	//16~17
-// // 		dest_shorthand	:2,	//18~19
-// // 			res_3	:12;	//20~31
-	
-// // 	union {
-// // 		struct {
-// // 			unsigned int	res_4	:24,	//32~55
-// // 			dest_field	:8;	//56~63		
-// // 			}apic_destination;
-			
-// // 		unsigned int x2apic_destination;	//32~63
-// // 		}destination;
-		
-// // }__attribute__((packed));
-
-
-
-// // //delivery mode
-// // #define	APIC_ICR_IOAPIC_Fixed 		 0	//LAPIC	IOAPIC 	ICR
-// // #define	IOAPIC_ICR_Lowest_Priority 	 1	//	IOAPIC 	ICR
-// // #define	APIC_ICR_IOAPIC_SMI		 2	//LAPIC	IOAPIC 	ICR
-
-// // #define	APIC_ICR_IOAPIC_NMI		 4	//LAPIC	IOAPIC 	ICR
-// // #define	APIC_ICR_IOAPIC_INIT		 5	//LAPIC	IOAPIC 	ICR
-// // #define	ICR_Start_up			 6	//		ICR
-// // #define	IOAPIC_ExtINT			 7	//	IOAPIC
-
-
-// // //timer mode
-// // #define APIC_LVT_Timer_One_Shot		0
-// // #define APIC_LVT_Timer_Periodic		1
-// // #define APIC_LVT_Timer_TSC_Deadline	2
-
-// // //mask
-// // #define APIC_ICR_IOAPIC_Masked		1
-// // #define APIC_ICR_IOAPIC_UN_Masked	0
-
-// // //trigger mode
-// // #define APIC_ICR_IOAPIC_Edge		0
-// // #define APIC_ICR_IOAPIC_Level		1
-
-// // //delivery status
-// // #define APIC_ICR_IOAPIC_Idle		0
-// // #define APIC_ICR_IOAPIC_Send_Pending	1
-
-// // //destination shorthand
-// // #define ICR_No_Shorthand		0
-// // #define ICR_Self			1
-// // #define ICR_ALL_INCLUDE_Self		2
-// // #define ICR_ALL_EXCLUDE_Self		3
-
-// // //destination mode
-// // #define ICR_IOAPIC_DELV_PHYSICAL	0
-// // #define ICR_IOAPIC_DELV_LOGIC		1
-
-// // //level
-// // #define ICR_LEVEL_DE_ASSERT		0
-// // #define ICR_LEVLE_ASSERT		1
-
-// // //remote irr
-// // #define APIC_IOAPIC_IRR_RESET		0
-// // #define APIC_IOAPIC_IRR_ACCEPT		1
-
-// // //pin polarity
-// // #define APIC_IOAPIC_POLARITY_HIGH	0
-// // #define APIC_IOAPIC_POLARITY_LOW	1
-
-// /* 
-
-// */
-
-// // struct IOAPIC_map
-// // {
-// // 	unsigned int physical_address;
-// // 	unsigned char * virtual_index_address;
-// // 	unsigned int *  virtual_data_address;
-// // 	unsigned int *  virtual_EOI_address;
-// // }ioapic_map;
-
-// // unsigned long ioapic_rte_read(unsigned char index);
-// // void ioapic_rte_write(unsigned char index,unsigned long value);
-
-// /* 
-
-// */
-
-// // void IOAPIC_pagetable_remap();
-
-// // /*
-
-// // */
-
-// // void do_IRQ(struct pt_regs * regs,unsigned long nr);
-
-// // /*
-
-// // */
-
-// // void APIC_IOAPIC_init();
-// // void Local_APIC_init();
-// // void IOAPIC_init();
-
-// // void IOAPIC_enable(unsigned long irq);
-// // void IOAPIC_disable(unsigned long irq);
-// // unsigned long IOAPIC_install(unsigned long irq,void * arg);
-// // void IOAPIC_uninstall(unsigned long irq);
-// // void IOAPIC_level_ack(unsigned long irq);
-// // void IOAPIC_edge_ack(unsigned long irq);
+//see also: https://wiki.osdev.org/APIC
+//see also: https://wiki.osdev.org/IOAPIC
+//see also: https://wiki.osdev.org/APIC_timer
+// APIC: Advanced Programmable Interrupt Controller,高级可编程中断控制器。
+// 每个处理器有一个 Local APIC(LAPIC),负责本处理器的定时器、IPI 及中断应答(EOI)；
+// 外部设备的中断经 IO-APIC 的重定向表(Redirection Table)转发给指定处理器的 LAPIC。
+// 启用 APIC 之后，8259 PIC 被全部屏蔽，不再使用。
+
+/*
+
+0~255 IDT
+
+0   ~   31	trap fault abort for system
+	0	devide error
+	1	debug
+	2	NMI
+	3	breakpoint
+	4	overflow
+	5	bound range
+	6	undefined opcode
+	7	device	not available
+	8	double fault
+	9	coprocessor segment overrun
+	10	invalid TSS
+	11	segment not present
+	12	stack segment fault
+	13	general protection
+	14	page fault
+	15
+	16	x87 FPU error
+	17	alignment check
+	18	machine check
+	19	SIMD exception
+	20	virtualization exception
+21  ~   31	Do not use
+
+32  ~   55	I/O APIC
+	32	8259A
+	33	keyboard
+	34	HPET timer 0,8254 counter 0
+	35	serial port A
+	36	serial port B
+	37	parallel port
+	38	floppy
+	39	parallel port
+	40	RTC,HPET timer 1
+	41	Generic
+	42	Generic
+	43	HPET timer 2
+	44	HPET timer 3
+	45	FERR#
+	46	SATA primary
+	47	SATA secondary
+	48	PIRQA
+	49	PIRQB
+	50	PIRQC
+	51	PIRQD
+	52	PIRQE
+	53	PIRQF
+	54	PIRQG
+	55	PIRQH
+
+0x80		system call
+
+150 ~   200	Local APIC
+	150	CMCI
+	151	Timer
+	152	Thermal Monitor
+	153	Performance Counter
+	154	LINT0
+	155	LINT1
+	156	Error
+
+200 ~   255	MP IPI
+
+*/
+
+use core::{arch::x86_64::{__cpuid, _rdtsc}, sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering}};
+use alloc::vec::Vec;
+use bitfield::bitfield;
+use conquer_once::spin::OnceCell;
+use spin::Mutex;
+use x86_64::{
+    VirtAddr,
+    instructions::port::Port,
+    registers::model_specific::Msr,
+    structures::paging::{FrameAllocator, Mapper, Size2MiB, Size4KiB, Translate},
+};
+use crate::{
+    device::acpi::madt::{self, MadtInfo, Polarity, TriggerMode},
+    memory::map_physical_region,
+    parallel::{cpu, interrupts::InterruptIndex},
+    serial_println,
+};
+
+//delivery mode
+pub const APIC_ICR_IOAPIC_FIXED : u8 = 0;	//LAPIC	IOAPIC 	ICR
+pub const IOAPIC_ICR_LOWEST_PRIORITY : u8 = 1;	//	IOAPIC 	ICR
+pub const APIC_ICR_IOAPIC_SMI : u8 = 2;	//LAPIC	IOAPIC 	ICR
+pub const APIC_ICR_IOAPIC_NMI : u8 = 4;	//LAPIC	IOAPIC 	ICR
+pub const APIC_ICR_IOAPIC_INIT : u8 =  5;	//LAPIC	IOAPIC 	ICR
+pub const ICR_START_UP : u8 = 6;	//		ICR
+pub const IOAPIC_EXT_INT : u8 = 7;	//	IOAPIC
+
+//mask
+pub const APIC_ICR_IOAPIC_MASKED : u8 = 1;
+pub const APIC_ICR_IOAPIC_UN_MASKED : u8 = 0;
+
+//trigger mode
+pub const APIC_ICR_IOAPIC_EDGE : u8 = 0;
+pub const APIC_ICR_IOAPIC_LEVEL : u8 = 1;
+
+//delivery status
+pub const APIC_ICR_IOAPIC_IDLE : u8 = 0;
+pub const APIC_ICR_IOAPIC_SEND_PENDING : u8 = 1;
+
+//destination shorthand
+pub const ICR_NO_SHORTHAND : u8 = 0;
+pub const ICR_SELF : u8 = 1;
+pub const ICR_ALL_INCLUDE_SELF : u8 = 2;
+pub const ICR_ALL_EXCLUDE_SELF : u8 = 3;
+
+//destination mode
+pub const ICR_IOAPIC_DELV_PHYSICAL : u8 = 0;
+pub const ICR_IOAPIC_DELV_LOGIC : u8 = 1;
+
+//level
+pub const ICR_LEVEL_DE_ASSERT : u8 = 0;
+pub const ICR_LEVLE_ASSERT : u8 = 1;
+
+//pin polarity
+pub const APIC_IOAPIC_POLARITY_HIGH : u8 = 0;
+pub const APIC_IOAPIC_POLARITY_LOW : u8 = 1;
+
+/// IA32_APIC_BASE: LAPIC 物理地址及全局使能位
+const IA32_APIC_BASE_MSR        : u32 = 0x1B;
+const IA32_APIC_BASE_ENABLE     : u64 = 1 << 11;
+/// TSC-Deadline 模式下写入截止时间的 MSR
+const IA32_TSC_DEADLINE_MSR     : u32 = 0x6E0;
+
+// Local APIC 寄存器偏移
+const LAPIC_ID                  : u32 = 0x020;
+const LAPIC_VERSION             : u32 = 0x030;
+const LAPIC_TASK_PRIORITY       : u32 = 0x080;
+const LAPIC_EOI                 : u32 = 0x0B0;
+const LAPIC_SPURIOUS            : u32 = 0x0F0;
+const LAPIC_ERROR_STATUS        : u32 = 0x280;
+const LAPIC_ICR_LOW             : u32 = 0x300;
+const LAPIC_ICR_HIGH            : u32 = 0x310;
+const LAPIC_LVT_TIMER           : u32 = 0x320;
+const LAPIC_LVT_LINT0           : u32 = 0x350;
+const LAPIC_LVT_LINT1           : u32 = 0x360;
+const LAPIC_LVT_ERROR           : u32 = 0x370;
+const LAPIC_TIMER_INITIAL_COUNT : u32 = 0x380;
+const LAPIC_TIMER_CURRENT_COUNT : u32 = 0x390;
+const LAPIC_TIMER_DIVIDE        : u32 = 0x3E0;
+
+const LAPIC_SPURIOUS_ENABLE     : u32 = 1 << 8;
+const LVT_MASKED                : u32 = 1 << 16;
+const LVT_LEVEL_TRIGGERED       : u32 = 1 << 15;
+const LVT_ACTIVE_LOW            : u32 = 1 << 13;
+/// 除数寄存器的值 0b0011 表示 16 分频
+const LAPIC_TIMER_DIVIDE_BY_16  : u32 = 0b0011;
+
+// IO-APIC 寄存器
+const IOAPIC_REGISTER_SELECT    : u64 = 0x00;
+const IOAPIC_REGISTER_WINDOW    : u64 = 0x10;
+const IOAPIC_ID                 : u32 = 0x00;
+const IOAPIC_VERSION            : u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE  : u32 = 0x10;
+
+/// 8254 PIT 的输入频率
+const PIT_FREQUENCY             : u64 = 1_193_182;
+/// 校准 LAPIC 定时器时使用的时间窗口
+const CALIBRATE_MILLISECONDS    : u64 = 10;
+
+/// 默认的 LAPIC 定时器频率
+pub const TIMER_FREQUENCY       : u64 = 100;
+
+/// LAPIC 定时器工作模式
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum TimerMode {
+    OneShot     = 0,
+    Periodic    = 1,
+    TscDeadline = 2,
+}
+
+bitfield!{
+    /// IO-APIC 重定向表项,64 位
+    #[derive(Clone,Copy)]
+    pub struct RedirectionEntry(u64);
+    impl Debug;
+    pub u8, vector, set_vector: 7, 0;
+    pub u8, delivery_mode, set_delivery_mode: 10, 8;
+    pub destination_logical, set_destination_logical: 11;
+    pub delivery_pending, _: 12;
+    pub active_low, set_active_low: 13;
+    pub remote_irr, _: 14;
+    pub level_triggered, set_level_triggered: 15;
+    pub masked, set_masked: 16;
+    pub u8, destination, set_destination: 63, 56;
+}
+
+/// APIC 是否已接管中断
+static APIC_ENABLED: AtomicBool = AtomicBool::new(false);
+/// LAPIC 寄存器的虚拟地址, 所有处理器相同
+static LOCAL_APIC_BASE: AtomicU64 = AtomicU64::new(0);
+/// 16 分频时 LAPIC 定时器每毫秒的计数
+static TIMER_TICKS_PER_MS: AtomicU32 = AtomicU32::new(0);
+/// TSC 每毫秒的计数
+static TSC_TICKS_PER_MS: AtomicU64 = AtomicU64::new(0);
+/// BSP 的 LAPIC 定时器中断次数, 各处理器的计数见 `cpu::PerCpu::ticks`
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+static MADT: OnceCell<MadtInfo> = OnceCell::uninit();
+static IO_APICS: Mutex<Vec<IoApic>> = Mutex::new(Vec::new());
+
+/// 当前处理器的 Local APIC
+pub struct LocalApic {
+    base : VirtAddr,
+}
+
+impl LocalApic {
+    /// 返回当前处理器的 LAPIC, 须在 `init` 之后调用
+    pub fn current() -> LocalApic {
+        LocalApic { base: VirtAddr::new(LOCAL_APIC_BASE.load(Ordering::Relaxed)) }
+    }
+
+    unsafe fn read(&self, register : u32) -> u32 {
+        (self.base + register as u64).as_ptr::<u32>().read_volatile()
+    }
+
+    unsafe fn write(&self, register : u32, value : u32) {
+        (self.base + register as u64).as_mut_ptr::<u32>().write_volatile(value)
+    }
+
+    pub fn id(&self) -> u8 {
+        (unsafe { self.read(LAPIC_ID) } >> 24) as u8
+    }
+
+    pub fn version(&self) -> u32 {
+        unsafe { self.read(LAPIC_VERSION) }
+    }
+
+    /// 使能本处理器的 LAPIC: 设置伪中断向量, 配置 LINT0/LINT1 及错误中断
+    pub unsafe fn enable(&self, madt : &MadtInfo) {
+        let mut msr = Msr::new(IA32_APIC_BASE_MSR);
+        let value = msr.read();
+        msr.write(value | IA32_APIC_BASE_ENABLE);
+
+        self.write(LAPIC_SPURIOUS, LAPIC_SPURIOUS_ENABLE | InterruptIndex::ApicSpurious as u32);
+        self.write(LAPIC_TASK_PRIORITY, 0);
+
+        // 外部中断全部经 IO-APIC 送达, LINT 只保留 MADT 中声明的 NMI
+        self.write(LAPIC_LVT_LINT0, LVT_MASKED);
+        self.write(LAPIC_LVT_LINT1, LVT_MASKED);
+        let processor_id = madt.processors.iter()
+            .find(|p| p.apic_id == self.id())
+            .map(|p| p.processor_id);
+        for nmi in madt.nmis.iter().filter(|n| n.processor_id == 0xFF || Some(n.processor_id) == processor_id) {
+            let mut lvt = (APIC_ICR_IOAPIC_NMI as u32) << 8;
+            if nmi.polarity == Polarity::ActiveLow {
+                lvt |= LVT_ACTIVE_LOW;
+            }
+            if nmi.trigger_mode == TriggerMode::Level {
+                lvt |= LVT_LEVEL_TRIGGERED;
+            }
+            self.write(if nmi.lint == 0 { LAPIC_LVT_LINT0 } else { LAPIC_LVT_LINT1 }, lvt);
+        }
+
+        self.write(LAPIC_LVT_ERROR, InterruptIndex::ApicError as u32);
+        // 写两次以清除之前的错误
+        self.write(LAPIC_ERROR_STATUS, 0);
+        self.write(LAPIC_ERROR_STATUS, 0);
+        self.end_of_interrupt();
+    }
+
+    pub fn end_of_interrupt(&self) {
+        unsafe { self.write(LAPIC_EOI, 0) };
+    }
+
+    pub fn error_status(&self) -> u32 {
+        unsafe {
+            self.write(LAPIC_ERROR_STATUS, 0);
+            self.read(LAPIC_ERROR_STATUS)
+        }
+    }
+
+    /// 发送处理器间中断(IPI)
+    pub unsafe fn send_ipi(&self, destination : u8, vector : u8, delivery_mode : u8, shorthand : u8) {
+        self.write(LAPIC_ICR_HIGH, (destination as u32) << 24);
+        self.write(LAPIC_ICR_LOW,
+            vector as u32
+            | (delivery_mode as u32) << 8
+            | (ICR_LEVLE_ASSERT as u32) << 14
+            | (shorthand as u32) << 18);
+        // 等待发送完成
+        while self.read(LAPIC_ICR_LOW) & (1 << 12) != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// 设置定时器。OneShot/Periodic 模式下 `count` 为初始计数(16 分频),
+    /// TscDeadline 模式下 `count` 不使用，需要另行调用 `set_tsc_deadline`
+    pub unsafe fn set_timer(&self, mode : TimerMode, count : u32) {
+        self.write(LAPIC_TIMER_DIVIDE, LAPIC_TIMER_DIVIDE_BY_16);
+        self.write(LAPIC_LVT_TIMER, InterruptIndex::ApicTimer as u32 | (mode as u32) << 17);
+        if mode != TimerMode::TscDeadline {
+            self.write(LAPIC_TIMER_INITIAL_COUNT, count);
+        }
+    }
+
+    pub unsafe fn set_tsc_deadline(&self, deadline : u64) {
+        Msr::new(IA32_TSC_DEADLINE_MSR).write(deadline);
+    }
+
+    pub fn stop_timer(&self) {
+        unsafe {
+            self.write(LAPIC_LVT_TIMER, LVT_MASKED);
+            self.write(LAPIC_TIMER_INITIAL_COUNT, 0);
+        }
+    }
+
+    /// 用 PIT 通道 2 计时，测量 LAPIC 定时器及 TSC 的频率
+    unsafe fn calibrate_timer(&self) {
+        self.write(LAPIC_TIMER_DIVIDE, LAPIC_TIMER_DIVIDE_BY_16);
+        self.write(LAPIC_LVT_TIMER, LVT_MASKED);
+
+        let tsc_start = _rdtsc();
+        self.write(LAPIC_TIMER_INITIAL_COUNT, u32::MAX);
+        pit_sleep(CALIBRATE_MILLISECONDS);
+        let elapsed = u32::MAX - self.read(LAPIC_TIMER_CURRENT_COUNT);
+        let tsc_elapsed = _rdtsc() - tsc_start;
+        self.write(LAPIC_TIMER_INITIAL_COUNT, 0);
+
+        TIMER_TICKS_PER_MS.store(elapsed / CALIBRATE_MILLISECONDS as u32, Ordering::Relaxed);
+        TSC_TICKS_PER_MS.store(tsc_elapsed / CALIBRATE_MILLISECONDS, Ordering::Relaxed);
+    }
+}
+
+/// 一个 IO-APIC
+pub struct IoApic {
+    base : VirtAddr,
+    pub id : u8,
+    pub gsi_base : u32,
+    /// 重定向表项数, 版本寄存器中的最大表项号加一, 最多 256
+    pub entries : u16,
+}
+
+impl IoApic {
+    unsafe fn new(base : VirtAddr, gsi_base : u32) -> IoApic {
+        let mut io_apic = IoApic { base, id: 0, gsi_base, entries: 0 };
+        io_apic.id = (io_apic.read(IOAPIC_ID) >> 24) as u8 & 0xF;
+        io_apic.entries = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xFF) as u16 + 1;
+        io_apic
+    }
+
+    unsafe fn read(&self, register : u32) -> u32 {
+        (self.base + IOAPIC_REGISTER_SELECT).as_mut_ptr::<u32>().write_volatile(register);
+        (self.base + IOAPIC_REGISTER_WINDOW).as_ptr::<u32>().read_volatile()
+    }
+
+    unsafe fn write(&self, register : u32, value : u32) {
+        (self.base + IOAPIC_REGISTER_SELECT).as_mut_ptr::<u32>().write_volatile(register);
+        (self.base + IOAPIC_REGISTER_WINDOW).as_mut_ptr::<u32>().write_volatile(value);
+    }
+
+    pub fn handles(&self, gsi : u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.entries as u32
+    }
+
+    pub fn read_entry(&self, index : u16) -> RedirectionEntry {
+        let register = IOAPIC_REDIRECTION_TABLE + index as u32 * 2;
+        unsafe {
+            let low = self.read(register) as u64;
+            let high = self.read(register + 1) as u64;
+            RedirectionEntry(high << 32 | low)
+        }
+    }
+
+    pub fn write_entry(&self, index : u16, entry : RedirectionEntry) {
+        let register = IOAPIC_REDIRECTION_TABLE + index as u32 * 2;
+        unsafe {
+            // 先写高位再写低位，低位中的屏蔽位最后生效
+            self.write(register + 1, (entry.0 >> 32) as u32);
+            self.write(register, entry.0 as u32);
+        }
+    }
+
+    pub fn set_masked(&self, index : u16, masked : bool) {
+        let mut entry = self.read_entry(index);
+        entry.set_masked(masked);
+        self.write_entry(index, entry);
+    }
+
+    pub fn mask_all(&self) {
+        for index in 0..self.entries {
+            let mut entry = RedirectionEntry(0);
+            entry.set_masked(true);
+            self.write_entry(index, entry);
+        }
+    }
+}
+
+/// APIC 是否已接管中断
+pub fn is_enabled() -> bool {
+    APIC_ENABLED.load(Ordering::Acquire)
+}
+
+pub fn madt() -> Option<&'static MadtInfo> {
+    MADT.try_get().ok()
+}
+
+/// LAPIC 定时器自启动以来的中断次数
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// 由 LAPIC 定时器中断调用
+pub(crate) fn on_timer_tick() {
+    match cpu::try_current() {
+        Some(cpu) => {
+            cpu.ticks.fetch_add(1, Ordering::Relaxed);
+            if cpu.index == 0 {
+                TICKS.fetch_add(1, Ordering::Relaxed);
+            }
+        },
+        None => { TICKS.fetch_add(1, Ordering::Relaxed); },
+    }
+}
+
+/// 向当前处理器的 LAPIC 发送 EOI
+pub fn end_of_interrupt() {
+    LocalApic::current().end_of_interrupt();
+}
+
+/// 用 PIT 通道 2 忙等待指定毫秒数(不超过 50ms)
+pub unsafe fn pit_sleep(milliseconds : u64) {
+    let mut speaker : Port<u8> = Port::new(0x61);
+    let mut command : Port<u8> = Port::new(0x43);
+    let mut channel2 : Port<u8> = Port::new(0x42);
+
+    let count = (PIT_FREQUENCY * milliseconds / 1000) as u16;
+    // 打开通道 2 的门控，关闭扬声器
+    let value = speaker.read() & 0xFC;
+    speaker.write(value | 0x01);
+    // 通道 2, 先低后高, 模式 0(计数结束时输出变高), 二进制
+    command.write(0b1011_0000);
+    channel2.write(count as u8);
+    channel2.write((count >> 8) as u8);
+    // 等待通道 2 输出变高
+    while speaker.read() & 0x20 == 0 {
+        core::hint::spin_loop();
+    }
+    speaker.write(value);
+}
+
+/// 屏蔽 8259 PIC 的全部中断
+pub unsafe fn disable_legacy_pic() {
+    let mut primary : Port<u8> = Port::new(0x21);
+    let mut secondary : Port<u8> = Port::new(0xA1);
+    primary.write(0xFF);
+    secondary.write(0xFF);
+}
+
+/// 将 ISA 中断 `irq` 路由到指定 LAPIC 的 `vector`, 重定向关系来自 MADT
+pub fn route_isa_irq(irq : u8, vector : u8, destination : u8) -> Result<(), &'static str> {
+    let madt = madt().ok_or("APIC not initialized")?;
+    let (gsi, polarity, trigger_mode) = madt.isa_irq_to_gsi(irq);
+    let io_apics = IO_APICS.lock();
+    let io_apic = io_apics.iter().find(|io| io.handles(gsi)).ok_or("no IO-APIC for GSI")?;
+
+    let mut entry = RedirectionEntry(0);
+    entry.set_vector(vector);
+    entry.set_delivery_mode(APIC_ICR_IOAPIC_FIXED);
+    entry.set_destination_logical(false);
+    // ISA 中断默认为高电平有效、边沿触发
+    entry.set_active_low(polarity == Polarity::ActiveLow);
+    entry.set_level_triggered(trigger_mode == TriggerMode::Level);
+    entry.set_masked(false);
+    entry.set_destination(destination);
+    io_apic.write_entry((gsi - io_apic.gsi_base) as u16, entry);
+    Ok(())
+}
+
+/// 屏蔽或打开一个 ISA 中断
+pub fn set_isa_irq_masked(irq : u8, masked : bool) -> Result<(), &'static str> {
+    let madt = madt().ok_or("APIC not initialized")?;
+    let (gsi, _, _) = madt.isa_irq_to_gsi(irq);
+    let io_apics = IO_APICS.lock();
+    let io_apic = io_apics.iter().find(|io| io.handles(gsi)).ok_or("no IO-APIC for GSI")?;
+    io_apic.set_masked((gsi - io_apic.gsi_base) as u16, masked);
+    Ok(())
+}
+
+/// 以指定模式启动当前处理器的 LAPIC 定时器, `microseconds` 为周期或延时
+pub fn start_timer(mode : TimerMode, microseconds : u64) {
+    let lapic = LocalApic::current();
+    unsafe {
+        match mode {
+            TimerMode::TscDeadline => {
+                let ticks = TSC_TICKS_PER_MS.load(Ordering::Relaxed) * microseconds / 1000;
+                lapic.set_timer(mode, 0);
+                lapic.set_tsc_deadline(_rdtsc() + ticks);
+            },
+            _ => {
+                let ticks = TIMER_TICKS_PER_MS.load(Ordering::Relaxed) as u64 * microseconds / 1000;
+                lapic.set_timer(mode, ticks.max(1).min(u32::MAX as u64) as u32);
+            },
+        }
+    }
+}
+
+/// CPU 是否支持 TSC-Deadline 定时器模式
+pub fn supports_tsc_deadline() -> bool {
+    unsafe { __cpuid(1).ecx & (1 << 24) != 0 }
+}
+
+fn supports_apic() -> bool {
+    unsafe { __cpuid(1).edx & (1 << 9) != 0 }
+}
+
+/// 外部设备中断: (ISA 中断号, 中断向量)
+const ISA_IRQS : [(u8, InterruptIndex); 6] = [
+    (1, InterruptIndex::Keyboard),
+    (3, InterruptIndex::Serial0),
+    (4, InterruptIndex::Serial1),
+    (12, InterruptIndex::Mouse),
+    (14, InterruptIndex::IDE0),
+    (15, InterruptIndex::IDE1),
+];
+
+/// 解析 MADT, 映射并初始化 LAPIC 与 IO-APIC, 重定向现有设备的中断，
+/// 全部重定向成功后屏蔽 8259 PIC, 最后以 `TIMER_FREQUENCY` 启动周期定时器; 返回错误时仍使用 PIC。
+///
+/// 须在 `device::acpi::init` 及堆初始化之后调用。
+pub fn init(
+    mapper: &mut (impl Mapper<Size4KiB> + Mapper<Size2MiB> + Translate),
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), &'static str> {
+    if !supports_apic() {
+        return Err("CPU does not support APIC");
+    }
+    let madt = madt::parse().ok_or("MADT not found")?;
+
+    let lapic_base = map_physical_region(madt.local_apic_address, 4096, mapper, frame_allocator)
+        .map_err(|_| "failed to map local APIC")?;
+    LOCAL_APIC_BASE.store(lapic_base.as_u64(), Ordering::Relaxed);
+
+    {
+        let mut io_apics = IO_APICS.lock();
+        for entry in madt.io_apics.iter() {
+            let base = map_physical_region(entry.address, 4096, mapper, frame_allocator)
+                .map_err(|_| "failed to map IO-APIC")?;
+            let io_apic = unsafe { IoApic::new(base, entry.gsi_base) };
+            io_apic.mask_all();
+            serial_println!("IO-APIC {}: address = 0x{:08x}, gsi_base = {}, entries = {}",
+                io_apic.id, entry.address.as_u64(), io_apic.gsi_base, io_apic.entries);
+            io_apics.push(io_apic);
+        }
+        if io_apics.is_empty() {
+            return Err("no IO-APIC found");
+        }
+    }
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let lapic = LocalApic::current();
+        let bsp_id = lapic.id();
+        let has_legacy_pic = madt.has_legacy_pic();
+        MADT.try_init_once(|| madt).map_err(|_| "APIC already initialized")?;
+        let madt = self::madt().ok_or("APIC not initialized")?;
+
+        // 重定向全部成功后才屏蔽 8259 PIC; 失败时撤销已写入的表项, 继续使用 PIC
+        for (irq, index) in ISA_IRQS.iter() {
+            if let Err(error) = route_isa_irq(*irq, *index as u8, bsp_id) {
+                IO_APICS.lock().iter().for_each(IoApic::mask_all);
+                return Err(error);
+            }
+        }
+        // 8254 PIT 不再使用, 由 LAPIC 定时器代替
+        let _ = set_isa_irq_masked(0, true);
+        if has_legacy_pic {
+            unsafe { disable_legacy_pic() };
+        }
+
+        unsafe { lapic.enable(madt) };
+        serial_println!("Local APIC: id = {}, version = 0x{:08x}, {} processors",
+            bsp_id, lapic.version(), madt.processors.len());
+
+        unsafe { lapic.calibrate_timer() };
+        serial_println!("Local APIC timer: {} ticks/ms, TSC: {} ticks/ms, TSC-deadline: {}",
+            TIMER_TICKS_PER_MS.load(Ordering::Relaxed),
+            TSC_TICKS_PER_MS.load(Ordering::Relaxed),
+            supports_tsc_deadline());
+
+        APIC_ENABLED.store(true, Ordering::Release);
+        start_timer(TimerMode::Periodic, 1_000_000 / TIMER_FREQUENCY);
+        Ok(())
+    })
+}
+
+/// 在应用处理器上使能 LAPIC 并启动周期定时器, 定时器频率沿用 BSP 的校准结果
+pub fn init_ap() -> Result<(), &'static str> {
+    let madt = madt().ok_or("APIC not initialized")?;
+    let lapic = LocalApic::current();
+    unsafe { lapic.enable(madt) };
+    start_timer(TimerMode::Periodic, 1_000_000 / TIMER_FREQUENCY);
+    Ok(())
+}
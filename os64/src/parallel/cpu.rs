@@ -0,0 +1,93 @@
+//see also: https://wiki.osdev.org/SWAPGS
+// 每个处理器的私有数据。GS 段基址(IA32_GS_BASE)指向本处理器的 PerCpu,
+// 结构的第一个字段保存它自己的地址，这样 `mov rax, gs:[0]` 就能取得当前处理器的数据。
+use core::{arch::asm, sync::atomic::{AtomicU64, AtomicUsize, Ordering}};
+use alloc::{boxed::Box, vec::Vec};
+use spin::Mutex;
+use x86_64::{VirtAddr, registers::model_specific::GsBase};
+use crate::global_descriptor_table::{self, CpuTables};
+use super::apic;
+
+/// 支持的最大处理器数
+pub const MAX_CPUS : usize = 64;
+
+#[repr(C)]
+pub struct PerCpu {
+    /// 指向自身, 必须是第一个字段
+    self_pointer : *const PerCpu,
+    /// 处理器序号, BSP 为 0, AP 按启动顺序递增
+    pub index : usize,
+    pub apic_id : u8,
+    /// 本处理器的 GDT 和 TSS
+    pub tables : &'static CpuTables,
+    /// 本处理器的 LAPIC 定时器中断次数
+    pub ticks : AtomicU64,
+    /// 本处理器持有的有层级的 IrqSpinLock, 第 n 位表示层级 n, 见 sync
+    pub held_lock_levels : AtomicU64,
+}
+
+// PerCpu 只有原子类型字段会被修改
+unsafe impl Sync for PerCpu {}
+unsafe impl Send for PerCpu {}
+
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(0);
+static CPUS: Mutex<Vec<&'static PerCpu>> = Mutex::new(Vec::new());
+
+/// 为当前处理器创建 PerCpu 并设置 GS 基址, 每个处理器只调用一次
+pub fn init(index : usize, apic_id : u8, tables : &'static CpuTables) -> &'static PerCpu {
+    assert!(index < MAX_CPUS, "too many processors");
+    let cpu = Box::leak(Box::new(PerCpu {
+        self_pointer : core::ptr::null(),
+        index,
+        apic_id,
+        tables,
+        ticks : AtomicU64::new(0),
+        held_lock_levels : AtomicU64::new(0),
+    }));
+    cpu.self_pointer = cpu as *const PerCpu;
+    GsBase::write(VirtAddr::from_ptr(cpu as *const PerCpu));
+
+    CPUS.lock().push(cpu);
+    CPU_COUNT.fetch_add(1, Ordering::AcqRel);
+    cpu
+}
+
+/// 初始化启动处理器的 PerCpu, 须在堆及 APIC 初始化之后调用
+pub fn init_bsp() -> &'static PerCpu {
+    let apic_id = if apic::is_enabled() { apic::LocalApic::current().id() } else { 0 };
+    init(0, apic_id, global_descriptor_table::bsp_tables())
+}
+
+/// 当前处理器的 PerCpu
+pub fn current() -> &'static PerCpu {
+    try_current().expect("per-CPU data not initialized")
+}
+
+/// 当前处理器的 PerCpu, 尚未初始化时返回 `None`
+///
+/// 按本处理器的 GS 基址判断: 其它处理器已经初始化时, 刚启动的 AP 的 GS 基址仍然是 0。
+pub fn try_current() -> Option<&'static PerCpu> {
+    if GsBase::read().as_u64() == 0 {
+        return None;
+    }
+    let pointer : *const PerCpu;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) pointer, options(nostack, readonly, preserves_flags));
+        pointer.as_ref()
+    }
+}
+
+/// 当前处理器的序号，初始化之前为 0
+pub fn current_index() -> usize {
+    try_current().map(|cpu| cpu.index).unwrap_or(0)
+}
+
+/// 已启动的处理器数
+pub fn count() -> usize {
+    CPU_COUNT.load(Ordering::Acquire).max(1)
+}
+
+/// 所有已启动的处理器
+pub fn all() -> Vec<&'static PerCpu> {
+    CPUS.lock().clone()
+}
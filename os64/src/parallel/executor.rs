@@ -1,64 +1,100 @@
 extern crate alloc;
 use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
-use core::task::{Context, Poll, Waker};
+use core::{sync::atomic::{AtomicU8, Ordering}, task::{Context, Poll, Waker}};
 use crossbeam_queue::ArrayQueue;
+use lazy_static::lazy_static;
+use spin::Mutex;
 
 use super::task::{Task, TaskId};
 
+lazy_static! {
+    /// 所有处理器共用的执行器, 每个处理器启动后都调用 `global().run()`
+    static ref GLOBAL_EXECUTOR: Executor = Executor::new();
+}
+
+pub fn global() -> &'static Executor {
+    &GLOBAL_EXECUTOR
+}
+
+/// 队列的容量, 也是任务数的上限
+const MAX_TASKS: usize = 100;
+
+// 任务的调度状态, 保证每个任务在队列中最多出现一次, 同时只在一个处理器上 poll
+const IDLE: u8 = 0;
+const QUEUED: u8 = 1;
+const RUNNING: u8 = 2;
+/// poll 期间又被唤醒, poll 返回后重新放入队列
+const NOTIFIED: u8 = 3;
+
+/// 可以同时在多个处理器上运行的执行器。
+///
+/// 任务被唤醒后放入共享的 `task_queue`, 由任意一个空闲的处理器取出并 poll;
+/// 同一个任务同时只会被一个处理器 poll。
 pub struct Executor {
-    tasks: BTreeMap<TaskId, Task>,
+    tasks: Mutex<BTreeMap<TaskId, Arc<Mutex<Task>>>>,
     task_queue: Arc<ArrayQueue<TaskId>>,
-    waker_cache: BTreeMap<TaskId, Waker>,
+    waker_cache: Mutex<BTreeMap<TaskId, Arc<TaskWaker>>>,
 }
 
 impl Executor {
     pub fn new() -> Self {
         Executor {
-            tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
-            waker_cache: BTreeMap::new(),
+            tasks: Mutex::new(BTreeMap::new()),
+            task_queue: Arc::new(ArrayQueue::new(MAX_TASKS)),
+            waker_cache: Mutex::new(BTreeMap::new()),
         }
     }
 
-    pub fn spawn(&mut self, task: Task) {
+    pub fn spawn(&self, task: Task) {
         let task_id = task.id;
-        if self.tasks.insert(task.id, task).is_some() {
-            panic!("task with same ID already in tasks");
+        {
+            let mut tasks = self.tasks.lock();
+            assert!(tasks.len() < MAX_TASKS, "too many tasks");
+            if tasks.insert(task.id, Arc::new(Mutex::new(task))).is_some() {
+                panic!("task with same ID already in tasks");
+            }
         }
+        let waker = TaskWaker::new(task_id, self.task_queue.clone());
+        waker.state.store(QUEUED, Ordering::Release);
+        self.waker_cache.lock().insert(task_id, waker);
         self.task_queue.push(task_id).expect("queue full");
     }
 
-    pub fn run(&mut self) -> ! {
+    pub fn run(&self) -> ! {
         loop {
             self.run_ready_tasks();
             self.sleep_if_idle();
         }
     }
 
-    fn run_ready_tasks(&mut self) {
-        // destructure `self` to avoid borrow checker errors
-        let Self {
-            tasks,
-            task_queue,
-            waker_cache,
-        } = self;
-
-        while let Ok(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
+    fn run_ready_tasks(&self) {
+        while let Ok(task_id) = self.task_queue.pop() {
+            let task = match self.tasks.lock().get(&task_id) {
+                Some(task) => task.clone(),
                 None => continue, // task no longer exists
             };
-            let waker: &mut Waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-            let mut context = Context::from_waker(waker);
+            let waker = match self.waker_cache.lock().get(&task_id) {
+                Some(waker) => waker.clone(),
+                None => continue,
+            };
+            // 任务在队列中只出现一次, 取出它的处理器是唯一 poll 它的, 锁不会有竞争
+            waker.state.store(RUNNING, Ordering::Release);
+            let mut task = task.lock();
+            let task_waker = Waker::from(waker.clone());
+            let mut context = Context::from_waker(&task_waker);
             match task.poll(&mut context) {
                 Poll::Ready(()) => {
                     // task done -> remove it and its cached waker
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
+                    // 状态停留在 RUNNING, 之后的唤醒不会再放入队列
+                    self.tasks.lock().remove(&task_id);
+                    self.waker_cache.lock().remove(&task_id);
+                }
+                Poll::Pending => {
+                    if waker.state.compare_exchange(RUNNING, IDLE, Ordering::AcqRel, Ordering::Acquire).is_err() {
+                        waker.state.store(QUEUED, Ordering::Release);
+                        waker.enqueue();
+                    }
                 }
-                Poll::Pending => {}
             }
         }
     }
@@ -78,18 +114,33 @@ impl Executor {
 struct TaskWaker {
     task_id: TaskId,
     task_queue: Arc<ArrayQueue<TaskId>>,
+    state: AtomicU8,
 }
 
 impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
+    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Arc<TaskWaker> {
+        Arc::new(TaskWaker {
             task_id,
             task_queue,
-        }))
+            state: AtomicU8::new(IDLE),
+        })
     }
 
+    /// 可以在中断处理程序中调用
     fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+        let previous = self.state.fetch_update(Ordering::AcqRel, Ordering::Acquire, |state| match state {
+            IDLE => Some(QUEUED),
+            RUNNING => Some(NOTIFIED),
+            _ => None,
+        });
+        if previous == Ok(IDLE) {
+            self.enqueue();
+        }
+    }
+
+    /// 每个任务在队列中最多出现一次, 任务数又不超过队列的容量, 放入不会失败
+    fn enqueue(&self) {
+        let _ = self.task_queue.push(self.task_id);
     }
 }
 
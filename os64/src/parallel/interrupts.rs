@@ -9,8 +9,8 @@
 // Secondary ATA ----> |____________|   Parallel Port 1----> |____________|
 //
 
-use x86_64::{structures::idt::{InterruptDescriptorTable, InterruptStackFrame,PageFaultErrorCode}};
-use crate::{hlt_loop, parallel::mouse::{self, on_mouse_action}, device::disk::ide::ide_handler};
+use x86_64::{PrivilegeLevel, VirtAddr, structures::idt::{InterruptDescriptorTable, InterruptStackFrame,PageFaultErrorCode}};
+use crate::{api::user, architecture::x86_64_asm::{asm_copy_user_copy, asm_copy_user_fault, asm_system_call_entry}, hlt_loop, memory::vma::FaultAccess, parallel::{apic, process, scheduler, sync::IrqSpinLock, mouse::{self, on_mouse_action}}, device::{console, serial, disk::ide::ide_handler}};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin::{self, Mutex};
@@ -20,8 +20,8 @@ use crate::{serial_println, serial_print};
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+pub static PICS: IrqSpinLock<ChainedPics> =
+    IrqSpinLock::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
@@ -37,8 +37,17 @@ lazy_static! {
         idt[InterruptIndex::Serial1.as_usize()].set_handler_fn(serial1_interrupt_handler);
         idt[InterruptIndex::IDE0.as_usize()].set_handler_fn(ide0_interrupt_handler);
         idt[InterruptIndex::IDE1.as_usize()].set_handler_fn(ide1_interrupt_handler);
-        idt[InterruptIndex::SystemCall.as_usize()].set_handler_fn(system_call_interrupt_handler);
+        unsafe {
+            let entry = VirtAddr::new(asm_system_call_entry as usize as u64);
+            idt[InterruptIndex::SystemCall.as_usize()].set_handler_addr(entry)
+                .set_privilege_level(PrivilegeLevel::Ring3);
+        }
+        idt[InterruptIndex::ApicTimer.as_usize()].set_handler_fn(apic_timer_interrupt_handler);
+        idt[InterruptIndex::ApicError.as_usize()].set_handler_fn(apic_error_interrupt_handler);
+        idt[InterruptIndex::ApicSpurious.as_usize()].set_handler_fn(apic_spurious_interrupt_handler);
         idt.page_fault.set_handler_fn(page_fault_handler);
+        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
         mouse::init(on_mouse_action);
         idt
     };
@@ -51,12 +60,14 @@ pub fn init_interrupt_descriptor_table() {
 extern "x86-interrupt" fn breakpoint_handler(
     stack_frame: InterruptStackFrame)
 {
+    user::clear_access_flag();
     serial_println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame, _error_code: u64) -> !
 {
+    user::clear_access_flag();
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
@@ -73,6 +84,9 @@ pub enum InterruptIndex {
     IDE0 = PIC_1_OFFSET + 14,
     IDE1 = PIC_1_OFFSET + 15, 
     SystemCall = 0x80, 
+    ApicTimer = 151,
+    ApicError = 156,
+    ApicSpurious = 0xFF,
 }
 
 impl InterruptIndex {
@@ -85,23 +99,35 @@ impl InterruptIndex {
     }
 }
 
+/// 中断处理结束时应答中断控制器: APIC 启用后应答 LAPIC, 否则应答 8259 PIC
+pub fn notify_end_of_interrupt(index: InterruptIndex) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe {
+            PICS.lock()
+                .notify_end_of_interrupt(index.as_u8());
+        }
+    }
+}
+
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    user::clear_access_flag();
     serial_print!(".");
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
+    notify_end_of_interrupt(InterruptIndex::Timer);
 }
 
+/// 按键交给控制台, 方向键等转换成 ANSI 转义序列, 与从串口终端收到的一样
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, KeyCode, ScancodeSet1};
     use spin::Mutex;
     use x86_64::instructions::port::Port;
+    user::clear_access_flag();
 
     lazy_static! {
         static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
             Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1,
-                HandleControl::Ignore)
+                HandleControl::MapLettersToUnicode)
             );
     }
 
@@ -112,24 +138,62 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
         if let Some(key) = keyboard.process_keyevent(key_event) {
             match key {
-                DecodedKey::Unicode(character) => serial_print!("{}", character),
-                DecodedKey::RawKey(key) => serial_print!("{:?}", key),
+                // 布局把 Delete 键解码为 DEL, 而串口终端的退格键发送的也是 DEL, 这里转换为删除键的序列以示区分
+                DecodedKey::Unicode('\u{7f}') | DecodedKey::RawKey(KeyCode::Delete) => console::push_input(b"\x1b[3~"),
+                DecodedKey::Unicode(character) => {
+                    console::push_input(character.encode_utf8(&mut [0; 4]).as_bytes());
+                },
+                DecodedKey::RawKey(key) => {
+                    let sequence : &[u8] = match key {
+                        KeyCode::ArrowUp => b"\x1b[A",
+                        KeyCode::ArrowDown => b"\x1b[B",
+                        KeyCode::ArrowRight => b"\x1b[C",
+                        KeyCode::ArrowLeft => b"\x1b[D",
+                        KeyCode::Home => b"\x1b[H",
+                        KeyCode::End => b"\x1b[F",
+                        _ => b"",
+                    };
+                    console::push_input(sequence);
+                },
             }
         }
     }
-
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+    notify_end_of_interrupt(InterruptIndex::Keyboard);
 }
 
 
+/// 异常是否发生在用户态
+fn from_user_mode(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment & 0x3 == PrivilegeLevel::Ring3 as u64
+}
+
+/// 缺页: 用户空间的地址交给当前进程按需分配页; 无法处理时结束进程，内核自身的缺页则停机
+///
+/// 内核在 api::user 中复制用户内存时的缺页无法处理时, 让复制提前结束, 由系统调用返回 EFAULT。
+/// 复制中缺页时 AC 为 1, 处理期间清除, 返回复制时由 iretq 恢复。
 extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
+    mut stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
     use x86_64::registers::control::Cr2;
+    user::clear_access_flag();
+
+    let address = Cr2::read();
+    let result = process::current().map(|current| current.handle_page_fault(address, FaultAccess::from(error_code)));
+    match result {
+        Some(Ok(())) => return,
+        Some(Err(e)) if from_user_mode(&stack_frame) => {
+            serial_println!("EXCEPTION: PAGE FAULT at {:?} ({}), rip = {:?}, error code: {:?}",
+                address, e, stack_frame.instruction_pointer, error_code);
+            process::terminate_current(process::SIGSEGV, "segmentation fault");
+        },
+        _ if stack_frame.instruction_pointer.as_u64() == asm_copy_user_copy as *const () as u64 => {
+            let resume = VirtAddr::new(asm_copy_user_fault as *const () as u64);
+            unsafe { stack_frame.as_mut().update(|frame| frame.instruction_pointer = resume) };
+            return;
+        },
+        _ => {},
+    }
 
     serial_println!("EXCEPTION: PAGE FAULT");
     serial_println!("Accessed Address: {:?}", Cr2::read());
@@ -138,6 +202,27 @@ extern "x86-interrupt" fn page_fault_handler(
     hlt_loop();
 }
 
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: InterruptStackFrame, error_code: u64)
+{
+    user::clear_access_flag();
+    if from_user_mode(&stack_frame) {
+        serial_println!("EXCEPTION: GENERAL PROTECTION FAULT, rip = {:?}, error code: 0x{:x}",
+            stack_frame.instruction_pointer, error_code);
+        process::terminate_current(process::SIGSEGV, "general protection fault");
+    }
+    panic!("EXCEPTION: GENERAL PROTECTION FAULT (error code: 0x{:x})\n{:#?}", error_code, stack_frame);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    user::clear_access_flag();
+    if from_user_mode(&stack_frame) {
+        serial_println!("EXCEPTION: INVALID OPCODE, rip = {:?}", stack_frame.instruction_pointer);
+        process::terminate_current(process::SIGILL, "invalid opcode");
+    }
+    panic!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
+}
+
 lazy_static! {
     static ref MOUSE: Mutex<u8> =
         Mutex::new(0);
@@ -147,53 +232,57 @@ extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: InterruptStackFr
     // use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
     use spin::Mutex;
     use x86_64::instructions::port::Port;
+    user::clear_access_flag();
 
     let mut mouse = MOUSE.lock();
     // serial_print!("-");
     mouse::mouse_handler();    
-
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Mouse.as_u8());
-    }
+    notify_end_of_interrupt(InterruptIndex::Mouse);
 }
 
 extern "x86-interrupt" fn ide0_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    user::clear_access_flag();
     ide_handler(0);
-	unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::IDE0.as_u8());
-    }
+    notify_end_of_interrupt(InterruptIndex::IDE0);
 }
 
 extern "x86-interrupt" fn ide1_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    user::clear_access_flag();
     ide_handler(1);
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::IDE1.as_u8());
-    }
+    notify_end_of_interrupt(InterruptIndex::IDE1);
 }
 
 extern "x86-interrupt" fn serial0_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    serial_print!("---serial0---");
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Serial0.as_u8());
-    }
+    user::clear_access_flag();
+    serial::COM2.handle_interrupt();
+    notify_end_of_interrupt(InterruptIndex::Serial0);
 }
 
 extern "x86-interrupt" fn serial1_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    serial_print!("---serial1---");
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Serial1.as_u8());
-    }
+    user::clear_access_flag();
+    serial::COM1.handle_interrupt();
+    notify_end_of_interrupt(InterruptIndex::Serial1);
 }
 
-extern "x86-interrupt" fn system_call_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    serial_print!("---system_call---");
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Serial1.as_u8());
+/// 中断了用户态时顺便结束收到 SIGKILL 等信号的进程, 它可能一直不进行系统调用
+extern "x86-interrupt" fn apic_timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    user::clear_access_flag();
+    apic::on_timer_tick();
+    apic::end_of_interrupt();
+    if from_user_mode(&stack_frame) {
+        process::check_fatal_signals();
     }
+    scheduler::tick();
+}
+
+extern "x86-interrupt" fn apic_error_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    user::clear_access_flag();
+    let status = apic::LocalApic::current().error_status();
+    serial_println!("APIC ERROR: status = 0x{:08x}", status);
+    apic::end_of_interrupt();
+}
+
+/// 伪中断不需要应答
+extern "x86-interrupt" fn apic_spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    user::clear_access_flag();
 }
@@ -0,0 +1,208 @@
+// 进程间通信: 通道和端口
+// 通道有两端, 每一端有自己的接收队列, 从一端发送的消息进入另一端的队列。消息由数据和描述符组成,
+// 接收方得到描述符的副本, 因此可以把文件或另一个通道交给别的进程。
+// 一端关闭后, 另一端仍可以收完已排队的消息, 之后接收和发送都返回 EPIPE。
+//
+// 端口是有名字的通道: 服务用 `Port::create` 注册名字并得到监听端, 客户端 `connect` 时内核创建一个新通道,
+// 把其中一端作为消息发到监听端, 服务从监听端收到它后就可以与客户端单独通信。
+//see also: https://fuchsia.dev/fuchsia-src/reference/kernel_objects/channel
+use alloc::{collections::{BTreeMap, VecDeque}, string::{String, ToString}, sync::Arc, vec::Vec};
+use os64_abi::{Errno, MAX_MESSAGE_HANDLES, MAX_MESSAGE_SIZE};
+use crate::device::disk::vfs::Descriptor;
+use super::{process, sync::{IrqSpinLock, WaitQueue}};
+
+/// 每个接收队列最多排队的消息数, 满了之后发送方阻塞
+pub const CHANNEL_CAPACITY : usize = 64;
+/// 端口名的最大长度
+pub const MAX_PORT_NAME_SIZE : usize = 64;
+
+/// 一条消息
+#[derive(Clone, Default)]
+pub struct Message {
+    pub data : Vec<u8>,
+    pub handles : Vec<Descriptor>,
+}
+
+impl Message {
+    pub fn new(data : Vec<u8>, handles : Vec<Descriptor>) -> Result<Message, Errno> {
+        if data.len() as u64 > MAX_MESSAGE_SIZE || handles.len() as u64 > MAX_MESSAGE_HANDLES {
+            return Err(Errno::E2BIG);
+        }
+        Ok(Message { data, handles })
+    }
+}
+
+struct ChannelState {
+    /// 两端各自的接收队列
+    queues : [VecDeque<Message>; 2],
+    /// 两端是否还没有关闭
+    open : [bool; 2],
+}
+
+struct Channel {
+    state : IrqSpinLock<ChannelState>,
+    /// 等待队列中有消息的接收方
+    readable : [WaitQueue; 2],
+    /// 等待对方队列有空位的发送方
+    writable : [WaitQueue; 2],
+}
+
+impl Channel {
+    fn with_state<R>(&self, f : impl FnOnce(&mut ChannelState) -> R) -> R {
+        f(&mut self.state.lock())
+    }
+}
+
+/// 通道的一端, 最后一个引用释放时关闭
+pub struct Endpoint {
+    channel : Arc<Channel>,
+    side : usize,
+}
+
+impl Endpoint {
+    /// 创建一个通道, 返回它的两端
+    pub fn pair() -> (Arc<Endpoint>, Arc<Endpoint>) {
+        let channel = Arc::new(Channel {
+            state : IrqSpinLock::new(ChannelState {
+                queues : [VecDeque::new(), VecDeque::new()],
+                open : [true, true],
+            }),
+            readable : [WaitQueue::new(), WaitQueue::new()],
+            writable : [WaitQueue::new(), WaitQueue::new()],
+        });
+        (Arc::new(Endpoint { channel : channel.clone(), side : 0 }), Arc::new(Endpoint { channel, side : 1 }))
+    }
+
+    fn peer(&self) -> usize {
+        1 - self.side
+    }
+
+    fn try_send(&self, message : &mut Option<Message>) -> Option<Result<(), Errno>> {
+        let peer = self.peer();
+        let result = self.channel.with_state(|state| {
+            if !state.open[peer] {
+                return Some(Err(Errno::EPIPE));
+            }
+            if state.queues[peer].len() >= CHANNEL_CAPACITY {
+                return None;
+            }
+            state.queues[peer].extend(message.take());
+            Some(Ok(()))
+        });
+        if let Some(Ok(())) = result {
+            self.channel.readable[peer].notify_one();
+        }
+        result
+    }
+
+    /// 发送消息; `blocking` 为 false 时对方队列已满则返回 EAGAIN
+    ///
+    /// 消息中不能有这一端的另一端(EINVAL): 它会进入自己的接收队列, 而这个队列只在它自己释放时清空,
+    /// 形成永远不会释放的环。发送这一端自己没有问题, 另一端释放时会清空它所在的队列。
+    pub fn send(&self, message : Message, blocking : bool) -> Result<(), Errno> {
+        if message.handles.iter().any(|handle| matches!(handle, Descriptor::Channel(endpoint) if endpoint.is_peer_of(self))) {
+            return Err(Errno::EINVAL);
+        }
+        let mut message = Some(message);
+        match blocking {
+            true => process::wait_interruptible(&self.channel.writable[self.side], || self.try_send(&mut message)),
+            false => self.try_send(&mut message).unwrap_or(Err(Errno::EAGAIN)),
+        }
+    }
+
+    /// `fits` 检查队首的消息能否被接收, 不能时消息留在队列中
+    fn try_receive(&self, fits : &impl Fn(&Message) -> Result<(), Errno>) -> Option<Result<Message, Errno>> {
+        let side = self.side;
+        let result = self.channel.with_state(|state| match state.queues[side].front() {
+            Some(message) => Some(fits(message).map(|_| state.queues[side].pop_front().unwrap_or_default())),
+            None if !state.open[1 - side] => Some(Err(Errno::EPIPE)),
+            None => None,
+        });
+        if let Some(Ok(_)) = result {
+            self.channel.writable[1 - side].notify_one();
+        }
+        result
+    }
+
+    /// 接收一条消息; `blocking` 为 false 时队列为空则返回 EAGAIN
+    pub fn receive_if(&self, blocking : bool, fits : impl Fn(&Message) -> Result<(), Errno>) -> Result<Message, Errno> {
+        match blocking {
+            true => process::wait_interruptible(&self.channel.readable[self.side], || self.try_receive(&fits)),
+            false => self.try_receive(&fits).unwrap_or(Err(Errno::EAGAIN)),
+        }
+    }
+
+    pub fn receive(&self, blocking : bool) -> Result<Message, Errno> {
+        self.receive_if(blocking, |_| Ok(()))
+    }
+
+    /// 是否与 `other` 是同一个通道的两端
+    pub fn is_peer_of(&self, other : &Endpoint) -> bool {
+        Arc::ptr_eq(&self.channel, &other.channel) && self.side != other.side
+    }
+
+    /// 另一端是否已经关闭
+    pub fn is_peer_closed(&self) -> bool {
+        let peer = self.peer();
+        self.channel.with_state(|state| !state.open[peer])
+    }
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        let side = self.side;
+        // 队列中的消息可能持有别的通道或文件, 在锁外释放
+        let pending = self.channel.with_state(|state| {
+            state.open[side] = false;
+            core::mem::take(&mut state.queues[side])
+        });
+        drop(pending);
+        for queue in self.channel.readable.iter().chain(self.channel.writable.iter()) {
+            queue.notify_all();
+        }
+    }
+}
+
+/// 已注册的端口: 名字到监听端的另一端
+static PORTS: IrqSpinLock<BTreeMap<String, Arc<Endpoint>>> = IrqSpinLock::new(BTreeMap::new());
+
+pub struct Port;
+
+impl Port {
+    /// 注册端口 `name`, 返回监听端; 监听端关闭后名字可以被重新注册
+    pub fn create(name : &str) -> Result<Arc<Endpoint>, Errno> {
+        if name.is_empty() || name.len() > MAX_PORT_NAME_SIZE {
+            return Err(Errno::EINVAL);
+        }
+        let (listener, connector) = Endpoint::pair();
+        let replaced = {
+            let mut ports = PORTS.lock();
+            if ports.get(name).map_or(false, |existing| !existing.is_peer_closed()) {
+                return Err(Errno::EEXIST);
+            }
+            ports.insert(name.to_string(), connector)
+        };
+        drop(replaced);
+        Ok(listener)
+    }
+
+    /// 连接到端口 `name`, 返回与服务通信的通道一端; 服务积压的连接太多时返回 EAGAIN
+    pub fn connect(name : &str) -> Result<Arc<Endpoint>, Errno> {
+        let connector = PORTS.lock().get(name).cloned().ok_or(Errno::ENOENT)?;
+        let (client, server) = Endpoint::pair();
+        match connector.send(Message::new(Vec::new(), alloc::vec![Descriptor::Channel(server)])?, false) {
+            Err(Errno::EPIPE) => {
+                let removed = {
+                    let mut ports = PORTS.lock();
+                    match ports.get(name) {
+                        Some(current) if Arc::ptr_eq(current, &connector) => ports.remove(name),
+                        _ => None,
+                    }
+                };
+                drop(removed);
+                Err(Errno::ENOENT)
+            },
+            result => result.map(|_| client),
+        }
+    }
+}
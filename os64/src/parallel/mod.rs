@@ -2,8 +2,16 @@ pub mod task;
 pub mod executor;
 pub mod simple_executor;
 pub mod interrupts;
+pub mod apic;
+pub mod cpu;
+pub mod smp;
+pub mod scheduler;
+pub mod sync;
 pub mod keyboard;
 pub mod mouse;
 pub mod ring_buffer;
 pub mod modules;
+pub mod module_loader;
 pub mod process;
+pub mod ipc;
+pub mod pipe;
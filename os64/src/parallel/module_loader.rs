@@ -0,0 +1,315 @@
+//see also: https://wiki.osdev.org/ELF_Tutorial#Relocation_Sections
+// 可加载的内核模块。
+// 模块是可重定位的 ELF 目标文件(ET_REL)：带 SHF_ALLOC 标志的段依次放进从内核堆分配的映像中，
+// 再按重定位段修正引用。未定义的符号先在内核导出的符号表中查找，再在已加载模块导出的符号中查找，
+// 提供符号的模块在本模块卸载之前不能卸载。
+// 内核堆和内核代码相距超过 2GB, 对外部函数的 32 位相对调用经过映像末尾的跳转桩，桩中的地址同时用作 GOT 项。
+// 模块以符号 `os64_module_info` 导出 ModuleDescriptor, 以 `os64_module_init`(返回 0 表示成功)
+// 和可选的 `os64_module_exit` 作为入口。
+use core::{alloc::Layout, convert::TryFrom, mem::size_of};
+use alloc::{collections::BTreeMap, string::{String, ToString}, vec::Vec};
+use xmas_elf::{ElfFile, header, sections::{SectionData, ShType, SHF_ALLOC}, symbol_table::Entry};
+use crate::{serial_print, serial_println};
+use super::{process, modules::{self, *}};
+
+/// 跳转桩: `jmp [rip + 0]` 之后是 8 字节的目标地址
+const STUB_SIZE : u64 = 16;
+const STUB_CODE : [u8; 6] = [0xFF, 0x25, 0x00, 0x00, 0x00, 0x00];
+/// 桩中目标地址的偏移, 该地址也作为 GOT 项
+const STUB_TARGET_OFFSET : u64 = 6;
+
+/// 把字符串输出到串口
+extern "C" fn os64_print(data : *const u8, length : usize) {
+    let bytes = unsafe { core::slice::from_raw_parts(data, length) };
+    serial_print!("{}", String::from_utf8_lossy(bytes));
+}
+
+/// 从内核堆分配内存, 失败时返回空指针
+extern "C" fn os64_alloc(size : usize, align : usize) -> *mut u8 {
+    match Layout::from_size_align(size, align) {
+        Ok(layout) if size > 0 => unsafe { alloc::alloc::alloc(layout) },
+        _ => core::ptr::null_mut(),
+    }
+}
+
+/// 释放 `os64_alloc` 分配的内存
+extern "C" fn os64_free(pointer : *mut u8, size : usize, align : usize) {
+    if let Ok(layout) = Layout::from_size_align(size, align) {
+        if !pointer.is_null() && size > 0 {
+            unsafe { alloc::alloc::dealloc(pointer, layout) };
+        }
+    }
+}
+
+extern "C" {
+    fn memcpy(target : *mut u8, source : *const u8, length : usize) -> *mut u8;
+    fn memmove(target : *mut u8, source : *const u8, length : usize) -> *mut u8;
+    fn memset(target : *mut u8, value : i32, length : usize) -> *mut u8;
+    fn memcmp(first : *const u8, second : *const u8, length : usize) -> i32;
+}
+
+/// 内核导出给模块的符号
+pub fn kernel_symbol(name : &str) -> Option<u64> {
+    let address = match name {
+        "os64_print" => os64_print as usize,
+        "os64_alloc" => os64_alloc as usize,
+        "os64_free" => os64_free as usize,
+        "memcpy" => memcpy as usize,
+        "memmove" => memmove as usize,
+        "memset" => memset as usize,
+        "memcmp" => memcmp as usize,
+        _ => return None,
+    };
+    Some(address as u64)
+}
+
+/// 通过 GOT 引用符号的重定位
+fn is_got(kind : u32) -> bool {
+    matches!(kind, R_X86_64_GOTPCREL | R_X86_64_GOTPCRELX | R_X86_64_REX_GOTPCRELX)
+}
+
+/// `target + addend - place`, 超出 32 位时返回 None
+fn relative(target : u64, addend : i64, place : u64) -> Option<i32> {
+    i32::try_from((target as i64).wrapping_add(addend).wrapping_sub(place as i64)).ok()
+}
+
+/// 从数据盘读取并加载模块, 返回模块名
+pub fn load_module_file(filename : &str) -> Result<String, &'static str> {
+    load_module(&process::read_file(filename)?)
+}
+
+/// 加载 ET_REL 模块并调用它的 init, 返回模块名
+pub fn load_module(data : &[u8]) -> Result<String, &'static str> {
+    let mut dependencies = Vec::new();
+    let result = link(data, &mut dependencies);
+    // 失败时释放解析符号时对其它模块的引用
+    if result.is_err() {
+        for name in &dependencies {
+            modules::put_module(name);
+        }
+    }
+    result
+}
+
+/// 装载、重定位并初始化模块, `dependencies` 记录已增加 use_count 的模块
+fn link(data : &[u8], dependencies : &mut Vec<String>) -> Result<String, &'static str> {
+    let elf_file = ElfFile::new(data)?;
+    if elf_file.header.pt2.type_().as_type() != header::Type::Relocatable
+        || elf_file.header.pt2.machine().as_machine() != header::Machine::X86_64 {
+        return Err("not an x86_64 relocatable object");
+    }
+
+    // 需要装入内存的段依次排列, 按各自的对齐要求
+    let sections : Vec<_> = elf_file.section_iter().collect();
+    let mut offsets : Vec<Option<u64>> = alloc::vec![None; sections.len()];
+    let mut size = 0u64;
+    for (index, section) in sections.iter().enumerate() {
+        if section.flags() & SHF_ALLOC == 0 || section.size() == 0 {
+            continue;
+        }
+        let align = section.align().max(1);
+        size = (size + align - 1) / align * align;
+        offsets[index] = Some(size);
+        size += section.size();
+    }
+
+    let symbols = match sections.iter().find(|section| matches!(section.get_type(), Ok(ShType::SymTab))) {
+        Some(table) => match table.get_data(&elf_file)? {
+            SectionData::SymbolTable64(entries) => entries,
+            _ => return Err("invalid symbol table"),
+        },
+        None => return Err("no symbol table"),
+    };
+
+    // 只处理目标段已装入的重定位段, 调试信息等的重定位被忽略
+    let mut relocation_sections = Vec::new();
+    for section in &sections {
+        if !matches!(section.get_type(), Ok(ShType::Rela)) {
+            continue;
+        }
+        let target = section.info() as usize;
+        if offsets.get(target).copied().flatten().is_none() {
+            continue;
+        }
+        match section.get_data(&elf_file)? {
+            SectionData::Rela64(entries) => relocation_sections.push((target, entries)),
+            _ => return Err("invalid relocation section"),
+        }
+    }
+
+    // 外部符号和通过 GOT 引用的符号各需要一个跳转桩, 放在映像末尾
+    let stub_start = (size + STUB_SIZE - 1) & !(STUB_SIZE - 1);
+    let mut stubs = BTreeMap::new();
+    for (_, entries) in &relocation_sections {
+        for rela in entries.iter() {
+            let index = rela.get_symbol_table_index() as usize;
+            let symbol = symbols.get(index).ok_or("invalid symbol index")?;
+            let external = matches!(SymbolSection::from(symbol.shndx()), SymbolSection::Undefined);
+            if external || is_got(rela.get_type()) {
+                let offset = stub_start + stubs.len() as u64 * STUB_SIZE;
+                stubs.entry(index).or_insert(offset);
+            }
+        }
+    }
+
+    let mut image = ModuleImage::new((stub_start + stubs.len() as u64 * STUB_SIZE) as usize)?;
+    let base = image.start();
+    for (index, section) in sections.iter().enumerate() {
+        // NOBITS 段(.bss)已经清零
+        if let (Some(offset), false) = (offsets[index], matches!(section.get_type(), Ok(ShType::NoBits))) {
+            let content = section.raw_data(&elf_file);
+            image.as_mut_slice()[offset as usize..offset as usize + content.len()].copy_from_slice(content);
+        }
+    }
+
+    // 每个符号的地址, 位于未装入的段中的为 None
+    let mut addresses = Vec::with_capacity(symbols.len());
+    for (index, symbol) in symbols.iter().enumerate() {
+        let address = match SymbolSection::from(symbol.shndx()) {
+            SymbolSection::Undefined if index == 0 => Some(0),
+            SymbolSection::Undefined => {
+                let name = symbol.get_name(&elf_file)?;
+                Some(resolve(name, symbol.info() >> 4 == SymbolBinding::Weak as u8, dependencies)?)
+            },
+            SymbolSection::Absolute => Some(symbol.value()),
+            SymbolSection::Common => return Err("common symbols are not supported"),
+            SymbolSection::Value(section) => offsets.get(section as usize).copied().flatten()
+                .map(|offset| base + offset + symbol.value()),
+        };
+        addresses.push(address);
+    }
+
+    for (index, offset) in &stubs {
+        let target = addresses[*index].ok_or("relocation against an unloaded section")?;
+        let stub = &mut image.as_mut_slice()[*offset as usize..(*offset + STUB_SIZE) as usize];
+        stub[..STUB_CODE.len()].copy_from_slice(&STUB_CODE);
+        stub[STUB_TARGET_OFFSET as usize..STUB_TARGET_OFFSET as usize + 8].copy_from_slice(&target.to_le_bytes());
+    }
+
+    for (target, entries) in &relocation_sections {
+        let section_offset = offsets[*target].ok_or("relocation against an unloaded section")?;
+        for rela in entries.iter() {
+            let index = rela.get_symbol_table_index() as usize;
+            let symbol = addresses[index].ok_or("relocation against an unloaded section")?;
+            let stub = stubs.get(&index).map(|offset| base + offset);
+            let offset = (section_offset + rela.get_offset()) as usize;
+            let place = base + offset as u64;
+            let addend = rela.get_addend() as i64;
+            let bytes = match rela.get_type() {
+                R_X86_64_NONE => continue,
+                R_X86_64_64 => symbol.wrapping_add(addend as u64).to_le_bytes().to_vec(),
+                R_X86_64_PC32 => relative(symbol, addend, place).ok_or("relocation out of range")?.to_le_bytes().to_vec(),
+                // 直接调用够不到时经过跳转桩
+                R_X86_64_PLT32 => relative(symbol, addend, place)
+                    .or_else(|| stub.and_then(|stub| relative(stub, addend, place)))
+                    .ok_or("relocation out of range")?
+                    .to_le_bytes().to_vec(),
+                kind if is_got(kind) => {
+                    let slot = stub.ok_or("missing GOT entry")? + STUB_TARGET_OFFSET;
+                    relative(slot, addend, place).ok_or("relocation out of range")?.to_le_bytes().to_vec()
+                },
+                R_X86_64_32 => u32::try_from((symbol as i64).wrapping_add(addend)).map_err(|_| "relocation out of range")?
+                    .to_le_bytes().to_vec(),
+                R_X86_64_32S => i32::try_from((symbol as i64).wrapping_add(addend)).map_err(|_| "relocation out of range")?
+                    .to_le_bytes().to_vec(),
+                _ => return Err("unsupported relocation type"),
+            };
+            if offset + bytes.len() > stub_start as usize {
+                return Err("relocation outside the module");
+            }
+            image.as_mut_slice()[offset..offset + bytes.len()].copy_from_slice(&bytes);
+        }
+    }
+
+    // 导出已定义的全局函数和数据
+    let mut exported = BTreeMap::new();
+    for (index, symbol) in symbols.iter().enumerate() {
+        let binding = symbol.info() >> 4;
+        let kind = symbol.info() & 0xF;
+        if matches!(SymbolSection::from(symbol.shndx()), SymbolSection::Undefined)
+            || (binding != SymbolBinding::Global as u8 && binding != SymbolBinding::Weak as u8)
+            || (kind != SymbolKind::Function as u8 && kind != SymbolKind::Object as u8) {
+            continue;
+        }
+        if let (Some(address), Ok(name)) = (addresses[index], symbol.get_name(&elf_file)) {
+            exported.insert(name.to_string(), ModuleSymbol {
+                name : name.to_string(),
+                address : address as usize,
+                size : symbol.size() as usize,
+            });
+        }
+    }
+
+    let descriptor = exported.get("os64_module_info").ok_or("no module descriptor")?;
+    let descriptor_offset = descriptor.address as u64 - base;
+    if descriptor_offset + size_of::<ModuleDescriptor>() as u64 > stub_start {
+        return Err("invalid module descriptor");
+    }
+    let descriptor = unsafe { core::ptr::read_unaligned(descriptor.address as *const ModuleDescriptor) };
+    if descriptor.api_version != MODULE_API_VERSION {
+        serial_println!("module {}: API version {}, kernel {}", descriptor.name(), descriptor.api_version, MODULE_API_VERSION);
+        return Err("module API version mismatch");
+    }
+    let name = descriptor.name().to_string();
+    if name.is_empty() || modules::loaded_modules().iter().any(|(loaded, _)| *loaded == name) {
+        return Err("module already loaded");
+    }
+    let init = exported.get("os64_module_init").ok_or("no module init")?.address;
+    let exit = exported.get("os64_module_exit")
+        .map(|symbol| unsafe { core::mem::transmute::<usize, extern "C" fn()>(symbol.address) });
+
+    let init = unsafe { core::mem::transmute::<usize, extern "C" fn() -> i32>(init) };
+    let status = init();
+    if status != 0 {
+        serial_println!("module {}: init failed with {}", name, status);
+        return Err("module init failed");
+    }
+
+    let mut module = ModuleLoadedInfo::new(ModuleInfo::new(&name, descriptor.version, descriptor.api_version));
+    module.symbols = exported;
+    module.image = Some(image);
+    module.exit = exit;
+    module.dependencies = dependencies.clone();
+    if let Err(e) = modules::register_module(module) {
+        if let Some(exit) = exit {
+            exit();
+        }
+        return Err(e);
+    }
+    serial_println!("module {} loaded at 0x{:016x}", name, base);
+    Ok(name)
+}
+
+/// 解析模块引用的外部符号: 先查内核导出的符号, 再查已加载模块导出的符号
+///
+/// 第一次引用某个模块的符号时增加它的 use_count 并记入 `dependencies`; 找不到的弱符号为 0。
+fn resolve(name : &str, weak : bool, dependencies : &mut Vec<String>) -> Result<u64, &'static str> {
+    if let Some(address) = kernel_symbol(name) {
+        return Ok(address);
+    }
+    if let Some((module, address)) = modules::find_symbol(name) {
+        if !dependencies.contains(&module) {
+            modules::get_module(&module)?;
+            dependencies.push(module);
+        }
+        return Ok(address as u64);
+    }
+    if weak {
+        return Ok(0);
+    }
+    serial_println!("module: undefined symbol {}", name);
+    Err("undefined symbol")
+}
+
+/// 卸载没有被引用的模块: 调用它的 exit, 再释放它对其它模块的引用和它的内存
+pub fn unload_module(name : &str) -> Result<(), &'static str> {
+    let module = modules::unregister_module(name)?;
+    if let Some(exit) = module.exit {
+        exit();
+    }
+    for dependency in &module.dependencies {
+        modules::put_module(dependency);
+    }
+    serial_println!("module {} unloaded", name);
+    Ok(())
+}
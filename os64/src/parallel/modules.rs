@@ -1,6 +1,8 @@
-use x86_64::{structures::paging::{PageTable, PageTableIndex, PageTableFlags}, VirtAddr, PhysAddr};
-use core::fmt;
-use alloc::{string::{String, ToString}, collections::BTreeMap, boxed::Box};
+use x86_64::{instructions::interrupts, structures::paging::{PageTable, PageTableIndex, PageTableFlags}, VirtAddr, PhysAddr};
+use core::{alloc::Layout, convert::TryInto, fmt, ops::Range};
+use alloc::{string::{String, ToString}, collections::BTreeMap, boxed::Box, vec::Vec};
+use spin::Mutex;
+use xmas_elf::{ElfFile, dynamic::Tag, program::{self, SegmentData}, sections::SectionData, symbol_table::Entry};
 use crate::{serial_println, memory::translate_addr};
 
 // enum PT
@@ -130,7 +132,7 @@ pub enum SymbolSection {
 }
 
 impl SymbolSection {
-    fn from(n: u16) -> SymbolSection {
+    pub fn from(n: u16) -> SymbolSection {
         match n {
             0x0 => SymbolSection::Undefined,
             0xFFF1 => SymbolSection::Absolute,
@@ -160,10 +162,41 @@ impl fmt::Display for SymbolSection {
     }
 }
 
-/// 512G - 2M
-pub const DEFAULT_STACK_ADDRESS : usize = 0o000_777_777_000_0000;
+//see also: https://gitlab.com/x86-psABIs/x86-64-ABI 4.4 Relocation
+pub const R_X86_64_NONE : u32 = 0;
+/// S + A
+pub const R_X86_64_64 : u32 = 1;
+/// S + A - P
+pub const R_X86_64_PC32 : u32 = 2;
+/// L + A - P, 过程链接表项
+pub const R_X86_64_PLT32 : u32 = 4;
+/// S, 全局偏移表中的项
+pub const R_X86_64_GLOB_DAT : u32 = 6;
+/// S, 过程链接表使用的全局偏移表项
+pub const R_X86_64_JUMP_SLOT : u32 = 7;
+/// B + A
+pub const R_X86_64_RELATIVE : u32 = 8;
+/// G + GOT + A - P, 全局偏移表项的相对地址
+pub const R_X86_64_GOTPCREL : u32 = 9;
+/// S + A, 零扩展到 64 位
+pub const R_X86_64_32 : u32 = 10;
+/// S + A, 符号扩展到 64 位
+pub const R_X86_64_32S : u32 = 11;
+/// 同 R_X86_64_GOTPCREL, 链接器可以改写指令
+pub const R_X86_64_GOTPCRELX : u32 = 41;
+pub const R_X86_64_REX_GOTPCRELX : u32 = 42;
+
+/// DT_PLTREL 的值, 表示 PLT 的重定位为 RELA 格式
+const DT_RELA : u64 = 7;
+/// Elf64_Rela 的大小
+const RELA_ENTRY_SIZE : u64 = 24;
+/// Elf64_Sym 的大小
+const SYMBOL_ENTRY_SIZE : u64 = 24;
+
 /// 64K
 pub const DEFAULT_STACK_SIZE : usize = 0o000_000_000_020_0000;
+/// 用户空间顶端 - 64K, 进程初始栈的起始地址
+pub const DEFAULT_STACK_ADDRESS : usize = crate::memory::vma::USER_SPACE_END as usize - DEFAULT_STACK_SIZE;
 /// 4K
 pub const DEFAULT_PAGE_SIZE : usize = 0o000_000_000_001_0000;
 
@@ -208,7 +241,8 @@ impl fmt::Debug for Elf64SymbolItem {
     }
 }
 
-///目前只保存 Global-Funciton 类型的Function
+///导出的符号, 只保存已定义的 Global/Weak 函数和数据
+#[derive(Clone,Debug)]
 pub struct ModuleSymbol {
     pub name: String,
     pub address: usize,
@@ -354,6 +388,65 @@ impl Page {
     }
 }
 
+/// 内核提供给模块的接口版本, 模块的 `api_version` 必须与它相同
+pub const MODULE_API_VERSION : u32 = 1;
+
+/// 模块描述, 内核模块以符号 `os64_module_info` 导出
+#[repr(C)]
+#[derive(Clone,Copy)]
+pub struct ModuleDescriptor {
+    /// 模块名, 以 0 结尾或填满
+    pub name : [u8; 32],
+    pub version : u32,
+    pub api_version : u32,
+}
+
+impl ModuleDescriptor {
+    pub fn name(&self) -> &str {
+        let length = self.name.iter().position(|byte| *byte == 0).unwrap_or(self.name.len());
+        core::str::from_utf8(&self.name[..length]).unwrap_or("")
+    }
+}
+
+/// 内核模块的代码和数据, 从内核堆中按页对齐分配, 释放时归还
+pub struct ModuleImage {
+    start : *mut u8,
+    layout : Layout,
+}
+
+// 映像只由拥有它的 ModuleLoadedInfo 访问
+unsafe impl Send for ModuleImage {}
+
+impl ModuleImage {
+    /// 分配 `size` 字节清零的内存
+    pub fn new(size : usize) -> Result<ModuleImage, &'static str> {
+        let layout = Layout::from_size_align(size.max(1), DEFAULT_PAGE_SIZE).map_err(|_| "module too large")?;
+        let start = unsafe { alloc::alloc::alloc_zeroed(layout) };
+        if start.is_null() {
+            return Err("out of memory");
+        }
+        Ok(ModuleImage { start, layout })
+    }
+
+    pub fn start(&self) -> u64 {
+        self.start as u64
+    }
+
+    pub fn size(&self) -> usize {
+        self.layout.size()
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.start, self.layout.size()) }
+    }
+}
+
+impl Drop for ModuleImage {
+    fn drop(&mut self) {
+        unsafe { alloc::alloc::dealloc(self.start, self.layout) };
+    }
+}
+
 ///模块基本信息
 pub struct ModuleInfo {
     pub name: String,
@@ -376,6 +469,13 @@ pub struct ModuleLoadedInfo {
     pub info: ModuleInfo,
     pub level4 : Page,
     pub symbols : BTreeMap<String,ModuleSymbol>,
+    /// 内核模块的映像
+    pub image : Option<ModuleImage>,
+    /// 卸载时调用的清理函数
+    pub exit : Option<extern "C" fn()>,
+    /// 提供了本模块所引用符号的模块, 本模块加载期间它们的 use_count 各加 1
+    pub dependencies : Vec<String>,
+    /// 引用本模块的模块及 `get_module` 的次数, 不为 0 时不能卸载
     use_count : usize,
 }
 
@@ -386,14 +486,228 @@ impl ModuleLoadedInfo {
             info,
             level4: Page::new(&l4_key),
             symbols: BTreeMap::new(),
+            image: None,
+            exit: None,
+            dependencies: Vec::new(),
             use_count: 0,
         }
     }
 
-    pub fn page_by_address(&mut self, virtual_address: usize, physical_memory_offset: u64) -> &mut Box<Page> {
-        let l3 = self.level4.sub_page(virtual_address, physical_memory_offset).unwrap();
-        let l2 = l3.sub_page(virtual_address, physical_memory_offset).unwrap();
-        let l1 = l2.sub_page(virtual_address, physical_memory_offset).unwrap();
-        l1.sub_page(virtual_address, physical_memory_offset).unwrap()
+    pub fn use_count(&self) -> usize {
+        self.use_count
+    }
+
+    /// 模块导出的符号
+    pub fn find_symbol(&self, name : &str) -> Option<&ModuleSymbol> {
+        self.symbols.get(name)
+    }
+}
+
+/// 已加载的模块, 以模块名为键; 模块之间通过它们导出的符号互相调用
+static LOADED_MODULES: Mutex<BTreeMap<String, ModuleLoadedInfo>> = Mutex::new(BTreeMap::new());
+
+/// 登记已加载的模块, 之后它导出的符号可以被其它模块引用
+pub fn register_module(module : ModuleLoadedInfo) -> Result<(), &'static str> {
+    interrupts::without_interrupts(|| {
+        let mut modules = LOADED_MODULES.lock();
+        if modules.contains_key(&module.info.name) {
+            return Err("module already loaded");
+        }
+        modules.insert(module.info.name.clone(), module);
+        Ok(())
+    })
+}
+
+/// 移除 use_count 为 0 的模块并返回它
+pub fn unregister_module(name : &str) -> Result<ModuleLoadedInfo, &'static str> {
+    interrupts::without_interrupts(|| {
+        let mut modules = LOADED_MODULES.lock();
+        match modules.get(name) {
+            None => Err("module not loaded"),
+            Some(module) if module.use_count > 0 => Err("module in use"),
+            Some(_) => modules.remove(name).ok_or("module not loaded"),
+        }
+    })
+}
+
+/// 增加模块的 use_count, 防止它在使用期间被卸载
+pub fn get_module(name : &str) -> Result<(), &'static str> {
+    interrupts::without_interrupts(|| {
+        let mut modules = LOADED_MODULES.lock();
+        let module = modules.get_mut(name).ok_or("module not loaded")?;
+        module.use_count += 1;
+        Ok(())
+    })
+}
+
+/// 减少 `get_module` 增加的 use_count
+pub fn put_module(name : &str) {
+    interrupts::without_interrupts(|| {
+        if let Some(module) = LOADED_MODULES.lock().get_mut(name) {
+            module.use_count = module.use_count.saturating_sub(1);
+        }
+    })
+}
+
+/// 在所有已加载的模块中查找符号, 返回导出它的模块名和地址
+pub fn find_symbol(name : &str) -> Option<(String, usize)> {
+    interrupts::without_interrupts(|| {
+        LOADED_MODULES.lock().values()
+            .find_map(|module| module.find_symbol(name).map(|symbol| (module.info.name.clone(), symbol.address)))
+    })
+}
+
+/// 已加载的模块名及其 use_count
+pub fn loaded_modules() -> Vec<(String, usize)> {
+    interrupts::without_interrupts(|| {
+        LOADED_MODULES.lock().values().map(|module| (module.info.name.clone(), module.use_count)).collect()
+    })
+}
+
+/// 需要写入的一个重定位: 链接地址 `address` 处的 8 字节改为 `value`
+#[derive(Clone,Copy,Debug)]
+pub struct Relocation {
+    pub address : u64,
+    pub value : u64,
+}
+
+/// 符号表段中的所有项
+fn symbol_entries<'a>(data : SectionData<'a>) -> Option<Vec<&'a dyn Entry>> {
+    match data {
+        SectionData::SymbolTable64(entries) => Some(entries.iter().map(|entry| entry as &dyn Entry).collect()),
+        SectionData::DynSymbolTable64(entries) => Some(entries.iter().map(|entry| entry as &dyn Entry).collect()),
+        _ => None,
+    }
+}
+
+/// 读取 ELF 中定义的全局函数和数据, 地址加上装载基址 `base`
+///
+/// 优先使用动态符号表 .dynsym, 没有时使用 .symtab。
+pub fn read_symbols(elf_file : &ElfFile, base : u64) -> BTreeMap<String, ModuleSymbol> {
+    let entries = elf_file.find_section_by_name(".dynsym")
+        .or_else(|| elf_file.find_section_by_name(".symtab"))
+        .and_then(|section| section.get_data(elf_file).ok())
+        .and_then(symbol_entries)
+        .unwrap_or_default();
+    let mut symbols = BTreeMap::new();
+    for entry in entries {
+        let binding = entry.info() >> 4;
+        let kind = entry.info() & 0xF;
+        if matches!(SymbolSection::from(entry.shndx()), SymbolSection::Undefined)
+            || (binding != SymbolBinding::Global as u8 && binding != SymbolBinding::Weak as u8)
+            || (kind != SymbolKind::Function as u8 && kind != SymbolKind::Object as u8) {
+            continue;
+        }
+        let name = match entry.get_name(elf_file) {
+            Ok(name) if !name.is_empty() => name,
+            _ => continue,
+        };
+        let address = match SymbolSection::from(entry.shndx()) {
+            SymbolSection::Absolute => entry.value(),
+            _ => base + entry.value(),
+        };
+        symbols.insert(name.to_string(), ModuleSymbol {
+            name : name.to_string(),
+            address : address as usize,
+            size : entry.size() as usize,
+        });
+    }
+    symbols
+}
+
+/// 链接地址 `address` 开始的 `size` 字节在文件中的位置, 必须完全落在一个 PT_LOAD 段的文件内容中
+fn file_range(elf_file : &ElfFile, address : u64, size : u64) -> Result<Range<usize>, &'static str> {
+    let end = address.checked_add(size).ok_or("dynamic data outside the image")?;
+    elf_file.program_iter()
+        .filter(|header| matches!(header.get_type(), Ok(program::Type::Load)))
+        .find(|header| header.virtual_addr() <= address && end <= header.virtual_addr() + header.file_size())
+        .map(|header| (header.offset() + (address - header.virtual_addr())) as usize)
+        .map(|start| start..start + size as usize)
+        .filter(|range| range.end <= elf_file.input.len())
+        .ok_or("dynamic data outside the image")
+}
+
+fn read_u64(data : &[u8], offset : usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// 动态符号表 DT_SYMTAB 中第 `index` 项的地址: 映像中定义的符号加上装载基址,
+/// 未定义的按 DT_STRTAB 中的名字由 `resolve` 查找, 找不到的弱符号为 0
+fn dynamic_symbol_address(elf_file : &ElfFile, symtab : u64, strtab : u64, index : u32, base : u64,
+    resolve : &impl Fn(&str) -> Option<u64>) -> Result<u64, &'static str> {
+    let symbol = &elf_file.input[file_range(elf_file, symtab + index as u64 * SYMBOL_ENTRY_SIZE, SYMBOL_ENTRY_SIZE)?];
+    let name_offset = u32::from_le_bytes(symbol[0..4].try_into().unwrap()) as u64;
+    let binding = symbol[4] >> 4;
+    let shndx = u16::from_le_bytes(symbol[6..8].try_into().unwrap());
+    let value = read_u64(symbol, 8);
+    match SymbolSection::from(shndx) {
+        SymbolSection::Absolute => Ok(value),
+        SymbolSection::Undefined => {
+            // 名字以 0 结尾, 不能超出它所在的段
+            let names = &elf_file.input[file_range(elf_file, strtab + name_offset, 1)?.start..];
+            let length = names.iter().position(|byte| *byte == 0).ok_or("invalid symbol name")?;
+            let name = core::str::from_utf8(&names[..length]).map_err(|_| "invalid symbol name")?;
+            match resolve(name) {
+                Some(address) => Ok(address),
+                None if binding == SymbolBinding::Weak as u8 => Ok(0),
+                None => Err("undefined symbol"),
+            }
+        },
+        _ => Ok(base + value),
+    }
+}
+
+/// 计算装载到基址 `base` 的 ET_DYN 映像中所有 RELA 重定位要写入的值
+///
+/// 重定位表由 PT_DYNAMIC 中的 DT_RELA/DT_RELASZ 和 DT_JMPREL/DT_PLTRELSZ 给出, 不依赖节头表。
+/// 支持 R_X86_64_RELATIVE、R_X86_64_GLOB_DAT、R_X86_64_JUMP_SLOT 和 R_X86_64_64。
+pub fn relocations(elf_file : &ElfFile, base : u64, resolve : impl Fn(&str) -> Option<u64>) -> Result<Vec<Relocation>, &'static str> {
+    let dynamic = match elf_file.program_iter().find(|header| matches!(header.get_type(), Ok(program::Type::Dynamic))) {
+        Some(header) => header,
+        None => return Ok(Vec::new()),
+    };
+    let entries = match dynamic.get_data(elf_file)? {
+        SegmentData::Dynamic64(entries) => entries,
+        _ => return Err("invalid dynamic segment"),
+    };
+    let (mut rela, mut rela_size, mut jmprel, mut jmprel_size, mut symtab, mut strtab) = (None, 0, None, 0, None, None);
+    for entry in entries {
+        match entry.get_tag() {
+            Ok(Tag::Null) => break,
+            Ok(Tag::Rela) => rela = Some(entry.get_ptr()?),
+            Ok(Tag::RelaSize) => rela_size = entry.get_val()?,
+            Ok(Tag::JmpRel) => jmprel = Some(entry.get_ptr()?),
+            Ok(Tag::PltRelSize) => jmprel_size = entry.get_val()?,
+            Ok(Tag::PltRel) if entry.get_val()? != DT_RELA => return Err("unsupported PLT relocation format"),
+            Ok(Tag::SymTab) => symtab = Some(entry.get_ptr()?),
+            Ok(Tag::StrTab) => strtab = Some(entry.get_ptr()?),
+            _ => {},
+        }
+    }
+
+    let mut result = Vec::new();
+    for (table, size) in [(rela, rela_size), (jmprel, jmprel_size)] {
+        let table = match table {
+            Some(table) if size > 0 => &elf_file.input[file_range(elf_file, table, size)?],
+            _ => continue,
+        };
+        for rela in table.chunks_exact(RELA_ENTRY_SIZE as usize) {
+            let offset = read_u64(rela, 0);
+            let info = read_u64(rela, 8);
+            let addend = read_u64(rela, 16);
+            let value = match info as u32 {
+                R_X86_64_NONE => continue,
+                R_X86_64_RELATIVE => base.wrapping_add(addend),
+                kind @ (R_X86_64_64 | R_X86_64_GLOB_DAT | R_X86_64_JUMP_SLOT) => {
+                    let symtab = symtab.ok_or("no dynamic symbol table")?;
+                    let strtab = strtab.ok_or("no dynamic string table")?;
+                    let address = dynamic_symbol_address(elf_file, symtab, strtab, (info >> 32) as u32, base, &resolve)?;
+                    if kind == R_X86_64_64 { address.wrapping_add(addend) } else { address }
+                },
+                _ => return Err("unsupported relocation type"),
+            };
+            result.push(Relocation { address : offset, value });
+        }
     }
+    Ok(result)
 }
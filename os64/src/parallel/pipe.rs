@@ -0,0 +1,120 @@
+// 匿名管道: 一个有界的字节缓冲区, 一端写入, 另一端按写入的顺序读出
+// 缓冲区满时写入方阻塞, 空时读取方阻塞; 写入端全部关闭后读到文件尾, 读取端全部关闭后写入返回 EPIPE。
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use os64_abi::Errno;
+use super::{process, sync::{IrqSpinLock, WaitQueue}};
+
+/// 管道缓冲区的大小
+pub const PIPE_CAPACITY : usize = 16 * 1024;
+
+struct PipeState {
+    data : VecDeque<u8>,
+    reader_open : bool,
+    writer_open : bool,
+}
+
+struct Pipe {
+    state : IrqSpinLock<PipeState>,
+    readable : WaitQueue,
+    writable : WaitQueue,
+}
+
+impl Pipe {
+    fn with_state<R>(&self, f : impl FnOnce(&mut PipeState) -> R) -> R {
+        f(&mut self.state.lock())
+    }
+}
+
+/// 管道的读取端, 释放时关闭
+pub struct PipeReader(Arc<Pipe>);
+
+/// 管道的写入端, 释放时关闭
+pub struct PipeWriter(Arc<Pipe>);
+
+/// 创建管道, 返回它的两端
+pub fn pipe() -> (PipeReader, PipeWriter) {
+    let pipe = Arc::new(Pipe {
+        state : IrqSpinLock::new(PipeState {
+            data : VecDeque::with_capacity(PIPE_CAPACITY),
+            reader_open : true,
+            writer_open : true,
+        }),
+        readable : WaitQueue::new(),
+        writable : WaitQueue::new(),
+    });
+    (PipeReader(pipe.clone()), PipeWriter(pipe))
+}
+
+impl PipeReader {
+    /// 读取最多 `size` 字节, 返回空时表示写入端已经关闭
+    ///
+    /// 管道为空时, `blocking` 为 true 则等待写入, 否则返回 EAGAIN; 等待中进程收到信号则返回 EINTR。
+    pub fn read(&self, size : usize, blocking : bool) -> Result<Vec<u8>, Errno> {
+        if size == 0 {
+            return Ok(Vec::new());
+        }
+        let try_read = || self.0.with_state(|state| {
+            if state.data.is_empty() {
+                return match state.writer_open {
+                    true => None,
+                    false => Some(Vec::new()),
+                };
+            }
+            let count = size.min(state.data.len());
+            Some(state.data.drain(..count).collect())
+        });
+        let data = match blocking {
+            true => process::wait_interruptible(&self.0.readable, || try_read().map(Ok))?,
+            false => try_read().ok_or(Errno::EAGAIN)?,
+        };
+        if !data.is_empty() {
+            self.0.writable.notify_all();
+        }
+        Ok(data)
+    }
+}
+
+impl PipeWriter {
+    /// 写入尽可能多的字节, 返回写入的字节数
+    ///
+    /// 管道已满时, `blocking` 为 true 则等待读取, 否则返回 EAGAIN; 等待中进程收到信号则返回 EINTR。
+    pub fn write(&self, data : &[u8], blocking : bool) -> Result<usize, Errno> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let try_write = || self.0.with_state(|state| {
+            if !state.reader_open {
+                return Some(Err(Errno::EPIPE));
+            }
+            let count = data.len().min(PIPE_CAPACITY - state.data.len());
+            if count == 0 {
+                return None;
+            }
+            state.data.extend(&data[..count]);
+            Some(Ok(count))
+        });
+        let written = match blocking {
+            true => process::wait_interruptible(&self.0.writable, try_write)?,
+            false => try_write().unwrap_or(Err(Errno::EAGAIN))?,
+        };
+        self.0.readable.notify_all();
+        Ok(written)
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.0.with_state(|state| {
+            state.reader_open = false;
+            state.data.clear();
+        });
+        self.0.writable.notify_all();
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.0.with_state(|state| state.writer_open = false);
+        self.0.readable.notify_all();
+    }
+}
@@ -1,147 +1,628 @@
-use core::slice;
-use alloc::{vec::Vec, rc::Rc, string::{ToString, String}, collections::BTreeMap};
+//see also: https://wiki.osdev.org/ELF
+// 用户进程。
+// 每个进程有自己的地址空间(见 memory::vma)，加载时只为 ELF 的 PT_LOAD 段、堆和栈建立 VMA,
+// 页在第一次访问时才由缺页处理分配。进程由一个内核线程运行，该线程通过 iretq 进入用户态；
+// 无法处理的缺页等异常会结束进程，而不是让内核停机。
+// fork 以写时复制的方式复制地址空间，子进程从父进程发起系统调用的位置返回；
+// exec 在同一个进程中换上新的映像, 进程 id 不变。
+// 位置无关的映像(ET_DYN)装载到随机的基址, 重定位在建立 VMA 之前直接写入文件内容。
+// 进程开始运行时栈顶按 System V 的约定放着 argc、argv、envp 和辅助向量(auxv)。
+//
+// fork 出的进程记下父进程, 结束时如果父进程还在, 退出状态保留到父进程 WAITPID 为止。
+// 信号只记在进程上, 在进程从系统调用返回时处理(见 api::signal); 阻塞中的系统调用因此返回 EINTR,
+// 用户态中运行的进程在时钟中断时检查要结束它的信号。
+use core::{slice, sync::atomic::{AtomicU64, Ordering}};
+use alloc::{vec::Vec, sync::Arc, string::{ToString, String}, collections::BTreeMap};
 use bitfield::size_of;
-use crate::{device::disk::{ide::IDE_DISKS, disk::DiskDriver, disk::SECTOR_SIZE, fat::{Fat16BootSector, Attributes, FAT16SuperBlock}}, serial_println, parallel::modules::{DEFAULT_PAGE_SIZE, Elf64SymbolItem}, serial_print};
-use super::{task::{TaskId, Task}, modules::{ModuleLoadedInfo, ModuleInfo, DEFAULT_STACK_ADDRESS, DEFAULT_STACK_SIZE}};
-use xmas_elf::{ElfFile, sections::ShType, program::Type};
+use x86_64::VirtAddr;
+use crate::{device::disk::vfs::{self, Descriptor, FileTable}, serial_println, parallel::modules::Elf64SymbolItem, serial_print};
+use crate::{api::{Errno, SyscallFrame}, architecture::x86_64_asm::{asm_enter_user_mode, asm_random_u64, asm_return_to_user}, memory::{self, HUGE_PAGE_SIZE, vma::{AddressSpace, FaultAccess, FaultError, Vma, VmaBacking, VmaFlags, VmaKind, MMAP_START, PAGE_SIZE, USER_SPACE_START}}};
+use super::{cpu, scheduler::{self, ThreadId}, sync::{AsyncWaitQueue, IrqSpinLock, Mutex, WaitQueue}, modules::{self, DEFAULT_STACK_ADDRESS, DEFAULT_STACK_SIZE}};
+use xmas_elf::{ElfFile, header, sections::ShType, program::Type};
+
+/// ET_DYN 映像可选的装载基址个数, 基址按 2MiB 对齐, 共 128GB
+const PIE_BASE_SLOTS : u64 = 1 << 16;
+pub use os64_abi::{MAX_ARGUMENT_SIZE, AT_NULL, AT_PHDR, AT_PHENT, AT_PHNUM, AT_PAGESZ, AT_ENTRY, AT_RANDOM};
+pub use os64_abi::{ExitStatus, NSIG, SIGINT, SIGILL, SIGKILL, SIGSEGV, SIGTERM};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ProcessId(u64);
+
+impl ProcessId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        ProcessId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+/// 进程对一个信号的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignalAction {
+    /// 结束进程
+    Default,
+    Ignore,
+    /// 调用用户态的 `handler(signal)`, 它返回到 `trampoline`, 由 `trampoline` 发起 SIGRETURN
+    Handler { handler : u64, trampoline : u64 },
+}
+
+#[derive(Clone)]
+struct Signals {
+    /// 第 n 位表示信号 n 尚未处理
+    pending : u64,
+    actions : [SignalAction; NSIG as usize],
+}
+
+impl Signals {
+    fn new() -> Signals {
+        Signals { pending : 0, actions : [SignalAction::Default; NSIG as usize] }
+    }
+}
 
 pub struct Process {
-    id : TaskId,
-    task: Task,
-    code: Vec<u8>,
+    id : ProcessId,
+    /// 程序名, exec 时改变
+    name : IrqSpinLock<String>,
+    /// 程序入口
+    entry : VirtAddr,
+    /// 初始的用户栈顶
+    stack_top : VirtAddr,
+    /// 地址空间和文件描述符表的锁内可能释放打开的文件(见 vfs), 所以使用会阻塞的锁
+    address_space : Mutex<AddressSpace>,
+    /// 打开的文件, exec 后仍然有效
+    files : Mutex<FileTable>,
+    /// 由 fork 创建时的父进程
+    parent : Option<ProcessId>,
+    signals : IrqSpinLock<Signals>,
+    /// 结束后的状态
+    status : IrqSpinLock<Option<ExitStatus>>,
 }
 
+/// 所有未结束的进程
+static PROCESSES: IrqSpinLock<BTreeMap<ProcessId, Arc<Process>>> = IrqSpinLock::new(BTreeMap::new());
+/// 运行用户进程的线程及其所属进程
+///
+/// 这两个表会在缺页处理中访问，锁持有期间关闭中断。
+static PROCESS_THREADS: IrqSpinLock<BTreeMap<ThreadId, ProcessId>> = IrqSpinLock::new(BTreeMap::new());
+/// 已经结束、还没有被父进程等待的进程及其父进程和状态
+///
+/// 与 `PROCESSES` 一起修改, 要同时持有时先锁 `PROCESSES`。
+static EXITED: IrqSpinLock<BTreeMap<ProcessId, (ProcessId, ExitStatus)>> = IrqSpinLock::new(BTreeMap::new());
+/// 有进程结束时唤醒
+static EXIT_WAITERS: WaitQueue = WaitQueue::new();
+/// 有进程结束时唤醒等待的任务
+static EXIT_TASKS: AsyncWaitQueue = AsyncWaitQueue::new();
+
 impl Process {
-    pub fn read(filename : &str,physical_memory_offset :u64) {
+    /// 从磁盘加载 ELF 文件并创建进程, 进程要调用 `start` 后才会运行
+    pub fn load(filename : &str) -> Result<Arc<Process>, &'static str> {
+        Process::load_with_args(filename, &[filename], &[])
+    }
+
+    /// 加载 ELF 文件并创建进程, 参数 `args`(含程序名)和环境变量 `env`(`NAME=value`)放在进程的栈上
+    pub fn load_with_args(filename : &str, args : &[&str], env : &[&str]) -> Result<Arc<Process>, &'static str> {
         let mut pm = ProcessManager::new();
-        pm.load(&filename.to_string(), physical_memory_offset);
-        // let mut code = Vec::new();
-        // File::open(path)?.read_to_end(&mut code)?;
-        // Ok(Self { 
-        //     task, 
-        //     executor: Executor::new(),
-        //     code 
-        // })
+        let (address_space, entry, stack_top) = pm.load(&filename.to_string(), args, env)?;
+        let process = Arc::new(Process {
+            id : ProcessId::new(),
+            name : IrqSpinLock::new(filename.to_string()),
+            entry,
+            stack_top,
+            address_space : Mutex::new(address_space),
+            files : Mutex::new(FileTable::with_stdio()),
+            parent : None,
+            signals : IrqSpinLock::new(Signals::new()),
+            status : IrqSpinLock::new(None),
+        });
+        PROCESSES.lock().insert(process.id, process.clone());
+        Ok(process)
+    }
+
+    pub fn id(&self) -> ProcessId {
+        self.id
+    }
+
+    pub fn name(&self) -> String {
+        self.name.lock().clone()
+    }
+
+    /// fork 出该进程的进程, 由内核加载的进程没有父进程
+    pub fn parent(&self) -> Option<ProcessId> {
+        self.parent
+    }
+
+    /// 已经结束时返回结束的原因
+    pub fn status(&self) -> Option<ExitStatus> {
+        *self.status.lock()
+    }
+
+    /// 等待该进程结束, 供内核使用; 不会回收父进程的 WAITPID 要用的状态
+    pub fn wait(&self) -> ExitStatus {
+        EXIT_WAITERS.wait_until(|| self.status())
+    }
+
+    /// `wait` 的异步版本, 供执行器中的任务使用
+    pub async fn wait_async(&self) -> ExitStatus {
+        EXIT_TASKS.wait_until(|| self.status()).await
+    }
+
+    /// 创建运行该进程的线程
+    pub fn start(self : &Arc<Self>) -> ThreadId {
+        let entry = self.entry;
+        let stack_top = self.stack_top;
+        self.spawn_thread(move || enter_user_mode(entry, stack_top))
+    }
+
+    /// 创建运行该进程的线程, 线程登记到进程后执行 `enter` 进入用户态
+    fn spawn_thread(self : &Arc<Self>, enter : impl FnOnce() + Send + 'static) -> ThreadId {
+        let id = self.id;
+        let page_table = self.address_space.lock().page_table();
+        scheduler::spawn_with_page_table(&self.name(), page_table, move || {
+            if let Some(thread) = scheduler::current_id() {
+                PROCESS_THREADS.lock().insert(thread, id);
+            }
+            enter()
+        })
+    }
+
+    /// 复制该进程, 必须由该进程自己的线程调用
+    ///
+    /// 子进程的地址空间是写时复制的副本, 它的线程以 `frame` 中的寄存器返回用户态, rax 为 0。
+    pub fn fork(self : &Arc<Self>, frame : &SyscallFrame) -> Result<Arc<Process>, &'static str> {
+        let address_space = self.with_address_space(|space| space.fork())?;
+        let actions = self.with_signals(|signals| signals.actions);
+        let child = Arc::new(Process {
+            id : ProcessId::new(),
+            name : IrqSpinLock::new(self.name()),
+            entry : self.entry,
+            stack_top : self.stack_top,
+            address_space : Mutex::new(address_space),
+            files : Mutex::new(self.with_files(|files| files.clone())),
+            parent : Some(self.id),
+            signals : IrqSpinLock::new(Signals { pending : 0, actions }),
+            status : IrqSpinLock::new(None),
+        });
+        PROCESSES.lock().insert(child.id, child.clone());
+        let registers = SyscallFrame { rax : 0, ..*frame };
+        child.spawn_thread(move || {
+            let registers = registers;
+            unsafe { asm_return_to_user(&registers) }
+        });
+        Ok(child)
+    }
+
+    /// 用文件 `filename` 中的程序替换该进程的映像, 返回新的入口和栈顶, 必须由该进程自己的线程调用
+    ///
+    /// 加载失败时原来的映像不受影响。成功后调用者应释放所有局部资源, 再用 `enter_user_mode` 进入新的映像。
+    pub fn exec(&self, filename : &str, args : &[&str], env : &[&str]) -> Result<(VirtAddr, VirtAddr), &'static str> {
+        let (address_space, entry, stack_top) = ProcessManager::new().load(&filename.to_string(), args, env)?;
+        let page_table = address_space.page_table();
+        let old = self.with_address_space(|space| core::mem::replace(space, address_space));
+        scheduler::set_page_table(page_table);
+        drop(old);
+        *self.name.lock() = filename.to_string();
+        // 旧映像中的处理函数不再存在, 忽略的信号仍然忽略
+        self.with_signals(|signals| for action in signals.actions.iter_mut() {
+            if let SignalAction::Handler { .. } = action {
+                *action = SignalAction::Default;
+            }
+        });
+        Ok((entry, stack_top))
+    }
+
+    /// 处理该进程用户空间中的缺页
+    ///
+    /// 共享内存和文件映射的页先在地址空间的锁外填充, 读文件期间不持有锁; 之后映射时它已经在对象中。
+    pub fn handle_page_fault(&self, address : VirtAddr, access : FaultAccess) -> Result<(), FaultError> {
+        if let Some((object, index)) = self.with_address_space(|space| space.shared_page(address)) {
+            object.frame(index).ok_or(FaultError::OutOfMemory)?;
+        }
+        self.address_space.lock().handle_page_fault(address, access)
+    }
+
+    /// 对地址空间进行操作
+    pub fn with_address_space<R>(&self, f : impl FnOnce(&mut AddressSpace) -> R) -> R {
+        f(&mut self.address_space.lock())
+    }
+
+    /// 让描述符 `fd` 引用 `descriptor`, 在 `start` 之前调用可以把标准输入输出重定向到管道
+    pub fn redirect(&self, fd : u64, descriptor : impl Into<Descriptor>) -> Result<(), Errno> {
+        let replaced = self.with_files(|files| files.set(fd, descriptor))?;
+        drop(replaced);
+        Ok(())
+    }
+
+    /// 对文件描述符表进行操作, 不能在其中访问用户内存
+    pub fn with_files<R>(&self, f : impl FnOnce(&mut FileTable) -> R) -> R {
+        f(&mut self.files.lock())
+    }
+
+    fn with_signals<R>(&self, f : impl FnOnce(&mut Signals) -> R) -> R {
+        f(&mut self.signals.lock())
+    }
+
+    /// 向该进程发送信号 `signal`, 为 0 时只检查进程是否存在
+    ///
+    /// 被忽略的信号直接丢弃; 否则唤醒进程的线程, 让阻塞中的系统调用返回 EINTR。
+    pub fn kill(&self, signal : u64) -> Result<(), Errno> {
+        if signal >= NSIG {
+            return Err(Errno::EINVAL);
+        }
+        if signal == 0 {
+            return Ok(());
+        }
+        let deliver = self.with_signals(|signals| {
+            let ignored = signals.actions[signal as usize] == SignalAction::Ignore && signal != SIGKILL;
+            if !ignored {
+                signals.pending |= 1 << signal;
+            }
+            !ignored
+        });
+        if deliver {
+            let threads : Vec<ThreadId> = PROCESS_THREADS.lock().iter()
+                .filter(|(_, process)| **process == self.id)
+                .map(|(thread, _)| *thread)
+                .collect();
+            for thread in threads {
+                scheduler::wake(thread);
+            }
+        }
+        Ok(())
+    }
+
+    /// 设置信号 `signal` 的处理方式, 返回原来的处理方式
+    ///
+    /// SIGKILL 不能改变; SIGSEGV 和 SIGILL 来自异常, 没有可以恢复的现场, 也只能结束进程。
+    pub fn set_signal_action(&self, signal : u64, action : SignalAction) -> Result<SignalAction, Errno> {
+        if signal == 0 || signal >= NSIG || signal == SIGKILL || signal == SIGSEGV || signal == SIGILL {
+            return Err(Errno::EINVAL);
+        }
+        Ok(self.with_signals(|signals| {
+            if action == SignalAction::Ignore {
+                signals.pending &= !(1 << signal);
+            }
+            core::mem::replace(&mut signals.actions[signal as usize], action)
+        }))
+    }
+
+    /// 是否有尚未处理的信号
+    pub fn has_pending_signals(&self) -> bool {
+        self.with_signals(|signals| signals.pending != 0)
+    }
+
+    /// 取出编号最小的未处理信号及其处理方式
+    pub fn take_signal(&self) -> Option<(u64, SignalAction)> {
+        self.with_signals(|signals| {
+            if signals.pending == 0 {
+                return None;
+            }
+            let signal = signals.pending.trailing_zeros() as u64;
+            signals.pending &= !(1 << signal);
+            let action = match signal {
+                SIGKILL => SignalAction::Default,
+                _ => signals.actions[signal as usize],
+            };
+            Some((signal, action))
+        })
+    }
 
-        // let mut executor = Executor::new();
-        // executor.spawn(task);
-        // Self { task, executor }
+    /// 是否有按默认方式处理、要结束进程的信号
+    fn has_fatal_signal(&self) -> bool {
+        self.with_signals(|signals| (1..NSIG)
+            .filter(|signal| signals.pending & (1 << signal) != 0)
+            .any(|signal| signal == SIGKILL || signals.actions[signal as usize] == SignalAction::Default))
     }
 
-    pub fn load(elf_file : &ElfFile) {
+    /// 等待该进程的子进程结束并回收它, 返回子进程的 id 和状态; `pid` 为 `None` 时等待任意一个子进程
+    ///
+    /// 没有符合的子进程时返回 ECHILD; 子进程都还在运行时, `blocking` 为 false 则返回 `None`。
+    pub fn wait_child(&self, pid : Option<u64>, blocking : bool) -> Result<Option<(ProcessId, ExitStatus)>, Errno> {
+        let matches = |id : ProcessId, parent : ProcessId| parent == self.id && pid.map_or(true, |pid| pid == id.0);
+        let try_wait = || {
+            let processes = PROCESSES.lock();
+            let mut exited = EXITED.lock();
+            let found = exited.iter().find(|(id, (parent, _))| matches(**id, *parent)).map(|(id, _)| *id);
+            if let Some((id, (_, status))) = found.and_then(|id| exited.remove_entry(&id)) {
+                return Some(Ok(Some((id, status))));
+            }
+            if !processes.values().any(|child| child.parent.map_or(false, |parent| matches(child.id, parent))) {
+                return Some(Err(Errno::ECHILD));
+            }
+            None
+        };
+        match blocking {
+            true => wait_interruptible(&EXIT_WAITERS, try_wait),
+            false => try_wait().unwrap_or(Ok(None)),
+        }
     }
+}
+
+/// 当前线程从 `entry` 开始执行用户态代码, 栈顶为 `stack_top`
+///
+/// 当前线程的内核栈被丢弃，调用者不能再持有需要释放的资源。
+pub fn enter_user_mode(entry : VirtAddr, stack_top : VirtAddr) -> ! {
+    let selectors = &cpu::current().tables.selectors;
+    let (code, data) = (selectors.user_code_selector.0, selectors.user_data_selector.0);
+    unsafe { asm_enter_user_mode(entry.as_u64(), stack_top.as_u64(), code, data) }
+}
+
+/// 当前线程所运行的进程, 内核线程返回 `None`
+pub fn current() -> Option<Arc<Process>> {
+    let thread = scheduler::current_id()?;
+    let id = *PROCESS_THREADS.lock().get(&thread)?;
+    PROCESSES.lock().get(&id).cloned()
+}
+
+/// 所有未结束的进程
+pub fn processes() -> Vec<Arc<Process>> {
+    PROCESSES.lock().values().cloned().collect()
+}
+
+/// 未结束的进程 `id`
+pub fn find(id : u64) -> Option<Arc<Process>> {
+    PROCESSES.lock().get(&ProcessId(id)).cloned()
+}
+
+/// 在 `queue` 上等待 `condition` 返回 `Some`, 当前进程收到信号时提前返回 EINTR
+///
+/// 内核线程没有进程, 不会被打断。
+pub fn wait_interruptible<R>(queue : &WaitQueue, mut condition : impl FnMut() -> Option<Result<R, Errno>>) -> Result<R, Errno> {
+    let process = current();
+    let result = queue.wait_until(|| condition().or_else(|| match &process {
+        Some(process) if process.has_pending_signals() => Some(Err(Errno::EINTR)),
+        _ => None,
+    }));
+    if let Err(Errno::EINTR) = result {
+        // 离开之前可能已经被 notify_one 选中, 把唤醒交给下一个等待者
+        queue.notify_one();
+    }
+    result
+}
 
-    pub fn run(&mut self) -> isize {
-        todo!()
-        // self.executor.spawn((self.code)(self.task));
-        // self.executor.run()
+/// 当前进程有要结束它的信号时结束它, 由中断了用户态的时钟中断调用
+pub fn check_fatal_signals() {
+    let signal = current().filter(|process| process.has_fatal_signal()).and_then(|process| process.take_signal());
+    if let Some((signal, _)) = signal {
+        terminate_current(signal, "killed");
     }
 }
 
+/// 以信号 `signal` 结束当前进程及运行它的线程, 可以在异常处理中调用
+pub fn terminate_current(signal : u64, reason : &str) -> ! {
+    if let Some(process) = remove_current(ExitStatus::Signaled(signal)) {
+        serial_println!("process {} ({}) terminated by signal {}: {}", process.id.as_u64(), process.name(), signal, reason);
+        release(process);
+    }
+    scheduler::exit();
+}
+
+/// 进程主动退出, 由 EXIT 系统调用使用
+pub fn exit_current(code : i64) -> ! {
+    if let Some(process) = remove_current(ExitStatus::Exited(code as u8)) {
+        serial_println!("process {} ({}) exited with code {}", process.id.as_u64(), process.name(), code);
+        release(process);
+    }
+    scheduler::exit();
+}
+
+/// 从进程表中移除当前进程并记下状态, 父进程还在时留给它等待
+///
+/// 该进程已结束的子进程不再有人等待, 一起丢弃。
+fn remove_current(status : ExitStatus) -> Option<Arc<Process>> {
+    let process = scheduler::current_id().and_then(|thread| {
+        let id = PROCESS_THREADS.lock().remove(&thread)?;
+        let mut processes = PROCESSES.lock();
+        let process = processes.remove(&id)?;
+        *process.status.lock() = Some(status);
+        let mut exited = EXITED.lock();
+        exited.retain(|_, (parent, _)| *parent != id);
+        if let Some(parent) = process.parent.filter(|parent| processes.contains_key(parent)) {
+            exited.insert(id, (parent, status));
+        }
+        Some(process)
+    });
+    EXIT_WAITERS.notify_all();
+    EXIT_TASKS.notify_all();
+    process
+}
+
+/// 释放进程, 当前线程先离开它的页表
+fn release(process : Arc<Process>) {
+    scheduler::set_page_table(memory::kernel_page_table());
+    drop(process);
+}
+
+/// 程序或文件的完整路径, 相对路径在数据盘的根目录中, 如 `firstapp` 为 `/disk/firstapp`
+pub fn file_path(filename : &str) -> String {
+    match filename.starts_with('/') {
+        true => filename.to_string(),
+        false => alloc::format!("{}/{}", vfs::DISK_MOUNT_POINT, filename),
+    }
+}
+
+/// 通过 VFS 读取整个文件, 路径见 `file_path`
+pub fn read_file(filename : &str) -> Result<Vec<u8>, &'static str> {
+    vfs::read_all(&file_path(filename)).map_err(|errno| match errno {
+        Errno::ENOENT => "file not found",
+        _ => "cannot read file",
+    })
+}
+
+/// ET_DYN 映像的装载基址, 在用户空间开头随机选择
+fn pie_base() -> u64 {
+    USER_SPACE_START + (asm_random_u64() % PIE_BASE_SLOTS) * HUGE_PAGE_SIZE
+}
+
+/// 在栈顶 `top` 之下放入参数, 返回进程开始运行时的栈指针
+///
+/// 从高到低依次为: AT_RANDOM 的 16 字节和所有字符串, 辅助向量(以 AT_NULL 结束), envp(以 0 结束),
+/// argv(以 0 结束), argc。栈指针指向 argc, 按 16 字节对齐。
+fn build_stack(space : &mut AddressSpace, top : u64, args : &[&str], env : &[&str], auxv : &[(u64, u64)]) -> Result<VirtAddr, &'static str> {
+    let strings_size : usize = args.iter().chain(env.iter()).map(|s| s.len() + 1).sum();
+    if strings_size > MAX_ARGUMENT_SIZE {
+        return Err("arguments too long");
+    }
+    let area_start = (top - 16 - strings_size as u64) & !15;
+    let mut area = Vec::with_capacity(16 + strings_size);
+    area.extend_from_slice(&asm_random_u64().to_le_bytes());
+    area.extend_from_slice(&asm_random_u64().to_le_bytes());
+    let mut pointers = Vec::with_capacity(args.len() + env.len());
+    for string in args.iter().chain(env.iter()) {
+        pointers.push(area_start + area.len() as u64);
+        area.extend_from_slice(string.as_bytes());
+        area.push(0);
+    }
+
+    let mut words = Vec::new();
+    words.push(args.len() as u64);
+    words.extend_from_slice(&pointers[..args.len()]);
+    words.push(0);
+    words.extend_from_slice(&pointers[args.len()..]);
+    words.push(0);
+    for (kind, value) in auxv.iter().chain([(AT_RANDOM, area_start), (AT_NULL, 0)].iter()) {
+        words.push(*kind);
+        words.push(*value);
+    }
+    let stack_pointer = (area_start - words.len() as u64 * 8) & !15;
+    let bytes : Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    space.write_bytes(VirtAddr::new(stack_pointer), &bytes).map_err(|_| "out of memory")?;
+    space.write_bytes(VirtAddr::new(area_start), &area).map_err(|_| "out of memory")?;
+    Ok(VirtAddr::new(stack_pointer))
+}
+
+/// 一个 PT_LOAD 段: 文件 `offset` 开始的 `file_size` 字节链接在地址 `address`, 总字节数 `mem_size`
+struct LoadSegment {
+    address : u64,
+    offset : usize,
+    file_size : usize,
+    mem_size : u64,
+    flags : VmaFlags,
+    kind : VmaKind,
+}
+
 struct ProcessManager {
-    pub modules : BTreeMap<String,ModuleLoadedInfo>,
 }
 
 impl ProcessManager {
     pub fn new() -> ProcessManager {
         ProcessManager {
-            modules: BTreeMap::new(),
         }
     }
 
-    pub fn read(&mut self, filename : &str) -> Vec<u8> {
-        let driver = Rc::new(IDE_DISKS[1]);
-        let mut data : [u32;SECTOR_SIZE] = [0;SECTOR_SIZE];
-        //读取启动扇区
-        let _ = driver.read(0, 1, &mut data);
-        let boot_sector = unsafe {*(data.as_mut_ptr() as *mut Fat16BootSector)};
-        let super_block = Rc::new(FAT16SuperBlock::new(driver.clone(), Rc::new(boot_sector)));
-
-        let slice = b"FIRSTAPP   ";
-        let result = super_block.root.find_children(slice, Attributes::empty());
-        serial_println!("found {} app", result.borrow().len());
-
-        let result = super_block.root.open_file(&super_block,result.borrow()[0].clone());
-        let file = result.expect("can not open file");
-        file.read_all_bytes(&super_block)
-    }
-
-    pub fn load(&mut self, filename : &String, physical_memory_offset :u64) {    
-        if !self.modules.contains_key(filename) {
-            let mut module = ModuleInfo::new(&filename,0,0);
-            let mut module = ModuleLoadedInfo::new(module);
-            let all_bytes = self.read(&filename);
-            let elf_file = ElfFile::new(&all_bytes).expect("elf format error");
-            
-            for program_header  in elf_file.program_iter() {
-                match program_header.get_type() {
-                    Ok(t) => {
-                        match t {
-                            Type::Phdr => {
-                                // serial_println!("Phdr: ...");
-                            },
-                            Type::Load => {
-                                // 需要进行加载 文件 offset 开始的 file_size 字节到 虚拟地址 virtual_addr,
-                                // 总字节数 mem_size, 对齐字节数为 align, 页读写跑属性为 flag
-                                let page_count = ((program_header.mem_size() as usize - 1) / DEFAULT_PAGE_SIZE) + 1;
-
-                                //需要映射到进程的地址
-                                let mut virtual_address = program_header.virtual_addr() as usize;
-                                let mut offset = program_header.offset() as usize;
-                                let mut copy_size = program_header.file_size() as usize;
-                                serial_println!("virtual_address = 0x{:016x}, offset = {}, copy_size = {}", virtual_address, offset, copy_size);
-
-                                for _ in 0..page_count {
-                                    let temp = module.page_by_address(virtual_address, physical_memory_offset);
-                                    let data = temp.get_data();
-                                    //复制内容
-                                    unsafe {
-                                        let size = if copy_size > 4096 { 4096 } else { copy_size };
-                                        for i in 0..size {
-                                            (*data)[i] = all_bytes[offset + i];
-                                        }
-                                        copy_size -= size;
-                                        offset += size;
-                                    }
-                                    virtual_address += DEFAULT_PAGE_SIZE;
-                                }
-                            },
-                            Type::OsSpecific(v) => {
-                                serial_println!("OsSpecific: v = 0x{:08x}", v);
-                            },
-                            // Type::Dynamic => {},
-                            // Type::Interp => {},
-                            // Type::Note => {},
-                            // Type::ShLib => {},
-                            // Type::Tls => {},
-                            // Type::GnuRelro => {},
-                            // Type::ProcessorSpecific(_) => {},
-                            _ => {},
-                        }
-                    },
-                    Err(_) => {
-                        serial_println!("Error");
+    pub fn read(&mut self, filename : &str) -> Result<Vec<u8>, &'static str> {
+        read_file(filename)
+    }
+
+    /// 读取 ELF 文件并为它建立地址空间，返回地址空间、程序入口和放好参数后的栈指针
+    ///
+    /// 只建立 VMA, 除了放参数的栈顶外不分配任何页: 代码和数据在第一次访问时从文件内容中复制。
+    pub fn load(&mut self, filename : &String, args : &[&str], env : &[&str]) -> Result<(AddressSpace, VirtAddr, VirtAddr), &'static str> {
+        let mut all_bytes = self.read(filename)?;
+        let elf_file = ElfFile::new(&all_bytes[..])?;
+        let dynamic = elf_file.header.pt2.type_().as_type() == header::Type::SharedObject;
+        let base = if dynamic { pie_base() } else { 0 };
+        let mut segments = Vec::new();
+        let mut program_headers = None;
+
+        for program_header in elf_file.program_iter() {
+            match program_header.get_type() {
+                Ok(Type::Load) => {
+                    // 文件 offset 开始的 file_size 字节对应虚拟地址 virtual_addr, 总字节数 mem_size, 其余部分为 0
+                    let virtual_address = program_header.virtual_addr();
+                    let offset = program_header.offset() as usize;
+                    let file_size = program_header.file_size() as usize;
+                    let mem_size = program_header.mem_size();
+                    serial_println!("virtual_address = 0x{:016x}, offset = {}, file_size = {}, mem_size = {}",
+                        virtual_address + base, offset, file_size, mem_size);
+                    if mem_size == 0 {
+                        continue;
+                    }
+                    if offset + file_size > all_bytes.len() || file_size as u64 > mem_size {
+                        return Err("segment out of file");
                     }
+
+                    let elf_flags = program_header.flags();
+                    let mut flags = VmaFlags::empty();
+                    if elf_flags.is_read() { flags |= VmaFlags::READ; }
+                    if elf_flags.is_write() { flags |= VmaFlags::WRITE; }
+                    if elf_flags.is_execute() { flags |= VmaFlags::EXECUTE; }
+                    let kind = if elf_flags.is_execute() { VmaKind::Code } else { VmaKind::Data };
+                    segments.push(LoadSegment { address : virtual_address, offset, file_size, mem_size, flags, kind });
+                },
+                Ok(Type::Phdr) => program_headers = Some(program_header.virtual_addr()),
+                Ok(Type::OsSpecific(v)) => {
+                    serial_println!("OsSpecific: v = 0x{:08x}", v);
+                },
+                Ok(_) => {},
+                Err(_) => {
+                    serial_println!("Error");
                 }
-                // pub type_: Type_,
-                // pub flags: Flags,
-                // pub offset: u64,
-                // pub virtual_addr: u64,
-                // pub physical_addr: u64,
-                // pub file_size: u64,
-                // pub mem_size: u64,
-                // pub align: u64,
             }
+        }
+
+        let entry = VirtAddr::try_new(elf_file.header.pt2.entry_point() + base).map_err(|_| "invalid entry point")?;
+        // 没有 PT_PHDR 时程序头表所在的 PT_LOAD 段决定它的地址
+        let header_offset = elf_file.header.pt2.ph_offset() as usize;
+        let program_headers = program_headers.or_else(|| segments.iter()
+            .find(|segment| segment.offset <= header_offset && header_offset < segment.offset + segment.file_size)
+            .map(|segment| segment.address + (header_offset - segment.offset) as u64));
+        let auxv = [
+            (AT_PHDR, program_headers.map_or(0, |address| address + base)),
+            (AT_PHENT, elf_file.header.pt2.ph_entry_size() as u64),
+            (AT_PHNUM, elf_file.header.pt2.ph_count() as u64),
+            (AT_PAGESZ, PAGE_SIZE),
+            (AT_ENTRY, entry.as_u64()),
+        ];
+        // 用户程序不能引用其它模块的符号, 只有映像自己定义的符号可用
+        let relocations = if dynamic { modules::relocations(&elf_file, base, |_| None)? } else { Vec::new() };
+        for relocation in relocations {
+            let segment = segments.iter()
+                .find(|segment| segment.address <= relocation.address
+                    && relocation.address + 8 <= segment.address + segment.file_size as u64)
+                .ok_or("relocation outside the image")?;
+            let offset = segment.offset + (relocation.address - segment.address) as usize;
+            all_bytes[offset..offset + 8].copy_from_slice(&relocation.value.to_le_bytes());
+        }
 
-            // init stack pages
-            let mut virtual_address = DEFAULT_STACK_ADDRESS;
-            let mut stack_size  = 0;
-            while stack_size < DEFAULT_STACK_SIZE {
-                let _ = module.page_by_address(virtual_address, physical_memory_offset);
-                virtual_address += DEFAULT_PAGE_SIZE;
-                stack_size += DEFAULT_PAGE_SIZE;
+        let all_bytes = Arc::new(all_bytes);
+        let mut address_space = AddressSpace::new()?;
+        let mut image_end = USER_SPACE_START;
+        for segment in segments {
+            let start = segment.address.checked_add(base).ok_or("segment out of user space")?;
+            let end = start.checked_add(segment.mem_size).ok_or("segment out of user space")?;
+            if end > MMAP_START {
+                return Err("segment overlaps the mmap area");
             }
+            let backing = VmaBacking::Image {
+                data : all_bytes.clone(),
+                offset : segment.offset,
+                address : start,
+                size : segment.file_size,
+            };
+            address_space.add_vma(Vma::new(start, end, segment.kind, segment.flags, backing))?;
+            image_end = image_end.max(end);
+        }
+
+        match address_space.find_vma(entry) {
+            Some(vma) if vma.flags.contains(VmaFlags::EXECUTE) => {},
+            _ => return Err("entry point is not in a code segment"),
         }
+
+        // 堆紧跟在映像之后, 初始为空
+        let heap_start = (image_end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        address_space.add_vma(Vma::new(heap_start, heap_start, VmaKind::Heap,
+            VmaFlags::READ | VmaFlags::WRITE, VmaBacking::Anonymous))?;
+
+        // 栈在用户空间的顶端, 缺页时向下增长
+        let stack_start = DEFAULT_STACK_ADDRESS as u64;
+        address_space.add_vma(Vma::new(stack_start, stack_start + DEFAULT_STACK_SIZE as u64, VmaKind::Stack,
+            VmaFlags::READ | VmaFlags::WRITE | VmaFlags::GROWS_DOWN, VmaBacking::Anonymous))?;
+        let stack_pointer = build_stack(&mut address_space, stack_start + DEFAULT_STACK_SIZE as u64, args, env, &auxv)?;
+
+        Ok((address_space, entry, stack_pointer))
     }
 
     pub fn print(elf_file : &ElfFile) {
@@ -214,9 +695,4 @@ impl ProcessManager {
             serial_println!("program: {:?}", p);
         }
     }
-
-    pub fn run(&mut self, filename : &String) {
-        //
-    }
-
-}
\ No newline at end of file
+}
@@ -0,0 +1,402 @@
+// 内核线程调度器
+// 每个线程固定在一个处理器上运行，每个处理器有自己的就绪队列和一个空闲线程。
+// LAPIC 定时器中断调用 `tick` 进行抢占，线程也可以通过 `yield_now`/`block_current` 主动让出处理器。
+// 调度器的锁持有期间关闭中断，因此不会被本处理器上的中断打断而死锁。
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use alloc::{boxed::Box, collections::{BTreeMap, VecDeque}, string::{String, ToString}, vec, vec::Vec};
+use x86_64::{VirtAddr, instructions::interrupts, registers::control::Cr3, structures::paging::PhysFrame};
+use crate::{architecture::x86_64_asm::{asm_switch_context, asm_thread_entry_trampoline}, memory};
+use super::{cpu, sync::{IrqSpinLock, SCHEDULER_LOCK_LEVEL}};
+
+/// 内核线程默认栈大小
+pub const DEFAULT_THREAD_STACK_SIZE : usize = 32 * 1024;
+/// 空闲线程只执行 hlt, 栈可以小一些
+const IDLE_THREAD_STACK_SIZE : usize = 8 * 1024;
+/// 新线程的初始 rflags, 中断关闭, 由 `thread_main` 打开
+const INITIAL_RFLAGS : u64 = 0x2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Ready,
+    Running,
+    Blocked,
+    Dead,
+}
+
+struct Thread {
+    id : ThreadId,
+    name : String,
+    state : ThreadState,
+    /// 所属处理器的序号
+    cpu : usize,
+    /// 切换出去时保存的栈指针
+    rsp : u64,
+    /// 线程栈, 只用来持有内存; 处理器启动时的线程使用原有的栈, 为 `None`
+    _stack : Option<Vec<u8>>,
+    entry : Option<Box<dyn FnOnce() + Send + 'static>>,
+    /// 线程运行时使用的 4 级页表, 用户进程的线程使用进程的页表
+    page_table : PhysFrame,
+    /// 在线程阻塞之前就被唤醒, 下一次 `block_current` 直接返回
+    wakeup_pending : bool,
+}
+
+impl Thread {
+    fn new(name : &str, cpu : usize, stack_size : usize, page_table : PhysFrame, entry : Box<dyn FnOnce() + Send + 'static>) -> Box<Thread> {
+        let id = ThreadId::new();
+        let mut stack = vec![0u8; stack_size];
+        let top = (stack.as_mut_ptr() as u64 + stack_size as u64) & !0xF;
+
+        // 与 asm_switch_context 的出栈顺序对应: r15 r14 r13 r12 rbx rbp rflags 返回地址
+        let frame : [u64; 8] = [0, 0, 0, id.0, 0, 0, INITIAL_RFLAGS, asm_thread_entry_trampoline as usize as u64];
+        let rsp = top - (frame.len() * 8) as u64;
+        unsafe {
+            core::ptr::copy_nonoverlapping(frame.as_ptr(), rsp as *mut u64, frame.len());
+        }
+
+        Box::new(Thread {
+            id,
+            name : name.to_string(),
+            state : ThreadState::Ready,
+            cpu,
+            rsp,
+            _stack : Some(stack),
+            entry : Some(entry),
+            page_table,
+            wakeup_pending : false,
+        })
+    }
+
+    /// 线程栈的栈顶, 从用户态进入内核时使用
+    fn kernel_stack_top(&self) -> Option<VirtAddr> {
+        self._stack.as_ref().map(|stack| VirtAddr::new((stack.as_ptr() as u64 + stack.len() as u64) & !0xF))
+    }
+}
+
+/// 一次线程切换所需的信息
+struct Switch {
+    /// 旧线程保存 rsp 的位置
+    old_rsp : *mut u64,
+    new_rsp : u64,
+    page_table : PhysFrame,
+    kernel_stack_top : Option<VirtAddr>,
+}
+
+/// 线程信息, 供调试和 `ps` 之类的命令使用
+#[derive(Debug, Clone)]
+pub struct ThreadInfo {
+    pub id : ThreadId,
+    pub name : String,
+    pub state : ThreadState,
+    pub cpu : usize,
+}
+
+struct Scheduler {
+    threads : BTreeMap<ThreadId, Box<Thread>>,
+    /// 每个处理器的就绪队列
+    run_queues : Vec<VecDeque<ThreadId>>,
+    /// 每个处理器正在运行的线程
+    current : Vec<Option<ThreadId>>,
+    /// 每个处理器的空闲线程, 就绪队列为空时运行
+    idle : Vec<Option<ThreadId>>,
+}
+
+impl Scheduler {
+    const fn new() -> Scheduler {
+        Scheduler {
+            threads : BTreeMap::new(),
+            run_queues : Vec::new(),
+            current : Vec::new(),
+            idle : Vec::new(),
+        }
+    }
+
+    fn ensure_cpu(&mut self, cpu : usize) {
+        while self.run_queues.len() <= cpu {
+            self.run_queues.push(VecDeque::new());
+            self.current.push(None);
+            self.idle.push(None);
+        }
+    }
+
+    /// 预留就绪队列的容量，使定时器中断中入队时不需要分配内存
+    fn reserve_queues(&mut self) {
+        let count = self.threads.len();
+        for queue in self.run_queues.iter_mut() {
+            if queue.capacity() < count {
+                queue.reserve(count - queue.len());
+            }
+        }
+    }
+
+    fn add(&mut self, thread : Box<Thread>) -> ThreadId {
+        let id = thread.id;
+        let cpu = thread.cpu;
+        self.ensure_cpu(cpu);
+        self.threads.insert(id, thread);
+        self.reserve_queues();
+        self.run_queues[cpu].push_back(id);
+        id
+    }
+
+    /// 就绪队列最短的处理器
+    fn least_loaded_cpu(&self) -> usize {
+        (0..ONLINE_CPUS.load(Ordering::Acquire).max(1))
+            .min_by_key(|cpu| self.run_queues.get(*cpu).map(|q| q.len()).unwrap_or(0))
+            .unwrap_or(0)
+    }
+
+    /// 选出下一个线程，并返回切换所需的信息
+    ///
+    /// `state` 是当前线程切换出去后的状态。
+    fn switch(&mut self, cpu : usize, state : ThreadState) -> Option<Switch> {
+        let current = self.current[cpu]?;
+        let idle = self.idle[cpu];
+
+        if state == ThreadState::Blocked {
+            let thread = self.threads.get_mut(&current)?;
+            if core::mem::replace(&mut thread.wakeup_pending, false) {
+                return None;
+            }
+        }
+
+        let next = match self.run_queues[cpu].pop_front() {
+            Some(next) => next,
+            // 当前线程仍可运行, 继续运行它
+            None if state == ThreadState::Ready => return None,
+            None => idle?,
+        };
+        if next == current {
+            return None;
+        }
+
+        {
+            let thread = self.threads.get_mut(&current)?;
+            thread.state = state;
+        }
+        if state == ThreadState::Ready && Some(current) != idle {
+            self.run_queues[cpu].push_back(current);
+        }
+
+        let (new_rsp, page_table, kernel_stack_top) = {
+            let thread = self.threads.get_mut(&next)?;
+            thread.state = ThreadState::Running;
+            (thread.rsp, thread.page_table, thread.kernel_stack_top())
+        };
+        self.current[cpu] = Some(next);
+        let old_rsp = &mut self.threads.get_mut(&current)?.rsp as *mut u64;
+        Some(Switch { old_rsp, new_rsp, page_table, kernel_stack_top })
+    }
+
+    /// 取出属于 `cpu` 的已退出线程。
+    ///
+    /// 只能由 `cpu` 自己调用: 这时它已经切换到了别的线程，退出线程的栈不再被使用。
+    fn take_dead(&mut self, cpu : usize) -> Vec<Box<Thread>> {
+        let current = self.current.get(cpu).copied().flatten();
+        let dead : Vec<ThreadId> = self.threads.values()
+            .filter(|t| t.state == ThreadState::Dead && t.cpu == cpu && Some(t.id) != current)
+            .map(|t| t.id)
+            .collect();
+        dead.iter().filter_map(|id| self.threads.remove(id)).collect()
+    }
+}
+
+static SCHEDULER: IrqSpinLock<Scheduler> = IrqSpinLock::with_level(Scheduler::new(), SCHEDULER_LOCK_LEVEL);
+/// 已经调用过 `init_cpu` 的处理器数
+static ONLINE_CPUS: AtomicUsize = AtomicUsize::new(0);
+
+/// 在关中断的情况下切换到当前处理器的下一个线程
+fn schedule(state : ThreadState) {
+    interrupts::without_interrupts(|| {
+        if ONLINE_CPUS.load(Ordering::Acquire) == 0 {
+            return;
+        }
+        let cpu = cpu::current_index();
+        let switch = {
+            let mut scheduler = SCHEDULER.lock();
+            if cpu >= scheduler.current.len() {
+                return;
+            }
+            scheduler.switch(cpu, state)
+        };
+        // 锁已释放; 只有本处理器会运行这两个线程，保存的位置在切换完成前不会被修改
+        if let Some(switch) = switch {
+            unsafe {
+                // 内核映射在所有页表中都相同, 先切换页表再切换栈是安全的
+                let (active, flags) = Cr3::read();
+                if active != switch.page_table {
+                    Cr3::write(switch.page_table, flags);
+                }
+                if let Some(top) = switch.kernel_stack_top {
+                    cpu::current().tables.set_kernel_stack(top);
+                }
+                asm_switch_context(switch.old_rsp, switch.new_rsp);
+            }
+        }
+    });
+}
+
+/// 新线程的 Rust 入口, 由 `asm_thread_entry_trampoline` 调用
+pub(crate) extern "C" fn thread_main(id : u64) -> ! {
+    let entry = {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.threads.get_mut(&ThreadId(id)).and_then(|t| t.entry.take())
+    };
+    interrupts::enable();
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit();
+}
+
+fn idle_main() {
+    loop {
+        reap();
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// 把当前处理器加入调度：当前的执行流成为一个线程，并为它创建空闲线程。
+///
+/// 须在 `cpu::init` 之后、打开定时器抢占之前调用。
+pub fn init_cpu() {
+    let cpu = cpu::current_index();
+    let idle = Thread::new("idle", cpu, IDLE_THREAD_STACK_SIZE, memory::kernel_page_table(), Box::new(idle_main));
+    {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.ensure_cpu(cpu);
+
+        let main = Box::new(Thread {
+            id : ThreadId::new(),
+            name : alloc::format!("cpu{}", cpu),
+            state : ThreadState::Running,
+            cpu,
+            rsp : 0,
+            _stack : None,
+            entry : None,
+            page_table : memory::kernel_page_table(),
+            wakeup_pending : false,
+        });
+        scheduler.current[cpu] = Some(main.id);
+        scheduler.threads.insert(main.id, main);
+
+        let idle_id = idle.id;
+        scheduler.threads.insert(idle_id, idle);
+        scheduler.idle[cpu] = Some(idle_id);
+        scheduler.reserve_queues();
+    }
+    ONLINE_CPUS.fetch_add(1, Ordering::AcqRel);
+}
+
+/// 创建内核线程, 放到就绪队列最短的处理器上
+pub fn spawn(name : &str, entry : impl FnOnce() + Send + 'static) -> ThreadId {
+    let cpu = SCHEDULER.lock().least_loaded_cpu();
+    spawn_on(cpu, name, entry)
+}
+
+/// 在指定处理器上创建内核线程
+pub fn spawn_on(cpu : usize, name : &str, entry : impl FnOnce() + Send + 'static) -> ThreadId {
+    reap();
+    let thread = Thread::new(name, cpu, DEFAULT_THREAD_STACK_SIZE, memory::kernel_page_table(), Box::new(entry));
+    SCHEDULER.lock().add(thread)
+}
+
+/// 创建使用页表 `page_table` 的线程, 用于运行用户进程
+pub fn spawn_with_page_table(name : &str, page_table : PhysFrame, entry : impl FnOnce() + Send + 'static) -> ThreadId {
+    reap();
+    let cpu = SCHEDULER.lock().least_loaded_cpu();
+    let thread = Thread::new(name, cpu, DEFAULT_THREAD_STACK_SIZE, page_table, Box::new(entry));
+    SCHEDULER.lock().add(thread)
+}
+
+/// 修改当前线程的页表并立即切换过去
+pub fn set_page_table(page_table : PhysFrame) {
+    let mut scheduler = SCHEDULER.lock();
+    let cpu = cpu::current_index();
+    if let Some(id) = scheduler.current.get(cpu).copied().flatten() {
+        if let Some(thread) = scheduler.threads.get_mut(&id) {
+            thread.page_table = page_table;
+        }
+    }
+    let (active, flags) = Cr3::read();
+    if active != page_table {
+        unsafe { Cr3::write(page_table, flags) };
+    }
+}
+
+/// 当前线程, 调度器启动之前为 `None`
+pub fn current_id() -> Option<ThreadId> {
+    let scheduler = SCHEDULER.lock();
+    scheduler.current.get(cpu::current_index()).copied().flatten()
+}
+
+/// 让出处理器
+pub fn yield_now() {
+    schedule(ThreadState::Ready);
+}
+
+/// 阻塞当前线程直到 `wake` 被调用。
+///
+/// 如果在调用之前线程已经被唤醒，直接返回。
+pub fn block_current() {
+    schedule(ThreadState::Blocked);
+}
+
+/// 唤醒一个阻塞的线程，线程尚未阻塞时记下这次唤醒
+pub fn wake(id : ThreadId) {
+    let mut scheduler = SCHEDULER.lock();
+    let cpu = match scheduler.threads.get_mut(&id) {
+        Some(thread) if thread.state == ThreadState::Blocked => {
+            thread.state = ThreadState::Ready;
+            thread.cpu
+        },
+        Some(thread) => {
+            if thread.state != ThreadState::Dead {
+                thread.wakeup_pending = true;
+            }
+            return;
+        },
+        None => return,
+    };
+    scheduler.run_queues[cpu].push_back(id);
+}
+
+/// 结束当前线程
+pub fn exit() -> ! {
+    schedule(ThreadState::Dead);
+    unreachable!("dead thread was scheduled again");
+}
+
+/// 由 LAPIC 定时器中断调用, 时间片用完后切换到下一个就绪线程
+pub(crate) fn tick() {
+    schedule(ThreadState::Ready);
+}
+
+/// 释放已退出线程的栈, 不能在中断处理中调用
+pub fn reap() {
+    let dead = SCHEDULER.lock().take_dead(cpu::current_index());
+    drop(dead);
+}
+
+/// 所有线程的信息
+pub fn threads() -> Vec<ThreadInfo> {
+    SCHEDULER.lock().threads.values().map(|t| ThreadInfo {
+        id : t.id,
+        name : t.name.clone(),
+        state : t.state,
+        cpu : t.cpu,
+    }).collect()
+}
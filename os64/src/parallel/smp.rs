@@ -0,0 +1,276 @@
+//see also: https://wiki.osdev.org/SMP
+//see also: https://wiki.osdev.org/Symmetric_Multiprocessing
+// 启动应用处理器(AP)。
+// BSP 依次向每个 AP 发送 INIT-SIPI-SIPI, AP 从 SIPI 指定的 4KB 页(1MB 以下)开始以实模式执行。
+// 跳板代码(trampoline)直接从实模式切换到长模式：开启 PAE, 使用 BSP 的 CR3, 设置 EFER.LME,
+// 同时打开分页和保护模式，然后远跳转到 64 位代码段，在新分配的栈上调用 `ap_main`。
+use core::{arch::global_asm, ptr, sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}};
+use alloc::{boxed::Box, vec};
+use x86_64::{
+    PhysAddr, VirtAddr,
+    registers::control::Cr3,
+    structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB, Translate},
+};
+use crate::{api::user, global_descriptor_table::CpuTables, memory::phys_to_virt, serial_println};
+use super::{apic::{self, LocalApic, APIC_ICR_IOAPIC_INIT, ICR_START_UP, ICR_NO_SHORTHAND}, cpu, executor, interrupts, scheduler};
+
+/// AP 启动时使用的栈大小
+const AP_STACK_SIZE : usize = 64 * 1024;
+/// SIPI 的向量是页号，跳板必须位于 1MB 以下
+const TRAMPOLINE_LIMIT : u64 = 0x100000;
+
+global_asm!(
+    ".pushsection .text.ap_trampoline, \"ax\"",
+    ".global ap_trampoline_start",
+    ".global ap_trampoline_end",
+    ".global ap_trampoline_data",
+    // 跳板内各标签相对起始处的偏移, 实模式下用作段内地址
+    ".set AP_GDT, ap_trampoline_gdt - ap_trampoline_start",
+    ".set AP_GDT_POINTER, ap_trampoline_gdt_pointer - ap_trampoline_start",
+    ".set AP_FAR_JUMP, ap_trampoline_far_jump - ap_trampoline_start",
+    ".set AP_LONG_MODE, ap_trampoline_long_mode - ap_trampoline_start",
+    ".set AP_DATA, ap_trampoline_data - ap_trampoline_start",
+    ".code16",
+    "ap_trampoline_start:",
+    "cli",
+    "cld",
+    "mov ax, cs",
+    "mov ds, ax",
+    // ebx = 跳板的物理地址
+    "xor ebx, ebx",
+    "mov bx, ax",
+    "shl ebx, 4",
+    // 修正临时 GDT 的基址和远跳转的目标地址
+    "lea eax, [ebx + AP_GDT]",
+    "mov dword ptr [AP_GDT_POINTER + 2], eax",
+    "lea eax, [ebx + AP_LONG_MODE]",
+    "mov dword ptr [AP_FAR_JUMP + 2], eax",
+    "lgdt [AP_GDT_POINTER]",
+    // CR4.PAE
+    "mov eax, cr4",
+    "or eax, 1 << 5",
+    "mov cr4, eax",
+    "mov eax, dword ptr [AP_DATA]",
+    "mov cr3, eax",
+    // EFER.LME | EFER.NXE
+    "mov ecx, 0xC0000080",
+    "rdmsr",
+    "or eax, (1 << 8) | (1 << 11)",
+    "wrmsr",
+    // 清除 CD/NW, 设置 PG | WP | PE
+    "mov eax, cr0",
+    "and eax, 0x9FFFFFFF",
+    "or eax, 0x80010001",
+    "mov cr0, eax",
+    // jmp far dword 0x08:long_mode
+    "ap_trampoline_far_jump:",
+    ".byte 0x66, 0xEA",
+    ".long 0",
+    ".word 0x08",
+    ".code64",
+    "ap_trampoline_long_mode:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "xor ax, ax",
+    "mov fs, ax",
+    "mov gs, ax",
+    "mov ebx, ebx",
+    "mov rsp, qword ptr [rbx + AP_DATA + 8]",
+    "mov rdi, qword ptr [rbx + AP_DATA + 24]",
+    "mov rax, qword ptr [rbx + AP_DATA + 16]",
+    "call rax",
+    "ud2",
+    ".align 16",
+    "ap_trampoline_gdt:",
+    ".quad 0",
+    ".quad 0x00209A0000000000",     // 64 位代码段
+    ".quad 0x0000920000000000",     // 数据段
+    "ap_trampoline_gdt_pointer:",
+    ".word 23",
+    ".long 0",
+    ".align 8",
+    "ap_trampoline_data:",
+    ".quad 0, 0, 0, 0",
+    "ap_trampoline_end:",
+    ".popsection",
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_data: u8;
+}
+
+/// 跳板末尾的参数区, 与汇编中的偏移对应
+#[repr(C)]
+struct TrampolineData {
+    cr3 : u64,
+    stack_top : u64,
+    entry : u64,
+    argument : u64,
+}
+
+/// 传给 `ap_main` 的参数
+struct ApBoot {
+    index : usize,
+    apic_id : u8,
+    tables : &'static CpuTables,
+}
+
+/// 预留的跳板页的物理地址, 0 表示没有
+static TRAMPOLINE: AtomicU64 = AtomicU64::new(0);
+/// 正在启动的 AP 是否已进入 `ap_main`
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+/// 下一个 AP 的序号
+static NEXT_CPU_INDEX: AtomicUsize = AtomicUsize::new(1);
+
+/// 从 frame allocator 中预留一页 1MB 以下的内存给跳板使用。
+///
+/// 低端的内存会被最先分配出去，所以须在堆初始化之前调用。
+pub fn reserve_trampoline(frame_allocator : &mut impl FrameAllocator<Size4KiB>) -> Option<PhysAddr> {
+    let frame = frame_allocator.allocate_frame()?;
+    if frame.start_address().as_u64() >= TRAMPOLINE_LIMIT {
+        serial_println!("SMP: no free frame below 1MB for the AP trampoline");
+        return None;
+    }
+    TRAMPOLINE.store(frame.start_address().as_u64(), Ordering::Relaxed);
+    Some(frame.start_address())
+}
+
+/// 恒等映射跳板页：AP 打开分页时正在执行跳板中的代码
+fn identity_map(
+    address : PhysAddr,
+    mapper : &mut (impl Mapper<Size4KiB> + Translate),
+    frame_allocator : &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), &'static str> {
+    let virt = VirtAddr::new(address.as_u64());
+    match mapper.translate_addr(virt) {
+        Some(phys) if phys == address => return Ok(()),
+        Some(_) => return Err("trampoline address is already in use"),
+        None => {},
+    }
+    let page = Page::<Size4KiB>::containing_address(virt);
+    let frame = PhysFrame::containing_address(address);
+    unsafe {
+        mapper.map_to(page, frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE, frame_allocator)
+            .map_err(|_| "failed to map trampoline")?
+            .flush();
+    }
+    Ok(())
+}
+
+/// 复制跳板代码并返回参数区
+unsafe fn install_trampoline(address : PhysAddr) -> *mut TrampolineData {
+    let start = &ap_trampoline_start as *const u8;
+    let end = &ap_trampoline_end as *const u8;
+    let data = &ap_trampoline_data as *const u8;
+    let size = end as usize - start as usize;
+    assert!(size <= 4096, "AP trampoline too large");
+
+    let target = phys_to_virt(address).as_mut_ptr::<u8>();
+    ptr::copy_nonoverlapping(start, target, size);
+    target.add(data as usize - start as usize) as *mut TrampolineData
+}
+
+/// 启动一个 AP 并等待它进入 `ap_main`
+unsafe fn start_ap(lapic : &LocalApic, apic_id : u8, vector : u8) -> bool {
+    AP_STARTED.store(false, Ordering::SeqCst);
+
+    lapic.send_ipi(apic_id, 0, APIC_ICR_IOAPIC_INIT, ICR_NO_SHORTHAND);
+    apic::pit_sleep(10);
+
+    // 按规范发送两次 SIPI, 第一次成功后不再发送第二次
+    for _ in 0..2 {
+        lapic.send_ipi(apic_id, vector, ICR_START_UP, ICR_NO_SHORTHAND);
+        apic::pit_sleep(1);
+        if AP_STARTED.load(Ordering::SeqCst) {
+            return true;
+        }
+    }
+    for _ in 0..100 {
+        if AP_STARTED.load(Ordering::SeqCst) {
+            return true;
+        }
+        apic::pit_sleep(1);
+    }
+    false
+}
+
+/// 启动 MADT 中列出的所有 AP, 返回成功启动的个数。
+///
+/// 须在 `apic::init`、`cpu::init_bsp` 和 `scheduler::init_cpu` 之后调用，
+/// 跳板页须已由 `reserve_trampoline` 预留。
+pub fn init(
+    mapper : &mut (impl Mapper<Size4KiB> + Translate),
+    frame_allocator : &mut impl FrameAllocator<Size4KiB>,
+) -> Result<usize, &'static str> {
+    let madt = apic::madt().ok_or("APIC not initialized")?;
+    let address = match TRAMPOLINE.load(Ordering::Relaxed) {
+        0 => return Err("no trampoline reserved"),
+        address => PhysAddr::new(address),
+    };
+    identity_map(address, mapper, frame_allocator)?;
+
+    let data = unsafe { install_trampoline(address) };
+    let (level_4_table, _) = Cr3::read();
+    if level_4_table.start_address().as_u64() > u32::MAX as u64 {
+        return Err("level 4 page table above 4GB");
+    }
+
+    let lapic = LocalApic::current();
+    let bsp_id = lapic.id();
+    let vector = (address.as_u64() >> 12) as u8;
+    let mut started = 0;
+
+    for processor in madt.processors.iter().filter(|p| p.usable() && p.apic_id != bsp_id) {
+        let index = NEXT_CPU_INDEX.load(Ordering::Relaxed);
+        if index >= cpu::MAX_CPUS {
+            break;
+        }
+
+        let stack = Box::leak(vec![0u8; AP_STACK_SIZE].into_boxed_slice());
+        let stack_top = (stack.as_ptr() as u64 + AP_STACK_SIZE as u64) & !0xF;
+        let boot = Box::leak(Box::new(ApBoot {
+            index,
+            apic_id : processor.apic_id,
+            tables : CpuTables::allocate(),
+        }));
+        unsafe {
+            data.write_volatile(TrampolineData {
+                cr3 : level_4_table.start_address().as_u64(),
+                stack_top,
+                entry : ap_main as usize as u64,
+                argument : boot as *const ApBoot as u64,
+            });
+        }
+
+        if unsafe { start_ap(&lapic, processor.apic_id, vector) } {
+            NEXT_CPU_INDEX.fetch_add(1, Ordering::Relaxed);
+            started += 1;
+        } else {
+            serial_println!("SMP: processor {} (APIC id {}) did not start", processor.processor_id, processor.apic_id);
+        }
+    }
+
+    serial_println!("SMP: {} processors online", cpu::count());
+    Ok(started)
+}
+
+/// AP 的 Rust 入口，运行在跳板设置的栈上，此时中断是关闭的
+extern "C" fn ap_main(boot : &'static ApBoot) -> ! {
+    boot.tables.load();
+    cpu::init(boot.index, boot.apic_id, boot.tables);
+    interrupts::init_interrupt_descriptor_table();
+    user::init_cpu();
+
+    if let Err(e) = apic::init_ap() {
+        serial_println!("CPU {}: {}", boot.index, e);
+    }
+    scheduler::init_cpu();
+    AP_STARTED.store(true, Ordering::SeqCst);
+
+    x86_64::instructions::interrupts::enable();
+    executor::global().run();
+}
@@ -0,0 +1,666 @@
+// 内核的同步原语。
+// `IrqSpinLock` 持有期间关闭本处理器的中断, 可以与中断处理程序共用; 其余的锁拿不到时让当前线程阻塞,
+// 等待者排在 `WaitQueue` 中由调度器唤醒, 只能在线程中使用, 调度器启动之前退化为忙等。
+// `AsyncMutex` 和 `AsyncSemaphore` 供执行器中的任务使用, 拿不到时返回 Pending, 释放时由 Waker 重新调度任务。
+//
+// 调试构建检查有层级的 IrqSpinLock 的获取顺序: 同一处理器上只能在持有较低层级的锁时获取更高层级的锁,
+// 违反时 panic, 这样两把锁以相反顺序获取的死锁在第一次出现错误的顺序时就能发现。
+//see also: https://www.kernel.org/doc/html/latest/locking/lockdep-design.html
+use core::{cell::UnsafeCell, future::Future, mem::ManuallyDrop, ops::{Deref, DerefMut}, pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering}, task::{Context, Poll, Waker}};
+use alloc::collections::VecDeque;
+use x86_64::instructions::interrupts;
+use super::{cpu, scheduler::{self, ThreadId}};
+
+/// 锁层级的上限, 层级为 0 的锁不检查顺序
+pub const MAX_LOCK_LEVEL : u32 = 63;
+
+// 会嵌套获取的全局锁的层级, 从低到高: 调度器的锁内会分配内存, 堆增长时分配物理帧, 串口总是最后获取。
+/// 调度器的锁
+pub const SCHEDULER_LOCK_LEVEL : u32 = 60;
+/// 内核堆分配器的锁
+pub const ALLOCATOR_LOCK_LEVEL : u32 = 61;
+/// 全局物理帧分配器的锁
+pub const FRAME_ALLOCATOR_LOCK_LEVEL : u32 = 62;
+/// 串口的锁, 持有期间不再获取其它锁
+pub const SERIAL_LOCK_LEVEL : u32 = MAX_LOCK_LEVEL;
+
+/// 记下当前处理器获取了层级为 `level` 的锁, 已经持有同级或更高层级的锁时 panic
+fn acquire_level(level : u32) {
+    if !cfg!(debug_assertions) || level == 0 {
+        return;
+    }
+    if let Some(cpu) = cpu::try_current() {
+        let held = cpu.held_lock_levels.load(Ordering::Relaxed);
+        if held >> level != 0 {
+            panic!("lock order violation: acquiring level {} while holding levels {:#x}", level, held);
+        }
+        cpu.held_lock_levels.store(held | 1 << level, Ordering::Relaxed);
+    }
+}
+
+fn release_level(level : u32) {
+    if !cfg!(debug_assertions) || level == 0 {
+        return;
+    }
+    if let Some(cpu) = cpu::try_current() {
+        cpu.held_lock_levels.fetch_and(!(1 << level), Ordering::Relaxed);
+    }
+}
+
+/// 持有期间关闭中断的自旋锁
+///
+/// 多个锁的守卫要按获取的相反顺序释放, 每个守卫释放时恢复获取之前的中断状态。
+pub struct IrqSpinLock<T : ?Sized> {
+    level : u32,
+    inner : spin::Mutex<T>,
+}
+
+pub struct IrqSpinLockGuard<'a, T : ?Sized> {
+    guard : ManuallyDrop<spin::MutexGuard<'a, T>>,
+    level : u32,
+    /// 获取之前中断是否打开
+    enabled : bool,
+}
+
+impl<T> IrqSpinLock<T> {
+    pub const fn new(value : T) -> IrqSpinLock<T> {
+        IrqSpinLock::with_level(value, 0)
+    }
+
+    /// 有层级的锁, 调试构建中检查获取顺序; `level` 为 1 到 `MAX_LOCK_LEVEL`
+    pub const fn with_level(value : T, level : u32) -> IrqSpinLock<T> {
+        IrqSpinLock { level, inner : spin::Mutex::new(value) }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T : ?Sized> IrqSpinLock<T> {
+    pub fn lock(&self) -> IrqSpinLockGuard<'_, T> {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        acquire_level(self.level);
+        IrqSpinLockGuard { guard : ManuallyDrop::new(self.inner.lock()), level : self.level, enabled }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqSpinLockGuard<'_, T>> {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => {
+                acquire_level(self.level);
+                Some(IrqSpinLockGuard { guard : ManuallyDrop::new(guard), level : self.level, enabled })
+            },
+            None => {
+                if enabled {
+                    interrupts::enable();
+                }
+                None
+            },
+        }
+    }
+}
+
+impl<T : Default> Default for IrqSpinLock<T> {
+    fn default() -> IrqSpinLock<T> {
+        IrqSpinLock::new(T::default())
+    }
+}
+
+impl<T : ?Sized> Deref for IrqSpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T : ?Sized> DerefMut for IrqSpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T : ?Sized> Drop for IrqSpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        release_level(self.level);
+        if self.enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+/// 等待某个条件的线程队列
+///
+/// 等待者先把自己加入队列再检查条件, 条件改变的一方在之后调用 `notify_*`;
+/// 由于 `wake` 会记下尚未阻塞的线程的唤醒, 两者交错时也不会丢失唤醒。
+#[derive(Default)]
+pub struct WaitQueue {
+    waiters : IrqSpinLock<VecDeque<ThreadId>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> WaitQueue {
+        WaitQueue { waiters : IrqSpinLock::new(VecDeque::new()) }
+    }
+
+    /// 阻塞当前线程直到 `condition` 返回 `Some`, 调度器启动之前忙等
+    pub fn wait_until<R>(&self, mut condition : impl FnMut() -> Option<R>) -> R {
+        let id = scheduler::current_id();
+        loop {
+            if let Some(id) = id {
+                let mut waiters = self.waiters.lock();
+                if !waiters.contains(&id) {
+                    waiters.push_back(id);
+                }
+            }
+            if let Some(result) = condition() {
+                if let Some(id) = id {
+                    self.waiters.lock().retain(|waiter| *waiter != id);
+                }
+                return result;
+            }
+            match id {
+                Some(_) => scheduler::block_current(),
+                None => core::hint::spin_loop(),
+            }
+        }
+    }
+
+    /// 唤醒最早等待的线程
+    pub fn notify_one(&self) {
+        let waiter = self.waiters.lock().pop_front();
+        if let Some(id) = waiter {
+            scheduler::wake(id);
+        }
+    }
+
+    /// 唤醒所有等待的线程
+    pub fn notify_all(&self) {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        for id in waiters {
+            scheduler::wake(id);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiters.lock().is_empty()
+    }
+}
+
+/// 拿不到时阻塞当前线程的互斥锁, 不能在中断处理程序中使用
+pub struct Mutex<T : ?Sized> {
+    locked : AtomicBool,
+    waiters : WaitQueue,
+    value : UnsafeCell<T>,
+}
+
+unsafe impl<T : ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T : ?Sized + Send> Sync for Mutex<T> {}
+
+pub struct MutexGuard<'a, T : ?Sized> {
+    mutex : &'a Mutex<T>,
+}
+
+unsafe impl<T : ?Sized + Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value : T) -> Mutex<T> {
+        Mutex { locked : AtomicBool::new(false), waiters : WaitQueue::new(), value : UnsafeCell::new(value) }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T : ?Sized> Mutex<T> {
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        match self.try_lock() {
+            Some(guard) => guard,
+            None => self.waiters.wait_until(|| self.try_lock()),
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).ok()
+            .map(|_| MutexGuard { mutex : self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+impl<T : ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T : ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T : ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+        self.mutex.waiters.notify_one();
+    }
+}
+
+struct RwState {
+    readers : usize,
+    writer : bool,
+    /// 正在等待的写者, 有写者等待时新的读者也要等待, 以免写者一直拿不到锁
+    waiting_writers : usize,
+}
+
+/// 拿不到时阻塞当前线程的读写锁, 写者优先
+pub struct RwLock<T : ?Sized> {
+    state : IrqSpinLock<RwState>,
+    waiters : WaitQueue,
+    value : UnsafeCell<T>,
+}
+
+unsafe impl<T : ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T : ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+pub struct RwLockReadGuard<'a, T : ?Sized> {
+    lock : &'a RwLock<T>,
+}
+
+pub struct RwLockWriteGuard<'a, T : ?Sized> {
+    lock : &'a RwLock<T>,
+}
+
+unsafe impl<T : ?Sized + Sync> Sync for RwLockReadGuard<'_, T> {}
+unsafe impl<T : ?Sized + Sync> Sync for RwLockWriteGuard<'_, T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(value : T) -> RwLock<T> {
+        RwLock {
+            state : IrqSpinLock::new(RwState { readers : 0, writer : false, waiting_writers : 0 }),
+            waiters : WaitQueue::new(),
+            value : UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T : ?Sized> RwLock<T> {
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        self.waiters.wait_until(|| self.try_read())
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let mut state = self.state.lock();
+        if state.writer || state.waiting_writers > 0 {
+            return None;
+        }
+        state.readers += 1;
+        Some(RwLockReadGuard { lock : self })
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        if let Some(guard) = self.try_write() {
+            return guard;
+        }
+        self.state.lock().waiting_writers += 1;
+        self.waiters.wait_until(|| {
+            let mut state = self.state.lock();
+            if state.writer || state.readers > 0 {
+                return None;
+            }
+            state.writer = true;
+            state.waiting_writers -= 1;
+            Some(RwLockWriteGuard { lock : self })
+        })
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        let mut state = self.state.lock();
+        if state.writer || state.readers > 0 {
+            return None;
+        }
+        state.writer = true;
+        Some(RwLockWriteGuard { lock : self })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+impl<T : ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T : ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        let last = {
+            let mut state = self.lock.state.lock();
+            state.readers -= 1;
+            state.readers == 0
+        };
+        if last {
+            self.lock.waiters.notify_all();
+        }
+    }
+}
+
+impl<T : ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T : ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T : ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.lock().writer = false;
+        self.lock.waiters.notify_all();
+    }
+}
+
+/// 计数信号量, 没有许可时阻塞当前线程
+pub struct Semaphore {
+    permits : IrqSpinLock<usize>,
+    waiters : WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(permits : usize) -> Semaphore {
+        Semaphore { permits : IrqSpinLock::new(permits), waiters : WaitQueue::new() }
+    }
+
+    /// 取得一个许可, 没有时等待
+    pub fn acquire(&self) {
+        self.waiters.wait_until(|| self.try_acquire().then(|| ()))
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        let mut permits = self.permits.lock();
+        if *permits == 0 {
+            return false;
+        }
+        *permits -= 1;
+        true
+    }
+
+    /// 归还一个许可
+    pub fn release(&self) {
+        *self.permits.lock() += 1;
+        self.waiters.notify_one();
+    }
+
+    pub fn available(&self) -> usize {
+        *self.permits.lock()
+    }
+}
+
+/// 与 `Mutex` 一起使用的条件变量
+///
+/// 和其它实现一样可能虚假唤醒, 调用者要在循环中重新检查条件, 或者使用 `wait_while`。
+#[derive(Default)]
+pub struct Condvar {
+    /// 每次通知加一, 等待者据此判断释放锁之后是否有过通知
+    generation : AtomicU64,
+    waiters : WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Condvar {
+        Condvar { generation : AtomicU64::new(0), waiters : WaitQueue::new() }
+    }
+
+    /// 释放 `guard` 并等待通知, 返回前重新获取锁
+    pub fn wait<'a, T : ?Sized>(&self, guard : MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let generation = self.generation.load(Ordering::Acquire);
+        let mutex = guard.mutex;
+        drop(guard);
+        self.waiters.wait_until(|| (self.generation.load(Ordering::Acquire) != generation).then(|| ()));
+        mutex.lock()
+    }
+
+    /// 在 `condition` 为 true 时一直等待
+    pub fn wait_while<'a, T : ?Sized>(&self, mut guard : MutexGuard<'a, T>, mut condition : impl FnMut(&mut T) -> bool) -> MutexGuard<'a, T> {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    pub fn notify_one(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+        self.waiters.notify_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+        self.waiters.notify_all();
+    }
+}
+
+/// 等待某个条件的任务队列, 是 `WaitQueue` 供执行器中的 future 使用的版本
+#[derive(Default)]
+pub struct AsyncWaitQueue {
+    /// 等待者的编号和 Waker
+    waiters : IrqSpinLock<VecDeque<(u64, Waker)>>,
+    next_key : AtomicU64,
+}
+
+impl AsyncWaitQueue {
+    pub const fn new() -> AsyncWaitQueue {
+        AsyncWaitQueue { waiters : IrqSpinLock::new(VecDeque::new()), next_key : AtomicU64::new(0) }
+    }
+
+    /// 等到 `condition` 返回 `Some` 的 future
+    pub fn wait_until<R, F : FnMut() -> Option<R>>(&self, condition : F) -> WaitUntil<'_, F> {
+        WaitUntil { queue : self, condition, key : None }
+    }
+
+    /// 唤醒最早等待的任务
+    pub fn notify_one(&self) {
+        let waiter = self.waiters.lock().pop_front();
+        if let Some((_, waker)) = waiter {
+            waker.wake();
+        }
+    }
+
+    /// 唤醒所有等待的任务
+    pub fn notify_all(&self) {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        for (_, waker) in waiters {
+            waker.wake();
+        }
+    }
+
+    /// 移除等待者 `key`, 返回它是否还在队列中
+    fn remove(&self, key : u64) -> bool {
+        let mut waiters = self.waiters.lock();
+        let count = waiters.len();
+        waiters.retain(|(waiter, _)| *waiter != key);
+        waiters.len() != count
+    }
+}
+
+/// `AsyncWaitQueue::wait_until` 返回的 future
+///
+/// 被唤醒后没有完成就被丢弃时, 把唤醒交给下一个等待者。
+pub struct WaitUntil<'a, F> {
+    queue : &'a AsyncWaitQueue,
+    condition : F,
+    /// 第一次 poll 时分配的编号
+    key : Option<u64>,
+}
+
+// 不会对 condition 做结构化的固定
+impl<F> Unpin for WaitUntil<'_, F> {}
+
+impl<R, F : FnMut() -> Option<R>> Future for WaitUntil<'_, F> {
+    type Output = R;
+
+    fn poll(self : Pin<&mut Self>, context : &mut Context) -> Poll<R> {
+        let this = self.get_mut();
+        let queue = this.queue;
+        let key = *this.key.get_or_insert_with(|| queue.next_key.fetch_add(1, Ordering::Relaxed));
+        // 先登记再检查条件, 与 WaitQueue 相同
+        {
+            let mut waiters = queue.waiters.lock();
+            match waiters.iter_mut().find(|(waiter, _)| *waiter == key) {
+                Some((_, waker)) => {
+                    if !waker.will_wake(context.waker()) {
+                        *waker = context.waker().clone();
+                    }
+                },
+                None => waiters.push_back((key, context.waker().clone())),
+            }
+        }
+        match (this.condition)() {
+            Some(result) => {
+                queue.remove(key);
+                this.key = None;
+                Poll::Ready(result)
+            },
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<F> Drop for WaitUntil<'_, F> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key {
+            if !self.queue.remove(key) {
+                self.queue.notify_one();
+            }
+        }
+    }
+}
+
+/// 拿不到时让出执行器的互斥锁, 守卫可以跨越 `.await` 持有
+pub struct AsyncMutex<T : ?Sized> {
+    locked : AtomicBool,
+    waiters : AsyncWaitQueue,
+    value : UnsafeCell<T>,
+}
+
+unsafe impl<T : ?Sized + Send> Send for AsyncMutex<T> {}
+unsafe impl<T : ?Sized + Send> Sync for AsyncMutex<T> {}
+
+pub struct AsyncMutexGuard<'a, T : ?Sized> {
+    mutex : &'a AsyncMutex<T>,
+}
+
+unsafe impl<T : ?Sized + Sync> Sync for AsyncMutexGuard<'_, T> {}
+
+impl<T> AsyncMutex<T> {
+    pub const fn new(value : T) -> AsyncMutex<T> {
+        AsyncMutex { locked : AtomicBool::new(false), waiters : AsyncWaitQueue::new(), value : UnsafeCell::new(value) }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T : ?Sized> AsyncMutex<T> {
+    pub async fn lock(&self) -> AsyncMutexGuard<'_, T> {
+        match self.try_lock() {
+            Some(guard) => guard,
+            None => self.waiters.wait_until(|| self.try_lock()).await,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<AsyncMutexGuard<'_, T>> {
+        self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).ok()
+            .map(|_| AsyncMutexGuard { mutex : self })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+impl<T : ?Sized> Deref for AsyncMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T : ?Sized> DerefMut for AsyncMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T : ?Sized> Drop for AsyncMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+        self.mutex.waiters.notify_one();
+    }
+}
+
+/// 没有许可时让出执行器的计数信号量
+pub struct AsyncSemaphore {
+    permits : IrqSpinLock<usize>,
+    waiters : AsyncWaitQueue,
+}
+
+impl AsyncSemaphore {
+    pub const fn new(permits : usize) -> AsyncSemaphore {
+        AsyncSemaphore { permits : IrqSpinLock::new(permits), waiters : AsyncWaitQueue::new() }
+    }
+
+    /// 取得一个许可, 没有时等待
+    pub async fn acquire(&self) {
+        self.waiters.wait_until(|| self.try_acquire().then(|| ())).await
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        let mut permits = self.permits.lock();
+        if *permits == 0 {
+            return false;
+        }
+        *permits -= 1;
+        true
+    }
+
+    /// 归还一个许可
+    pub fn release(&self) {
+        *self.permits.lock() += 1;
+        self.waiters.notify_one();
+    }
+
+    pub fn available(&self) -> usize {
+        *self.permits.lock()
+    }
+}
@@ -9,11 +9,11 @@ use core::{
 
 pub struct Task {
     pub id: TaskId,
-    pub future: Pin<Box<dyn Future<Output = ()>>>,
+    pub future: Pin<Box<dyn Future<Output = ()> + Send>>,
 }
 
 impl Task {
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+    pub fn new(future: impl Future<Output = ()> + Send + 'static) -> Task {
         Task {
             id: TaskId::new(),
             future: Box::pin(future),
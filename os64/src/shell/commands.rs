@@ -0,0 +1,248 @@
+// shell 的内建命令
+use core::fmt;
+use alloc::{format, string::String, vec::Vec};
+use os64_abi::{Errno, FILE_KIND_DIRECTORY, NSIG, SIGINT, SIGKILL, SIGTERM};
+use crate::{
+    device::{acpi::power, clock::real_time_clock, disk::{file_system::FileOpenMode, vfs}, pci, serial},
+    memory,
+    parallel::{process::{self, Process}, scheduler},
+};
+use super::Shell;
+
+/// 一次读取文件或目录的大小
+const READ_SIZE : usize = 4096;
+
+pub enum CommandError {
+    /// 参数不对, 附带用法
+    Usage(&'static str),
+    Errno(Errno),
+    Failed(&'static str),
+}
+
+impl From<Errno> for CommandError {
+    fn from(errno : Errno) -> Self {
+        CommandError::Errno(errno)
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::Usage(usage) => write!(f, "usage: {}", usage),
+            CommandError::Errno(errno) => write!(f, "{}", errno),
+            CommandError::Failed(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+type CommandResult = Result<(), CommandError>;
+
+pub struct Command {
+    pub name : &'static str,
+    pub usage : &'static str,
+    pub description : &'static str,
+    pub run : fn(&mut Shell, &[&str]) -> CommandResult,
+}
+
+pub const COMMANDS : &[Command] = &[
+    Command { name : "help", usage : "help", description : "list the commands", run : help },
+    Command { name : "echo", usage : "echo [text...]", description : "print the arguments", run : echo },
+    Command { name : "pwd", usage : "pwd", description : "print the current directory", run : pwd },
+    Command { name : "cd", usage : "cd [directory]", description : "change the current directory", run : cd },
+    Command { name : "ls", usage : "ls [path...]", description : "list directory contents", run : ls },
+    Command { name : "cat", usage : "cat <file...>", description : "print files", run : cat },
+    Command { name : "run", usage : "run <app> [args...] [&]", description : "run a program from the current directory or /disk, `&` runs it in the background", run : run },
+    Command { name : "ps", usage : "ps", description : "list processes and threads", run : ps },
+    Command { name : "kill", usage : "kill <pid> [INT|KILL|TERM|signal]", description : "send a signal to a process", run : kill },
+    Command { name : "mem", usage : "mem", description : "show memory usage", run : mem },
+    Command { name : "lsdev", usage : "lsdev", description : "list devices", run : lsdev },
+    Command { name : "date", usage : "date", description : "show the date and time", run : date },
+    Command { name : "history", usage : "history", description : "list the command history", run : history },
+    Command { name : "reboot", usage : "reboot", description : "restart the computer", run : reboot },
+    Command { name : "shutdown", usage : "shutdown", description : "power off the computer", run : shutdown },
+];
+
+fn help(_shell : &mut Shell, _args : &[&str]) -> CommandResult {
+    for command in COMMANDS {
+        println!("{:<36} {}", command.usage, command.description);
+    }
+    Ok(())
+}
+
+fn echo(_shell : &mut Shell, args : &[&str]) -> CommandResult {
+    println!("{}", args.join(" "));
+    Ok(())
+}
+
+fn pwd(shell : &mut Shell, _args : &[&str]) -> CommandResult {
+    println!("{}", shell.directory());
+    Ok(())
+}
+
+fn cd(shell : &mut Shell, args : &[&str]) -> CommandResult {
+    let path = match args {
+        [] => String::from("/"),
+        [path] => shell.resolve(path),
+        _ => return Err(CommandError::Usage("cd [directory]")),
+    };
+    if vfs::stat(&path)?.kind != FILE_KIND_DIRECTORY as u64 {
+        return Err(Errno::ENOTDIR.into());
+    }
+    shell.directory = path;
+    Ok(())
+}
+
+fn ls(shell : &mut Shell, args : &[&str]) -> CommandResult {
+    let paths : Vec<String> = match args {
+        [] => alloc::vec![String::from(shell.directory())],
+        args => args.iter().map(|path| shell.resolve(path)).collect(),
+    };
+    for path in &paths {
+        if paths.len() > 1 {
+            println!("{}:", path);
+        }
+        list(path)?;
+    }
+    Ok(())
+}
+
+/// 列出目录中的文件和大小, 文件则只列出它自己
+fn list(path : &str) -> CommandResult {
+    let stat = vfs::stat(path)?;
+    if stat.kind != FILE_KIND_DIRECTORY as u64 {
+        println!("{:>10} {}", stat.size, path);
+        return Ok(());
+    }
+    let handle = vfs::open(path, FileOpenMode::OPEN | FileOpenMode::READ)?;
+    loop {
+        let entries = vfs::read_dir(&handle, READ_SIZE)?;
+        if entries.is_empty() {
+            return Ok(());
+        }
+        // 每一项为类型、名字和结尾的 0
+        for entry in entries.split(|byte| *byte == 0).filter(|entry| !entry.is_empty()) {
+            let name = core::str::from_utf8(&entry[1..]).unwrap_or("?");
+            match entry[0] == FILE_KIND_DIRECTORY {
+                true => println!("{:>10} {}/", "", name),
+                false => {
+                    let size = vfs::stat(&super::resolve(path, name)).map_or(0, |stat| stat.size);
+                    println!("{:>10} {}", size, name);
+                },
+            }
+        }
+    }
+}
+
+fn cat(shell : &mut Shell, args : &[&str]) -> CommandResult {
+    if args.is_empty() {
+        return Err(CommandError::Usage("cat <file...>"));
+    }
+    for path in args {
+        let handle = vfs::open(&shell.resolve(path), FileOpenMode::OPEN | FileOpenMode::READ)?;
+        loop {
+            let data = vfs::read(&handle, READ_SIZE)?;
+            if data.is_empty() {
+                break;
+            }
+            crate::device::console::write(&data);
+        }
+    }
+    Ok(())
+}
+
+/// 加载并启动程序, 和 ls、cat 一样通过 VFS 找到文件; 不在后台运行时由 shell 等它结束
+fn run(shell : &mut Shell, args : &[&str]) -> CommandResult {
+    let (background, args) = match args.split_last() {
+        Some((&"&", args)) => (true, args),
+        _ => (false, args),
+    };
+    let name = match args.first() {
+        Some(name) => *name,
+        None => return Err(CommandError::Usage("run <app> [args...] [&]")),
+    };
+    let path = shell.find_program(name).ok_or(Errno::ENOENT)?;
+    let directory = format!("PWD={}", shell.directory());
+    let process = Process::load_with_args(&path, args, &[&directory]).map_err(CommandError::Failed)?;
+    process.start();
+    match background {
+        true => println!("[{}] {}", process.id().as_u64(), name),
+        false => shell.foreground = Some(process),
+    }
+    Ok(())
+}
+
+fn ps(_shell : &mut Shell, _args : &[&str]) -> CommandResult {
+    println!("{:>5} {:>5}  {}", "PID", "PPID", "NAME");
+    for process in process::processes() {
+        let parent = process.parent().map_or(String::from("-"), |parent| format!("{}", parent.as_u64()));
+        println!("{:>5} {:>5}  {}", process.id().as_u64(), parent, process.name());
+    }
+    println!();
+    println!("{:>5} {:>4} {:<8} {}", "TID", "CPU", "STATE", "NAME");
+    for thread in scheduler::threads() {
+        println!("{:>5} {:>4} {:<8} {}", thread.id.as_u64(), thread.cpu, format!("{:?}", thread.state), thread.name);
+    }
+    Ok(())
+}
+
+fn kill(_shell : &mut Shell, args : &[&str]) -> CommandResult {
+    const USAGE : &str = "kill <pid> [INT|KILL|TERM|signal]";
+    let (pid, signal) = match args {
+        [pid] => (*pid, "TERM"),
+        [pid, signal] => (*pid, *signal),
+        _ => return Err(CommandError::Usage(USAGE)),
+    };
+    let pid : u64 = pid.parse().map_err(|_| CommandError::Usage(USAGE))?;
+    let signal = match signal.trim_start_matches("SIG") {
+        "INT" => SIGINT,
+        "KILL" => SIGKILL,
+        "TERM" => SIGTERM,
+        number => number.parse().ok().filter(|signal| (1..NSIG).contains(signal)).ok_or(CommandError::Usage(USAGE))?,
+    };
+    let process = process::find(pid).ok_or(Errno::ESRCH)?;
+    process.kill(signal)?;
+    Ok(())
+}
+
+fn mem(_shell : &mut Shell, _args : &[&str]) -> CommandResult {
+    if let Some(stats) = memory::frame_stats() {
+        println!("physical memory: {}", stats);
+    }
+    println!("kernel heap: {}", memory::allocator::heap_stats());
+    Ok(())
+}
+
+fn lsdev(_shell : &mut Shell, _args : &[&str]) -> CommandResult {
+    for device in pci::devices() {
+        println!("{}", pci::describe(&device));
+    }
+    for port in serial::ports().iter().filter(|port| port.is_present()) {
+        println!("isa {:#x} serial port {}, {} baud{}", port.base(), port.name(), port.baud_rate(),
+            if port.is_console() { " [console]" } else { "" });
+    }
+    println!("isa 0x60 PS/2 keyboard [console]");
+    println!("isa 0x70 real time clock");
+    Ok(())
+}
+
+fn date(_shell : &mut Shell, _args : &[&str]) -> CommandResult {
+    let datetime = real_time_clock::get_datetime();
+    let (date, time) = (datetime.0, datetime.1);
+    println!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", date.0, date.1, date.2, time.0, time.1, time.2);
+    Ok(())
+}
+
+fn history(shell : &mut Shell, _args : &[&str]) -> CommandResult {
+    for (index, line) in shell.editor.history().enumerate() {
+        println!("{:>4}  {}", index + 1, line);
+    }
+    Ok(())
+}
+
+fn reboot(_shell : &mut Shell, _args : &[&str]) -> CommandResult {
+    power::reboot()
+}
+
+fn shutdown(_shell : &mut Shell, _args : &[&str]) -> CommandResult {
+    power::shutdown()
+}
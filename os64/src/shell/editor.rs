@@ -0,0 +1,275 @@
+// 行编辑器: 把终端输入的字节整理成一行命令, 并给出要回显到终端的内容
+// 支持左右移动、Home/End、退格和删除、上下翻阅历史, 以及 Ctrl-A/E/U/C。
+// 方向键等以 ANSI 转义序列到达, 键盘中断把这些键转换成同样的序列。
+//see also: https://en.wikipedia.org/wiki/ANSI_escape_code
+use core::fmt::Write;
+use alloc::{collections::VecDeque, string::String, vec::Vec};
+
+/// 保留的历史命令数
+pub const HISTORY_SIZE : usize = 32;
+
+const CTRL_A : u8 = 0x01;
+const CTRL_C : u8 = 0x03;
+const CTRL_E : u8 = 0x05;
+const BACKSPACE : u8 = 0x08;
+const CTRL_U : u8 = 0x15;
+const ESCAPE : u8 = 0x1B;
+/// 多数终端的退格键发送 DEL
+const DELETE : u8 = 0x7F;
+
+/// 转义序列的解析状态
+enum Escape {
+    None,
+    /// 收到 ESC
+    Start,
+    /// 收到 ESC [ 或 ESC O, 以及之后的数字参数
+    Sequence(u16),
+}
+
+pub struct LineEditor {
+    line : Vec<char>,
+    /// 光标在 `line` 中的位置
+    cursor : usize,
+    history : VecDeque<String>,
+    /// 正在显示的历史命令, 0 为最近的一条; `None` 表示正在编辑新的一行
+    browsing : Option<usize>,
+    /// 翻阅历史之前正在编辑的行
+    draft : Vec<char>,
+    escape : Escape,
+    /// 还不完整的 UTF-8 字符
+    partial : Vec<u8>,
+    /// 上一个字节是回车, 紧跟的换行被忽略
+    after_return : bool,
+}
+
+impl LineEditor {
+    pub fn new() -> LineEditor {
+        LineEditor {
+            line : Vec::new(),
+            cursor : 0,
+            history : VecDeque::new(),
+            browsing : None,
+            draft : Vec::new(),
+            escape : Escape::None,
+            partial : Vec::new(),
+            after_return : false,
+        }
+    }
+
+    /// 输入的历史命令, 从早到晚
+    pub fn history(&self) -> impl Iterator<Item = &str> {
+        self.history.iter().map(|line| line.as_str())
+    }
+
+    /// 处理输入的一个字节, 要回显的内容追加到 `echo`; 输入完一行时返回这一行
+    ///
+    /// Ctrl-C 放弃正在编辑的行, 返回空行。
+    pub fn feed(&mut self, byte : u8, echo : &mut String) -> Option<String> {
+        let after_return = core::mem::replace(&mut self.after_return, byte == b'\r');
+        match self.escape {
+            Escape::Start => {
+                self.escape = match byte {
+                    b'[' | b'O' => Escape::Sequence(0),
+                    _ => Escape::None,
+                };
+                return None;
+            },
+            Escape::Sequence(parameter) => {
+                match byte {
+                    b'0'..=b'9' => self.escape = Escape::Sequence(parameter.saturating_mul(10).saturating_add((byte - b'0') as u16)),
+                    b';' => {},
+                    0x40..=0x7E => {
+                        self.escape = Escape::None;
+                        self.control_sequence(parameter, byte, echo);
+                    },
+                    _ => self.escape = Escape::None,
+                }
+                return None;
+            },
+            Escape::None => {},
+        }
+        match byte {
+            b'\n' if after_return => None,
+            b'\r' | b'\n' => {
+                echo.push('\n');
+                Some(self.finish())
+            },
+            CTRL_C => {
+                echo.push_str("^C\n");
+                self.reset();
+                Some(String::new())
+            },
+            ESCAPE => {
+                self.escape = Escape::Start;
+                None
+            },
+            BACKSPACE | DELETE => {
+                if self.cursor > 0 {
+                    self.cursor -= 1;
+                    move_left(echo, 1);
+                    self.delete(echo);
+                }
+                None
+            },
+            CTRL_A => {
+                self.home(echo);
+                None
+            },
+            CTRL_E => {
+                self.end(echo);
+                None
+            },
+            CTRL_U => {
+                self.replace(Vec::new(), echo);
+                None
+            },
+            byte if byte < 0x20 => None,
+            byte => {
+                self.partial.push(byte);
+                match core::str::from_utf8(&self.partial) {
+                    Ok(text) => {
+                        let characters : Vec<char> = text.chars().collect();
+                        self.partial.clear();
+                        characters.into_iter().for_each(|character| self.insert(character, echo));
+                    },
+                    // 无效的字节丢弃
+                    Err(e) if e.error_len().is_some() => self.partial.clear(),
+                    Err(_) => {},
+                }
+                None
+            },
+        }
+    }
+
+    /// CSI 序列: 方向键、Home/End 和删除键
+    fn control_sequence(&mut self, parameter : u16, command : u8, echo : &mut String) {
+        match (command, parameter) {
+            (b'A', _) => self.previous(echo),
+            (b'B', _) => self.next(echo),
+            (b'C', _) => {
+                if self.cursor < self.line.len() {
+                    echo.push(self.line[self.cursor]);
+                    self.cursor += 1;
+                }
+            },
+            (b'D', _) => {
+                if self.cursor > 0 {
+                    self.cursor -= 1;
+                    move_left(echo, 1);
+                }
+            },
+            (b'H', _) | (b'~', 1) | (b'~', 7) => self.home(echo),
+            (b'F', _) | (b'~', 4) | (b'~', 8) => self.end(echo),
+            (b'~', 3) => self.delete(echo),
+            _ => {},
+        }
+    }
+
+    fn insert(&mut self, character : char, echo : &mut String) {
+        self.line.insert(self.cursor, character);
+        self.cursor += 1;
+        echo.push(character);
+        self.redraw_tail(echo, 0);
+    }
+
+    /// 删除光标处的字符
+    fn delete(&mut self, echo : &mut String) {
+        if self.cursor < self.line.len() {
+            self.line.remove(self.cursor);
+            self.redraw_tail(echo, 1);
+        }
+    }
+
+    fn home(&mut self, echo : &mut String) {
+        move_left(echo, self.cursor);
+        self.cursor = 0;
+    }
+
+    fn end(&mut self, echo : &mut String) {
+        echo.extend(&self.line[self.cursor..]);
+        self.cursor = self.line.len();
+    }
+
+    /// 重新输出光标之后的内容, 用空格覆盖原来多出的 `erased` 个字符, 光标不动
+    fn redraw_tail(&self, echo : &mut String, erased : usize) {
+        let tail = &self.line[self.cursor..];
+        echo.extend(tail);
+        echo.extend(core::iter::repeat(' ').take(erased));
+        move_left(echo, tail.len() + erased);
+    }
+
+    /// 用 `line` 替换整行, 光标移到末尾
+    fn replace(&mut self, line : Vec<char>, echo : &mut String) {
+        move_left(echo, self.cursor);
+        echo.push_str("\x1b[K");
+        echo.extend(&line);
+        self.cursor = line.len();
+        self.line = line;
+    }
+
+    /// 上一条历史命令
+    fn previous(&mut self, echo : &mut String) {
+        let index = self.browsing.map_or(0, |index| index + 1);
+        if index >= self.history.len() {
+            return;
+        }
+        if self.browsing.is_none() {
+            self.draft = self.line.clone();
+        }
+        self.browsing = Some(index);
+        let line = self.history[self.history.len() - 1 - index].chars().collect();
+        self.replace(line, echo);
+    }
+
+    /// 下一条历史命令, 越过最近的一条时回到翻阅之前的行
+    fn next(&mut self, echo : &mut String) {
+        match self.browsing {
+            None => {},
+            Some(0) => {
+                self.browsing = None;
+                let draft = core::mem::take(&mut self.draft);
+                self.replace(draft, echo);
+            },
+            Some(index) => {
+                self.browsing = Some(index - 1);
+                let line = self.history[self.history.len() - index].chars().collect();
+                self.replace(line, echo);
+            },
+        }
+    }
+
+    /// 清空正在编辑的行
+    fn reset(&mut self) {
+        self.line.clear();
+        self.cursor = 0;
+        self.browsing = None;
+        self.draft.clear();
+        self.partial.clear();
+    }
+
+    /// 结束当前行, 非空且与上一条不同时加入历史
+    fn finish(&mut self) -> String {
+        let line : String = self.line.iter().collect();
+        self.reset();
+        if !line.trim().is_empty() && self.history.back().map_or(true, |last| *last != line) {
+            if self.history.len() == HISTORY_SIZE {
+                self.history.pop_front();
+            }
+            self.history.push_back(line.clone());
+        }
+        line
+    }
+}
+
+impl Default for LineEditor {
+    fn default() -> Self {
+        LineEditor::new()
+    }
+}
+
+/// 光标左移 `count` 列
+fn move_left(echo : &mut String, count : usize) {
+    if count > 0 {
+        let _ = write!(echo, "\x1b[{}D", count);
+    }
+}
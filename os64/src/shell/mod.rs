@@ -0,0 +1,148 @@
+// 内核 shell: 执行器中的一个任务, 从控制台(键盘和串口)读取命令并执行, 输出到串口
+// 前台运行的程序结束之前不再读取输入, 控制台的输入交给该程序。
+use core::fmt::{self, Write};
+use alloc::{string::String, sync::Arc, vec::Vec};
+use os64_abi::FILE_KIND_FILE;
+use crate::{device::{console, disk::vfs::{self, DISK_MOUNT_POINT}}, parallel::process::Process};
+
+/// 输出到控制台
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::shell::print(format_args!($($arg)*)));
+}
+
+/// 输出到控制台, 加上换行
+macro_rules! println {
+    () => (print!("\n"));
+    ($fmt:expr) => (print!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => (print!(concat!($fmt, "\n"), $($arg)*));
+}
+
+mod commands;
+pub mod editor;
+
+use self::{commands::COMMANDS, editor::LineEditor};
+
+struct ConsoleWriter;
+
+impl Write for ConsoleWriter {
+    fn write_str(&mut self, s : &str) -> fmt::Result {
+        console::write(s.as_bytes());
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn print(args : fmt::Arguments) {
+    let _ = ConsoleWriter.write_fmt(args);
+}
+
+pub struct Shell {
+    /// 当前目录, 总是规范的绝对路径
+    directory : String,
+    editor : LineEditor,
+    /// 命令启动的、要等它结束的程序
+    foreground : Option<Arc<Process>>,
+}
+
+impl Shell {
+    pub fn new() -> Shell {
+        Shell { directory : String::from("/"), editor : LineEditor::new(), foreground : None }
+    }
+
+    /// 读取并执行命令, 不会返回
+    pub async fn run(mut self) {
+        println!("OS64 shell, type `help` for commands");
+        loop {
+            print!("{} $ ", self.directory);
+            let line = self.read_line().await;
+            self.execute(&line).await;
+        }
+    }
+
+    /// 读取一行, 编辑的过程回显到控制台
+    async fn read_line(&mut self) -> String {
+        let mut echo = String::new();
+        loop {
+            let byte = console::read_byte().await;
+            let line = self.editor.feed(byte, &mut echo);
+            if !echo.is_empty() {
+                console::write(echo.as_bytes());
+                echo.clear();
+            }
+            if let Some(line) = line {
+                return line;
+            }
+        }
+    }
+
+    /// 执行一行命令, 它启动了前台程序时等待程序结束
+    pub async fn execute(&mut self, line : &str) {
+        let words : Vec<&str> = line.split_whitespace().collect();
+        let (name, args) = match words.split_first() {
+            Some((name, args)) => (*name, args),
+            None => return,
+        };
+        match COMMANDS.iter().find(|command| command.name == name) {
+            Some(command) => {
+                if let Err(e) = (command.run)(self, args) {
+                    println!("{}: {}", name, e);
+                }
+            },
+            None => println!("{}: command not found", name),
+        }
+        if let Some(process) = self.foreground.take() {
+            let status = process.wait_async().await;
+            if !status.success() {
+                println!("[{}] {}", process.id().as_u64(), status);
+            }
+        }
+    }
+
+    /// 当前目录
+    pub fn directory(&self) -> &str {
+        &self.directory
+    }
+
+    /// 相对于当前目录的路径转换为绝对路径
+    pub fn resolve(&self, path : &str) -> String {
+        resolve(&self.directory, path)
+    }
+
+    /// 程序文件的路径: 含 '/' 的名字按当前目录解析, 否则依次在当前目录和数据盘中查找
+    pub fn find_program(&self, name : &str) -> Option<String> {
+        let mut paths = alloc::vec![self.resolve(name)];
+        if !name.contains('/') {
+            paths.push(resolve(DISK_MOUNT_POINT, name));
+        }
+        paths.into_iter().find(|path| vfs::stat(path).map_or(false, |stat| stat.kind == FILE_KIND_FILE as u64))
+    }
+}
+
+impl Default for Shell {
+    fn default() -> Self {
+        Shell::new()
+    }
+}
+
+/// 把 `path` 按目录 `directory` 转换为规范的绝对路径, 处理 "." 和 ".."
+pub fn resolve(directory : &str, path : &str) -> String {
+    let mut parts : Vec<&str> = match path.starts_with('/') {
+        true => Vec::new(),
+        false => directory.split('/').filter(|part| !part.is_empty()).collect(),
+    };
+    for part in path.split('/') {
+        match part {
+            "" | "." => {},
+            ".." => {
+                parts.pop();
+            },
+            part => parts.push(part),
+        }
+    }
+    alloc::format!("/{}", parts.join("/"))
+}
+
+/// 运行 shell, 作为执行器的任务
+pub async fn run() {
+    Shell::new().run().await
+}
@@ -0,0 +1,185 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os64::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use alloc::{sync::Arc, vec};
+use core::panic::PanicInfo;
+use os64::api::{user::UserSlice, Errno};
+use os64::device::disk::{file_system::FileOpenMode, vfs};
+use os64::memory;
+use os64::architecture::x86_64_asm::asm_copy_user;
+use os64::memory::shared::SharedMemory;
+use os64::memory::vma::{AddressSpace, FaultAccess, Vma, VmaBacking, VmaFlags, VmaKind, MMAP_START, USER_SPACE_START};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os64::test_panic_handler(info)
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use os64::memory::{self, GlobalFrameAllocator, allocator, frame_allocator::BitmapFrameAllocator};
+
+    os64::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    memory::init_frame_allocator(unsafe { BitmapFrameAllocator::init(&boot_info.memory_map) });
+    allocator::init_heap(&mut mapper, &mut GlobalFrameAllocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+/// 缺页时的访问方式: 用户态写入一个不存在的页
+fn write_access() -> FaultAccess {
+    FaultAccess { write : true, execute : false, present : false, user : true }
+}
+
+#[test_case]
+fn anonymous_mapping_is_released() {
+    let mut space = AddressSpace::new().unwrap();
+    let start = space.map_anonymous(3 * 4096, VmaFlags::READ | VmaFlags::WRITE).unwrap();
+    assert!(start.as_u64() >= MMAP_START);
+    space.handle_page_fault(start + 4096u64, write_access()).unwrap();
+    assert_eq!(space.resident_pages(), 1);
+    space.unmap(start, 3 * 4096).unwrap();
+    assert_eq!(space.resident_pages(), 0);
+    assert!(space.find_vma(start).is_none());
+}
+
+#[test_case]
+fn program_break_moves() {
+    let mut space = AddressSpace::new().unwrap();
+    let heap = USER_SPACE_START + 0x10000;
+    space.add_vma(Vma::new(heap, heap, VmaKind::Heap, VmaFlags::READ | VmaFlags::WRITE, VmaBacking::Anonymous)).unwrap();
+    assert_eq!(space.program_break().as_u64(), heap);
+    let end = space.set_program_break(VirtAddr::new(heap + 100)).unwrap();
+    assert_eq!(end.as_u64(), heap + 4096);
+    space.handle_page_fault(VirtAddr::new(heap), write_access()).unwrap();
+    space.set_program_break(VirtAddr::new(heap)).unwrap();
+    assert_eq!(space.resident_pages(), 0);
+}
+
+#[test_case]
+fn shared_memory_uses_the_same_frames() {
+    let object = SharedMemory::create(2 * 4096).unwrap();
+    let mut first = AddressSpace::new().unwrap();
+    let mut second = AddressSpace::new().unwrap();
+    let a = first.map_shared(object.clone(), VmaFlags::READ | VmaFlags::WRITE).unwrap();
+    let b = second.map_shared(object.clone(), VmaFlags::READ | VmaFlags::WRITE).unwrap();
+    first.handle_page_fault(a, write_access()).unwrap();
+    second.handle_page_fault(b, write_access()).unwrap();
+    assert_eq!(object.resident_pages(), 1);
+    // 解除映射不释放对象的页
+    first.unmap(a, 2 * 4096).unwrap();
+    drop(second);
+    assert_eq!(object.resident_pages(), 1);
+    SharedMemory::unlink(object.id()).unwrap();
+}
+
+#[test_case]
+fn file_mappings_read_pages_on_demand() {
+    let file = vfs::open("/mapped", FileOpenMode::CREATE | FileOpenMode::WRITE).unwrap();
+    let mut data = vec![1u8; 4096];
+    data.extend_from_slice(b"second page");
+    vfs::write(&file, &data).unwrap();
+    drop(file);
+
+    let object = SharedMemory::for_file("/mapped").unwrap();
+    assert_eq!(object.size(), 4096 + 11);
+    assert_eq!(object.resident_pages(), 0);
+    assert!(Arc::ptr_eq(&object, &SharedMemory::for_file("/mapped").unwrap()));
+    // 只读出缺页的这一页, 文件尾之后为 0
+    let frame = object.frame(1).unwrap();
+    let page = unsafe { core::slice::from_raw_parts(memory::phys_to_virt(frame.start_address()).as_ptr::<u8>(), 4096) };
+    assert_eq!(&page[..11], b"second page");
+    assert!(page[11..].iter().all(|byte| *byte == 0));
+    assert_eq!(object.resident_pages(), 1);
+    drop(object);
+    assert_eq!(vfs::open_files(), 0);
+    vfs::remove("/mapped").unwrap();
+}
+
+#[test_case]
+fn fork_copies_on_write() {
+    let mut parent = AddressSpace::new().unwrap();
+    let start = parent.map_anonymous(4096, VmaFlags::READ | VmaFlags::WRITE).unwrap();
+    parent.handle_page_fault(start, write_access()).unwrap();
+    let mut child = parent.fork().unwrap();
+    assert_eq!(child.resident_pages(), 1);
+    assert!(child.find_vma(start).is_some());
+    // 两边的页都变为只读, 写入时先复制的一方得到新的帧, 另一方直接恢复可写
+    let cow = FaultAccess { present : true, ..write_access() };
+    child.handle_page_fault(start, cow).unwrap();
+    parent.handle_page_fault(start, cow).unwrap();
+    assert!(child.handle_page_fault(start, cow).is_err());
+    assert_eq!(parent.resident_pages(), 1);
+}
+
+#[test_case]
+fn write_bytes_populates_pages() {
+    let mut space = AddressSpace::new().unwrap();
+    let start = space.map_anonymous(2 * 4096, VmaFlags::READ | VmaFlags::WRITE).unwrap();
+    // 跨过页边界
+    space.write_bytes(start + 4090u64, b"argument").unwrap();
+    assert_eq!(space.resident_pages(), 2);
+    assert!(space.write_bytes(VirtAddr::new(USER_SPACE_START), b"x").is_err());
+}
+
+#[test_case]
+fn large_mappings_use_huge_pages() {
+    let mut space = AddressSpace::new().unwrap();
+    let start = space.map_anonymous(2 * memory::HUGE_PAGE_SIZE, VmaFlags::READ | VmaFlags::WRITE).unwrap();
+    let huge = start.align_up(memory::HUGE_PAGE_SIZE);
+    // 一次缺页映射整个 2MiB 区域
+    space.handle_page_fault(huge + 4096u64, write_access()).unwrap();
+    assert_eq!(space.resident_pages(), memory::HUGE_PAGE_FRAMES);
+    assert!(space.is_mapped(huge));
+    assert!(space.is_mapped(huge + (memory::HUGE_PAGE_SIZE - 4096)));
+    // 解除其中一页的映射时拆分为 4K 页
+    space.unmap(huge + 4096u64, 4096).unwrap();
+    assert_eq!(space.resident_pages(), memory::HUGE_PAGE_FRAMES - 1);
+    assert!(space.is_mapped(huge));
+    assert!(!space.is_mapped(huge + 4096u64));
+    space.unmap(start, 2 * memory::HUGE_PAGE_SIZE).unwrap();
+    assert_eq!(space.resident_pages(), 0);
+}
+
+#[test_case]
+fn protect_splits_mappings() {
+    let mut space = AddressSpace::new().unwrap();
+    let start = space.map_anonymous(3 * 4096, VmaFlags::READ | VmaFlags::WRITE).unwrap();
+    space.handle_page_fault(start + 4096u64, write_access()).unwrap();
+    space.protect(start + 4096u64, 4096, VmaFlags::READ).unwrap();
+    assert_eq!(space.vmas().count(), 3);
+    assert_eq!(space.find_vma(start + 4096u64).unwrap().flags, VmaFlags::READ);
+    let protection = FaultAccess { present : true, ..write_access() };
+    assert!(space.handle_page_fault(start + 4096u64, protection).is_err());
+    assert!(space.handle_page_fault(start + 2 * 4096u64, write_access()).is_ok());
+    // 未映射的部分和只读的共享映射
+    assert!(space.protect(start, 4 * 4096, VmaFlags::READ).is_err());
+    let object = SharedMemory::create(4096).unwrap();
+    let shared = space.map_shared(object.clone(), VmaFlags::READ).unwrap();
+    assert!(space.protect(shared, 4096, VmaFlags::READ | VmaFlags::WRITE).is_err());
+    SharedMemory::unlink(object.id()).unwrap();
+}
+
+#[test_case]
+fn user_copies_fail_instead_of_faulting() {
+    // 内核地址在复制前就被拒绝
+    let kernel = &USER_SPACE_START as *const u64 as u64;
+    assert_eq!(UserSlice::new(kernel, 8).read(), Err(Errno::EFAULT));
+    assert_eq!(UserSlice::new(u64::MAX - 4, 8).read(), Err(Errno::EFAULT));
+    // 没有映射的用户地址缺页后复制提前结束
+    let mut buffer = [0u8; 16];
+    let remaining = unsafe { asm_copy_user(buffer.as_mut_ptr(), (USER_SPACE_START + 0x1000) as *const u8, buffer.len()) };
+    assert_eq!(remaining, buffer.len());
+}
@@ -0,0 +1,174 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os64::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{vec, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use os64::device::disk::{disk::{DiskDriver, DiskIdentifyInfo, SECTOR_BYTES}, fat::FAT16SuperBlock, file_system::{FileOpenMode, FilePosition}, vfs};
+use os64_abi::{Errno, FILE_KIND_DIRECTORY, FILE_KIND_FILE};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os64::test_panic_handler(info)
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use os64::memory::{self, GlobalFrameAllocator, allocator, frame_allocator::BitmapFrameAllocator};
+
+    os64::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    memory::init_frame_allocator(unsafe { BitmapFrameAllocator::init(&boot_info.memory_map) });
+    allocator::init_heap(&mut mapper, &mut GlobalFrameAllocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn files_can_be_written_and_read_back() {
+    let file = vfs::open("/notes.txt", FileOpenMode::CREATE | FileOpenMode::WRITE | FileOpenMode::READ).unwrap();
+    assert_eq!(vfs::write(&file, b"hello world"), Ok(11));
+    assert_eq!(vfs::seek(&file, FilePosition::Start(6)), Ok(6));
+    assert_eq!(vfs::read(&file, 64).unwrap(), b"world");
+    assert_eq!(vfs::stat("/notes.txt").unwrap().size, 11);
+    // 已存在时只有 CREATE 会失败, 只读打开不能写
+    assert_eq!(vfs::open("/notes.txt", FileOpenMode::CREATE | FileOpenMode::WRITE).unwrap_err(), Errno::EEXIST);
+    let reader = vfs::open("/notes.txt", FileOpenMode::OPEN | FileOpenMode::READ).unwrap();
+    assert_eq!(vfs::write(&reader, b"x"), Err(Errno::EBADF));
+    drop(file);
+    drop(reader);
+    assert_eq!(vfs::open_files(), 0);
+    vfs::remove("/notes.txt").unwrap();
+    assert_eq!(vfs::stat("/notes.txt").unwrap_err(), Errno::ENOENT);
+}
+
+#[test_case]
+fn directories_can_be_listed_renamed_and_removed() {
+    vfs::create_directory("/docs").unwrap();
+    vfs::open("/docs/a", FileOpenMode::CREATE | FileOpenMode::WRITE).unwrap();
+    vfs::create_directory("/docs/sub").unwrap();
+    let directory = vfs::open("/docs", FileOpenMode::OPEN | FileOpenMode::READ).unwrap();
+    let mut expected = vec![FILE_KIND_FILE, b'a', 0, FILE_KIND_DIRECTORY];
+    expected.extend_from_slice(b"sub\0");
+    assert_eq!(vfs::read_dir(&directory, 64).unwrap(), expected);
+    assert!(vfs::read_dir(&directory, 64).unwrap().is_empty());
+    drop(directory);
+
+    assert_eq!(vfs::remove("/docs"), Err(Errno::ENOTEMPTY));
+    assert_eq!(vfs::rename("/docs", "/docs/sub/docs"), Err(Errno::EINVAL));
+    vfs::rename("/docs/a", "/docs/sub/b").unwrap();
+    assert_eq!(vfs::stat("/docs/sub/b").unwrap().kind, FILE_KIND_FILE as u64);
+    vfs::remove("/docs/sub/b").unwrap();
+    vfs::remove("/docs/sub").unwrap();
+    vfs::remove("/docs").unwrap();
+}
+
+#[test_case]
+fn positions_beyond_the_maximum_file_size_are_rejected() {
+    use os64::device::disk::memory_fs::MAX_FILE_SIZE;
+
+    let file = vfs::open("/big", FileOpenMode::CREATE | FileOpenMode::WRITE).unwrap();
+    assert_eq!(vfs::seek(&file, FilePosition::Start(usize::MAX)), Err(Errno::EINVAL));
+    assert_eq!(vfs::seek(&file, FilePosition::Current(-1)), Err(Errno::EINVAL));
+    assert_eq!(vfs::seek(&file, FilePosition::Start(MAX_FILE_SIZE)), Ok(MAX_FILE_SIZE));
+    assert_eq!(vfs::write(&file, b"x"), Err(Errno::EFBIG));
+    assert_eq!(vfs::seek(&file, FilePosition::Current(isize::MAX)), Err(Errno::EINVAL));
+    assert_eq!(vfs::stat("/big").unwrap().size, 0);
+    drop(file);
+    vfs::remove("/big").unwrap();
+}
+
+/// 内存中的磁盘, 容纳一个很小的 FAT16 卷
+struct RamDisk(Vec<u8>);
+
+impl DiskDriver for RamDisk {
+    fn init(&self) -> Result<DiskIdentifyInfo, ()> {
+        Err(())
+    }
+
+    fn read(&self, sector : u64, count : usize, data : &mut [u32]) -> Result<(), ()> {
+        let start = sector as usize * SECTOR_BYTES;
+        let bytes = self.0.get(start..start + count * SECTOR_BYTES).ok_or(())?;
+        for (word, chunk) in data.iter_mut().zip(bytes.chunks(4)) {
+            *word = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        Ok(())
+    }
+
+    fn write(&self, _sector : u64, _count : usize, _data : &[u32]) -> Result<(), ()> {
+        Err(())
+    }
+}
+
+/// 256 个扇区, 每簇一个扇区: 启动扇区, 一份 FAT, 一个扇区的根目录, 之后是簇 2 开始的数据
+fn fat16_image() -> RamDisk {
+    fn entry(image : &mut [u8], offset : usize, name : &[u8; 11], attributes : u8, cluster : u16, size : u32) {
+        image[offset..offset + 11].copy_from_slice(name);
+        image[offset + 11] = attributes;
+        image[offset + 26..offset + 28].copy_from_slice(&cluster.to_le_bytes());
+        image[offset + 28..offset + 32].copy_from_slice(&size.to_le_bytes());
+    }
+
+    let mut image = vec![0u8; 256 * SECTOR_BYTES];
+    image[11..13].copy_from_slice(&512u16.to_le_bytes());
+    image[13] = 1;
+    image[14..16].copy_from_slice(&1u16.to_le_bytes());
+    image[16] = 1;
+    image[17..19].copy_from_slice(&16u16.to_le_bytes());
+    image[19..21].copy_from_slice(&256u16.to_le_bytes());
+    image[22..24].copy_from_slice(&1u16.to_le_bytes());
+    image[510..512].copy_from_slice(&0xAA55u16.to_le_bytes());
+    for (cluster, value) in [0xFFF8u16, 0xFFFF, 0xFFFF, 0xFFFF, 0xFFFF].iter().enumerate() {
+        image[512 + cluster * 2..512 + cluster * 2 + 2].copy_from_slice(&value.to_le_bytes());
+    }
+    let root = 2 * SECTOR_BYTES;
+    entry(&mut image, root, b"OS64TEST   ", 0x08, 0, 0);
+    entry(&mut image, root + 32, b"HELLO   TXT", 0x20, 2, 5);
+    entry(&mut image, root + 64, b"SUB        ", 0x10, 3, 0);
+    image[3 * SECTOR_BYTES..3 * SECTOR_BYTES + 5].copy_from_slice(b"hello");
+    let sub = 4 * SECTOR_BYTES;
+    entry(&mut image, sub, b".          ", 0x10, 3, 0);
+    entry(&mut image, sub + 32, b"..         ", 0x10, 0, 0);
+    entry(&mut image, sub + 64, b"DATA    BIN", 0x20, 4, 3);
+    image[5 * SECTOR_BYTES..5 * SECTOR_BYTES + 3].copy_from_slice(&[1, 2, 3]);
+    RamDisk(image)
+}
+
+#[test_case]
+fn fat16_volumes_are_mounted_read_only() {
+    vfs::create_directory("/fat").unwrap();
+    vfs::mount::<FAT16SuperBlock>("/fat", fat16_image(), true).unwrap();
+    assert_eq!(vfs::mount::<FAT16SuperBlock>("/fat", fat16_image(), true), Err(Errno::EBUSY));
+
+    let directory = vfs::open("/fat", FileOpenMode::OPEN | FileOpenMode::READ).unwrap();
+    let mut expected = vec![FILE_KIND_FILE];
+    expected.extend_from_slice(b"HELLO.TXT\0");
+    expected.push(FILE_KIND_DIRECTORY);
+    expected.extend_from_slice(b"SUB\0");
+    assert_eq!(vfs::read_dir(&directory, 64).unwrap(), expected);
+    drop(directory);
+
+    // 名字不区分大小写, ".." 回到上一级
+    let file = vfs::open("/fat/sub/../hello.txt", FileOpenMode::OPEN | FileOpenMode::READ).unwrap();
+    assert_eq!(vfs::read(&file, 64).unwrap(), b"hello");
+    drop(file);
+    assert_eq!(vfs::read_all("/fat/SUB/data.bin").unwrap(), [1, 2, 3]);
+    assert_eq!(vfs::stat("/fat/sub").unwrap().kind, FILE_KIND_DIRECTORY as u64);
+
+    assert_eq!(vfs::open("/fat/hello.txt", FileOpenMode::OPEN | FileOpenMode::WRITE).unwrap_err(), Errno::EROFS);
+    assert_eq!(vfs::open("/fat/new", FileOpenMode::CREATE | FileOpenMode::WRITE).unwrap_err(), Errno::EROFS);
+    assert_eq!(vfs::remove("/fat/hello.txt"), Err(Errno::EROFS));
+    assert_eq!(vfs::rename("/fat/hello.txt", "/hello.txt"), Err(Errno::EROFS));
+    assert_eq!(vfs::remove("/fat"), Err(Errno::EBUSY));
+    assert_eq!(vfs::open_files(), 0);
+}
@@ -0,0 +1,38 @@
+// tests/modules.rs 使用的内核模块, 改动后重新生成 hello_module.o:
+// gcc -c -O2 -fpie -ffreestanding -fno-stack-protector -fno-asynchronous-unwind-tables -mno-red-zone -mgeneral-regs-only hello_module.c -o hello_module.o
+typedef unsigned long size_t;
+
+struct module_descriptor {
+    char name[32];
+    unsigned int version;
+    unsigned int api_version;
+};
+
+// 内核导出的符号, 经过跳转桩调用
+void os64_print(const char *data, size_t length);
+
+struct module_descriptor os64_module_info = { "hello", 1, 1 };
+
+// init 运行后为 1, exit 运行后为 2
+int hello_state;
+
+static int answer(void) {
+    return 42;
+}
+
+// 数据中的绝对地址, 需要 R_X86_64_64 重定位
+int (*hello_callback)(void) = answer;
+
+int hello_answer(void) {
+    return hello_callback();
+}
+
+int os64_module_init(void) {
+    os64_print("hello init\n", 11);
+    hello_state = 1;
+    return 0;
+}
+
+void os64_module_exit(void) {
+    hello_state = 2;
+}
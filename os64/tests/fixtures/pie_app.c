@@ -0,0 +1,21 @@
+// tests/process.rs 运行的 PIE 程序, 改动后重新生成 pie_app:
+// gcc -O2 -fpie -static-pie -nostdlib -ffreestanding -fno-stack-protector -fno-asynchronous-unwind-tables pie_app.c -o pie_app
+// python3 -c "import sys; d = bytearray(open(sys.argv[1], 'rb').read()); d[0x28:0x30] = bytes(8); d[0x3C:0x40] = bytes(4); open(sys.argv[1], 'wb').write(d)" pie_app
+// 第二步去掉节头表, 内核只能从 PT_DYNAMIC 找到重定位。
+#define OS64_API_EXIT 1
+
+static long value = 40;
+static long offset = 2;
+
+// 装载时需要 R_X86_64_RELATIVE 重定位的指针
+long *values[] = { &value, &offset };
+
+static void exit(long code) {
+    __asm__ volatile ("int $0x80" : : "a"(OS64_API_EXIT), "D"(code) : "memory");
+    for (;;) {}
+}
+
+void _start(void) {
+    // 重定位正确时退出码为 42
+    exit(*values[0] + *values[1]);
+}
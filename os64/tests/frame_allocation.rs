@@ -0,0 +1,67 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os64::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use os64::memory::{self, GlobalFrameAllocator, frame_allocator::BitmapFrameAllocator};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator};
+
+entry_point!(main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os64::test_panic_handler(info)
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+
+    os64::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let _mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe { BitmapFrameAllocator::init(&boot_info.memory_map) };
+    memory::init_frame_allocator(frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn freed_frame_is_reused() {
+    let frame = GlobalFrameAllocator.allocate_frame().expect("out of memory");
+    unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+    let again = GlobalFrameAllocator.allocate_frame().expect("out of memory");
+    assert_eq!(frame, again);
+    unsafe { GlobalFrameAllocator.deallocate_frame(again) };
+}
+
+#[test_case]
+fn stats_track_allocations() {
+    let before = memory::frame_stats().unwrap();
+    let frame = GlobalFrameAllocator.allocate_frame().unwrap();
+    assert_eq!(memory::frame_stats().unwrap().free_frames, before.free_frames - 1);
+    unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+    assert_eq!(memory::frame_stats().unwrap().free_frames, before.free_frames);
+}
+
+#[test_case]
+fn contiguous_allocation_is_aligned() {
+    let start = memory::allocate_contiguous(16, 16).expect("no contiguous memory");
+    assert_eq!(start.start_address().as_u64() % (16 * 4096), 0);
+    unsafe { memory::deallocate_contiguous(start, 16) };
+}
+
+#[test_case]
+fn translate_physical_window() {
+    // bootloader 用大页映射物理内存窗口
+    let frame = GlobalFrameAllocator.allocate_frame().unwrap();
+    let virt = memory::phys_to_virt(frame.start_address()) + 0x123u64;
+    let phys = unsafe { memory::translate_addr(virt, memory::physical_memory_offset()) };
+    assert_eq!(phys, Some(frame.start_address() + 0x123u64));
+    unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+}
@@ -19,16 +19,15 @@ fn panic(info: &PanicInfo) -> ! {
 }
 
 fn main(boot_info: &'static BootInfo) -> ! {
-    use os64::memory::{self, BootInfoFrameAllocator};
+    use os64::memory::{self, GlobalFrameAllocator, frame_allocator::BitmapFrameAllocator};
     use x86_64::VirtAddr;
 
     os64::init();
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe {
-        BootInfoFrameAllocator::init(&boot_info.memory_map)
-    };
-    allocator::init_heap(&mut mapper, &mut frame_allocator)
+    // slab 分配器从全局帧分配器取页
+    memory::init_frame_allocator(unsafe { BitmapFrameAllocator::init(&boot_info.memory_map) });
+    allocator::init_heap(&mut mapper, &mut GlobalFrameAllocator)
         .expect("heap initialization failed");
 
     test_main();
@@ -59,4 +58,29 @@ fn many_boxes() {
         let x = Box::new(i);
         assert_eq!(*x, i);
     }
-}
\ No newline at end of file
+}
+
+#[cfg(any(feature = "slab-allocator", feature = "fixed-size-block-allocator"))]
+#[test_case]
+fn heap_grows_beyond_initial_size() {
+    let mut data = Vec::<u8>::with_capacity(2 * HEAP_SIZE);
+    data.resize(2 * HEAP_SIZE, 0xAB);
+    assert!(data.iter().all(|byte| *byte == 0xAB));
+    // 上限不能低于已映射的大小
+    allocator::set_heap_limit(0);
+    assert!(allocator::heap_limit() > 2 * HEAP_SIZE);
+    allocator::set_heap_limit(allocator::HEAP_MAX_SIZE);
+    drop(data);
+}
+
+#[cfg(feature = "slab-allocator")]
+#[test_case]
+fn slab_statistics() {
+    let in_use = || allocator::ALLOCATOR.lock().cache_stats()
+        .find(|stats| stats.object_size == 32).unwrap().objects_in_use;
+    let before = in_use();
+    let boxes: Vec<Box<[u8; 24]>> = (0..200).map(|_| Box::new([0; 24])).collect();
+    assert_eq!(in_use(), before + 200);
+    drop(boxes);
+    assert_eq!(in_use(), before);
+}
@@ -0,0 +1,143 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os64::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use os64::api::Errno;
+use os64::device::disk::vfs::{Descriptor, FileTable};
+use os64::parallel::ipc::{Endpoint, Message, Port, CHANNEL_CAPACITY};
+use os64::parallel::pipe::{pipe, PIPE_CAPACITY};
+use os64::parallel::process::{self, ExitStatus, SIGKILL};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os64::test_panic_handler(info)
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use os64::memory::{self, GlobalFrameAllocator, allocator, frame_allocator::BitmapFrameAllocator};
+
+    os64::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    memory::init_frame_allocator(unsafe { BitmapFrameAllocator::init(&boot_info.memory_map) });
+    allocator::init_heap(&mut mapper, &mut GlobalFrameAllocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+fn text(data : &[u8]) -> Message {
+    Message::new(data.to_vec(), vec![]).unwrap()
+}
+
+#[test_case]
+fn messages_arrive_in_order() {
+    let (left, right) = Endpoint::pair();
+    assert_eq!(right.receive(false).err(), Some(Errno::EAGAIN));
+    left.send(text(b"one"), false).unwrap();
+    left.send(text(b"two"), false).unwrap();
+    assert_eq!(right.receive(false).unwrap().data, b"one");
+    assert_eq!(right.receive(false).unwrap().data, b"two");
+    // 每个方向有自己的队列
+    right.send(text(b"back"), false).unwrap();
+    assert_eq!(left.receive(false).unwrap().data, b"back");
+}
+
+#[test_case]
+fn full_queue_and_closed_peer() {
+    let (left, right) = Endpoint::pair();
+    for _ in 0..CHANNEL_CAPACITY {
+        left.send(text(b"x"), false).unwrap();
+    }
+    assert_eq!(left.send(text(b"x"), false).err(), Some(Errno::EAGAIN));
+    assert!(right.receive_if(false, |_| Err(Errno::E2BIG)).is_err());
+    drop(left);
+    // 已排队的消息仍然可以收到
+    for _ in 0..CHANNEL_CAPACITY {
+        right.receive(true).unwrap();
+    }
+    assert_eq!(right.receive(true).err(), Some(Errno::EPIPE));
+    assert_eq!(right.send(text(b"x"), true).err(), Some(Errno::EPIPE));
+}
+
+#[test_case]
+fn the_peer_end_cannot_be_sent_through_its_own_channel() {
+    let (left, right) = Endpoint::pair();
+    let message = Message::new(vec![], vec![Descriptor::Channel(right.clone())]).unwrap();
+    assert_eq!(left.send(message, false).err(), Some(Errno::EINVAL));
+    assert!(right.is_peer_of(&left) && !left.is_peer_of(&left));
+    // 发送自己这一端可以, 对端释放时清空它所在的队列
+    let message = Message::new(vec![], vec![Descriptor::Channel(left.clone())]).unwrap();
+    left.send(message, false).unwrap();
+    assert_eq!(right.receive(false).unwrap().handles.len(), 1);
+}
+
+#[test_case]
+fn endpoints_can_be_passed_through_ports() {
+    let listener = Port::create("test.echo").unwrap();
+    assert_eq!(Port::create("test.echo").err(), Some(Errno::EEXIST));
+    let client = Port::connect("test.echo").unwrap();
+    let server = match listener.receive(false).unwrap().handles.pop() {
+        Some(Descriptor::Channel(server)) => server,
+        _ => panic!("connection without a channel"),
+    };
+    client.send(text(b"ping"), false).unwrap();
+    assert_eq!(server.receive(false).unwrap().data, b"ping");
+    drop(listener);
+    assert_eq!(Port::connect("test.echo").err(), Some(Errno::ENOENT));
+    // 监听端关闭后名字可以重新注册
+    Port::create("test.echo").unwrap();
+}
+
+#[test_case]
+fn pipes_are_bounded_and_report_closed_ends() {
+    let (reader, writer) = pipe();
+    let data = vec![7u8; PIPE_CAPACITY + 10];
+    assert_eq!(writer.write(&data, false), Ok(PIPE_CAPACITY));
+    assert_eq!(writer.write(&data, false), Err(Errno::EAGAIN));
+    assert_eq!(reader.read(10, true).unwrap().len(), 10);
+    assert_eq!(writer.write(&data, false), Ok(10));
+    drop(writer);
+    // 写入端关闭后读完剩下的数据, 然后是文件尾
+    assert_eq!(reader.read(2 * PIPE_CAPACITY, true).unwrap().len(), PIPE_CAPACITY);
+    assert!(reader.read(1, true).unwrap().is_empty());
+
+    let (reader, writer) = pipe();
+    drop(reader);
+    assert_eq!(writer.write(b"x", true), Err(Errno::EPIPE));
+}
+
+#[test_case]
+fn standard_output_can_be_redirected() {
+    let mut files = FileTable::with_stdio();
+    assert_eq!(files.len(), 3);
+    let (reader, writer) = pipe();
+    let writer : Descriptor = writer.into();
+    files.set(1, writer.clone()).unwrap();
+    drop(writer);
+    files.get(1).unwrap().write(b"hello").unwrap();
+    assert_eq!(Descriptor::from(reader).read(16).unwrap(), b"hello");
+    assert!(files.get(0).unwrap().write(b"").is_ok());
+    assert_eq!(files.get(1).unwrap().read(1).err(), Some(Errno::EBADF));
+}
+
+#[test_case]
+fn exit_status_uses_the_wait_encoding() {
+    assert_eq!(ExitStatus::Exited(3).to_raw(), 0x300);
+    assert_eq!(ExitStatus::from_raw(0x300), ExitStatus::Exited(3));
+    assert_eq!(ExitStatus::from_raw(ExitStatus::Signaled(SIGKILL).to_raw()), ExitStatus::Signaled(SIGKILL));
+    assert!(ExitStatus::Exited(0).success());
+    assert!(!ExitStatus::Signaled(SIGKILL).success());
+    assert!(process::find(u64::MAX).is_none());
+}
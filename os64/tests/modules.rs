@@ -0,0 +1,74 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os64::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use os64::parallel::{module_loader, modules::{self, ModuleInfo, ModuleLoadedInfo, ModuleSymbol}};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os64::test_panic_handler(info)
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use os64::memory::{self, GlobalFrameAllocator, allocator, frame_allocator::BitmapFrameAllocator};
+
+    os64::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    memory::init_frame_allocator(unsafe { BitmapFrameAllocator::init(&boot_info.memory_map) });
+    allocator::init_heap(&mut mapper, &mut GlobalFrameAllocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn module_in_use_cannot_be_unloaded() {
+    let mut module = ModuleLoadedInfo::new(ModuleInfo::new("provider", 1, modules::MODULE_API_VERSION));
+    module.symbols.insert("provider_function".into(), ModuleSymbol { name : "provider_function".into(), address : 0x1000, size : 16 });
+    modules::register_module(module).unwrap();
+    assert_eq!(modules::find_symbol("provider_function"), Some(("provider".into(), 0x1000)));
+
+    modules::get_module("provider").unwrap();
+    assert!(module_loader::unload_module("provider").is_err());
+    modules::put_module("provider");
+    module_loader::unload_module("provider").unwrap();
+    assert!(modules::find_symbol("provider_function").is_none());
+}
+
+/// fixtures/hello_module.c 编译出的 ET_REL 模块
+static HELLO_MODULE : &[u8] = include_bytes!("fixtures/hello_module.o");
+
+#[test_case]
+fn relocatable_module_is_linked_and_initialized() {
+    assert_eq!(module_loader::load_module(HELLO_MODULE).unwrap(), "hello");
+    // init 经过跳转桩调用了内核的 os64_print
+    let (module, state) = modules::find_symbol("hello_state").unwrap();
+    assert_eq!(module, "hello");
+    let state = state as *const i32;
+    assert_eq!(unsafe { state.read_volatile() }, 1);
+    // 经过 .data 中重定位后的函数指针调用
+    let (_, answer) = modules::find_symbol("hello_answer").unwrap();
+    let answer = unsafe { core::mem::transmute::<usize, extern "C" fn() -> i32>(answer) };
+    assert_eq!(answer(), 42);
+    assert!(module_loader::load_module(HELLO_MODULE).is_err());
+
+    module_loader::unload_module("hello").unwrap();
+    assert!(modules::find_symbol("hello_answer").is_none());
+}
+
+#[test_case]
+fn rejects_non_relocatable_objects() {
+    assert!(module_loader::load_module(&[0u8; 64]).is_err());
+    assert!(module_loader::kernel_symbol("os64_print").is_some());
+}
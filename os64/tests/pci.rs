@@ -0,0 +1,104 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os64::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::{panic::PanicInfo, sync::atomic::{AtomicUsize, Ordering}};
+use os64::device::pci::{self, PciAddress, PciDevice, PciDriver, PciMatch, CLASS_BRIDGE};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os64::test_panic_handler(info)
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use os64::memory::{self, GlobalFrameAllocator, allocator, frame_allocator::BitmapFrameAllocator};
+
+    os64::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    memory::init_frame_allocator(unsafe { BitmapFrameAllocator::init(&boot_info.memory_map) });
+    allocator::init_heap(&mut mapper, &mut GlobalFrameAllocator)
+        .expect("heap initialization failed");
+    // 没有 ACPI 时通过传统端口访问配置空间
+    pci::init(&mut mapper, &mut GlobalFrameAllocator);
+
+    test_main();
+    loop {}
+}
+
+fn device(vendor_id : u16, device_id : u16, class : u8, subclass : u8, prog_if : u8) -> PciDevice {
+    PciDevice {
+        address : PciAddress::new(0, 0, 1, 0),
+        vendor_id,
+        device_id,
+        subsystem_vendor_id : 0,
+        subsystem_id : 0,
+        class,
+        subclass,
+        prog_if,
+        revision : 0,
+        header_type : 0,
+        interrupt_line : 0,
+        interrupt_pin : 0,
+        bars : [None; 6],
+        capabilities : Vec::new(),
+        driver : None,
+    }
+}
+
+#[test_case]
+fn drivers_match_by_id_or_class() {
+    let ide = device(0x8086, 0x7010, 0x01, 0x01, 0x80);
+    assert!(PciMatch::Id { vendor_id : 0x8086, device_id : 0x7010 }.matches(&ide));
+    assert!(!PciMatch::Id { vendor_id : 0x8086, device_id : 0x7011 }.matches(&ide));
+    assert!(!PciMatch::Id { vendor_id : 0x1234, device_id : 0x7010 }.matches(&ide));
+
+    assert!(PciMatch::Class { class : 0x01, subclass : 0x01, prog_if : Some(0x80) }.matches(&ide));
+    assert!(!PciMatch::Class { class : 0x01, subclass : 0x01, prog_if : Some(0x8A) }.matches(&ide));
+    assert!(!PciMatch::Class { class : 0x01, subclass : 0x06, prog_if : None }.matches(&ide));
+    // 不指定 prog_if 时匹配所有编程接口
+    assert!(PciMatch::Class { class : 0x01, subclass : 0x01, prog_if : None }.matches(&ide));
+}
+
+/// 接管主桥的驱动, probe 中查询设备列表
+struct HostBridgeDriver {
+    probed : AtomicUsize,
+}
+
+impl PciDriver for HostBridgeDriver {
+    fn name(&self) -> &'static str {
+        "test-host-bridge"
+    }
+
+    fn id_table(&self) -> &[PciMatch] {
+        &[PciMatch::Class { class : CLASS_BRIDGE, subclass : 0x00, prog_if : None }]
+    }
+
+    fn probe(&self, device : &PciDevice) -> Result<(), &'static str> {
+        assert!(pci::devices().iter().any(|d| d.address == device.address));
+        self.probed.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+static HOST_BRIDGE_DRIVER : HostBridgeDriver = HostBridgeDriver { probed : AtomicUsize::new(0) };
+
+#[test_case]
+fn registered_drivers_are_probed_outside_the_device_lock() {
+    pci::register_driver(&HOST_BRIDGE_DRIVER);
+    let bound = pci::devices().iter().filter(|d| d.driver == Some("test-host-bridge")).count();
+    assert!(bound > 0);
+    assert_eq!(bound, HOST_BRIDGE_DRIVER.probed.load(Ordering::Relaxed));
+    // 已接管的设备不会再次 probe
+    pci::probe_drivers();
+    assert_eq!(bound, HOST_BRIDGE_DRIVER.probed.load(Ordering::Relaxed));
+}
@@ -0,0 +1,52 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os64::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use os64::device::disk::{file_system::FileOpenMode, vfs};
+use os64::parallel::{cpu, process::{ExitStatus, Process}, scheduler};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os64::test_panic_handler(info)
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use os64::memory::{self, GlobalFrameAllocator, allocator, frame_allocator::BitmapFrameAllocator};
+
+    os64::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    memory::init_frame_allocator(unsafe { BitmapFrameAllocator::init(&boot_info.memory_map) });
+    allocator::init_heap(&mut mapper, &mut GlobalFrameAllocator)
+        .expect("heap initialization failed");
+    // 测试的执行流成为一个线程, 等待进程时让出处理器
+    cpu::init_bsp();
+    scheduler::init_cpu();
+
+    test_main();
+    loop {}
+}
+
+/// fixtures/pie_app.c 编译出的 ET_DYN 程序, 去掉了节头表
+static PIE_APP : &[u8] = include_bytes!("fixtures/pie_app");
+
+#[test_case]
+fn position_independent_programs_are_relocated() {
+    let file = vfs::open("/pie_app", FileOpenMode::CREATE | FileOpenMode::WRITE).unwrap();
+    assert_eq!(vfs::write(&file, PIE_APP), Ok(PIE_APP.len()));
+    drop(file);
+
+    let process = Process::load("/pie_app").unwrap();
+    process.start();
+    // 通过重定位后的指针读出 40 和 2
+    assert_eq!(process.wait(), ExitStatus::Exited(42));
+}
@@ -0,0 +1,43 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os64::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use os64::device::{Device, serial::{COM1, CONTROL_BAUD_RATE, DEFAULT_BAUD_RATE}};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os64::test_panic_handler(info)
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use os64::memory::{self, GlobalFrameAllocator, allocator, frame_allocator::BitmapFrameAllocator};
+
+    os64::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    memory::init_frame_allocator(unsafe { BitmapFrameAllocator::init(&boot_info.memory_map) });
+    allocator::init_heap(&mut mapper, &mut GlobalFrameAllocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn serial_baud_rate_is_set_through_control() {
+    assert!(COM1.open().is_ok());
+    assert!(COM1.control(CONTROL_BAUD_RATE, 7).is_err());
+    assert!(COM1.control(CONTROL_BAUD_RATE, 0).is_err());
+    assert!(COM1.control(0xFFFF, 0).is_err());
+    assert!(COM1.control(CONTROL_BAUD_RATE, 115200).is_ok());
+    assert_eq!(COM1.baud_rate(), 115200);
+    assert!(COM1.control(CONTROL_BAUD_RATE, DEFAULT_BAUD_RATE as usize).is_ok());
+    assert_eq!(COM1.baud_rate(), DEFAULT_BAUD_RATE);
+}
@@ -0,0 +1,84 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os64::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::string::String;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use os64::device::disk::{file_system::FileOpenMode, vfs};
+use os64::shell::{self, editor::LineEditor, Shell};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os64::test_panic_handler(info)
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use os64::memory::{self, GlobalFrameAllocator, allocator, frame_allocator::BitmapFrameAllocator};
+
+    os64::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    memory::init_frame_allocator(unsafe { BitmapFrameAllocator::init(&boot_info.memory_map) });
+    allocator::init_heap(&mut mapper, &mut GlobalFrameAllocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+/// 依次输入 `input`, 返回最后得到的一行
+fn feed(editor : &mut LineEditor, input : &[u8]) -> Option<String> {
+    let mut echo = String::new();
+    input.iter().fold(None, |line, byte| editor.feed(*byte, &mut echo).or(line))
+}
+
+#[test_case]
+fn line_editor_handles_cursor_movement_and_deletion() {
+    let mut editor = LineEditor::new();
+    // 在 "lx" 的 x 之前插入 s, 删掉 x, 回车后跟着的换行被忽略
+    assert_eq!(feed(&mut editor, b"lx\x1b[Ds\x1b[3~\r\n").as_deref(), Some("ls"));
+    assert_eq!(feed(&mut editor, b"cat\x08\x7ft\r").as_deref(), Some("ct"));
+    assert_eq!(feed(&mut editor, b"abc\x03").as_deref(), Some(""));
+}
+
+#[test_case]
+fn line_editor_recalls_history() {
+    let mut editor = LineEditor::new();
+    feed(&mut editor, b"ps\r");
+    feed(&mut editor, b"mem\r");
+    feed(&mut editor, b"mem\r");
+    assert_eq!(feed(&mut editor, b"\x1b[A\x1b[A\r").as_deref(), Some("ps"));
+    // 越过最近的一条回到正在编辑的行
+    assert_eq!(feed(&mut editor, b"da\x1b[A\x1b[B\x1b[Bte\r").as_deref(), Some("date"));
+    assert!(editor.history().eq(["ps", "mem", "ps", "date"].iter().copied()));
+}
+
+#[test_case]
+fn paths_resolve_against_the_current_directory() {
+    assert_eq!(shell::resolve("/", "apps"), "/apps");
+    assert_eq!(shell::resolve("/apps/bin", "../lib/./x"), "/apps/lib/x");
+    assert_eq!(shell::resolve("/apps", "/etc//config/"), "/etc/config");
+    assert_eq!(shell::resolve("/apps", "../.."), "/");
+}
+
+#[test_case]
+fn programs_are_found_through_the_vfs() {
+    vfs::open("/tool", FileOpenMode::CREATE | FileOpenMode::WRITE).unwrap();
+    vfs::create_directory("/bin").unwrap();
+    let shell = Shell::new();
+    assert_eq!(shell.find_program("tool").as_deref(), Some("/tool"));
+    assert_eq!(shell.find_program("./tool").as_deref(), Some("/tool"));
+    // 目录不是程序, 数据盘没有挂载
+    assert_eq!(shell.find_program("bin"), None);
+    assert_eq!(shell.find_program("firstapp"), None);
+    vfs::remove("/bin").unwrap();
+    vfs::remove("/tool").unwrap();
+}
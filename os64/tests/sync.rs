@@ -0,0 +1,120 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os64::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::{future::Future, pin::Pin, task::{Context, Poll}};
+use core::panic::PanicInfo;
+use futures_util::task::noop_waker_ref;
+use os64::parallel::sync::{AsyncMutex, AsyncSemaphore, IrqSpinLock, Mutex, RwLock, Semaphore, SCHEDULER_LOCK_LEVEL};
+use x86_64::{VirtAddr, instructions::interrupts};
+
+entry_point!(main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os64::test_panic_handler(info)
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use os64::memory::{self, GlobalFrameAllocator, allocator, frame_allocator::BitmapFrameAllocator};
+
+    os64::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    memory::init_frame_allocator(unsafe { BitmapFrameAllocator::init(&boot_info.memory_map) });
+    allocator::init_heap(&mut mapper, &mut GlobalFrameAllocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+fn poll<F : Future + Unpin>(future : &mut F) -> Poll<F::Output> {
+    Pin::new(future).poll(&mut Context::from_waker(noop_waker_ref()))
+}
+
+#[test_case]
+fn irq_spin_lock_restores_interrupt_state() {
+    let lock = IrqSpinLock::new(0);
+    let enabled = interrupts::are_enabled();
+    {
+        let mut value = lock.lock();
+        *value += 1;
+        assert!(!interrupts::are_enabled());
+        assert!(lock.try_lock().is_none());
+        assert!(!interrupts::are_enabled());
+    }
+    assert_eq!(interrupts::are_enabled(), enabled);
+    assert_eq!(*lock.lock(), 1);
+}
+
+#[test_case]
+fn leveled_locks_nest_from_lower_to_higher_levels() {
+    // 与调度器同级的锁内可以分配内存和物理帧(获取更高层级的堆和帧分配器的锁)
+    let lock = IrqSpinLock::with_level(0, SCHEDULER_LOCK_LEVEL);
+    let mut value = lock.lock();
+    let boxed = Box::new(41);
+    let frame = os64::memory::allocate_zeroed_frame().expect("out of frames");
+    *value = *boxed + 1;
+    unsafe { os64::memory::deallocate_frame(frame) };
+    drop(value);
+    assert_eq!(*lock.lock(), 42);
+}
+
+#[test_case]
+fn locks_exclude_each_other() {
+    let mutex = Mutex::new(1);
+    let guard = mutex.lock();
+    assert!(mutex.try_lock().is_none());
+    drop(guard);
+    *mutex.lock() += 1;
+    assert_eq!(mutex.into_inner(), 2);
+
+    let lock = RwLock::new(0);
+    let first = lock.read();
+    let second = lock.try_read();
+    assert!(second.is_some());
+    assert!(lock.try_write().is_none());
+    drop((first, second));
+    *lock.write() = 5;
+    assert_eq!(*lock.read(), 5);
+}
+
+#[test_case]
+fn semaphores_count_permits() {
+    let semaphore = Semaphore::new(2);
+    semaphore.acquire();
+    assert!(semaphore.try_acquire());
+    assert!(!semaphore.try_acquire());
+    semaphore.release();
+    assert_eq!(semaphore.available(), 1);
+}
+
+#[test_case]
+fn async_locks_wait_for_release() {
+    let mutex = AsyncMutex::new(0);
+    let guard = mutex.try_lock().unwrap();
+    let mut waiting = Box::pin(mutex.lock());
+    assert!(poll(&mut waiting).is_pending());
+    drop(guard);
+    match poll(&mut waiting) {
+        Poll::Ready(mut guard) => *guard += 1,
+        Poll::Pending => panic!("mutex was released"),
+    }
+    drop(waiting);
+    assert_eq!(mutex.into_inner(), 1);
+
+    let semaphore = AsyncSemaphore::new(1);
+    let mut first = Box::pin(semaphore.acquire());
+    assert!(poll(&mut first).is_ready());
+    let mut second = Box::pin(semaphore.acquire());
+    assert!(poll(&mut second).is_pending());
+    semaphore.release();
+    assert!(poll(&mut second).is_ready());
+}